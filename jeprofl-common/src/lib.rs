@@ -2,60 +2,478 @@
 
 pub const MIN_ALLOC_INDEX: u32 = 0;
 pub const MAX_ALLOC_INDEX: u32 = 1;
+/// Per cpu count of the allocations `--sample-every` sampling saw, see [`is_sampled`]
 pub const COUNT_INDEX: u32 = 2;
 pub const SAMPLE_EVERY_INDEX: u32 = 3;
-pub const FUNCTION_INFO_INDEX: u32 = 4;
+pub const TRACK_FREES_INDEX: u32 = 4;
+/// Non zero to keep stacks of different threads apart, otherwise `tid` of keys is zero
+pub const GROUP_BY_THREAD_INDEX: u32 = 5;
+/// Non zero to also capture kernel stacks of allocations
+pub const KERNEL_STACKS_INDEX: u32 = 6;
+/// [`BucketScheme`] of histograms, see [`BucketScheme::to_config`]
+pub const BUCKET_SCHEME_INDEX: u32 = 7;
+pub const BUCKET_PARAM_INDEX: u32 = 8;
+/// Number of keys the ebpf side inserted into the `HISTOGRAMS` map, summed over
+/// cpus. Keys the LRU map evicted are the inserted ones neither present nor
+/// removed by userspace.
+pub const HISTOGRAMS_INSERTED_INDEX: u32 = 9;
+/// Non zero to send every allocation to the `EVENTS` ring buffer as an
+/// [`AllocEvent`] instead of aggregating it in `HISTOGRAMS`
+pub const RING_BUFFER_INDEX: u32 = 10;
+/// Number of events dropped because the ring buffer was full, summed over cpus
+pub const RING_BUFFER_DROPS_INDEX: u32 = 11;
+/// Non zero for size-biased sampling: allocations of at least this many bytes are
+/// always recorded, smaller ones every `SAMPLE_EVERY_INDEX`th time with that weight
+pub const SAMPLE_ABOVE_INDEX: u32 = 12;
+/// Non zero to send allocations as [`StackSample`]s with a copy of the user stack,
+/// which userspace unwinds with DWARF CFI, instead of frame pointer stacks. Needs
+/// `RING_BUFFER_INDEX`
+pub const DWARF_UNWIND_INDEX: u32 = 13;
+/// Number of allocations and free sites dropped because `get_stackid` failed,
+/// mostly on a full stack map, summed over cpus
+pub const STACK_ID_FAILURES_INDEX: u32 = 14;
+/// Number of allocations and frees dropped because an argument or return value
+/// couldn't be read, summed over cpus
+pub const ARG_READ_FAILURES_INDEX: u32 = 15;
+/// Number of allocations and frees dropped because an ebpf map insert failed,
+/// mostly on a full map, summed over cpus
+pub const INSERT_FAILURES_INDEX: u32 = 16;
+/// `FUNCTION_INFO_INDEX + function id` is the index of the argument holding the
+/// allocation size
+pub const FUNCTION_INFO_INDEX: u32 = 17;
+/// `SIZE_MULTIPLIER_INDEX + function id` is the index of the argument the size is
+/// multiplied by, e.g. element count of `calloc`, or [`NO_SIZE_MULTIPLIER`]
+pub const SIZE_MULTIPLIER_INDEX: u32 = FUNCTION_INFO_INDEX + MAX_FUNCTIONS;
+/// Non zero for byte based sampling: an allocation is sampled whenever the bytes a
+/// cpu allocated cross a multiple of this, see [`byte_sample_weight`]
+pub const SAMPLE_EVERY_BYTES_INDEX: u32 = SIZE_MULTIPLIER_INDEX + MAX_FUNCTIONS;
+/// Per cpu bytes allocated since the last `SAMPLE_EVERY_BYTES_INDEX` sample
+pub const SAMPLED_BYTES_INDEX: u32 = SAMPLE_EVERY_BYTES_INDEX + 1;
+/// Non zero to record how long every sampled allocation call took, by its stack
+pub const TRACK_LATENCY_INDEX: u32 = SAMPLED_BYTES_INDEX + 1;
+/// Set by userspace after every other setting, the probes record nothing before
+pub const CONFIG_READY_INDEX: u32 = TRACK_LATENCY_INDEX + 1;
+/// Bits of the `TRACK_FREES_INDEX` config value
+pub const TRACK_FREES: u64 = 1;
+pub const TRACK_FREE_SITES: u64 = 1 << 1;
+/// Returned pointers are kept only for the old size a `realloc` of them frees
+pub const TRACK_POINTERS: u64 = 1 << 2;
+pub const CONFIG_SIZE: u32 = CONFIG_READY_INDEX + 1;
 
-const MAX_TRACKED_ALLOCATION_SIZE: usize = const {
+// the ready flag is the last setting, new ones go before it
+const _: () = assert!(CONFIG_READY_INDEX == CONFIG_SIZE - 1);
+
+/// Ids of the traced allocation functions, each of them has its own ebpf program
+pub const MALLOC_ID: u32 = 0;
+pub const CALLOC_ID: u32 = 1;
+pub const REALLOC_ID: u32 = 2;
+pub const MALLOCX_ID: u32 = 3;
+pub const RALLOCX_ID: u32 = 4;
+pub const XALLOCX_ID: u32 = 5;
+pub const ALIGNED_ALLOC_ID: u32 = 6;
+/// Returns the allocation through the out-param in its first argument
+pub const POSIX_MEMALIGN_ID: u32 = 7;
+pub const MEMALIGN_ID: u32 = 8;
+pub const MAX_FUNCTIONS: u32 = 9;
+
+/// Keys hold the id of the function in the low bits of their `function_id`,
+/// and above them log2 of the alignment `mallocx`, `rallocx` and `xallocx`
+/// requested through their flags, zero without one
+pub const FUNCTION_ID_BITS: u32 = 8;
+/// `MALLOCX_LG_ALIGN` bits of the flags of jemalloc's `x` functions
+pub const MALLOCX_LG_ALIGN_MASK: u64 = 0x3f;
+
+/// `function_id` of the key of an allocation with the `flags` of an `x` function
+#[inline(always)]
+pub fn function_id_with_flags(function_id: u32, flags: u64) -> u32 {
+    function_id | (((flags & MALLOCX_LG_ALIGN_MASK) as u32) << FUNCTION_ID_BITS)
+}
+
+/// Id of the function and the requested alignment of a key's `function_id`
+pub fn split_function_id(function_id: u32) -> (u32, Option<u64>) {
+    let lg_align = (function_id >> FUNCTION_ID_BITS) & MALLOCX_LG_ALIGN_MASK as u32;
+    let alignment = (lg_align != 0).then(|| 1 << lg_align);
+    (function_id & ((1 << FUNCTION_ID_BITS) - 1), alignment)
+}
+
+pub const NO_SIZE_MULTIPLIER: u64 = u64::MAX;
+
+/// Whether the `count`th allocation a cpu saw is sampled. Every cpu counts its own
+/// allocations in `COUNT_INDEX`, so each samples `count / sample_every` of them and
+/// up to `sample_every - 1` per cpu are seen but not sampled yet. The counters
+/// are 64 bit and don't wrap in practice, they only restart from zero when
+/// another run resets them.
+#[inline(always)]
+pub fn is_sampled(count: u64, sample_every: u64) -> bool {
+    sample_every <= 1 || count.is_multiple_of(sample_every)
+}
+
+/// Adds an allocation of `size` bytes to the bytes a cpu allocated since its last
+/// sample, `accumulated`, and returns its weight if they reach `every` bytes: the
+/// number of allocations of its size `every` bytes stand for, 1 for allocations
+/// of `every` bytes and more, so every sample accounts for about `every` bytes
+/// like jemalloc's `lg_prof_sample`. `None` if it's sampled out, `every` must not
+/// be zero.
+#[inline(always)]
+pub fn byte_sample_weight(accumulated: &mut u64, size: u64, every: u64) -> Option<u64> {
+    *accumulated = accumulated.saturating_add(size);
+    if *accumulated < every {
+        return None;
+    }
+    *accumulated %= every;
+    Some(every.checked_div(size).unwrap_or(1).max(1))
+}
+
+/// Kernel stack id of allocations captured without `KERNEL_STACKS_INDEX`
+pub const NO_KERNEL_STACK: u32 = u32::MAX;
+
+/// log2 of the smallest allocation size counted in [`Histogram::overflow`] instead
+/// of a bucket, 16 GiB or 1 TiB with the `max-1tib` feature
+pub const MAX_TRACKED_ALLOCATION_SIZE: usize = const {
     const GIB: usize = 1024 * 1024 * 1024;
+    #[cfg(not(feature = "max-1tib"))]
     const MAX: usize = 16 * GIB;
+    #[cfg(feature = "max-1tib")]
+    const MAX: usize = 1024 * GIB;
     MAX.ilog2() as usize
 };
 
+/// Every power of two is split into `1 << SUB_BUCKET_BITS` buckets by [`BucketScheme::Log2Subdivided`]
+const SUB_BUCKET_BITS: u32 = 2;
+
+pub const HISTOGRAM_BUCKETS: usize = MAX_TRACKED_ALLOCATION_SIZE << SUB_BUCKET_BITS;
+
+/// How allocation sizes are mapped to histogram buckets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BucketScheme {
+    /// One bucket per power of two
+    Log2,
+    /// Every power of two is split into 4 equally wide buckets
+    Log2Subdivided,
+    /// Buckets of the given width in bytes, the last one also holds everything above it
+    Linear(u64),
+    /// One bucket per jemalloc size class, see [`jemalloc_size_class`]
+    Jemalloc,
+}
+
+/// Largest size class of jemalloc below which [`jemalloc_size_class`] spaces the
+/// classes by 16 bytes
+const JEMALLOC_TINY_MAX: u64 = 128;
+
+/// Bucket of the first size class above [`JEMALLOC_TINY_MAX`]
+const JEMALLOC_FIRST_GROUP_BUCKET: usize = 9;
+
+/// Number of jemalloc size classes up to `1 << MAX_TRACKED_ALLOCATION_SIZE`
+const JEMALLOC_BUCKETS: usize =
+    JEMALLOC_FIRST_GROUP_BUCKET + ((MAX_TRACKED_ALLOCATION_SIZE - 7) << 2);
+
+const _: () = assert!(JEMALLOC_BUCKETS <= HISTOGRAM_BUCKETS);
+
+/// Bucket of the jemalloc size class an allocation of `size` bytes is served
+/// from, with the 16 byte quantum of x86_64 and aarch64: 8, then 16 to 128 in
+/// steps of 16, then four classes per power of two, e.g. 160, 192, 224, 256.
+/// Computed like jemalloc's `sz_size2index` instead of a lookup table, which the
+/// ebpf verifier would have to walk. `size` is not zero.
+#[inline(always)]
+fn jemalloc_size_class(size: u64) -> usize {
+    if size <= 8 {
+        0
+    } else if size <= JEMALLOC_TINY_MAX {
+        size.div_ceil(16) as usize
+    } else {
+        // the group of a power of two holds its 4 classes up to and including it
+        let lg = (size - 1).ilog2() + 1;
+        let class = ((size - 1) >> (lg - 3)) + 1;
+        JEMALLOC_FIRST_GROUP_BUCKET + ((lg as usize - 8) << 2) + (class as usize - 5)
+    }
+}
+
+impl BucketScheme {
+    /// Values of the `BUCKET_SCHEME_INDEX` and `BUCKET_PARAM_INDEX` config slots
+    pub fn to_config(&self) -> (u64, u64) {
+        match self {
+            Self::Log2 => (0, 0),
+            Self::Log2Subdivided => (1, 0),
+            Self::Linear(width) => (2, *width),
+            Self::Jemalloc => (3, 0),
+        }
+    }
+
+    pub fn from_config(scheme: u64, param: u64) -> Self {
+        match scheme {
+            1 => Self::Log2Subdivided,
+            // zero width would divide by zero
+            2 => Self::Linear(if param == 0 { 1 } else { param }),
+            3 => Self::Jemalloc,
+            _ => Self::Log2,
+        }
+    }
+
+    /// Number of buckets the scheme uses
+    pub fn bucket_count(&self) -> usize {
+        match self {
+            Self::Log2 => MAX_TRACKED_ALLOCATION_SIZE,
+            Self::Log2Subdivided | Self::Linear(_) => HISTOGRAM_BUCKETS,
+            // up to the class of `1 << MAX_TRACKED_ALLOCATION_SIZE`
+            Self::Jemalloc => JEMALLOC_BUCKETS,
+        }
+    }
+
+    /// Bucket of a non zero `value`, `None` if it's too large for the histogram
+    pub fn bucket(&self, value: u64) -> Option<usize> {
+        let pow2 = value.ilog2();
+        let too_large = match self {
+            Self::Linear(_) => false,
+            // classes round up, the largest tracked power of two is the last class
+            Self::Jemalloc => value > 1 << MAX_TRACKED_ALLOCATION_SIZE,
+            _ => pow2 as usize >= MAX_TRACKED_ALLOCATION_SIZE,
+        };
+        if too_large {
+            return None;
+        }
+        let bucket = match self {
+            Self::Log2 => pow2 as usize,
+            Self::Log2Subdivided => {
+                // the bits right after the leading one select the sub bucket
+                let sub = if pow2 >= SUB_BUCKET_BITS {
+                    value >> (pow2 - SUB_BUCKET_BITS)
+                } else {
+                    value << (SUB_BUCKET_BITS - pow2)
+                } & ((1 << SUB_BUCKET_BITS) - 1);
+                ((pow2 as usize) << SUB_BUCKET_BITS) | sub as usize
+            }
+            Self::Linear(width) => {
+                let bucket = value / width;
+                if bucket >= HISTOGRAM_BUCKETS as u64 {
+                    HISTOGRAM_BUCKETS - 1
+                } else {
+                    bucket as usize
+                }
+            }
+            Self::Jemalloc => jemalloc_size_class(value),
+        };
+        Some(bucket)
+    }
+
+    /// Smallest and largest size of the bucket, both inclusive
+    pub fn bounds(&self, bucket: usize) -> (u64, u64) {
+        match self {
+            Self::Log2 => (1 << bucket, (1 << (bucket + 1)) - 1),
+            Self::Log2Subdivided => {
+                let pow2 = (bucket >> SUB_BUCKET_BITS) as u32;
+                let sub = (bucket & ((1 << SUB_BUCKET_BITS) - 1)) as u64;
+                if pow2 >= SUB_BUCKET_BITS {
+                    let width = 1u64 << (pow2 - SUB_BUCKET_BITS);
+                    let lower = (1 << pow2) + sub * width;
+                    (lower, lower + width - 1)
+                } else {
+                    // small powers have fewer values than sub buckets
+                    let lower = (1 << pow2) + (sub >> (SUB_BUCKET_BITS - pow2));
+                    (lower, lower)
+                }
+            }
+            Self::Linear(width) => {
+                let lower = (bucket as u64).saturating_mul(*width);
+                if bucket == HISTOGRAM_BUCKETS - 1 {
+                    (lower, u64::MAX)
+                } else {
+                    (lower, lower.saturating_add(width - 1))
+                }
+            }
+            Self::Jemalloc => match bucket {
+                0 => (1, 8),
+                1..JEMALLOC_FIRST_GROUP_BUCKET => {
+                    let class = bucket as u64 * 16;
+                    // the 16 byte class starts after the 8 byte one
+                    (if bucket == 1 { 9 } else { class - 15 }, class)
+                }
+                _ => {
+                    let group = bucket - JEMALLOC_FIRST_GROUP_BUCKET;
+                    let spacing = 1u64 << (group / 4 + 5);
+                    let class = (group % 4 + 5) as u64 * spacing;
+                    (class - spacing + 1, class)
+                }
+            },
+        }
+    }
+
+    /// Size the bucket is labeled with: the size class for [`Self::Jemalloc`],
+    /// which is what the allocations occupy, otherwise the lower bound
+    pub fn label(&self, bucket: usize) -> u64 {
+        let (lower, upper) = self.bounds(bucket);
+        match self {
+            Self::Jemalloc => upper,
+            _ => lower,
+        }
+    }
+}
+
+impl core::fmt::Display for BucketScheme {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Log2 => write!(f, "log2"),
+            Self::Log2Subdivided => write!(f, "log2-subdivided"),
+            Self::Linear(width) => write!(f, "linear:{width}"),
+            Self::Jemalloc => write!(f, "jemalloc"),
+        }
+    }
+}
+
+impl core::str::FromStr for BucketScheme {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log2" => Ok(Self::Log2),
+            "log2-subdivided" => Ok(Self::Log2Subdivided),
+            "jemalloc" => Ok(Self::Jemalloc),
+            _ => {
+                let width = s
+                    .strip_prefix("linear:")
+                    .ok_or("expected log2, log2-subdivided, jemalloc or linear:<WIDTH>")?;
+                match width.parse::<u64>() {
+                    Ok(width) if width > 0 => Ok(Self::Linear(width)),
+                    _ => Err("linear bucket width must be a positive number of bytes"),
+                }
+            }
+        }
+    }
+}
+
+/// Whether an allocation of `size` bytes passes the `MIN_ALLOC_INDEX`..=`MAX_ALLOC_INDEX`
+/// filter, both bounds are inclusive
+pub fn is_size_tracked(size: u64, min_size: u64, max_size: u64) -> bool {
+    size >= min_size && size <= max_size
+}
+
+/// Key of the `HISTOGRAMS` map. The cpu isn't part of it, the per cpu map
+/// keeps the allocations of every cpu apart, so a stack takes one entry.
 #[repr(C)]
 #[derive(Clone, Debug, Copy, Hash, PartialEq, Eq)]
 pub struct HistogramKey {
-    pid_stack: u64,
-    cpu: u64, // for alignment
+    pid: u32,
+    stack_id: u32,
+    tid: u32,
+    kernel_stack_id: u32,
+    function_id: u32,
 }
 
+// map keys are compared bytewise, padding would hold garbage of the ebpf stack
+const _: () = assert!(core::mem::size_of::<HistogramKey>() == 5 * 4);
+
 impl HistogramKey {
-    pub fn new(pid: u32, stack_id: u32, cpu: u32) -> Self {
+    pub fn new(key: ReducedEventKey) -> Self {
         Self {
-            pid_stack: ((pid as u64) << 32 | stack_id as u64),
-            cpu: cpu as u64,
+            pid: key.pid,
+            stack_id: key.stack_id,
+            tid: key.tid,
+            kernel_stack_id: key.kernel_stack_id,
+            function_id: key.function_id,
         }
     }
 
-    pub fn into_parts(&self) -> UnpackedHistogramKey {
-        let pid = (self.pid_stack >> 32) as u32;
-        let stack_id = self.pid_stack as u32;
+    /// The key with another user stack
+    pub fn with_stack_id(&self, stack_id: u32) -> Self {
+        Self { stack_id, ..*self }
+    }
+
+    pub fn reduced(&self) -> ReducedEventKey {
+        ReducedEventKey {
+            pid: self.pid,
+            tid: self.tid,
+            stack_id: self.stack_id,
+            kernel_stack_id: self.kernel_stack_id,
+            function_id: self.function_id,
+        }
+    }
+
+    /// The key of the allocations of the `cpu`, the index of its per cpu value
+    pub fn into_parts(&self, cpu: u32) -> UnpackedHistogramKey {
         UnpackedHistogramKey {
-            pid,
-            stack_id,
-            cpu: self.cpu as u32,
+            pid: self.pid,
+            tid: self.tid,
+            stack_id: self.stack_id,
+            kernel_stack_id: self.kernel_stack_id,
+            function_id: self.function_id,
+            cpu,
         }
     }
 }
 
+/// Allocation sent through the ring buffer, `delta` is added `weight` times to the
+/// histogram of `key`
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub struct AllocEvent {
+    pub key: HistogramKey,
+    /// Cpu the allocation ran on
+    pub cpu: u32,
+    pub delta: i64,
+    pub weight: u64,
+    /// `CLOCK_MONOTONIC` time of the allocation
+    pub timestamp_ns: u64,
+}
+
+/// Bytes of the user stack [`StackSample`]s carry, enough for the frames of
+/// typical call chains
+pub const STACK_COPY_SIZE: usize = 16 * 1024;
+
+/// Allocation sent through the ring buffer with `DWARF_UNWIND_INDEX`, followed by
+/// `stack_len` bytes of the user stack from `sp` up
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub struct StackSampleHeader {
+    /// `stack_id` of the key is zero, userspace numbers the unwound stacks
+    pub event: AllocEvent,
+    /// Instruction, stack and frame pointer of the probed function
+    pub ip: u64,
+    pub sp: u64,
+    pub bp: u64,
+    /// Zero if the stack couldn't be read
+    pub stack_len: u64,
+}
+
+#[repr(C)]
+pub struct StackSample {
+    pub header: StackSampleHeader,
+    pub stack: [u8; STACK_COPY_SIZE],
+}
+
 #[derive(Clone, Debug, Copy, Hash, Eq, PartialEq)]
 pub struct UnpackedHistogramKey {
     pub pid: u32,
+    pub tid: u32,
     pub stack_id: u32,
+    pub kernel_stack_id: u32,
+    pub function_id: u32,
     pub cpu: u32,
 }
 
+#[repr(C)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ReducedEventKey {
     pub pid: u32,
+    /// zero when stacks are grouped per process
+    pub tid: u32,
     pub stack_id: u32,
+    /// [`NO_KERNEL_STACK`] unless kernel stacks are captured
+    pub kernel_stack_id: u32,
+    /// Allocation function, one of the `*_ID` constants, with the alignment of
+    /// the `x` functions, see [`split_function_id`]
+    pub function_id: u32,
 }
 
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReducedEventKey {}
+
 impl UnpackedHistogramKey {
     pub fn as_reduced(&self) -> ReducedEventKey {
         ReducedEventKey {
             pid: self.pid,
+            tid: self.tid,
             stack_id: self.stack_id,
+            kernel_stack_id: self.kernel_stack_id,
+            function_id: self.function_id,
         }
     }
 }
@@ -63,46 +481,293 @@ impl UnpackedHistogramKey {
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for HistogramKey {}
 
+/// Allocation recorded by the entry probe, waiting for the returned pointer.
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
-pub struct Histogram {
-    pub data: [u64; MAX_TRACKED_ALLOCATION_SIZE],
+pub struct AllocInfo {
+    pub pid: u32,
+    pub tid: u32,
+    pub stack_id: u32,
+    pub kernel_stack_id: u32,
+    pub function_id: u32,
+    /// Number of allocations this sampled one stands for
+    pub weight: u32,
+    pub size: u64,
+}
+
+impl AllocInfo {
+    pub fn new(key: ReducedEventKey, size: u64, weight: u32) -> Self {
+        Self {
+            pid: key.pid,
+            tid: key.tid,
+            stack_id: key.stack_id,
+            kernel_stack_id: key.kernel_stack_id,
+            function_id: key.function_id,
+            weight,
+            size,
+        }
+    }
+
+    pub fn key(&self) -> ReducedEventKey {
+        ReducedEventKey {
+            pid: self.pid,
+            tid: self.tid,
+            stack_id: self.stack_id,
+            kernel_stack_id: self.kernel_stack_id,
+            function_id: self.function_id,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AllocInfo {}
+
+/// Pair of allocation and free sites for the memory that flowed between them.
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Hash, PartialEq, Eq)]
+pub struct FreeSiteKey {
+    pub pid: u32,
+    /// thread which allocated the memory, zero when stacks are grouped per process
+    pub tid: u32,
+    pub alloc_stack_id: u32,
+    pub alloc_kernel_stack_id: u32,
+    pub alloc_function_id: u32,
+    pub free_stack_id: u32,
+}
+
+impl FreeSiteKey {
+    pub fn new(alloc: &AllocInfo, free_stack_id: u32) -> Self {
+        Self {
+            pid: alloc.pid,
+            tid: alloc.tid,
+            alloc_stack_id: alloc.stack_id,
+            alloc_kernel_stack_id: alloc.kernel_stack_id,
+            alloc_function_id: alloc.function_id,
+            free_stack_id,
+        }
+    }
+
+    pub fn alloc_key(&self) -> ReducedEventKey {
+        ReducedEventKey {
+            pid: self.pid,
+            tid: self.tid,
+            stack_id: self.alloc_stack_id,
+            kernel_stack_id: self.alloc_kernel_stack_id,
+            function_id: self.alloc_function_id,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FreeSiteKey {}
+
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+pub struct FreeSiteStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FreeSiteStats {}
+
+impl FreeSiteStats {
+    pub fn merge(&mut self, other: &FreeSiteStats) {
+        self.count = self.count.saturating_add(other.count);
+        self.bytes = self.bytes.saturating_add(other.bytes);
+    }
+}
+
+/// Memory which was allocated but not freed yet. Memory freed on another cpu
+/// makes per cpu values negative, only their sum is meaningful.
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+pub struct LiveStats {
+    pub bytes: i64,
+    pub count: i64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LiveStats {}
+
+impl LiveStats {
+    pub fn merge(&mut self, other: &LiveStats) {
+        self.bytes = self.bytes.wrapping_add(other.bytes);
+        self.count = self.count.wrapping_add(other.count);
+    }
+}
+
+/// Allocation sizes of a stack in `N` buckets. The ebpf and the userspace side
+/// share the default [`HISTOGRAM_BUCKETS`], so the map values agree; sizes of
+/// buckets past `N` count as overflow.
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub struct Histogram<const N: usize = HISTOGRAM_BUCKETS> {
+    /// Counts of the buckets of the [`BucketScheme`] the histogram was recorded with
+    pub data: [u64; N],
+    /// Allocations of `1 << MAX_TRACKED_ALLOCATION_SIZE` bytes and more, which don't
+    /// fit any bucket. Always zero for [`BucketScheme::Linear`].
+    pub overflow: u64,
+    /// Sum of the allocated bytes, not a count, see [`Histogram::total_bytes`]
     pub total: u64,
 }
 
+// the buckets are followed by `overflow` and `total` without padding
+const _: () = assert!(core::mem::size_of::<Histogram>() == (HISTOGRAM_BUCKETS + 2) * 8);
+
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for Histogram {}
+unsafe impl<const N: usize> aya::Pod for Histogram<N> {}
 
 impl Histogram {
     #[allow(clippy::new_without_default)]
     pub const fn new() -> Self {
+        Self::zeroed()
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    /// Empty histogram of any bucket count, [`Histogram::new`] has the shared one
+    pub const fn zeroed() -> Self {
         Self {
-            data: [0; MAX_TRACKED_ALLOCATION_SIZE],
+            data: [0; N],
+            overflow: 0,
             total: 0,
         }
     }
 
+    /// Records an allocation in [`BucketScheme::Log2`] buckets
     pub fn increment(&mut self, value: u64) {
+        self.record(value, BucketScheme::Log2);
+    }
+
+    pub fn record(&mut self, value: u64, scheme: BucketScheme) {
+        self.record_weighted(value, 1, scheme);
+    }
+
+    /// Records `weight` allocations of `value` bytes at once, for a sampled
+    /// allocation standing for `weight` of them
+    pub fn record_weighted(&mut self, value: u64, weight: u64, scheme: BucketScheme) {
         if value == 0 {
             // log(0) is undefined
             return;
         }
-        let pow2 = value.ilog2() as usize;
+        match scheme
+            .bucket(value)
+            .and_then(|bucket| self.data.get_mut(bucket))
+        {
+            Some(bucket) => *bucket = bucket.saturating_add(weight),
+            None => self.overflow = self.overflow.saturating_add(weight),
+        }
+        self.total = self.total.saturating_add(value.saturating_mul(weight));
+    }
 
-        if let Some(bucket) = self.data.get_mut(pow2) {
-            *bucket += 1;
+    /// Records a size change of `delta` bytes. Growth is recorded as an allocation of
+    /// `delta` bytes, shrinking only lowers the total.
+    pub fn add_delta(&mut self, delta: i64, scheme: BucketScheme) {
+        self.add_weighted_delta(delta, 1, scheme);
+    }
+
+    /// [`Histogram::add_delta`] for a sampled size change standing for `weight` of them
+    pub fn add_weighted_delta(&mut self, delta: i64, weight: u64, scheme: BucketScheme) {
+        if delta >= 0 {
+            self.record_weighted(delta as u64, weight, scheme);
+        } else {
+            self.total = self
+                .total
+                .saturating_sub(delta.unsigned_abs().saturating_mul(weight));
         }
-        self.total = self.total.saturating_add(value);
     }
 
-    pub fn merge(&mut self, other: &Histogram) {
+    pub fn merge(&mut self, other: &Histogram<N>) {
         self.total = self.total.saturating_add(other.total);
+        self.overflow = self.overflow.saturating_add(other.overflow);
         for (l, r) in self.data.iter_mut().zip(other.data.iter()) {
             *l = l.saturating_add(*r);
         }
     }
 
-    pub fn total_count(&self) -> u64 {
-        self.data.iter().sum()
+    /// Adds what `current` gained since `prev`, an earlier snapshot of the same histogram.
+    /// Buckets only grow, so a bucket below its snapshot means the map entry was cleared
+    /// or evicted in between and `current` is added as a whole.
+    pub fn merge_delta(&mut self, current: &Histogram<N>, prev: &Histogram<N>) {
+        let was_reset = current
+            .data
+            .iter()
+            .zip(prev.data.iter())
+            .any(|(c, p)| c < p);
+        if was_reset || current.overflow < prev.overflow {
+            self.merge(current);
+            return;
+        }
+        self.overflow = self
+            .overflow
+            .saturating_add(current.overflow - prev.overflow);
+        for (l, (c, p)) in self
+            .data
+            .iter_mut()
+            .zip(current.data.iter().zip(prev.data.iter()))
+        {
+            *l = l.saturating_add(c - p);
+        }
+        // shrinking reallocs lower the total
+        if current.total >= prev.total {
+            self.total = self.total.saturating_add(current.total - prev.total);
+        } else {
+            self.total = self.total.saturating_sub(prev.total - current.total);
+        }
+    }
+
+    /// Multiplies every bucket and the total by `factor`, turning sampled numbers into
+    /// estimates of the real traffic.
+    pub fn scale(&mut self, factor: u64) {
+        self.total = self.total.saturating_mul(factor);
+        self.overflow = self.overflow.saturating_mul(factor);
+        for bucket in self.data.iter_mut() {
+            *bucket = bucket.saturating_mul(factor);
+        }
+    }
+
+    /// Number of recorded allocations, including the overflowing ones
+    pub fn allocation_count(&self) -> u64 {
+        self.data
+            .iter()
+            .fold(self.overflow, |count, bucket| count.saturating_add(*bucket))
+    }
+
+    /// A bucket, the count or the total reached `u64::MAX` and stopped growing,
+    /// the numbers of the histogram are lower bounds
+    pub fn is_saturated(&self) -> bool {
+        self.total == u64::MAX || self.allocation_count() == u64::MAX
+    }
+
+    /// Sum of the sizes of the recorded allocations
+    pub fn total_bytes(&self) -> u64 {
+        self.total
+    }
+
+    /// Smallest size of the bucket holding the `p` (`0.0..=1.0`) quantile of allocation
+    /// sizes, zero for an empty histogram
+    #[cfg(feature = "user")]
+    pub fn percentile(&self, p: f64, scheme: BucketScheme) -> u64 {
+        let count = self.allocation_count();
+        if count == 0 {
+            return 0;
+        }
+        // rank of the allocation at the quantile, rounded up without `f64::ceil` of std
+        let exact = p.clamp(0.0, 1.0) * count as f64;
+        let mut rank = exact as u64;
+        if (rank as f64) < exact {
+            rank += 1;
+        }
+        let rank = rank.clamp(1, count);
+
+        let mut seen = 0u64;
+        for (bucket, bucket_count) in self.data.iter().enumerate() {
+            seen = seen.saturating_add(*bucket_count);
+            if seen >= rank {
+                return scheme.label(bucket);
+            }
+        }
+        1 << MAX_TRACKED_ALLOCATION_SIZE
     }
 }
@@ -0,0 +1,17 @@
+use std::env;
+
+/// Sets `bpf_target_arch` like aya-ebpf does, the registers of `pt_regs` are
+/// named differently on every architecture
+fn main() {
+    println!("cargo::rerun-if-env-changed=CARGO_CFG_BPF_TARGET_ARCH");
+    println!("cargo::rustc-check-cfg=cfg(bpf_target_arch, values(\"x86_64\", \"arm\", \"aarch64\", \"riscv64\"))");
+    let arch = match env::var("CARGO_CFG_BPF_TARGET_ARCH") {
+        Ok(arch) => arch,
+        Err(_) => {
+            let host = env::var("HOST").unwrap();
+            host.split_once('-')
+                .map_or(host.clone(), |(arch, _)| arch.to_string())
+        }
+    };
+    println!("cargo::rustc-cfg=bpf_target_arch=\"{arch}\"");
+}
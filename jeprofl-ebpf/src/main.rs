@@ -1,77 +1,624 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::bindings::BPF_F_USER_STACK;
-use aya_ebpf::helpers::bpf_get_smp_processor_id;
+use aya_ebpf::bindings::{BPF_F_USER_STACK, BPF_NOEXIST};
+use aya_ebpf::cty::c_long;
+use aya_ebpf::helpers::{
+    bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_user, bpf_probe_read_user_buf,
+};
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::{PerCpuArray, PerCpuHashMap, StackTrace};
+use aya_ebpf::macros::uretprobe;
+use aya_ebpf::maps::{HashMap, LruPerCpuHashMap, PerCpuArray, PerCpuHashMap, RingBuf, StackTrace};
 use aya_ebpf::{helpers::bpf_get_current_pid_tgid, macros::uprobe, programs::ProbeContext};
 use jeprofl_common::{
-    Histogram, HistogramKey, COUNT_INDEX, FUNCTION_INFO_INDEX, MAX_ALLOC_INDEX, MIN_ALLOC_INDEX,
-    SAMPLE_EVERY_INDEX,
+    byte_sample_weight, function_id_with_flags, is_sampled, is_size_tracked, AllocEvent, AllocInfo,
+    BucketScheme, FreeSiteKey, FreeSiteStats, Histogram, HistogramKey, LiveStats, ReducedEventKey,
+    StackSample, StackSampleHeader, ALIGNED_ALLOC_ID, ARG_READ_FAILURES_INDEX, BUCKET_PARAM_INDEX,
+    BUCKET_SCHEME_INDEX, CALLOC_ID, CONFIG_READY_INDEX, CONFIG_SIZE, COUNT_INDEX,
+    DWARF_UNWIND_INDEX, FUNCTION_INFO_INDEX, GROUP_BY_THREAD_INDEX, HISTOGRAMS_INSERTED_INDEX,
+    INSERT_FAILURES_INDEX, KERNEL_STACKS_INDEX, MALLOCX_ID, MALLOC_ID, MAX_ALLOC_INDEX,
+    MEMALIGN_ID, MIN_ALLOC_INDEX, NO_KERNEL_STACK, NO_SIZE_MULTIPLIER, POSIX_MEMALIGN_ID,
+    RALLOCX_ID, REALLOC_ID, RING_BUFFER_DROPS_INDEX, RING_BUFFER_INDEX, SAMPLED_BYTES_INDEX,
+    SAMPLE_ABOVE_INDEX, SAMPLE_EVERY_BYTES_INDEX, SAMPLE_EVERY_INDEX, SIZE_MULTIPLIER_INDEX,
+    STACK_COPY_SIZE, STACK_ID_FAILURES_INDEX, TRACK_FREES, TRACK_FREES_INDEX, TRACK_FREE_SITES,
+    TRACK_LATENCY_INDEX, XALLOCX_ID,
 };
 
 #[map(name = "CONFIG")]
-static STATE: PerCpuArray<u64> = PerCpuArray::with_max_entries(5, 0);
+static STATE: PerCpuArray<u64> = PerCpuArray::with_max_entries(CONFIG_SIZE, 0);
 
 #[map(name = "STACKTRACES")]
 static mut STACKTRACES: StackTrace = StackTrace::with_max_entries(1024 * 1024, 0);
 
+/// Shrunk by userspace unless kernel stacks are captured
+#[map(name = "KERNEL_STACKTRACES")]
+static mut KERNEL_STACKTRACES: StackTrace = StackTrace::with_max_entries(64 * 1024, 0);
+
+/// Least recently updated stacks are evicted when the map is full, so new hot
+/// stacks are still captured
 #[map(name = "HISTOGRAMS")]
-static mut HISTOGRAMS: PerCpuHashMap<HistogramKey, Histogram> = // pid, stack_id to histogram
+static mut HISTOGRAMS: LruPerCpuHashMap<HistogramKey, Histogram> =
+    // pid, stack_id to histogram
+    LruPerCpuHashMap::with_max_entries(1024 * 1024, 0);
+
+/// Allocations are sent here instead of `HISTOGRAMS` with `RING_BUFFER_INDEX`,
+/// resized by userspace
+#[map(name = "EVENTS")]
+static EVENTS: RingBuf = RingBuf::with_byte_size(16 * 1024 * 1024, 0);
+
+/// Histograms don't fit the ebpf stack, new ones are copied from here
+static EMPTY_HISTOGRAM: Histogram = Histogram::new();
+
+/// Allocation which entered the allocator but didn't return yet
+#[derive(Clone, Copy)]
+struct PendingAlloc {
+    info: AllocInfo,
+    /// pointer passed to `realloc`-shaped functions, zero for the rest
+    old_ptr: u64,
+    /// where `posix_memalign` writes the allocation, zero for the rest
+    out_ptr: u64,
+    /// `bpf_ktime_get_ns` at the entry with `TRACK_LATENCY_INDEX`, zero otherwise
+    entered_ns: u64,
+}
+
+/// tid -> allocation which entered the allocator but didn't return yet
+#[map(name = "INFLIGHT")]
+static INFLIGHT: HashMap<u32, PendingAlloc> = HashMap::with_max_entries(64 * 1024, 0);
+
+/// returned pointer -> allocation it came from, resized by userspace
+#[map(name = "ALLOCATIONS")]
+static ALLOCATIONS: HashMap<u64, AllocInfo> = HashMap::with_max_entries(1024 * 1024, 0);
+
+#[map(name = "LIVE")]
+static LIVE: PerCpuHashMap<ReducedEventKey, LiveStats> =
+    PerCpuHashMap::with_max_entries(1024 * 1024, 0);
+
+#[map(name = "FREE_SITES")]
+static FREE_SITES: PerCpuHashMap<FreeSiteKey, FreeSiteStats> =
     PerCpuHashMap::with_max_entries(1024 * 1024, 0);
 
+/// Nanoseconds between the entry and the return of the allocation calls of
+/// every stack, shrunk by userspace unless `TRACK_LATENCY_INDEX` is set
+#[map(name = "LATENCIES")]
+static LATENCIES: PerCpuHashMap<ReducedEventKey, Histogram> =
+    PerCpuHashMap::with_max_entries(64 * 1024, 0);
+
 #[uprobe]
 pub fn malloc(ctx: ProbeContext) -> u32 {
-    try_malloc(ctx).unwrap_or_else(|ret| ret)
+    try_malloc(ctx, MALLOC_ID).unwrap_or_else(|ret| ret)
 }
 
-fn try_malloc(ctx: ProbeContext) -> Result<u32, u32> {
-    unsafe {
-        const ARG_INDEX: usize = 0;
-        if !should_process() {
-            return Ok(0);
-        }
-        // todo: somehow make solver to believe that arg lies in the range [0, 3] to allow dynamic profiling
-        // let Some(arg_index) = STATE.get(FUNCTION_INFO_INDEX).copied() else {
-        //     return Err(0);
-        // };
-        //
-        // let Some(arg_index) = STATE.get(FUNCTION_INFO_INDEX).copied() else {
-        //     return Err(0);
-        // };
-        //
-        // if !check_bounds_unsigned(arg_index as _, 0, 3) {
-        //     return Err(0);
-        // }
-        //
-        // let arg_index = arg_index as usize;
-
-        let size = match ctx.arg::<u64>(ARG_INDEX) {
-            Some(s) => s,
-            None => return Err(0),
+#[uprobe]
+pub fn calloc(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, CALLOC_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn mallocx(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, MALLOCX_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn xallocx(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, XALLOCX_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn aligned_alloc(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, ALIGNED_ALLOC_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn posix_memalign(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, POSIX_MEMALIGN_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn memalign(ctx: ProbeContext) -> u32 {
+    try_malloc(ctx, MEMALIGN_ID).unwrap_or_else(|ret| ret)
+}
+
+/// Entry of functions returning a new allocation, the size is read from the
+/// arguments configured for `function_id`
+fn try_malloc(ctx: ProbeContext, function_id: u32) -> Result<u32, u32> {
+    if !config_ready() {
+        return Ok(0);
+    }
+    let size_index = STATE
+        .get(FUNCTION_INFO_INDEX + function_id)
+        .copied()
+        .unwrap_or(0);
+    let mut size = read_arg(&ctx, size_index).ok_or_else(|| arg_read_failure(0))?;
+    let multiplier_index = STATE
+        .get(SIZE_MULTIPLIER_INDEX + function_id)
+        .copied()
+        .unwrap_or(NO_SIZE_MULTIPLIER);
+    if multiplier_index != NO_SIZE_MULTIPLIER {
+        let multiplier = read_arg(&ctx, multiplier_index).ok_or_else(|| arg_read_failure(0))?;
+        size = size.saturating_mul(multiplier);
+    }
+    let Some(weight) = sample_weight(size) else {
+        return Ok(0);
+    };
+
+    let key_function_id = key_function_id(&ctx, function_id)?;
+    let Some(info) = capture_allocation(&ctx, size, key_function_id, weight)? else {
+        return Ok(0);
+    };
+
+    unsafe { update_hist(&ctx, size as i64, &info)? };
+
+    // `xallocx` resizes in place and returns the size, it has no pointer to track
+    let tracks_pointer = tracked_frees() != 0 && function_id != XALLOCX_ID;
+    if tracks_pointer || tracks_latency() {
+        let tid = bpf_get_current_pid_tgid() as u32;
+        let out_ptr = match function_id {
+            POSIX_MEMALIGN_ID => ctx.arg(0).ok_or_else(|| arg_read_failure(0))?,
+            _ => 0,
+        };
+        let pending = PendingAlloc {
+            info,
+            old_ptr: 0,
+            out_ptr,
+            entered_ns: entry_time(),
         };
+        INFLIGHT.insert(&tid, &pending, 0).map_err(insert_failure)?;
+    }
+
+    Ok(0)
+}
+
+/// Entry of `realloc`-shaped functions: `(ptr, size, ...)`. Only the size
+/// difference to the old allocation is known on return, so accounting happens
+/// in [`realloc_ret`].
+#[uprobe]
+pub fn realloc(ctx: ProbeContext) -> u32 {
+    try_realloc(ctx, REALLOC_ID).unwrap_or_else(|ret| ret)
+}
+
+#[uprobe]
+pub fn rallocx(ctx: ProbeContext) -> u32 {
+    try_realloc(ctx, RALLOCX_ID).unwrap_or_else(|ret| ret)
+}
 
-        let min_size = *STATE.get(MIN_ALLOC_INDEX).unwrap_or(&0);
-        let max_size = *STATE.get(MAX_ALLOC_INDEX).unwrap_or(&u64::MAX);
+fn try_realloc(ctx: ProbeContext, function_id: u32) -> Result<u32, u32> {
+    if !config_ready() {
+        return Ok(0);
+    }
+    let old_ptr: u64 = ctx.arg(0).ok_or_else(|| arg_read_failure(0))?;
+    let size: u64 = ctx.arg(1).ok_or_else(|| arg_read_failure(0))?;
+    let Some(weight) = sample_weight(size) else {
+        return Ok(0);
+    };
+
+    let key_function_id = key_function_id(&ctx, function_id)?;
+    let Some(info) = capture_allocation(&ctx, size, key_function_id, weight)? else {
+        return Ok(0);
+    };
 
-        if size <= min_size || size >= max_size {
-            return Ok(0);
+    let tid = bpf_get_current_pid_tgid() as u32;
+    let pending = PendingAlloc {
+        info,
+        old_ptr,
+        out_ptr: 0,
+        entered_ns: entry_time(),
+    };
+    INFLIGHT.insert(&tid, &pending, 0).map_err(insert_failure)?;
+    Ok(0)
+}
+
+/// `function_id` of the key, for the `x` functions with the alignment their
+/// flags request
+fn key_function_id(ctx: &ProbeContext, function_id: u32) -> Result<u32, u32> {
+    // mallocx(size, flags), rallocx(ptr, size, flags), xallocx(ptr, size, extra, flags)
+    let flags_index = match function_id {
+        MALLOCX_ID => 1,
+        RALLOCX_ID => 2,
+        XALLOCX_ID => 3,
+        _ => return Ok(function_id),
+    };
+    let flags = read_arg(ctx, flags_index).ok_or_else(|| arg_read_failure(0))?;
+    Ok(function_id_with_flags(function_id, flags))
+}
+
+/// Reads the argument with a runtime index. The verifier rejects loads from a
+/// computed context offset, so every argument is loaded at a constant offset and
+/// only then selected.
+fn read_arg(ctx: &ProbeContext, index: u64) -> Option<u64> {
+    let args: [Option<u64>; 4] = [ctx.arg(0), ctx.arg(1), ctx.arg(2), ctx.arg(3)];
+    match index {
+        0 => args[0],
+        1 => args[1],
+        2 => args[2],
+        3 => args[3],
+        _ => None,
+    }
+}
+
+/// Applies the size filter and captures the stack of an allocation of `size` bytes.
+/// Returns `None` for filtered allocations.
+fn capture_allocation(
+    ctx: &ProbeContext,
+    size: u64,
+    function_id: u32,
+    weight: u32,
+) -> Result<Option<AllocInfo>, u32> {
+    let min_size = *STATE.get(MIN_ALLOC_INDEX).unwrap_or(&0);
+    let max_size = *STATE.get(MAX_ALLOC_INDEX).unwrap_or(&u64::MAX);
+
+    if !is_size_tracked(size, min_size, max_size) {
+        return Ok(None);
+    }
+
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32; // tgid, what userspace calls pid
+    let tid = match STATE.get(GROUP_BY_THREAD_INDEX) {
+        Some(v) if *v != 0 => pid_tgid as u32,
+        _ => 0,
+    };
+    let stack_id = if dwarf_unwind() {
+        // userspace unwinds the copied stack
+        0
+    } else {
+        match unsafe { STACKTRACES.get_stackid(ctx, BPF_F_USER_STACK.into()) } {
+            Ok(stack_id) => stack_id as u32, // userspace stacks are always 32-bit
+            Err(_) => {
+                count_stack_id_failure();
+                return Err(0);
+            }
         }
+    };
+    let kernel_stack_id = match STATE.get(KERNEL_STACKS_INDEX) {
+        // a missing kernel stack shouldn't lose the allocation
+        Some(v) if *v != 0 => match unsafe { KERNEL_STACKTRACES.get_stackid(ctx, 0) } {
+            Ok(stack_id) => stack_id as u32,
+            Err(_) => NO_KERNEL_STACK,
+        },
+        _ => NO_KERNEL_STACK,
+    };
+
+    let key = ReducedEventKey {
+        pid,
+        tid,
+        stack_id,
+        kernel_stack_id,
+        function_id,
+    };
+    Ok(Some(AllocInfo::new(key, size, weight)))
+}
+
+#[uretprobe]
+pub fn malloc_ret(ctx: ProbeContext) -> u32 {
+    try_malloc_ret(ctx).unwrap_or_else(|ret| ret)
+}
+
+fn try_malloc_ret(ctx: ProbeContext) -> Result<u32, u32> {
+    let Some(PendingAlloc { info, .. }) = take_pending() else {
+        // allocation was sampled out or filtered
+        return Ok(0);
+    };
+    if tracked_frees() == 0 {
+        // pending only for its latency
+        return Ok(0);
+    }
+
+    let ptr: u64 = ctx.ret().ok_or_else(|| arg_read_failure(0))?;
+    if ptr == 0 {
+        // allocation failed, nothing will be freed
+        return Ok(0);
+    }
+    track_pointer(ptr, &info)
+}
 
-        let pid = bpf_get_current_pid_tgid() as u32;
-        let stack_id = match STACKTRACES.get_stackid(&ctx, BPF_F_USER_STACK.into()) {
-            Ok(stack_id) => stack_id,
-            Err(_) => return Err(0),
-        } as u32; // userspace stacks are always 32-bit
+/// `xallocx` returns the resulting size, not a pointer, only its latency is recorded
+#[uretprobe]
+pub fn xallocx_ret(_ctx: ProbeContext) -> u32 {
+    take_pending();
+    0
+}
+
+/// `posix_memalign` returns an error code, the allocation is read from the out-param
+#[uretprobe]
+pub fn posix_memalign_ret(ctx: ProbeContext) -> u32 {
+    try_posix_memalign_ret(ctx).unwrap_or_else(|ret| ret)
+}
+
+fn try_posix_memalign_ret(ctx: ProbeContext) -> Result<u32, u32> {
+    let Some(PendingAlloc { info, out_ptr, .. }) = take_pending() else {
+        // allocation was sampled out or filtered
+        return Ok(0);
+    };
+    if tracked_frees() == 0 {
+        // pending only for its latency
+        return Ok(0);
+    }
 
-        let current_cpu = bpf_get_smp_processor_id();
-        update_hist(size, pid, stack_id, current_cpu)?;
+    let error: i32 = ctx.ret().ok_or_else(|| arg_read_failure(0))?;
+    if error != 0 || out_ptr == 0 {
+        // allocation failed, nothing will be freed
+        return Ok(0);
     }
+    let ptr = unsafe { bpf_probe_read_user(out_ptr as *const u64) }.map_err(arg_read_failure)?;
+    track_pointer(ptr, &info)
+}
+
+#[uretprobe]
+pub fn realloc_ret(ctx: ProbeContext) -> u32 {
+    try_realloc_ret(ctx).unwrap_or_else(|ret| ret)
+}
+
+fn try_realloc_ret(ctx: ProbeContext) -> Result<u32, u32> {
+    let Some(PendingAlloc { info, old_ptr, .. }) = take_pending() else {
+        // allocation was sampled out or filtered
+        return Ok(0);
+    };
+
+    let ptr: u64 = ctx.ret().ok_or_else(|| arg_read_failure(0))?;
+    // `realloc(ptr, 0)` may free `ptr` and return null, otherwise null is a
+    // failure which leaves the old allocation intact
+    let freed = ptr == 0 && info.size == 0 && old_ptr != 0;
+    if ptr == 0 && !freed {
+        return Ok(0);
+    }
+
+    // old size is known only if the old pointer was returned by a sampled
+    // call, otherwise the whole new size is accounted as a fresh allocation
+    let old = match old_ptr {
+        0 => None,
+        _ => (unsafe { ALLOCATIONS.get(&old_ptr) }).copied(),
+    };
+    let old_size = match old {
+        Some(old) => {
+            ALLOCATIONS.remove(&old_ptr).ok();
+            if tracks_live() {
+                update_live(&old, -1)?;
+            }
+            old.size
+        }
+        // freed an allocation we don't know the size of
+        None if freed => return Ok(0),
+        None => 0,
+    };
+
+    let delta = (info.size as i64).wrapping_sub(old_size as i64);
+    // with `DWARF_UNWIND_INDEX` the stack is copied here, it starts at the caller
+    unsafe { update_hist(&ctx, delta, &info)? };
 
+    if freed {
+        return Ok(0);
+    }
+    track_pointer(ptr, &info)
+}
+
+/// Remembers the allocation of a returned pointer until it is freed or resized
+fn track_pointer(ptr: u64, info: &AllocInfo) -> Result<u32, u32> {
+    ALLOCATIONS.insert(&ptr, info, 0).map_err(insert_failure)?;
+    if tracks_live() {
+        update_live(info, 1)?;
+    }
+    Ok(0)
+}
+
+/// Takes the allocation the thread entered with, recording its latency
+fn take_pending() -> Option<PendingAlloc> {
+    let tid = bpf_get_current_pid_tgid() as u32;
+    let pending = (unsafe { INFLIGHT.get(&tid) }).copied()?;
+    INFLIGHT.remove(&tid).ok();
+    if pending.entered_ns != 0 {
+        let latency = unsafe { bpf_ktime_get_ns() }.saturating_sub(pending.entered_ns);
+        record_latency(&pending.info, latency).ok();
+    }
+    Some(pending)
+}
+
+fn tracks_latency() -> bool {
+    STATE.get(TRACK_LATENCY_INDEX).copied().unwrap_or(0) != 0
+}
+
+/// Start of the call for its latency, zero if it isn't tracked
+fn entry_time() -> u64 {
+    if tracks_latency() {
+        unsafe { bpf_ktime_get_ns() }
+    } else {
+        0
+    }
+}
+
+/// Adds a call of `latency` nanoseconds to the log2 histogram of the stack
+fn record_latency(info: &AllocInfo, latency: u64) -> Result<u32, u32> {
+    let key = info.key();
+    let hist = match LATENCIES.get_ptr_mut(&key) {
+        Some(hist) => hist,
+        None => {
+            LATENCIES
+                .insert(&key, &EMPTY_HISTOGRAM, 0)
+                .map_err(insert_failure)?;
+            LATENCIES.get_ptr_mut(&key).ok_or(0u32)?
+        }
+    };
+    let Some(hist) = (unsafe { hist.as_mut() }) else {
+        // should be impossible
+        return Err(0);
+    };
+    // a zero latency isn't recorded, like an allocation of zero bytes
+    hist.record(latency.max(1), BucketScheme::Log2);
+    Ok(0)
+}
+
+#[uprobe]
+pub fn free(ctx: ProbeContext) -> u32 {
+    try_free(ctx).unwrap_or_else(|ret| ret)
+}
+
+fn try_free(ctx: ProbeContext) -> Result<u32, u32> {
+    let ptr: u64 = ctx.arg(0).ok_or_else(|| arg_read_failure(0))?;
+    let Some(info) = (unsafe { ALLOCATIONS.get(&ptr) }).copied() else {
+        // not tracked by us
+        return Ok(0);
+    };
+    ALLOCATIONS.remove(&ptr).ok();
+    if !tracks_live() {
+        // probed only so `realloc` doesn't find the stale size of a reused pointer
+        return Ok(0);
+    }
+    update_live(&info, -1)?;
+
+    if tracked_frees() & TRACK_FREE_SITES == 0 {
+        return Ok(0);
+    }
+    let free_stack_id = match unsafe { STACKTRACES.get_stackid(&ctx, BPF_F_USER_STACK.into()) } {
+        Ok(stack_id) => stack_id as u32,
+        Err(_) => {
+            count_stack_id_failure();
+            return Err(0);
+        }
+    };
+
+    let key = FreeSiteKey::new(&info, free_stack_id);
+    match FREE_SITES.get_ptr_mut(&key) {
+        None => {
+            let stats = FreeSiteStats {
+                count: info.weight as u64,
+                bytes: info.size * info.weight as u64,
+            };
+            FREE_SITES.insert(&key, &stats, 0).map_err(insert_failure)?;
+        }
+        Some(stats) => {
+            let Some(stats) = (unsafe { stats.as_mut() }) else {
+                // should be impossible
+                return Err(0);
+            };
+            stats.count += info.weight as u64;
+            stats.bytes += info.size * info.weight as u64;
+        }
+    }
+    Ok(0)
+}
+
+/// Adds (`sign == 1`) or subtracts (`sign == -1`) the allocation from the live memory of its stack
+fn update_live(info: &AllocInfo, sign: i64) -> Result<u32, u32> {
+    let key = info.key();
+    let count = sign.wrapping_mul(info.weight as i64);
+    let bytes = count.wrapping_mul(info.size as i64);
+    match LIVE.get_ptr_mut(&key) {
+        None => {
+            let stats = LiveStats { bytes, count };
+            LIVE.insert(&key, &stats, 0).map_err(insert_failure)?;
+        }
+        Some(stats) => {
+            let Some(stats) = (unsafe { stats.as_mut() }) else {
+                // should be impossible
+                return Err(0);
+            };
+            stats.bytes = stats.bytes.wrapping_add(bytes);
+            stats.count = stats.count.wrapping_add(count);
+        }
+    }
     Ok(0)
 }
 
+/// Userspace reports the failures, the stack map is most likely full
+fn count_stack_id_failure() {
+    count_failure(STACK_ID_FAILURES_INDEX);
+}
+
+/// Counts a failed read of an argument, return value or out-param, the error is
+/// returned by the probe
+fn arg_read_failure(error: c_long) -> u32 {
+    count_failure(ARG_READ_FAILURES_INDEX);
+    error as u32
+}
+
+/// `-EEXIST` of a `BPF_NOEXIST` insert of a present key
+const EEXIST: c_long = -17;
+
+/// Counts a failed map insert, the error is returned by the probe
+fn insert_failure(error: c_long) -> u32 {
+    count_failure(INSERT_FAILURES_INDEX);
+    error as u32
+}
+
+fn count_failure(index: u32) {
+    if let Some(failures) = STATE.get_ptr_mut(index) {
+        unsafe { *failures += 1 };
+    }
+}
+
+fn dwarf_unwind() -> bool {
+    STATE.get(DWARF_UNWIND_INDEX).copied().unwrap_or(0) != 0
+}
+
+/// Instruction, stack and frame pointer of the probed user code
+fn registers(ctx: &ProbeContext) -> (u64, u64, u64) {
+    #[cfg(bpf_target_arch = "x86_64")]
+    {
+        let regs = unsafe { &*ctx.regs };
+        (regs.rip, regs.rsp, regs.rbp)
+    }
+    #[cfg(not(bpf_target_arch = "x86_64"))]
+    {
+        // unwinding is implemented for x86_64 only, an empty stack is sent
+        let _ = ctx;
+        (0, 0, 0)
+    }
+}
+
+/// Sends the allocation with the registers and a copy of the user stack
+fn output_stack_sample(ctx: &ProbeContext, event: AllocEvent) -> Result<(), ()> {
+    let Some(mut entry) = EVENTS.reserve::<StackSample>(0) else {
+        return Err(());
+    };
+    let sample = entry.as_mut_ptr();
+    let (ip, sp, bp) = registers(ctx);
+    let stack = unsafe { &mut (*sample).stack };
+    // the copy fails as a whole if it reaches past the top of the stack, as it
+    // does in the first frames of a thread, so smaller ones are tried too
+    let stack_len = if unsafe { bpf_probe_read_user_buf(sp as *const u8, stack) }.is_ok() {
+        STACK_COPY_SIZE
+    } else if unsafe { bpf_probe_read_user_buf(sp as *const u8, &mut stack[..STACK_COPY_SIZE / 4]) }
+        .is_ok()
+    {
+        STACK_COPY_SIZE / 4
+    } else if unsafe {
+        bpf_probe_read_user_buf(sp as *const u8, &mut stack[..STACK_COPY_SIZE / 16])
+    }
+    .is_ok()
+    {
+        STACK_COPY_SIZE / 16
+    } else {
+        0
+    };
+    unsafe {
+        (*sample).header = StackSampleHeader {
+            event,
+            ip,
+            sp,
+            bp,
+            stack_len: stack_len as u64,
+        };
+    }
+    entry.submit(0);
+    Ok(())
+}
+
+/// Whether userspace wrote every setting, allocations before are ignored instead
+/// of being recorded with zeroed filters. Frees and returns follow the entries
+fn config_ready() -> bool {
+    STATE.get(CONFIG_READY_INDEX).copied().unwrap_or(0) != 0
+}
+
+/// `TRACK_FREES` | `TRACK_FREE_SITES` bits, zero if frees aren't tracked
+fn tracked_frees() -> u64 {
+    STATE.get(TRACK_FREES_INDEX).copied().unwrap_or(0)
+}
+
+/// Live memory is tracked, not just the pointers `realloc` needs
+fn tracks_live() -> bool {
+    tracked_frees() & TRACK_FREES != 0
+}
+
+/// `--sample-every` sampling, every cpu samples every `SAMPLE_EVERY_INDEX`th of its
+/// own allocations
 fn should_process() -> bool {
     let sample_every = match STATE.get(SAMPLE_EVERY_INDEX) {
         None => {
@@ -87,49 +634,89 @@ fn should_process() -> bool {
         return true;
     };
     *ctr += 1;
-    (*ctr % sample_every) == 0
+    is_sampled(*ctr, sample_every)
 }
 
-unsafe fn update_hist(size: u64, pid: u32, stack_id: u32, current_cpu: u32) -> Result<u32, u32> {
-    let key = HistogramKey::new(pid, stack_id, current_cpu as _);
-    match HISTOGRAMS.get_ptr_mut(&key) {
-        None => {
-            let mut histogram = Histogram::new();
-            histogram.increment(size);
-            HISTOGRAMS
-                .insert(&key, &histogram, 0)
-                .map_err(|e| e as u32)?; //todo use lru?
-        }
-        Some(hist) => {
-            let Some(hist) = hist.as_mut() else {
-                // should be impossible
-                return Err(0);
-            };
-            hist.increment(size);
+/// Number of allocations an allocation of `size` bytes stands for, `None` if it's
+/// sampled out. With `SAMPLE_ABOVE_INDEX` big allocations are always recorded and
+/// the sampled small ones carry the sampling rate as their weight, with
+/// `SAMPLE_EVERY_BYTES_INDEX` samples carry the bytes between them, otherwise
+/// every sample has weight 1 and userspace scales the totals.
+fn sample_weight(size: u64) -> Option<u32> {
+    let every_bytes = STATE.get(SAMPLE_EVERY_BYTES_INDEX).copied().unwrap_or(0);
+    if every_bytes != 0 {
+        let Some(accumulated) = STATE.get_ptr_mut(SAMPLED_BYTES_INDEX) else {
+            return Some(1);
+        };
+        let Some(accumulated) = (unsafe { accumulated.as_mut() }) else {
+            return Some(1);
+        };
+        let weight = byte_sample_weight(accumulated, size, every_bytes)?;
+        return Some(weight.min(u32::MAX as u64) as u32);
+    }
+    let threshold = STATE.get(SAMPLE_ABOVE_INDEX).copied().unwrap_or(0);
+    if threshold == 0 {
+        return should_process().then_some(1);
+    }
+    if size >= threshold {
+        return Some(1);
+    }
+    let sample_every = STATE.get(SAMPLE_EVERY_INDEX).copied().unwrap_or(1).max(1);
+    should_process().then_some(sample_every as u32)
+}
+
+/// Records an allocation of `delta` bytes, negative `delta` is a shrinking realloc
+unsafe fn update_hist(ctx: &ProbeContext, delta: i64, info: &AllocInfo) -> Result<u32, u32> {
+    let key = HistogramKey::new(info.key());
+    if STATE.get(RING_BUFFER_INDEX).copied().unwrap_or(0) != 0 {
+        let event = AllocEvent {
+            key,
+            cpu: bpf_get_smp_processor_id(),
+            delta,
+            weight: info.weight as u64,
+            timestamp_ns: bpf_ktime_get_ns(),
+        };
+        let sent = if dwarf_unwind() {
+            output_stack_sample(ctx, event).is_ok()
+        } else {
+            EVENTS.output(&event, 0).is_ok()
+        };
+        if !sent {
+            if let Some(drops) = STATE.get_ptr_mut(RING_BUFFER_DROPS_INDEX) {
+                *drops += 1;
+            }
         }
+        return Ok(0);
     }
+    let scheme = BucketScheme::from_config(
+        STATE.get(BUCKET_SCHEME_INDEX).copied().unwrap_or(0),
+        STATE.get(BUCKET_PARAM_INDEX).copied().unwrap_or(0),
+    );
+    let hist = match HISTOGRAMS.get_ptr_mut(&key) {
+        Some(hist) => hist,
+        None => {
+            match HISTOGRAMS.insert(&key, &EMPTY_HISTOGRAM, BPF_NOEXIST.into()) {
+                // counted once, userspace tells evictions from the inserted keys
+                Ok(()) => {
+                    if let Some(inserted) = STATE.get_ptr_mut(HISTOGRAMS_INSERTED_INDEX) {
+                        *inserted += 1;
+                    }
+                }
+                // another cpu inserted the key since the lookup
+                Err(EEXIST) => {}
+                Err(e) => return Err(insert_failure(e)),
+            }
+            HISTOGRAMS.get_ptr_mut(&key).ok_or(0u32)?
+        }
+    };
+    let Some(hist) = hist.as_mut() else {
+        // should be impossible
+        return Err(0);
+    };
+    hist.add_weighted_delta(delta, info.weight as u64, scheme);
     Ok(0)
 }
 
-// unsafe fn update_counter(pid: u32, stack_id: u32) -> Result<u32, u32> {
-//     match HISTOGRAMS.get_ptr_mut(&(pid, stack_id)) {
-//         None => {
-//             let mut counter = Counter::new();
-//             HISTOGRAMS
-//                 .insert(&(pid, stack_id), &counter, 0)
-//                 .map_err(|e| e as u32)?; //todo use lru?
-//         }
-//         Some(counter) => {
-//             let Some(counter) = counter.as_mut() else {
-//                 // should be impossible
-//                 return Err(0);
-//             };
-//             counter.increment();
-//         }
-//     }
-//     Ok(0)
-// }
-
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
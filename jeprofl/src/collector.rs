@@ -1,69 +1,618 @@
-use crate::resolver::{ResolvedStackTrace, Resolver};
-use crate::OrderBy;
-use aya::maps::{MapData, PerCpuHashMap, StackTraceMap};
+use crate::metrics::{self, MetricsSnapshot, StackMetrics};
+use crate::pprof::ProfileBuilder;
+use crate::raw::{self, OfflineSymbolizer, RawMapping, RawProcess, RawProfile, RawStack};
+use crate::resolver::{self, OwnedSymbol, ResolvedStackTrace, Resolver, ResolverOptions};
+use crate::topology;
+use crate::tui::{self, TuiStack};
+use crate::unwind::Unwinder;
+use crate::{CsvFormat, JemallocAllocFunctions, MergeBy, OrderBy, SkipMode, WeightMetric};
+use anyhow::Context;
+use aya::maps::{MapData, PerCpuArray, PerCpuHashMap, RingBuf, StackTraceMap};
 
 use itertools::Itertools;
-use jeprofl_common::{Histogram, HistogramKey, ReducedEventKey, UnpackedHistogramKey};
+use jeprofl_common::{
+    split_function_id, AllocEvent, BucketScheme, FreeSiteKey, FreeSiteStats, Histogram,
+    HistogramKey, LiveStats, ReducedEventKey, StackSampleHeader, UnpackedHistogramKey,
+    ARG_READ_FAILURES_INDEX, COUNT_INDEX, HISTOGRAMS_INSERTED_INDEX, INSERT_FAILURES_INDEX,
+    MAX_TRACKED_ALLOCATION_SIZE, NO_KERNEL_STACK, RING_BUFFER_DROPS_INDEX, STACK_ID_FAILURES_INDEX,
+};
+use regex::Regex;
 use rustc_hash::{FxHashMap, FxHashSet};
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::io::Write as _;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
+/// Settings of the collector thread which don't change during the run.
+#[derive(Debug, Clone)]
+pub struct CollectorConfig {
+    pub skip_total_alloc_size_lower_than: u64,
+    pub skip_total_count_lower_than: u64,
+    pub skip_mode: SkipMode,
+    pub scale_factor: u64,
+    pub size_biased: Option<SizeBiasedSampling>,
+    /// `--sample-every-bytes`, samples are already weighted
+    pub sample_every_bytes: Option<u64>,
+    pub bucket_scheme: BucketScheme,
+    /// Keep the raw instruction pointers of stacks and the mappings of the
+    /// processes for `--raw-output`
+    pub raw_capture: bool,
+    /// Where metrics are published after every poll for `--metrics-addr`
+    pub metrics: Option<metrics::Published>,
+    /// Max number of stacks with their own metrics
+    pub metrics_top: usize,
+    pub resolver: ResolverOptions,
+    /// Where the stacks are published after every poll for `--tui`
+    pub tui: Option<tui::Published>,
+    /// Allocations of this long after the start are discarded, see `--warmup`
+    pub warmup: Duration,
+    pub windows: Option<WindowOutput>,
+    /// Count the allocated bytes of every second for `--timeline`
+    pub timeline: bool,
+    /// Ring buffer events carry stack copies to unwind, see `--dwarf-unwind`
+    pub dwarf_unwind: bool,
+    /// How often the maps are polled, see `--poll-interval`
+    pub poll_interval: Duration,
+    /// How often stacks below the skip thresholds and orphaned stacks are
+    /// removed from the maps
+    pub clean_up_interval: Duration,
+    /// Threads whose allocations are dropped, see `--exclude-thread`
+    pub thread_filter: ThreadFilter,
+    /// Max number of stacks kept in userspace, the smallest by `order_by` are
+    /// evicted and ignored from then on, see `--max-stacks`
+    pub max_stacks: Option<usize>,
+    pub order_by: OrderBy,
+}
+
+/// `--exclude-thread` and `--only-thread`: allocations are kept by the name of
+/// the allocating thread, which needs stacks grouped per thread
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFilter {
+    pub exclude: Vec<String>,
+    /// Empty keeps every thread which isn't excluded
+    pub only: Vec<String>,
+}
+
+impl ThreadFilter {
+    pub fn is_active(&self) -> bool {
+        !self.exclude.is_empty() || !self.only.is_empty()
+    }
+
+    /// Whether allocations of the thread are kept, `None` if its name isn't
+    /// known, e.g. because it exited before it was seen
+    fn keeps(&self, name: Option<&str>) -> bool {
+        // the kernel truncates names to 15 bytes
+        let matches = |pattern: &String| {
+            name.is_some_and(|name| pattern.as_bytes()[..pattern.len().min(15)] == *name.as_bytes())
+        };
+        !self.exclude.iter().any(matches) && (self.only.is_empty() || self.only.iter().any(matches))
+    }
+}
+
+/// `--interval`: a report of every window of the capture, besides the one of
+/// the whole capture
+#[derive(Debug, Clone)]
+pub struct WindowOutput {
+    pub interval: Duration,
+    pub dir: PathBuf,
+    /// How the windows are rendered, the capture duration is set per window
+    pub report: ReportOptions,
+}
+
+/// `--sample-above`: allocations of at least `threshold` bytes are all recorded,
+/// smaller ones are sampled 1/`sample_every` and weighted by it on the ebpf side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SizeBiasedSampling {
+    pub threshold: u64,
+    pub sample_every: u64,
+}
+
+/// Fraction of the allocations `--sample-every` sampling recorded, from the per
+/// cpu `COUNT_INDEX` counters. Every cpu samples every `sample_every`th of its own
+/// allocations, so the last up to `sample_every - 1` of every cpu are seen but not
+/// sampled, with many cpus and a short capture the fraction is well below
+/// `1 / sample_every`. `None` until something was sampled.
+pub fn effective_sampling_fraction(per_cpu_counts: &[u64], sample_every: u64) -> Option<f64> {
+    let sample_every = sample_every.max(1);
+    let seen: u64 = per_cpu_counts.iter().sum();
+    let sampled: u64 = per_cpu_counts
+        .iter()
+        .map(|count| count / sample_every)
+        .sum();
+    (sampled > 0).then(|| sampled as f64 / seen as f64)
+}
+
+/// What `EventProcessor::print_histogram` renders and where.
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub order_by: OrderBy,
+    pub csv_path: Option<PathBuf>,
+    pub csv_format: CsvFormat,
+    pub flame_graph: Option<PathBuf>,
+    pub flame: FlameOptions,
+    /// Writes the folded stacks, weighted like a single `--flame-weight` graph
+    pub folded_path: Option<PathBuf>,
+    /// Writes a gzipped pprof profile to the path
+    pub pprof: Option<PathBuf>,
+    /// Writes every stack with its histogram as a json array to the path
+    pub json_path: Option<PathBuf>,
+    /// Keep only stacks with a frame from a source file which path contains one of these
+    pub include_files: Vec<String>,
+    /// Keep only stacks with a frame which symbol matches one of these
+    pub filters: Vec<Regex>,
+    /// Drop stacks with a frame which symbol matches one of these
+    pub excludes: Vec<Regex>,
+    /// Print only the aggregate block
+    pub summary_only: bool,
+    /// One line per stack instead of its frames and histogram
+    pub compact: bool,
+    /// Sum the stacks by their frame at this depth and print one line per
+    /// function instead of the stacks, see `--by-function`
+    pub by_function: Option<usize>,
+    /// Color histogram bars by size with ANSI escapes, the pager renders them
+    pub color: bool,
+    /// Add the source file and line to frames, in the pager and the CSV
+    pub source_lines: bool,
+    /// Render only this many of the biggest stacks, also in the CSV and flame graphs
+    pub top: Option<usize>,
+    /// Overrides `top` for the CSV
+    pub csv_top: Option<usize>,
+    /// Sorts the CSV rows by it instead of `order_by`
+    pub csv_weight: Option<WeightMetric>,
+    /// Render the smallest stacks first
+    pub ascending: bool,
+    /// Render the deepest stacks first, stacks of the same depth by `order_by`
+    pub order_by_depth: bool,
+    /// Keep only this many frames from the allocation function on, stacks
+    /// which agree in them are summed in every output
+    pub max_depth: Option<usize>,
+    /// Drop stacks of which no frame could be symbolized
+    pub drop_unsymbolized: bool,
+    /// Print the total size and count of every allocation function
+    pub per_function: bool,
+    /// Print how the traffic of every stack splits across cpus and NUMA nodes
+    pub by_cpu: bool,
+    /// More than one allocation function was probed, stacks are labeled with theirs
+    pub multiple_functions: bool,
+    /// How long the profile was captured for
+    pub capture_duration: Duration,
+    pub merge_by: MergeBy,
+}
+
+/// How the `--flame` graphs are rendered
+#[derive(Debug, Clone)]
+pub struct FlameOptions {
+    /// Roots the graph at the entry of the program, otherwise at the allocation function
+    pub reverse: bool,
+    pub palette: inferno::flamegraph::Palette,
+    /// Width of the image in pixels, inferno picks one if `None`
+    pub width: Option<usize>,
+    pub title: Option<String>,
+    /// Only the graph weighted by it is written, otherwise both by count and by bytes
+    pub weight: Option<WeightMetric>,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            order_by: OrderBy::Traffic,
+            csv_path: None,
+            csv_format: CsvFormat::Text,
+            flame_graph: None,
+            flame: FlameOptions::default(),
+            folded_path: None,
+            pprof: None,
+            json_path: None,
+            include_files: Vec::new(),
+            filters: Vec::new(),
+            excludes: Vec::new(),
+            summary_only: false,
+            compact: false,
+            by_function: None,
+            color: false,
+            source_lines: false,
+            top: None,
+            csv_top: None,
+            csv_weight: None,
+            ascending: false,
+            order_by_depth: false,
+            max_depth: None,
+            drop_unsymbolized: false,
+            per_function: false,
+            by_cpu: false,
+            multiple_functions: false,
+            capture_duration: Duration::ZERO,
+            merge_by: MergeBy::Stack,
+        }
+    }
+}
+
+impl Default for FlameOptions {
+    fn default() -> Self {
+        Self {
+            reverse: true,
+            palette: Default::default(),
+            width: None,
+            title: None,
+            weight: None,
+        }
+    }
+}
+
+/// Maps of the loaded ebpf program which the collector polls.
+pub struct CollectorMaps {
+    pub histograms: PerCpuHashMap<MapData, HistogramKey, Histogram>,
+    pub stack_traces: StackTraceMap<MapData>,
+    /// Present when kernel stacks are captured
+    pub kernel_stack_traces: Option<StackTraceMap<MapData>>,
+    /// Present when free sites are tracked
+    pub free_sites: Option<PerCpuHashMap<MapData, FreeSiteKey, FreeSiteStats>>,
+    /// Present when frees are tracked
+    pub live: Option<PerCpuHashMap<MapData, ReducedEventKey, LiveStats>>,
+    /// Present when allocation latencies are tracked
+    pub latencies: Option<PerCpuHashMap<MapData, ReducedEventKey, Histogram>>,
+    /// Counters of the ebpf side are read from it
+    pub config: PerCpuArray<MapData, u64>,
+    /// Present when allocations are delivered through the ring buffer instead
+    /// of being aggregated in `histograms`
+    pub events: Option<RingBuf<MapData>>,
+}
+
+/// Longest the collector waits on the ring buffer without checking for
+/// cancellation, so long poll intervals don't delay the exit
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sleeps for `interval` or until canceled. The thread is unparked on
+/// cancellation, so it wakes up right away
+fn wait_canceled(canceled: &AtomicBool, interval: Duration) {
+    let start = std::time::Instant::now();
+    while let Some(left) = interval.checked_sub(start.elapsed()) {
+        if canceled.load(Ordering::Acquire) {
+            return;
+        }
+        thread::park_timeout(left);
+    }
+}
+
+/// Stacks of the stack map no map key refers to anymore. They are removed only
+/// if they were orphaned at the previous clean up as well, so stacks of keys
+/// inserted after the maps were polled survive
+fn orphaned_stacks_of(
+    stack_ids: impl Iterator<Item = u32>,
+    referenced: &FxHashSet<u32>,
+) -> FxHashSet<u32> {
+    stack_ids.filter(|id| !referenced.contains(id)).collect()
+}
+
 pub fn spawn_collector(
-    mut buf: PerCpuHashMap<MapData, HistogramKey, Histogram>,
+    maps: CollectorMaps,
     canceled: Arc<AtomicBool>,
-    mut stack_trace_map: StackTraceMap<MapData>,
-    skip_total_alloc_size_lower_than: u64,
-    skip_total_count_lower_than: u64,
+    config: CollectorConfig,
 ) -> JoinHandle<EventProcessor> {
+    let CollectorMaps {
+        histograms: mut buf,
+        stack_traces: mut stack_trace_map,
+        mut kernel_stack_traces,
+        mut free_sites,
+        live,
+        mut latencies,
+        config: config_map,
+        mut events,
+    } = maps;
+
     thread::spawn(move || {
-        let resolver = Resolver::new();
-        let mut processor = EventProcessor::new();
+        let resolver = Resolver::with_options(config.resolver);
+        let mut processor = EventProcessor::new(config.scale_factor);
+        processor.tracks_frees = live.is_some();
+        processor.bucket_scheme = config.bucket_scheme;
+        processor.size_biased = config.size_biased;
+        processor.sample_every_bytes = config.sample_every_bytes;
+        processor.raw_capture = config.raw_capture;
+        processor.thread_filter = config.thread_filter;
+        processor.max_stacks = config.max_stacks.map(|max| (max, config.order_by));
+        if config.timeline {
+            processor.timeline = Some(Timeline::starting_now());
+        }
+        let mut unwinder = config.dwarf_unwind.then(Unwinder::new);
 
         let mut keys_to_drop = FxHashSet::default();
         let mut last_clean_up = std::time::Instant::now();
+        let mut removed_keys = 0;
+        let started = std::time::Instant::now();
+        let mut warming_up = !config.warmup.is_zero();
+        // what was captured before the current window and when it started
+        let mut window_start = (processor.clone(), std::time::Instant::now());
+        let mut window_index = 1;
+        // user stacks the maps referenced at the last poll
+        let mut referenced_stacks = FxHashSet::default();
+        // unreferenced stacks at the last clean up
+        let mut orphaned_stacks = FxHashSet::default();
+        // allocations the sampling saw up to the last poll
+        let mut sampling_seen = 0;
 
         loop {
-            thread::sleep(Duration::from_secs(1));
-            if canceled.load(Ordering::Acquire) {
-                return processor;
+            match &mut events {
+                Some(events) => {
+                    let tick = std::time::Instant::now();
+                    while let Some(left) = config.poll_interval.checked_sub(tick.elapsed()) {
+                        if canceled.load(Ordering::Acquire) {
+                            break;
+                        }
+                        wait_readable(events, left.min(CANCEL_CHECK_INTERVAL));
+                        processor.drain_events(
+                            events,
+                            &resolver,
+                            &stack_trace_map,
+                            kernel_stack_traces.as_ref(),
+                            unwinder.as_mut(),
+                        );
+                    }
+                }
+                None => wait_canceled(&canceled, config.poll_interval),
+            }
+            // after cancellation one more pass drains everything captured up to the stop
+            let is_final_drain = canceled.load(Ordering::Acquire);
+            if let Some(events) = &mut events {
+                processor.drain_events(
+                    events,
+                    &resolver,
+                    &stack_trace_map,
+                    kernel_stack_traces.as_ref(),
+                    unwinder.as_mut(),
+                );
+                let dropped: u64 = config_map
+                    .get(&RING_BUFFER_DROPS_INDEX, 0)
+                    .map(|values| values.iter().sum())
+                    .unwrap_or(0);
+                if dropped > processor.dropped_events {
+                    log::warn!("Ring buffer is full, {dropped} events were dropped so far");
+                    processor.dropped_events = dropped;
+                }
             }
 
-            let mut was_skiped_on_cpus = true;
+            // read before the keys, so keys inserted during the iteration aren't
+            // mistaken for evicted ones
+            let inserted_keys: u64 = config_map
+                .get(&HISTOGRAMS_INSERTED_INDEX, 0)
+                .map(|values| values.iter().sum())
+                .unwrap_or(0);
+            let mut present_keys = 0;
+            referenced_stacks.clear();
+
             for val in buf.iter() {
                 let (key, per_cpu_histograms) = val.unwrap();
-                let unpacked_key = key.into_parts();
+                let mut was_skiped_on_cpus = true;
+                let mut was_empty_on_cpus = true;
+                present_keys += 1;
+                let reduced_key = key.reduced();
+                if processor.capped_stacks.contains(&reduced_key.stack_id) {
+                    // removed from the maps at the next clean up
+                    keys_to_drop.insert(key);
+                    continue;
+                }
+                referenced_stacks.insert(reduced_key.stack_id);
                 // per cpu histograms
-                for hist in per_cpu_histograms.iter() {
-                    if hist.total < skip_total_alloc_size_lower_than
-                        && hist.total_count() < skip_total_count_lower_than
-                    {
+                for (cpu, hist) in per_cpu_histograms.iter().enumerate() {
+                    // every cpu has a slot of the key, mostly without allocations
+                    if hist.allocation_count() == 0 {
                         continue;
                     }
-                    was_skiped_on_cpus = false;
-                    if canceled.load(Ordering::Acquire) {
-                        return processor;
+                    was_empty_on_cpus = false;
+                    if config.skip_mode.skips(
+                        hist,
+                        config.skip_total_alloc_size_lower_than,
+                        config.skip_total_count_lower_than,
+                    ) {
+                        continue;
                     }
+                    was_skiped_on_cpus = false;
+                    let unpacked_key = key.into_parts(cpu as u32);
                     processor.process(unpacked_key, hist, &resolver, &stack_trace_map);
+                    if let Some(kernel_stack_traces) = &kernel_stack_traces {
+                        processor.resolve_kernel_trace(
+                            unpacked_key.kernel_stack_id,
+                            &resolver,
+                            kernel_stack_traces,
+                        );
+                    }
                 }
 
-                if was_skiped_on_cpus {
+                if was_skiped_on_cpus && was_empty_on_cpus {
                     keys_to_drop.insert(key);
+                } else if was_skiped_on_cpus {
+                    keys_to_drop.insert(key);
+                    let total_bytes: u64 = per_cpu_histograms.iter().map(|h| h.total_bytes()).sum();
+                    let reason = if total_bytes < config.skip_total_alloc_size_lower_than {
+                        Skipped::Size
+                    } else {
+                        Skipped::Count
+                    };
+                    processor.skipped_stacks.insert(reduced_key, reason);
                 } else {
                     keys_to_drop.remove(&key);
+                    processor.skipped_stacks.remove(&reduced_key);
+                }
+            }
+
+            let evicted = inserted_keys.saturating_sub(present_keys + removed_keys);
+            if evicted > processor.evicted_stacks {
+                log::warn!(
+                    "HISTOGRAMS map is full, {} stacks were evicted so far",
+                    evicted
+                );
+                processor.evicted_stacks = evicted;
+            }
+
+            let failures = |index| {
+                config_map
+                    .get(&index, 0)
+                    .map(|values| values.iter().sum())
+                    .unwrap_or(0)
+            };
+            let stack_id_failures: u64 = failures(STACK_ID_FAILURES_INDEX);
+            if stack_id_failures > processor.stack_id_failures {
+                log::warn!(
+                    "Stack map is full, {stack_id_failures} allocations and frees were dropped so far"
+                );
+                processor.stack_id_failures = stack_id_failures;
+            }
+            let arg_read_failures: u64 = failures(ARG_READ_FAILURES_INDEX);
+            if arg_read_failures > processor.arg_read_failures {
+                log::warn!(
+                    "Arguments couldn't be read, {arg_read_failures} allocations and frees were dropped so far"
+                );
+                processor.arg_read_failures = arg_read_failures;
+            }
+            let insert_failures: u64 = failures(INSERT_FAILURES_INDEX);
+            if insert_failures > processor.insert_failures {
+                log::warn!(
+                    "An ebpf map is full, {insert_failures} allocations and frees were dropped so far"
+                );
+                processor.insert_failures = insert_failures;
+            }
+
+            // size-biased and raw samples aren't scaled
+            if config.scale_factor > 1 {
+                let counts: Vec<u64> = config_map
+                    .get(&COUNT_INDEX, 0)
+                    .map(|values| values.iter().copied().collect())
+                    .unwrap_or_default();
+                let seen = counts.iter().sum();
+                if seen < sampling_seen {
+                    log::warn!("Sampling counters were reset during the run, is another jeprofl tracing the same functions?");
+                }
+                sampling_seen = seen;
+                if let Some(fraction) = effective_sampling_fraction(&counts, config.scale_factor) {
+                    processor.scale_factor = ((1.0 / fraction).round() as u64).max(1);
+                }
+            }
+
+            if let Some(free_sites) = &free_sites {
+                for val in free_sites.iter() {
+                    let (key, per_cpu_stats) = val.unwrap();
+                    let mut stats = FreeSiteStats::default();
+                    for cpu_stats in per_cpu_stats.iter() {
+                        stats.merge(cpu_stats);
+                    }
+                    referenced_stacks.insert(key.alloc_stack_id);
+                    referenced_stacks.insert(key.free_stack_id);
+                    processor.process_free_site(key, stats, &resolver, &stack_trace_map);
+                }
+            }
+
+            if let Some(live) = &live {
+                for val in live.iter() {
+                    let (key, per_cpu_stats) = val.unwrap();
+                    let mut stats = LiveStats::default();
+                    for cpu_stats in per_cpu_stats.iter() {
+                        stats.merge(cpu_stats);
+                    }
+                    referenced_stacks.insert(key.stack_id);
+                    processor.update_live(key, stats);
+                }
+            }
+
+            if let Some(latencies) = &latencies {
+                for val in latencies.iter() {
+                    let (key, per_cpu_hists) = val.unwrap();
+                    let mut hist = Histogram::new();
+                    for cpu_hist in per_cpu_hists.iter() {
+                        hist.merge(cpu_hist);
+                    }
+                    referenced_stacks.insert(key.stack_id);
+                    processor.process_latency(key, hist, &resolver, &stack_trace_map);
+                }
+            }
+
+            if warming_up && started.elapsed() >= config.warmup {
+                processor.discard_captured();
+                if let Some(free_sites) = &mut free_sites {
+                    // they are polled in full, not by growth like the histograms
+                    let keys: Vec<_> = free_sites.keys().filter_map(Result::ok).collect();
+                    for key in keys {
+                        free_sites.remove(&key).ok();
+                    }
+                }
+                if let Some(latencies) = &mut latencies {
+                    let keys: Vec<_> = latencies.keys().filter_map(Result::ok).collect();
+                    for key in keys {
+                        latencies.remove(&key).ok();
+                    }
+                }
+                warming_up = false;
+                window_start = (processor.clone(), std::time::Instant::now());
+                log::info!("Warmup is over, collecting allocations from now on");
+            }
+
+            if let Some(windows) = &config.windows {
+                let (baseline, since) = &window_start;
+                if !warming_up && (since.elapsed() >= windows.interval || is_final_drain) {
+                    let window = processor.since(baseline);
+                    if let Err(e) = window.write_window(windows, window_index, since.elapsed()) {
+                        log::warn!("Failed to write window {window_index}: {e:#}");
+                    }
+                    window_index += 1;
+                    window_start = (processor.clone(), std::time::Instant::now());
                 }
             }
 
-            if last_clean_up.elapsed() > Duration::from_secs(60) {
+            processor.evict_smallest_stacks();
+
+            if let Some(published) = &config.metrics {
+                let snapshot = processor.metrics_snapshot(config.metrics_top, present_keys);
+                *published.lock().unwrap() = snapshot.render();
+            }
+            if let Some(published) = &config.tui {
+                *published.lock().unwrap() = Arc::new(processor.tui_snapshot());
+            }
+
+            if is_final_drain {
+                if let Err(e) = resolver.save_symbol_cache() {
+                    log::warn!("Failed to save the symbol cache: {e}");
+                }
+                let (hits, misses) = resolver.cache_stats();
+                log::info!(
+                    "Symbolized {} frames, {hits} of them served from cache, {misses} resolved cold",
+                    hits + misses
+                );
+                return processor;
+            }
+
+            if last_clean_up.elapsed() > config.clean_up_interval {
                 for key in keys_to_drop.drain() {
-                    let unpacked_key = key.into_parts();
-                    buf.remove(&key).ok(); // it may be already deleted
-                    stack_trace_map.remove(&unpacked_key.stack_id).ok();
+                    let reduced_key = key.reduced();
+                    if buf.remove(&key).is_ok() {
+                        // it may be already deleted
+                        removed_keys += 1;
+                    }
+                    processor.forget_snapshots(reduced_key);
+                    stack_trace_map.remove(&reduced_key.stack_id).ok();
+                    if let Some(kernel_stack_traces) = &mut kernel_stack_traces {
+                        kernel_stack_traces
+                            .remove(&reduced_key.kernel_stack_id)
+                            .ok();
+                    }
+                }
+                // stacks of keys the LRU map evicted would fill the stack map for
+                // good. The ring buffer keeps no keys in the maps
+                if events.is_none() {
+                    let unreferenced = orphaned_stacks_of(
+                        stack_trace_map.stack_ids().filter_map(Result::ok),
+                        &referenced_stacks,
+                    );
+                    for stack_id in unreferenced.intersection(&orphaned_stacks) {
+                        stack_trace_map.remove(stack_id).ok();
+                    }
+                    orphaned_stacks = unreferenced;
                 }
                 last_clean_up = std::time::Instant::now();
             }
@@ -73,15 +622,256 @@ pub fn spawn_collector(
 #[derive(Clone, Debug)]
 pub struct EventProcessor {
     allocations_stats: FxHashMap<UnpackedHistogramKey, Histogram>,
+    /// Last polled value of every per cpu slot, only the growth since it is recorded
+    snapshots: FxHashMap<UnpackedHistogramKey, Histogram>,
     resolved_traces: FxHashMap<u32, ResolvedStackTrace>,
+    resolved_kernel_traces: FxHashMap<u32, ResolvedStackTrace>,
+    free_sites: FxHashMap<FreeSiteKey, FreeSiteStats>,
+    live: FxHashMap<ReducedEventKey, LiveStats>,
+    /// Highest live bytes of every stack at any poll. The ebpf side can't track
+    /// it, every cpu sees only its part of the live bytes
+    live_peaks: FxHashMap<ReducedEventKey, i64>,
+    /// Calls of every stack by their latency in nanoseconds, filled with
+    /// `--profile-duration-histogram`
+    latencies: FxHashMap<ReducedEventKey, Histogram>,
+    /// Instruction pointers of the stacks, filled with `raw_capture`
+    raw_traces: FxHashMap<u32, Vec<u64>>,
+    /// Executable mappings of the traced processes, filled with `raw_capture`
+    mappings: FxHashMap<u32, Vec<RawMapping>>,
+    raw_capture: bool,
+    /// Stacks the full ebpf map evicted, their allocations since the last poll are lost
+    evicted_stacks: u64,
+    /// Allocations dropped because the ring buffer was full
+    dropped_events: u64,
+    /// Allocations and free sites dropped because their stack couldn't be stored
+    stack_id_failures: u64,
+    /// Allocations and frees dropped because an argument couldn't be read
+    arg_read_failures: u64,
+    /// Allocations and frees dropped because an ebpf map insert failed
+    insert_failures: u64,
+    tracks_frees: bool,
+    bucket_scheme: BucketScheme,
+    /// Every rendered number is multiplied by this factor, so sampled profiles
+    /// report estimates of the real traffic. `1` means raw numbers.
+    scale_factor: u64,
+    /// `--sample-every`, `scale_factor` follows the effective sampling fraction
+    sample_every: u64,
+    /// Samples are already weighted, `scale_factor` is 1 then
+    size_biased: Option<SizeBiasedSampling>,
+    /// `--sample-every-bytes`, samples are weighted too
+    sample_every_bytes: Option<u64>,
+    /// Allocations of every second, filled with `--timeline`
+    timeline: Option<Timeline>,
+    thread_filter: ThreadFilter,
+    /// Whether the allocations of a pid and tid pass `thread_filter`
+    kept_threads: FxHashMap<(u32, u32), bool>,
+    /// `--max-stacks` and the order the smallest stacks are evicted by
+    max_stacks: Option<(usize, OrderBy)>,
+    /// Stacks evicted to stay within `max_stacks`, their allocations are ignored
+    capped_stacks: FxHashSet<u32>,
+    /// Stacks dropped by [`EventProcessor::drop_shallow_stacks`]
+    shallow_stacks: u64,
+    /// `comm` of the traced processes, read while they are alive
+    process_names: FxHashMap<u32, String>,
+    /// Keys below the skip thresholds on every cpu at their last poll, none of
+    /// their allocations are reported
+    skipped_stacks: FxHashMap<ReducedEventKey, Skipped>,
+    /// Target each child found with `--include-children` descends from, the
+    /// report sums the children into it
+    parent_pids: FxHashMap<u32, u32>,
+}
+
+/// Threshold a skipped key is below
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Skipped {
+    /// `--skip-size`
+    Size,
+    /// `--skip-count`
+    Count,
+}
+
+/// Stacks missing from the report, by the filter which dropped them
+#[derive(Debug, Default)]
+struct DroppedStacks {
+    skip_size: usize,
+    skip_count: usize,
+    shallow: usize,
+    capped: usize,
+    filtered: usize,
+    unsymbolized: usize,
+    empty: usize,
+    top: usize,
+}
+
+impl DroppedStacks {
+    fn total(&self) -> usize {
+        self.skip_size
+            + self.skip_count
+            + self.shallow
+            + self.capped
+            + self.filtered
+            + self.unsymbolized
+            + self.empty
+            + self.top
+    }
+
+    fn print(&self, displayed: usize, mut pager: impl std::fmt::Write) -> anyhow::Result<()> {
+        writeln!(
+            pager,
+            "captured {} stacks, displaying {displayed} after filters",
+            displayed + self.total()
+        )?;
+        let reasons = [
+            (self.skip_size, "below --skip-size"),
+            (self.skip_count, "below --skip-count"),
+            (self.shallow, "shorter than --min-frames"),
+            (self.capped, "evicted by --max-stacks"),
+            (
+                self.filtered,
+                "not matching --include-file, --filter or --exclude",
+            ),
+            (self.unsymbolized, "dropped by --drop-unsymbolized"),
+            (self.empty, "without allocated bytes"),
+            (self.top, "beyond --top"),
+        ];
+        for (count, reason) in reasons {
+            if count > 0 {
+                writeln!(pager, "  {count} {reason}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Allocated bytes and allocations of every second since the start, from the
+/// timestamps of the ring buffer events
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    start_ns: u64,
+    seconds: BTreeMap<u64, (i64, u64)>,
+}
+
+impl Timeline {
+    fn starting_now() -> Self {
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // the ebpf timestamps come from the same clock
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        Self::new(now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+    }
+
+    fn new(start_ns: u64) -> Self {
+        Self {
+            start_ns,
+            seconds: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, timestamp_ns: u64, delta: i64, weight: u64) {
+        let second = timestamp_ns.saturating_sub(self.start_ns) / 1_000_000_000;
+        let (bytes, count) = self.seconds.entry(second).or_default();
+        *bytes = bytes.saturating_add(delta.saturating_mul(weight as i64));
+        *count = count.saturating_add(weight);
+    }
+
+    /// Every second up to the last one with allocations, quiet ones too
+    fn rows(&self, scale_factor: u64) -> Vec<(u64, i64, u64)> {
+        let last = self.seconds.keys().next_back().map_or(0, |last| last + 1);
+        (0..last)
+            .map(|second| {
+                let (bytes, count) = self.seconds.get(&second).copied().unwrap_or_default();
+                (
+                    second,
+                    bytes.saturating_mul(scale_factor as i64),
+                    count.saturating_mul(scale_factor),
+                )
+            })
+            .collect()
+    }
 }
 
 impl EventProcessor {
-    pub fn new() -> Self {
+    pub fn new(scale_factor: u64) -> Self {
         Self {
             allocations_stats: FxHashMap::with_capacity_and_hasher(1024, Default::default()),
+            snapshots: FxHashMap::with_capacity_and_hasher(1024, Default::default()),
             resolved_traces: Default::default(),
+            resolved_kernel_traces: Default::default(),
+            free_sites: Default::default(),
+            live: Default::default(),
+            live_peaks: Default::default(),
+            latencies: Default::default(),
+            raw_traces: Default::default(),
+            mappings: Default::default(),
+            raw_capture: false,
+            evicted_stacks: 0,
+            dropped_events: 0,
+            stack_id_failures: 0,
+            arg_read_failures: 0,
+            insert_failures: 0,
+            tracks_frees: false,
+            bucket_scheme: BucketScheme::Log2,
+            scale_factor: scale_factor.max(1),
+            sample_every: scale_factor.max(1),
+            size_biased: None,
+            sample_every_bytes: None,
+            timeline: None,
+            thread_filter: ThreadFilter::default(),
+            kept_threads: Default::default(),
+            max_stacks: None,
+            capped_stacks: Default::default(),
+            shallow_stacks: 0,
+            process_names: Default::default(),
+            skipped_stacks: Default::default(),
+            parent_pids: Default::default(),
+        }
+    }
+
+    fn is_estimated(&self) -> bool {
+        self.scale_factor > 1 || self.size_biased.is_some() || self.sample_every_bytes.is_some()
+    }
+
+    /// Currently live bytes and allocations of the stack, `None` if frees aren't tracked
+    fn live_of(&self, key: &ReducedEventKey) -> Option<(u64, u64)> {
+        if !self.tracks_frees {
+            return None;
+        }
+        let stats = self.live.get(key).copied().unwrap_or_default();
+        Some((
+            (stats.bytes.max(0) as u64).saturating_mul(self.scale_factor),
+            (stats.count.max(0) as u64).saturating_mul(self.scale_factor),
+        ))
+    }
+
+    /// Value of the stack `order_by` sorts and flame graphs weight by
+    fn weight_of(&self, key: &ReducedEventKey, hist: &Histogram, weight: WeightMetric) -> u64 {
+        match weight {
+            WeightMetric::Count => hist.allocation_count(),
+            WeightMetric::Bytes => hist.total_bytes(),
+            WeightMetric::Live => self.live_of(key).map_or(0, |(bytes, _)| bytes),
+            WeightMetric::Peak => self.peak_live_of(key).unwrap_or_default(),
+        }
+    }
+
+    /// Peak live bytes of the stack, `None` if frees aren't tracked
+    fn peak_live_of(&self, key: &ReducedEventKey) -> Option<u64> {
+        if !self.tracks_frees {
+            return None;
         }
+        let peak = self.live_peaks.get(key).copied().unwrap_or_default();
+        Some((peak.max(0) as u64).saturating_mul(self.scale_factor))
+    }
+
+    /// Stores the polled live stats of the stack and raises its peak
+    fn update_live(&mut self, key: ReducedEventKey, stats: LiveStats) {
+        if !self.keeps_thread(key.pid, key.tid) || self.capped_stacks.contains(&key.stack_id) {
+            return;
+        }
+        let peak = self.live_peaks.entry(key).or_default();
+        *peak = (*peak).max(stats.bytes);
+        self.live.insert(key, stats);
     }
 
     fn process(
@@ -91,329 +881,3702 @@ impl EventProcessor {
         resolver: &Resolver,
         stacktrace_map: &StackTraceMap<MapData>,
     ) {
-        self.allocations_stats.insert(key, *event); // just update with latest snapshot TODO: merge somehow
+        if !self.keeps_thread(key.pid, key.tid) || self.capped_stacks.contains(&key.stack_id) {
+            return;
+        }
+        self.record(key, event);
+        self.resolve(key, resolver, stacktrace_map);
+    }
 
-        match self.resolved_traces.entry(key.stack_id) {
-            Entry::Occupied(_) => {}
-            Entry::Vacant(e) => {
-                let Ok(trace) = stacktrace_map.get(&key.stack_id, 0) else {
-                    return;
-                };
-                let stack_trace = match resolver.resolve_stacktrace(&trace, key.pid) {
-                    Ok(stacktrace) => stacktrace,
-                    Err(e) => {
-                        log::debug!("Failed to resolve {e}");
-                        return;
-                    }
-                };
-                e.insert(stack_trace);
+    /// Aggregates the allocations waiting in the ring buffer, with an `unwinder`
+    /// they are stack samples of `--dwarf-unwind`
+    fn drain_events(
+        &mut self,
+        events: &mut RingBuf<MapData>,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+        kernel_stacktrace_map: Option<&StackTraceMap<MapData>>,
+        mut unwinder: Option<&mut Unwinder>,
+    ) {
+        while let Some(item) = events.next() {
+            let event = match unwinder.as_deref_mut() {
+                Some(unwinder) => self.unwind_sample(&item, unwinder, resolver),
+                None if item.len() >= std::mem::size_of::<AllocEvent>() => {
+                    // SAFETY: the ebpf side writes `AllocEvent`s, the length is checked above
+                    Some(unsafe { std::ptr::read_unaligned(item.as_ptr() as *const AllocEvent) })
+                }
+                None => None,
+            };
+            let Some(event) = event else {
+                log::debug!("Skipping truncated event of {} bytes", item.len());
+                continue;
+            };
+            let key = event.key.into_parts(event.cpu);
+            if !self.keeps_thread(key.pid, key.tid) || self.capped_stacks.contains(&key.stack_id) {
+                continue;
+            }
+            self.record_event(key, event.delta, event.weight);
+            if let Some(timeline) = &mut self.timeline {
+                timeline.record(event.timestamp_ns, event.delta, event.weight);
+            }
+            if unwinder.is_none() {
+                self.resolve(key, resolver, stacktrace_map);
+            }
+            if let Some(kernel_stacktrace_map) = kernel_stacktrace_map {
+                self.resolve_kernel_trace(key.kernel_stack_id, resolver, kernel_stacktrace_map);
             }
         }
     }
 
-    fn merge(&self) -> FxHashMap<ReducedEventKey, Histogram> {
-        let mut allocations_stats: FxHashMap<ReducedEventKey, Histogram> =
-            FxHashMap::with_capacity_and_hasher(self.allocations_stats.len(), Default::default());
-
-        for (key, stat) in &self.allocations_stats {
-            match allocations_stats.entry(key.as_reduced()) {
-                Entry::Occupied(mut e) => e.get_mut().merge(stat),
-                Entry::Vacant(e) => {
-                    e.insert(*stat);
+    /// Unwinds the stack copy of a `--dwarf-unwind` sample, returns its allocation
+    /// keyed by the id of the unwound stack
+    fn unwind_sample(
+        &mut self,
+        item: &[u8],
+        unwinder: &mut Unwinder,
+        resolver: &Resolver,
+    ) -> Option<AllocEvent> {
+        let header_size = std::mem::size_of::<StackSampleHeader>();
+        if item.len() < header_size {
+            return None;
+        }
+        // SAFETY: the ebpf side writes `StackSample`s, the length is checked above
+        let header: StackSampleHeader =
+            unsafe { std::ptr::read_unaligned(item.as_ptr() as *const StackSampleHeader) };
+        let stack = item[header_size..].get(..header.stack_len as usize)?;
+        let pid = header.event.key.reduced().pid;
+        let ips = unwinder.unwind(pid, header.ip, header.sp, header.bp, stack);
+        let stack_id = unwinder.stack_id(&ips);
+        if !self.resolved_traces.contains_key(&stack_id) {
+            self.save_mappings(pid);
+            self.save_process_name(pid);
+            if self.raw_capture {
+                self.raw_traces.insert(stack_id, ips.clone());
+            }
+            match resolver.resolve_ips(&ips, pid) {
+                Ok(trace) => {
+                    self.resolved_traces.insert(stack_id, trace);
                 }
+                Err(e) => log::debug!("Failed to resolve stack {stack_id} of pid {pid}: {e}"),
             }
         }
-        allocations_stats
+        Some(AllocEvent {
+            key: header.event.key.with_stack_id(stack_id),
+            ..header.event
+        })
     }
 
-    pub fn print_histogram(
-        &self,
-        order_by: OrderBy,
-        mut pager: impl std::fmt::Write,
-        csv_path: Option<PathBuf>,
-        flame_graph: Option<PathBuf>,
-    ) -> anyhow::Result<()> {
-        let stats = self.merge();
-        writeln!(pager, "total stack traces: {}\n", stats.len())?;
+    /// Adds an allocation received through the ring buffer, `delta` is negative
+    /// for shrinking reallocations, `weight` is the number of allocations it stands for
+    fn record_event(&mut self, key: UnpackedHistogramKey, delta: i64, weight: u64) {
+        self.allocations_stats
+            .entry(key)
+            .or_insert_with(Histogram::new)
+            .add_weighted_delta(delta, weight, self.bucket_scheme);
+    }
 
-        let mut entries: Vec<(_, _)> = stats.iter().filter(|(_, hist)| hist.total > 0).collect();
+    /// Resolves the stack of the key, with `raw_capture` also saves the mappings
+    /// of its process while it is alive
+    fn resolve(
+        &mut self,
+        key: UnpackedHistogramKey,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+    ) {
+        self.save_mappings(key.pid);
+        self.save_process_name(key.pid);
+        self.resolve_trace(key.stack_id, key.pid, resolver, stacktrace_map);
+    }
 
-        match order_by {
-            OrderBy::Count => {
-                entries.sort_by_key(|(_, hist)| hist.data.iter().sum::<u64>());
-            }
-            OrderBy::Traffic => {
-                entries.sort_by_key(|(_, hist)| hist.total);
+    /// With `raw_capture` saves the mappings of the process while it is alive
+    fn save_mappings(&mut self, pid: u32) {
+        if self.raw_capture {
+            if let Entry::Vacant(e) = self.mappings.entry(pid) {
+                let mappings = raw::read_mappings(pid).unwrap_or_else(|err| {
+                    log::warn!("Failed to read mappings of {pid}: {err}");
+                    Vec::new()
+                });
+                e.insert(mappings);
             }
         }
+    }
 
-        let mut csv_writer = CsvWriter::new(csv_path)?;
-        for (key, hist) in &entries {
-            print_section(&mut pager, '*')?;
+    /// Saves the name of the process once, it is gone from `/proc` by the report
+    fn save_process_name(&mut self, pid: u32) {
+        if let Entry::Vacant(e) = self.process_names.entry(pid) {
+            let name = std::fs::read_to_string(format!("/proc/{pid}/comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            e.insert(name);
+        }
+    }
 
-            if let Some(resolved_trace) = self.resolved_traces.get(&key.stack_id) {
-                for fun in resolved_trace.symbols.iter() {
-                    writeln!(pager, "{} - {}", fun.address, fun.symbol)?;
-                }
-            } else {
-                writeln!(pager, "No resolved stacktrace")?;
+    /// Accumulates the growth of the per cpu slot, `key.cpu`, since it was polled
+    /// last time
+    fn record(&mut self, key: UnpackedHistogramKey, event: &Histogram) {
+        let stats = self
+            .allocations_stats
+            .entry(key)
+            .or_insert_with(Histogram::new);
+        match self.snapshots.entry(key) {
+            Entry::Occupied(mut e) => {
+                stats.merge_delta(event, e.get());
+                e.insert(*event);
+            }
+            Entry::Vacant(e) => {
+                stats.merge(event);
+                e.insert(*event);
             }
-
-            print_section(&mut pager, '-')?;
-
-            print_histogram(hist, &mut pager)?;
-            writeln!(&mut pager, "\n")?;
-            csv_writer.write(key, hist, self)?;
         }
-        csv_writer.finish()?;
+    }
 
-        if let Some(path) = flame_graph {
-            let path_without_extension = match path.file_stem() {
-                Some(stem) => path.with_file_name(stem),
-                None => path,
-            };
+    /// Forgets the allocations captured so far. The snapshots stay, so only
+    /// the growth of the map after this call is recorded
+    fn discard_captured(&mut self) {
+        self.allocations_stats.clear();
+        self.free_sites.clear();
+        self.latencies.clear();
+        if let Some(timeline) = &mut self.timeline {
+            timeline.seconds.clear();
+        }
+    }
 
-            let result = path_without_extension.to_string_lossy();
-            let args = [
-                (
-                    PathBuf::from(format!("{}-by-count.svg", result)),
-                    OrderBy::Count,
-                ),
-                (
-                    PathBuf::from(format!("{}-by-traffic.svg", result)),
-                    OrderBy::Traffic,
-                ),
-            ];
+    /// Writes the `--timeline` CSV: second since the start, allocated bytes and
+    /// allocations of that second
+    pub fn write_timeline(&self, path: &Path) -> anyhow::Result<()> {
+        let timeline = self
+            .timeline
+            .as_ref()
+            .context("the timeline wasn't recorded")?;
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["second", "bytes", "count"])?;
+        for row in timeline.rows(self.scale_factor) {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
 
-            for (path, order_by) in args {
-                let file = std::fs::File::create(&path)?;
-                let file = BufWriter::new(file);
-                self.write_flame_graph(file, order_by)?;
-                log::info!("Flamegraph written to {:?}", path);
+    /// Allocations and frees captured since `baseline`, an earlier clone of this processor.
+    /// The ebpf maps aren't cleared between windows, so nothing of the next window is lost
+    fn since(&self, baseline: &EventProcessor) -> EventProcessor {
+        let mut window = self.clone();
+        for (key, hist) in &mut window.allocations_stats {
+            if let Some(prev) = baseline.allocations_stats.get(key) {
+                let mut delta = Histogram::new();
+                delta.merge_delta(hist, prev);
+                *hist = delta;
+            }
+        }
+        window
+            .allocations_stats
+            .retain(|_, hist| hist.allocation_count() > 0);
+        for (key, stats) in &mut window.free_sites {
+            if let Some(prev) = baseline.free_sites.get(key) {
+                stats.count = stats.count.saturating_sub(prev.count);
+                stats.bytes = stats.bytes.saturating_sub(prev.bytes);
+            }
+        }
+        window.free_sites.retain(|_, stats| stats.count > 0);
+        for (key, hist) in &mut window.latencies {
+            if let Some(prev) = baseline.latencies.get(key) {
+                let mut delta = Histogram::new();
+                delta.merge_delta(hist, prev);
+                *hist = delta;
             }
         }
+        window
+            .latencies
+            .retain(|_, hist| hist.allocation_count() > 0);
+        window
+    }
 
+    /// Writes the report of the `index`th window to the `--interval-dir`
+    fn write_window(
+        &self,
+        windows: &WindowOutput,
+        index: usize,
+        duration: Duration,
+    ) -> anyhow::Result<()> {
+        let path = windows.dir.join(format!("window-{index:04}.txt"));
+        let mut report = String::new();
+        writeln!(report, "window {index}, {:.1}s", duration.as_secs_f64())?;
+        self.print_histogram(
+            &mut report,
+            ReportOptions {
+                capture_duration: duration,
+                ..windows.report.clone()
+            },
+        )?;
+        std::fs::write(&path, report)?;
+        log::info!("Window {index} written to {path:?}");
         Ok(())
     }
 
-    fn write_flame_graph(&self, writer: impl std::io::Write, mode: OrderBy) -> anyhow::Result<()> {
-        let traces = self
-            .allocations_stats
-            .iter()
-            .filter_map(|st| {
-                let symbols = self.resolved_traces.get(&st.0.stack_id)?;
-                let stat = match mode {
-                    OrderBy::Count => st.1.data.iter().sum(),
-                    OrderBy::Traffic => st.1.total,
-                };
-                Some(symbols.as_inferno(stat))
-            })
-            .collect_vec();
+    /// Whether allocations of the thread pass `--exclude-thread` and
+    /// `--only-thread`. The name is read once, when the thread is first seen
+    fn keeps_thread(&mut self, pid: u32, tid: u32) -> bool {
+        if !self.thread_filter.is_active() {
+            return true;
+        }
+        let filter = &self.thread_filter;
+        *self.kept_threads.entry((pid, tid)).or_insert_with(|| {
+            let name = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/comm")).ok();
+            let name = name.as_deref().map(str::trim_end);
+            if name.is_none() {
+                log::debug!("Name of thread {tid} of {pid} is unknown");
+            }
+            filter.keeps(name)
+        })
+    }
 
-        let count_name = match mode {
-            OrderBy::Count => "count",
-            OrderBy::Traffic => "total allocated",
+    /// Evicts the smallest stacks beyond `max_stacks` with everything captured
+    /// of them, like the LRU map of the ebpf side, so a workload with countless
+    /// distinct stacks can't exhaust the memory of the profiler
+    fn evict_smallest_stacks(&mut self) {
+        let Some((max_stacks, order_by)) = self.max_stacks else {
+            return;
         };
+        let mut weights: FxHashMap<u32, u64> = FxHashMap::default();
+        for (key, hist) in &self.merge() {
+            *weights.entry(key.stack_id).or_default() += self.weight_of(key, hist, order_by.into());
+        }
+        if weights.len() <= max_stacks {
+            return;
+        }
+        let mut stacks: Vec<_> = weights.into_iter().collect();
+        stacks.sort_unstable_by_key(|&(stack_id, weight)| (weight, Reverse(stack_id)));
+        let evicted: FxHashSet<u32> = stacks[..stacks.len() - max_stacks]
+            .iter()
+            .map(|&(stack_id, _)| stack_id)
+            .collect();
+        if self.capped_stacks.is_empty() {
+            log::warn!(
+                "More than {max_stacks} stacks were captured, the smallest are evicted, the profile is approximate"
+            );
+        }
+        self.remove_stacks(&evicted);
+        self.capped_stacks.extend(evicted);
+    }
 
-        let mut settings = inferno::flamegraph::Options::default();
-        settings.count_name = count_name.to_string();
-        settings.reverse_stack_order = true;
+    /// Number of keys the skip thresholds dropped for `reason`
+    fn skipped_by(&self, reason: Skipped) -> usize {
+        self.skipped_stacks
+            .values()
+            .filter(|r| **r == reason)
+            .count()
+    }
 
-        let vec_of_strs = traces.iter().map(|x| x.as_str()).collect_vec();
+    /// Drops the stacks with fewer than `min_frames` resolved frames, mostly
+    /// truncated stacks of binaries without frame pointers, see `--min-frames`
+    pub fn drop_shallow_stacks(&mut self, min_frames: usize) {
+        let shallow: FxHashSet<u32> = self
+            .allocations_stats
+            .keys()
+            .map(|key| key.stack_id)
+            .filter(|stack_id| self.depth_of(*stack_id) < min_frames)
+            .collect();
+        self.shallow_stacks += shallow.len() as u64;
+        self.remove_stacks(&shallow);
+    }
 
-        inferno::flamegraph::from_lines(&mut settings, vec_of_strs, writer)?;
+    /// Removes everything captured of the stacks
+    fn remove_stacks(&mut self, stack_ids: &FxHashSet<u32>) {
+        self.allocations_stats
+            .retain(|key, _| !stack_ids.contains(&key.stack_id));
+        self.snapshots
+            .retain(|key, _| !stack_ids.contains(&key.stack_id));
+        self.live
+            .retain(|key, _| !stack_ids.contains(&key.stack_id));
+        self.live_peaks
+            .retain(|key, _| !stack_ids.contains(&key.stack_id));
+        self.latencies
+            .retain(|key, _| !stack_ids.contains(&key.stack_id));
+        self.free_sites
+            .retain(|key, _| !stack_ids.contains(&key.alloc_stack_id));
+        for stack_id in stack_ids {
+            self.resolved_traces.remove(stack_id);
+            self.raw_traces.remove(stack_id);
+        }
+    }
+
+    /// Drops the snapshots of a key removed from the map, so its next values count in full
+    fn forget_snapshots(&mut self, key: ReducedEventKey) {
+        self.snapshots.retain(|k, _| k.as_reduced() != key);
+    }
+
+    fn process_free_site(
+        &mut self,
+        key: FreeSiteKey,
+        stats: FreeSiteStats,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+    ) {
+        if !self.keeps_thread(key.pid, key.tid) || self.capped_stacks.contains(&key.alloc_stack_id)
+        {
+            return;
+        }
+        self.free_sites.insert(key, stats); // per cpu values are already summed up
+        self.resolve_trace(key.free_stack_id, key.pid, resolver, stacktrace_map);
+    }
+
+    fn process_latency(
+        &mut self,
+        key: ReducedEventKey,
+        hist: Histogram,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+    ) {
+        if !self.keeps_thread(key.pid, key.tid) || self.capped_stacks.contains(&key.stack_id) {
+            return;
+        }
+        self.latencies.insert(key, hist); // per cpu values are already summed up
+        self.resolve_trace(key.stack_id, key.pid, resolver, stacktrace_map);
+    }
+
+    fn resolve_trace(
+        &mut self,
+        stack_id: u32,
+        pid: u32,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+    ) {
+        match self.resolved_traces.entry(stack_id) {
+            Entry::Occupied(_) => {}
+            Entry::Vacant(e) => {
+                let Ok(trace) = stacktrace_map.get(&stack_id, 0) else {
+                    return;
+                };
+                if self.raw_capture {
+                    self.raw_traces.insert(stack_id, resolver::ips(&trace));
+                }
+                let stack_trace = match resolver.resolve_stacktrace(&trace, pid) {
+                    Ok(stacktrace) => stacktrace,
+                    Err(e) => {
+                        log::debug!("Failed to resolve stack {stack_id} of pid {pid}: {e}");
+                        return;
+                    }
+                };
+                e.insert(stack_trace);
+            }
+        }
+    }
+
+    fn resolve_kernel_trace(
+        &mut self,
+        kernel_stack_id: u32,
+        resolver: &Resolver,
+        stacktrace_map: &StackTraceMap<MapData>,
+    ) {
+        if kernel_stack_id == NO_KERNEL_STACK {
+            return;
+        }
+        if let Entry::Vacant(e) = self.resolved_kernel_traces.entry(kernel_stack_id) {
+            let Ok(trace) = stacktrace_map.get(&kernel_stack_id, 0) else {
+                return;
+            };
+            match resolver.resolve_kernel_stacktrace(&trace) {
+                Ok(stack_trace) => {
+                    e.insert(stack_trace);
+                }
+                Err(e) => log::debug!("Failed to resolve kernel stack {e}"),
+            }
+        }
+    }
+
+    /// Unsymbolized stacks for `--raw-output`, in raw sampled numbers
+    pub fn to_raw(&self) -> RawProfile {
+        let mut stats: FxHashMap<ReducedEventKey, Histogram> = FxHashMap::default();
+        for (key, stat) in &self.allocations_stats {
+            stats
+                .entry(key.as_reduced())
+                .or_insert_with(Histogram::new)
+                .merge(stat);
+        }
+        let mut stacks: Vec<_> = stats
+            .into_iter()
+            .map(|(key, hist)| RawStack {
+                pid: key.pid,
+                tid: key.tid,
+                stack_id: key.stack_id,
+                function_id: key.function_id,
+                total: hist.total_bytes(),
+                overflow: hist.overflow,
+                buckets: hist.data[..self.bucket_scheme.bucket_count()].to_vec(),
+                ips: self
+                    .raw_traces
+                    .get(&key.stack_id)
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+            .collect();
+        stacks.sort_by_key(|stack| (stack.pid, stack.tid, stack.stack_id, stack.function_id));
+        let mut processes: Vec<_> = self
+            .mappings
+            .iter()
+            .map(|(pid, mappings)| RawProcess {
+                pid: *pid,
+                mappings: mappings.clone(),
+            })
+            .collect();
+        processes.sort_by_key(|process| process.pid);
+        RawProfile {
+            scale_factor: self.scale_factor,
+            size_biased: self.size_biased,
+            sample_every_bytes: self.sample_every_bytes,
+            bucket_scheme: self.bucket_scheme.to_string(),
+            processes,
+            stacks,
+        }
+    }
+
+    /// Rebuilds the profile from `--raw-output`, symbolizing the stacks offline
+    pub fn from_raw(profile: RawProfile, symbolizer: &OfflineSymbolizer) -> anyhow::Result<Self> {
+        let mut processor = Self::new(profile.scale_factor);
+        processor.size_biased = profile.size_biased;
+        processor.sample_every_bytes = profile.sample_every_bytes;
+        processor.bucket_scheme = profile
+            .bucket_scheme
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid bucket scheme {}: {e}", profile.bucket_scheme))?;
+        let mappings: FxHashMap<u32, Vec<RawMapping>> = profile
+            .processes
+            .into_iter()
+            .map(|process| (process.pid, process.mappings))
+            .collect();
+        for stack in profile.stacks {
+            let mut hist = Histogram::new();
+            for (bucket, count) in hist.data.iter_mut().zip(&stack.buckets) {
+                *bucket = *count;
+            }
+            hist.overflow = stack.overflow;
+            hist.total = stack.total;
+            let key = UnpackedHistogramKey {
+                pid: stack.pid,
+                tid: stack.tid,
+                stack_id: stack.stack_id,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: stack.function_id,
+                cpu: 0,
+            };
+            processor.allocations_stats.insert(key, hist);
+            if let Entry::Vacant(e) = processor.resolved_traces.entry(stack.stack_id) {
+                if !stack.ips.is_empty() {
+                    let process_mappings = mappings.get(&stack.pid).map(Vec::as_slice);
+                    e.insert(
+                        symbolizer.symbolize(&stack.ips, process_mappings.unwrap_or_default()),
+                    );
+                }
+            }
+        }
+        Ok(processor)
+    }
+
+    fn merge(&self) -> FxHashMap<ReducedEventKey, Histogram> {
+        let mut allocations_stats: FxHashMap<ReducedEventKey, Histogram> =
+            FxHashMap::with_capacity_and_hasher(self.allocations_stats.len(), Default::default());
+
+        for (key, stat) in &self.allocations_stats {
+            match allocations_stats.entry(key.as_reduced()) {
+                Entry::Occupied(mut e) => e.get_mut().merge(stat),
+                Entry::Vacant(e) => {
+                    e.insert(*stat);
+                }
+            }
+        }
+        if self.is_estimated() {
+            for hist in allocations_stats.values_mut() {
+                hist.scale(self.scale_factor);
+            }
+        }
+        allocations_stats
+    }
+
+    /// Every stack with its histogram merged across cpus and scaled like the
+    /// report, and its frames if it was symbolized
+    pub fn stacks(
+        &self,
+    ) -> impl Iterator<Item = (ReducedEventKey, Histogram, Option<&ResolvedStackTrace>)> {
+        self.merge()
+            .into_iter()
+            .map(|(key, hist)| (key, hist, self.resolved_traces.get(&key.stack_id)))
+    }
+
+    /// Allocated bytes of all stacks, scaled like the report
+    pub fn total_bytes(&self) -> u64 {
+        self.merge().values().map(Histogram::total_bytes).sum()
+    }
+
+    /// One line of `key=value` totals for scripts, e.g. to check that nothing
+    /// was dropped. `None` pids stand for every process running the program
+    pub fn exit_summary(
+        &self,
+        pids: &[Option<i32>],
+        functions: &[JemallocAllocFunctions],
+        duration: Duration,
+    ) -> String {
+        let stats = self.merge();
+        let pids = pids
+            .iter()
+            .map(|pid| pid.map_or_else(|| "all".to_string(), |pid| pid.to_string()))
+            .join(",");
+        let dropped = self.dropped_events
+            + self.stack_id_failures
+            + self.arg_read_failures
+            + self.insert_failures;
+        format!(
+            "jeprofl: pid={pids} functions={} duration={:.1}s distinct_stacks={} total_bytes={} total_allocs={} dropped={dropped} evicted_stacks={} shallow_stacks={}",
+            functions.iter().join(","),
+            duration.as_secs_f64(),
+            stats.len(),
+            stats.values().map(Histogram::total_bytes).sum::<u64>(),
+            stats.values().map(Histogram::allocation_count).sum::<u64>(),
+            self.evicted_stacks + self.capped_stacks.len() as u64,
+            self.shallow_stacks
+        )
+    }
+
+    /// The `n` biggest stacks by `order_by`, in the order of the report
+    pub fn top_n(
+        &self,
+        order_by: OrderBy,
+        n: usize,
+    ) -> Vec<(ReducedEventKey, Histogram, Option<&ResolvedStackTrace>)> {
+        let mut stacks: Vec<_> = self
+            .stacks()
+            .filter(|(_, hist, _)| hist.total_bytes() > 0)
+            .collect();
+        stacks.sort_by_key(|(key, hist, _)| {
+            report_order(key, self.weight_of(key, hist, order_by.into()))
+        });
+        stacks.truncate(n);
+        stacks
+    }
+
+    /// Number of resolved frames of the stack, 0 if it wasn't resolved
+    fn depth_of(&self, stack_id: u32) -> usize {
+        self.resolved_traces
+            .get(&stack_id)
+            .map_or(0, |trace| trace.symbols.len())
+    }
+
+    /// Children of the targets, found with `--include-children`, and the target
+    /// each descends from
+    pub fn set_parent_pids(&mut self, parent_pids: FxHashMap<u32, u32>) {
+        self.parent_pids = parent_pids;
+    }
+
+    /// Copy of the profile where the allocations of the children are the ones
+    /// of the target they descend from
+    fn children_folded(&self) -> EventProcessor {
+        let parent = |pid: u32| self.parent_pids.get(&pid).copied().unwrap_or(pid);
+        let mut folded = EventProcessor {
+            allocations_stats: FxHashMap::default(),
+            snapshots: FxHashMap::default(),
+            free_sites: FxHashMap::default(),
+            live: FxHashMap::default(),
+            live_peaks: FxHashMap::default(),
+            latencies: FxHashMap::default(),
+            skipped_stacks: FxHashMap::default(),
+            parent_pids: FxHashMap::default(),
+            ..self.clone()
+        };
+        for (key, hist) in &self.allocations_stats {
+            let key = UnpackedHistogramKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            folded
+                .allocations_stats
+                .entry(key)
+                .or_insert_with(Histogram::new)
+                .merge(hist);
+        }
+        for (key, stats) in &self.live {
+            let key = ReducedEventKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            folded.live.entry(key).or_default().merge(stats);
+        }
+        for (key, peak) in &self.live_peaks {
+            let key = ReducedEventKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            // peaks of different processes may be at different times, their sum is an upper bound
+            *folded.live_peaks.entry(key).or_default() += peak;
+        }
+        for (key, hist) in &self.latencies {
+            let key = ReducedEventKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            folded
+                .latencies
+                .entry(key)
+                .or_insert_with(Histogram::new)
+                .merge(hist);
+        }
+        for (key, stats) in &self.free_sites {
+            let key = FreeSiteKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            folded.free_sites.entry(key).or_default().merge(stats);
+        }
+        for (key, skipped) in &self.skipped_stacks {
+            let key = ReducedEventKey {
+                pid: parent(key.pid),
+                ..*key
+            };
+            folded.skipped_stacks.insert(key, *skipped);
+        }
+        folded
+    }
+
+    /// Total size and allocations of every child folded into its parent, the
+    /// biggest first
+    fn print_children(&self, mut pager: impl std::fmt::Write) -> anyhow::Result<()> {
+        let mut children: FxHashMap<u32, (u64, u64)> = FxHashMap::default();
+        for (key, hist) in &self.allocations_stats {
+            if self.parent_pids.contains_key(&key.pid) {
+                let (bytes, count) = children.entry(key.pid).or_default();
+                *bytes = bytes.saturating_add(hist.total_bytes());
+                *count = count.saturating_add(hist.allocation_count());
+            }
+        }
+        if children.is_empty() {
+            return Ok(());
+        }
+        let mut children: Vec<_> = children.into_iter().collect();
+        children.sort_by_key(|(pid, (bytes, _))| (Reverse(*bytes), *pid));
+        writeln!(
+            pager,
+            "{} child processes, summed into their parent below:",
+            children.len()
+        )?;
+        writeln!(
+            pager,
+            "{:>8} {:>8} {:<16} {:>12} {:>12}",
+            "parent", "pid", "process", "bytes", "allocations"
+        )?;
+        for (pid, (bytes, count)) in children {
+            let name = self
+                .process_names
+                .get(&pid)
+                .filter(|name| !name.is_empty())
+                .map_or("?", String::as_str);
+            writeln!(
+                pager,
+                "{:>8} {pid:>8} {name:<16} {:>12} {count:>12}",
+                self.parent_pids[&pid],
+                bytesize::to_string(bytes, true),
+            )?;
+        }
+        writeln!(pager)?;
+        Ok(())
+    }
+
+    /// Copy of the profile where stacks resolved to the same frames share the
+    /// smallest of their stack ids, e.g. stacks which differ only in the
+    /// addresses within the same functions. With `max_depth` the frames are
+    /// truncated to it first
+    fn merged_by_symbols(&self, max_depth: Option<usize>) -> EventProcessor {
+        let mut stack_ids: Vec<_> = self.resolved_traces.keys().copied().collect();
+        stack_ids.sort_unstable();
+        let max_depth = max_depth.unwrap_or(usize::MAX);
+        let mut first_of_frames: FxHashMap<Vec<&str>, u32> = FxHashMap::default();
+        let mut canonical = FxHashMap::default();
+        for &stack_id in &stack_ids {
+            let frames = self.resolved_traces[&stack_id]
+                .symbols
+                .iter()
+                .take(max_depth)
+                .flat_map(|fun| {
+                    fun.inlined
+                        .iter()
+                        .map(String::as_str)
+                        .chain([fun.symbol.as_str()])
+                })
+                .collect();
+            canonical.insert(stack_id, *first_of_frames.entry(frames).or_insert(stack_id));
+        }
+        let canonical = |stack_id: u32| canonical.get(&stack_id).copied().unwrap_or(stack_id);
+
+        let mut merged = EventProcessor {
+            allocations_stats: FxHashMap::default(),
+            snapshots: FxHashMap::default(),
+            free_sites: FxHashMap::default(),
+            live: FxHashMap::default(),
+            live_peaks: FxHashMap::default(),
+            latencies: FxHashMap::default(),
+            ..self.clone()
+        };
+        for trace in merged.resolved_traces.values_mut() {
+            trace.symbols.truncate(max_depth);
+        }
+        for (key, hist) in &self.allocations_stats {
+            let key = UnpackedHistogramKey {
+                stack_id: canonical(key.stack_id),
+                ..*key
+            };
+            merged
+                .allocations_stats
+                .entry(key)
+                .or_insert_with(Histogram::new)
+                .merge(hist);
+        }
+        for (key, stats) in &self.live {
+            let key = ReducedEventKey {
+                stack_id: canonical(key.stack_id),
+                ..*key
+            };
+            merged.live.entry(key).or_default().merge(stats);
+        }
+        for (key, peak) in &self.live_peaks {
+            let key = ReducedEventKey {
+                stack_id: canonical(key.stack_id),
+                ..*key
+            };
+            // peaks of different stacks may be at different times, their sum is an upper bound
+            *merged.live_peaks.entry(key).or_default() += peak;
+        }
+        for (key, hist) in &self.latencies {
+            let key = ReducedEventKey {
+                stack_id: canonical(key.stack_id),
+                ..*key
+            };
+            merged
+                .latencies
+                .entry(key)
+                .or_insert_with(Histogram::new)
+                .merge(hist);
+        }
+        for (key, stats) in &self.free_sites {
+            let key = FreeSiteKey {
+                alloc_stack_id: canonical(key.alloc_stack_id),
+                free_stack_id: canonical(key.free_stack_id),
+                ..*key
+            };
+            merged.free_sites.entry(key).or_default().merge(stats);
+        }
+        merged
+    }
+
+    /// Allocated bytes of every stack per cpu
+    fn split_by_cpu(&self) -> FxHashMap<ReducedEventKey, BTreeMap<u32, u64>> {
+        let mut split: FxHashMap<ReducedEventKey, BTreeMap<u32, u64>> = FxHashMap::default();
+        for (key, stat) in &self.allocations_stats {
+            *split
+                .entry(key.as_reduced())
+                .or_default()
+                .entry(key.cpu)
+                .or_default() += stat.total_bytes().saturating_mul(self.scale_factor);
+        }
+        split
+    }
+
+    /// Caller of the allocation function, the most telling frame of the stack
+    fn top_frame(&self, stack_id: u32) -> &str {
+        self.resolved_traces
+            .get(&stack_id)
+            .and_then(|trace| trace.symbols.get(1).or(trace.symbols.first()))
+            .map_or("unknown", |fun| fun.symbol.as_str())
+    }
+
+    /// One line per stack with its totals, median size and top frame
+    fn print_compact(
+        &self,
+        shown: &[(&ReducedEventKey, &Histogram)],
+        multiple_functions: bool,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        writeln!(
+            pager,
+            "{:>10} | {:>9} | {:>10} | {:>5} | Frame",
+            "Total", "Count", "p50", "Depth"
+        )?;
+        for (key, hist) in shown {
+            let mut function = match split_function_id(key.function_id) {
+                _ if multiple_functions => format!(" [{}]", function_name(key.function_id)),
+                (_, Some(alignment)) => format!(" [align {alignment}]"),
+                (_, None) => String::new(),
+            };
+            if hist.is_saturated() {
+                function.push_str(&format!(" [{SATURATED_NOTE}]"));
+            }
+            writeln!(
+                pager,
+                "{:>10} | {:>9} | {:>10} | {:>5} | {}{function}",
+                bytesize::to_string(hist.total_bytes(), true),
+                hist.allocation_count(),
+                bytesize::to_string(hist.percentile(0.5, self.bucket_scheme), true),
+                self.depth_of(key.stack_id),
+                self.top_frame(key.stack_id)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// One line per stack with the latency of its sampled allocation calls,
+    /// the most time spent first
+    fn print_latencies(
+        &self,
+        entries: &[(&ReducedEventKey, &Histogram)],
+        top: Option<usize>,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        let mut latencies: Vec<_> = entries
+            .iter()
+            .filter_map(|(key, _)| Some((*key, self.latencies.get(*key)?)))
+            .filter(|(_, hist)| hist.allocation_count() > 0)
+            .collect();
+        latencies.sort_by_key(|(key, hist)| report_order(key, hist.total_bytes()));
+        let shown = top_entries(&latencies, top, false);
+        let nanos = |ns: u64| format!("{:?}", Duration::from_nanos(ns));
+        writeln!(pager, "Latency of the sampled allocation calls:")?;
+        writeln!(
+            pager,
+            "{:>9} | {:>10} | {:>10} | {:>10} | Frame",
+            "Calls", "p50", "p99", "Total"
+        )?;
+        for (key, hist) in &shown {
+            writeln!(
+                pager,
+                "{:>9} | {:>10} | {:>10} | {:>10} | {}",
+                hist.allocation_count(),
+                nanos(hist.percentile(0.5, BucketScheme::Log2)),
+                nanos(hist.percentile(0.99, BucketScheme::Log2)),
+                nanos(hist.total_bytes()),
+                self.top_frame(key.stack_id)
+            )?;
+        }
+        if shown.len() < latencies.len() {
+            writeln!(
+                pager,
+                "... and {} more stacks omitted",
+                latencies.len() - shown.len()
+            )?;
+        }
+        writeln!(pager)?;
+        Ok(())
+    }
+
+    /// Sums the stacks by their frame at `depth`, 0 is the allocation function,
+    /// and prints one line per function
+    fn print_by_function(
+        &self,
+        entries: &[(&ReducedEventKey, &Histogram)],
+        depth: usize,
+        order_by: OrderBy,
+        top: Option<usize>,
+        ascending: bool,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        let mut functions: FxHashMap<&str, (Histogram, u64, u64)> = FxHashMap::default();
+        for (key, hist) in entries {
+            let function = self
+                .resolved_traces
+                .get(&key.stack_id)
+                .and_then(|trace| trace.symbols.get(depth))
+                .map_or("unknown", |fun| fun.symbol.as_str());
+            let (merged, stacks, weight) = functions
+                .entry(function)
+                .or_insert_with(|| (Histogram::new(), 0, 0));
+            merged.merge(hist);
+            *stacks += 1;
+            *weight += self.weight_of(key, hist, order_by.into());
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by_key(|(function, (_, _, weight))| (Reverse(*weight), *function));
+        let shown = top_entries(&functions, top, ascending);
+        writeln!(
+            pager,
+            "{:>10} | {:>9} | {:>10} | {:>6} | Function",
+            "Total", "Count", "p50", "Stacks"
+        )?;
+        for (function, (hist, stacks, _)) in &shown {
+            writeln!(
+                pager,
+                "{:>10} | {:>9} | {:>10} | {:>6} | {function}",
+                bytesize::to_string(hist.total_bytes(), true),
+                hist.allocation_count(),
+                bytesize::to_string(hist.percentile(0.5, self.bucket_scheme), true),
+                stacks
+            )?;
+        }
+        let omitted = functions.len() - shown.len();
+        if omitted > 0 {
+            writeln!(pager, "... and {omitted} more functions omitted")?;
+        }
+        Ok(())
+    }
+
+    /// Totals of the profile and of the `top` biggest stacks by allocated bytes
+    fn metrics_snapshot(&self, top: usize, map_entries: u64) -> MetricsSnapshot {
+        let stats = self.merge();
+        // stacks of different processes and threads share the labels
+        let mut stacks: FxHashMap<u32, StackMetrics> = FxHashMap::default();
+        for (key, hist) in &stats {
+            let stack = stacks.entry(key.stack_id).or_insert_with(|| StackMetrics {
+                stack_id: key.stack_id,
+                frame: self.top_frame(key.stack_id).to_string(),
+                bytes: 0,
+                count: 0,
+                live_bytes: None,
+            });
+            stack.bytes = stack.bytes.saturating_add(hist.total_bytes());
+            stack.count = stack.count.saturating_add(hist.allocation_count());
+            if let Some((live_bytes, _)) = self.live_of(key) {
+                *stack.live_bytes.get_or_insert(0) += live_bytes;
+            }
+        }
+        let mut stacks: Vec<_> = stacks.into_values().collect();
+        stacks.sort_by_key(|stack| (Reverse(stack.bytes), stack.stack_id));
+
+        MetricsSnapshot {
+            distinct_stacks: stacks.len() as u64,
+            alloc_bytes: stacks.iter().map(|stack| stack.bytes).sum(),
+            alloc_count: stacks.iter().map(|stack| stack.count).sum(),
+            stacks: stacks.into_iter().take(top).collect(),
+            map_entries,
+            evicted_stacks: self.evicted_stacks,
+            dropped_events: self.dropped_events,
+            stack_id_failures: self.stack_id_failures,
+            arg_read_failures: self.arg_read_failures,
+            insert_failures: self.insert_failures,
+        }
+    }
+
+    /// Every stack with its resolved frames for the `--tui` view
+    fn tui_snapshot(&self) -> tui::Snapshot {
+        let stacks = self
+            .merge()
+            .into_iter()
+            .map(|(key, histogram)| TuiStack {
+                pid: key.pid,
+                tid: key.tid,
+                stack_id: key.stack_id,
+                function: function_name(key.function_id),
+                frames: self
+                    .resolved_traces
+                    .get(&key.stack_id)
+                    .map(|trace| {
+                        trace
+                            .symbols
+                            .iter()
+                            .flat_map(OwnedSymbol::names)
+                            .map(str::to_string)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                live_bytes: self.live_of(&key).map(|(bytes, _)| bytes),
+                histogram,
+            })
+            .collect();
+        let mut note = String::new();
+        self.print_scale_note(&mut note).ok();
+        tui::Snapshot {
+            stacks,
+            bucket_scheme: self.bucket_scheme,
+            note: (!note.is_empty()).then(|| note.trim_end().to_string()),
+        }
+    }
+
+    /// Whether the stack passes the `--include-file` filter
+    fn matches_files(&self, stack_id: u32, include_files: &[String]) -> bool {
+        if include_files.is_empty() {
+            return true;
+        }
+        let Some(trace) = self.resolved_traces.get(&stack_id) else {
+            return false;
+        };
+        include_files.iter().any(|pattern| trace.has_file(pattern))
+    }
+
+    fn is_unsymbolized(&self, stack_id: u32) -> bool {
+        self.resolved_traces
+            .get(&stack_id)
+            .is_some_and(ResolvedStackTrace::is_unsymbolized)
+    }
+
+    /// Warns about stacks of only unknown frames, their symbols are bogus
+    fn print_unsymbolized_note(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        dropped: bool,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        let unsymbolized = stats
+            .keys()
+            .map(|key| key.stack_id)
+            .unique()
+            .filter(|stack_id| self.is_unsymbolized(*stack_id))
+            .count();
+        if unsymbolized == 0 {
+            return Ok(());
+        }
+        write!(
+            pager,
+            "{unsymbolized} stacks could not be symbolized, check --debuginfod or frame pointers"
+        )?;
+        if dropped {
+            write!(pager, ", they are dropped")?;
+        }
+        writeln!(pager)?;
+        Ok(())
+    }
+
+    /// Total size, allocations and distinct stacks of every process, biggest
+    /// first. Prints nothing for a single process, returns whether it printed
+    fn print_per_pid(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<bool> {
+        let mut processes: FxHashMap<u32, (u64, u64, FxHashSet<u32>)> = FxHashMap::default();
+        for (key, hist) in stats {
+            let (bytes, count, stacks) = processes.entry(key.pid).or_default();
+            *bytes = bytes.saturating_add(hist.total_bytes());
+            *count = count.saturating_add(hist.allocation_count());
+            stacks.insert(key.stack_id);
+        }
+        if processes.len() < 2 {
+            return Ok(false);
+        }
+        let mut processes: Vec<_> = processes.into_iter().collect();
+        processes.sort_by_key(|(pid, (bytes, _, _))| (Reverse(*bytes), *pid));
+        writeln!(pager, "{} processes:", processes.len())?;
+        writeln!(
+            pager,
+            "{:>8} {:<16} {:>12} {:>12} {:>8}",
+            "pid", "process", "bytes", "allocations", "stacks"
+        )?;
+        for (pid, (bytes, count, stacks)) in processes {
+            let name = self
+                .process_names
+                .get(&pid)
+                .filter(|name| !name.is_empty())
+                .map_or("?", String::as_str);
+            writeln!(
+                pager,
+                "{pid:>8} {name:<16} {:>12} {count:>12} {:>8}",
+                bytesize::to_string(bytes, true),
+                stacks.len()
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Whether the stack passes the `--filter` and `--exclude` filters
+    fn matches_symbols(&self, stack_id: u32, filters: &[Regex], excludes: &[Regex]) -> bool {
+        let trace = self.resolved_traces.get(&stack_id);
+        let has_symbol = |pattern: &Regex| trace.is_some_and(|trace| trace.has_symbol(pattern));
+        (filters.is_empty() || filters.iter().any(has_symbol)) && !excludes.iter().any(has_symbol)
+    }
+
+    pub fn print_histogram(
+        &self,
+        mut pager: impl std::fmt::Write,
+        options: ReportOptions,
+    ) -> anyhow::Result<()> {
+        if matches!(options.merge_by, MergeBy::Symbols) || options.max_depth.is_some() {
+            return self.merged_by_symbols(options.max_depth).print_histogram(
+                pager,
+                ReportOptions {
+                    merge_by: MergeBy::Stack,
+                    max_depth: None,
+                    ..options
+                },
+            );
+        }
+        if !self.parent_pids.is_empty() {
+            self.print_children(&mut pager)?;
+            return self.children_folded().print_histogram(pager, options);
+        }
+        let ReportOptions {
+            order_by,
+            csv_path,
+            csv_format,
+            flame_graph,
+            flame,
+            folded_path,
+            pprof,
+            json_path,
+            include_files,
+            filters,
+            excludes,
+            summary_only,
+            compact,
+            color,
+            source_lines,
+            top,
+            csv_top,
+            csv_weight,
+            ascending,
+            order_by_depth,
+            max_depth: _,
+            drop_unsymbolized,
+            per_function,
+            by_function,
+            by_cpu,
+            multiple_functions,
+            capture_duration,
+            merge_by: _,
+        } = options;
+
+        let mut stats = self.merge();
+        let matches_filters = |key: &ReducedEventKey| {
+            self.matches_files(key.stack_id, &include_files)
+                && self.matches_symbols(key.stack_id, &filters, &excludes)
+        };
+        let is_dropped_unsymbolized =
+            |key: &ReducedEventKey| drop_unsymbolized && self.is_unsymbolized(key.stack_id);
+        if summary_only {
+            stats.retain(|key, _| matches_filters(key) && !is_dropped_unsymbolized(key));
+            return self.print_summary(&stats, capture_duration, pager);
+        }
+        let mut unsymbolized_note = String::new();
+        self.print_unsymbolized_note(&stats, drop_unsymbolized, &mut unsymbolized_note)?;
+        let mut dropped = DroppedStacks {
+            skip_size: self.skipped_by(Skipped::Size),
+            skip_count: self.skipped_by(Skipped::Count),
+            shallow: self.shallow_stacks as usize,
+            capped: self.capped_stacks.len(),
+            ..Default::default()
+        };
+        let captured = stats.len();
+        stats.retain(|key, _| matches_filters(key));
+        dropped.filtered = captured - stats.len();
+        let matching = stats.len();
+        stats.retain(|key, _| !is_dropped_unsymbolized(key));
+        dropped.unsymbolized = matching - stats.len();
+
+        let mut entries: Vec<(_, _)> = stats
+            .iter()
+            .filter(|(_, hist)| hist.total_bytes() > 0)
+            .collect();
+        dropped.empty = stats.len() - entries.len();
+
+        let sort = |entries: &mut Vec<(&ReducedEventKey, &Histogram)>, weight| {
+            entries.sort_by_key(|(key, hist)| {
+                let depth = if order_by_depth {
+                    self.depth_of(key.stack_id)
+                } else {
+                    0
+                };
+                (
+                    Reverse(depth),
+                    report_order(key, self.weight_of(key, hist, weight)),
+                )
+            })
+        };
+        sort(&mut entries, order_by.into());
+
+        let shown = top_entries(&entries, top, ascending);
+        dropped.top = entries.len() - shown.len();
+        dropped.print(shown.len(), &mut pager)?;
+        pager.write_str(&unsymbolized_note)?;
+        self.print_scale_note(&mut pager)?;
+        self.print_loss_notes(&mut pager)?;
+        let saturated = entries
+            .iter()
+            .filter(|(_, hist)| hist.is_saturated())
+            .count();
+        if saturated > 0 {
+            writeln!(
+                pager,
+                "{saturated} stacks overflowed their counters, their numbers are lower bounds"
+            )?;
+        }
+        if per_function {
+            print_per_function(&stats, &mut pager)?;
+        }
+        let multiple_pids = self.print_per_pid(&stats, &mut pager)?;
+        writeln!(pager)?;
+
+        let free_sites = self.group_free_sites();
+        let (cpu_split, cpu_nodes) = if by_cpu {
+            (self.split_by_cpu(), topology::cpu_nodes())
+        } else {
+            Default::default()
+        };
+        if let Some(depth) = by_function {
+            self.print_by_function(&entries, depth, order_by, top, ascending, &mut pager)?;
+        } else if compact {
+            self.print_compact(&shown, multiple_functions, &mut pager)?;
+        } else {
+            for (key, hist) in &shown {
+                print_section(&mut pager, '*')?;
+
+                if key.tid != 0 {
+                    writeln!(pager, "pid {} tid {}", key.pid, key.tid)?;
+                } else if multiple_pids {
+                    writeln!(pager, "pid {}", key.pid)?;
+                }
+                if multiple_functions {
+                    writeln!(pager, "function: {}", function_name(key.function_id))?;
+                } else if let (_, Some(alignment)) = split_function_id(key.function_id) {
+                    writeln!(pager, "alignment: {alignment}")?;
+                }
+                if let Some(resolved_trace) = self.resolved_traces.get(&key.stack_id) {
+                    for fun in resolved_trace.symbols.iter() {
+                        for inlined in &fun.inlined {
+                            writeln!(pager, "{} -   {inlined} [inlined]", fun.address)?;
+                        }
+                        writeln!(pager, "{} - {}", fun.address, fun.display(source_lines))?;
+                    }
+                } else {
+                    writeln!(pager, "No resolved stacktrace")?;
+                }
+                if let Some(kernel_trace) = self.resolved_kernel_traces.get(&key.kernel_stack_id) {
+                    writeln!(pager, "--- kernel ---")?;
+                    for fun in kernel_trace.symbols.iter() {
+                        writeln!(pager, "{} - {}", fun.address, fun.symbol)?;
+                    }
+                }
+
+                print_section(&mut pager, '-')?;
+
+                if hist.is_saturated() {
+                    writeln!(pager, "{SATURATED_NOTE}")?;
+                }
+                write_histogram(hist, self.bucket_scheme, color, &mut pager)?;
+                if let Some((bytes_per_sec, count_per_sec)) = rates(hist, capture_duration) {
+                    writeln!(
+                        pager,
+                        "Rate: {}/s in {count_per_sec:.1} allocations/s",
+                        bytesize::to_string(bytes_per_sec as u64, true)
+                    )?;
+                }
+                if let Some(split) = cpu_split.get(key) {
+                    print_cpu_split(split, &cpu_nodes, &mut pager)?;
+                }
+                if let Some((live_bytes, live_count)) = self.live_of(key) {
+                    writeln!(
+                        pager,
+                        "Currently live: {} in {} allocations, peak {}",
+                        bytesize::to_string(live_bytes, true),
+                        live_count,
+                        bytesize::to_string(self.peak_live_of(key).unwrap_or_default(), true)
+                    )?;
+                }
+                if let Some(sites) = free_sites.get(key) {
+                    self.print_free_sites(sites, &mut pager)?;
+                }
+                writeln!(&mut pager, "\n")?;
+            }
+        }
+        let omitted = entries.len() - shown.len();
+        if omitted > 0 && by_function.is_none() {
+            // `shown` are the first entries, reversed with `ascending`
+            let omitted_bytes = entries[shown.len()..].iter().fold(0u64, |sum, (_, hist)| {
+                sum.saturating_add(hist.total_bytes())
+            });
+            writeln!(
+                pager,
+                "... and {omitted} more stacks omitted ({})",
+                bytesize::to_string(omitted_bytes, true)
+            )?;
+        }
+        if !self.latencies.is_empty() {
+            writeln!(pager)?;
+            self.print_latencies(&entries, top, &mut pager)?;
+        }
+
+        let mut csv_writer = CsvWriter::new(
+            csv_path,
+            csv_format,
+            self.bucket_scheme,
+            self.scale_factor,
+            source_lines,
+            capture_duration,
+        )?;
+        let mut csv_entries = entries.clone();
+        if let Some(weight) = csv_weight {
+            sort(&mut csv_entries, weight);
+        }
+        for (key, hist) in top_entries(&csv_entries, csv_top.or(top), ascending) {
+            csv_writer.write(key, hist, self)?;
+        }
+        csv_writer.finish()?;
+
+        if let Some(path) = json_path {
+            let stacks: Vec<_> = entries
+                .iter()
+                .map(|(key, hist)| JsonStack::new(key, hist, self))
+                .collect();
+            let file = BufWriter::new(std::fs::File::create(&path)?);
+            serde_json::to_writer_pretty(file, &stacks)?;
+            log::info!("json written to {:?}", path);
+        }
+
+        if let Some(path) = pprof {
+            let file = BufWriter::new(std::fs::File::create(&path)?);
+            self.write_pprof(&stats, file, capture_duration)?;
+            log::info!("pprof profile written to {:?}", path);
+        }
+
+        if let Some(path) = folded_path {
+            let stats: FxHashMap<ReducedEventKey, Histogram> =
+                shown.iter().map(|(key, hist)| (**key, **hist)).collect();
+            let mut file = BufWriter::new(std::fs::File::create(&path)?);
+            self.write_folded(
+                &stats,
+                &mut file,
+                flame.weight.unwrap_or(WeightMetric::Bytes),
+            )?;
+            file.flush()?;
+        }
+
+        if let Some(path) = flame_graph {
+            let stats: FxHashMap<ReducedEventKey, Histogram> =
+                shown.iter().map(|(key, hist)| (**key, **hist)).collect();
+            let path_without_extension = match path.file_stem() {
+                Some(stem) => path.with_file_name(stem),
+                None => path.clone(),
+            };
+
+            let result = path_without_extension.to_string_lossy();
+            let args = match flame.weight {
+                Some(weight) => vec![(path.clone(), weight)],
+                None => vec![
+                    (
+                        PathBuf::from(format!("{}-by-count.svg", result)),
+                        WeightMetric::Count,
+                    ),
+                    (
+                        PathBuf::from(format!("{}-by-traffic.svg", result)),
+                        WeightMetric::Bytes,
+                    ),
+                ],
+            };
+
+            for (path, weight) in args {
+                let file = std::fs::File::create(&path)?;
+                let file = BufWriter::new(file);
+                self.write_flame_graph(&stats, file, weight, &flame)?;
+                log::info!("Flamegraph written to {:?}", path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn print_scale_note(&self, mut pager: impl std::fmt::Write) -> anyhow::Result<()> {
+        if let Some(sampling) = self.size_biased {
+            writeln!(
+                pager,
+                "allocations below {} sampled 1/{}: sizes and counts below are estimates",
+                bytesize::to_string(sampling.threshold, true),
+                sampling.sample_every
+            )?;
+        } else if let Some(every) = self.sample_every_bytes {
+            writeln!(
+                pager,
+                "sampled every {} allocated: sizes and counts below are estimates",
+                bytesize::to_string(every, true)
+            )?;
+        } else if self.is_estimated() {
+            writeln!(
+                pager,
+                "sampled 1/{}: sizes and counts below are estimates scaled by {}",
+                self.sample_every, self.scale_factor
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Warns about data the ebpf side couldn't deliver
+    fn print_loss_notes(&self, mut pager: impl std::fmt::Write) -> anyhow::Result<()> {
+        if self.evicted_stacks > 0 {
+            writeln!(
+                pager,
+                "{} stacks were evicted from the full ebpf map, allocations they had since the last poll are lost",
+                self.evicted_stacks
+            )?;
+        }
+        if self.dropped_events > 0 {
+            writeln!(
+                pager,
+                "{} allocations were dropped because the ring buffer was full",
+                self.dropped_events
+            )?;
+        }
+        if self.stack_id_failures > 0 {
+            writeln!(
+                pager,
+                "{} allocations and frees were dropped because the stack map was full",
+                self.stack_id_failures
+            )?;
+        }
+        if !self.capped_stacks.is_empty() {
+            writeln!(
+                pager,
+                "{} of the smallest stacks were evicted to stay within --max-stacks, their allocations are missing",
+                self.capped_stacks.len()
+            )?;
+        }
+        if self.shallow_stacks > 0 {
+            writeln!(
+                pager,
+                "{} stacks with fewer than --min-frames frames were dropped",
+                self.shallow_stacks
+            )?;
+        }
+        if self.arg_read_failures > 0 {
+            writeln!(
+                pager,
+                "{} allocations and frees were dropped because their arguments couldn't be read",
+                self.arg_read_failures
+            )?;
+        }
+        if self.insert_failures > 0 {
+            writeln!(
+                pager,
+                "{} allocations and frees were dropped because an ebpf map was full",
+                self.insert_failures
+            )?;
+        }
+        Ok(())
+    }
+
+    fn print_summary(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        capture_duration: Duration,
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        let total_bytes = stats
+            .values()
+            .fold(0u64, |sum, hist| sum.saturating_add(hist.total_bytes()));
+        let total_count = stats.values().fold(0u64, |sum, hist| {
+            sum.saturating_add(hist.allocation_count())
+        });
+        let seconds = capture_duration.as_secs_f64().max(f64::EPSILON);
+
+        let resolved: Vec<_> = stats
+            .keys()
+            .filter_map(|key| self.resolved_traces.get(&key.stack_id))
+            .collect();
+        let frames: usize = resolved.iter().map(|trace| trace.symbols.len()).sum();
+        let unknown_frames: usize = resolved
+            .iter()
+            .flat_map(|trace| trace.symbols.iter())
+            .filter(|fun| fun.address == 0)
+            .count();
+
+        self.print_scale_note(&mut pager)?;
+        self.print_loss_notes(&mut pager)?;
+        writeln!(
+            pager,
+            "duration:          {:.1}s",
+            capture_duration.as_secs_f64()
+        )?;
+        writeln!(pager, "unique stacks:     {}", stats.len())?;
+        writeln!(
+            pager,
+            "total allocated:   {} ({}/s)",
+            bytesize::to_string(total_bytes, true),
+            bytesize::to_string((total_bytes as f64 / seconds) as u64, true)
+        )?;
+        writeln!(
+            pager,
+            "total allocations: {} ({:.1}/s)",
+            total_count,
+            total_count as f64 / seconds
+        )?;
+        if self.tracks_frees {
+            let (live_bytes, live_count) = stats
+                .keys()
+                .filter_map(|key| self.live_of(key))
+                .fold((0, 0), |(bytes, count), (b, c)| (bytes + b, count + c));
+            writeln!(
+                pager,
+                "currently live:    {} in {} allocations",
+                bytesize::to_string(live_bytes, true),
+                live_count
+            )?;
+        }
+        writeln!(
+            pager,
+            "symbolized stacks: {} of {} ({:.2}%)",
+            resolved.len(),
+            stats.len(),
+            percentage(resolved.len() as u64, stats.len() as u64)
+        )?;
+        writeln!(
+            pager,
+            "unknown frames:    {} of {} ({:.2}%)",
+            unknown_frames,
+            frames,
+            percentage(unknown_frames as u64, frames as u64)
+        )?;
+        Ok(())
+    }
+
+    /// Free sites of every allocation site, the ones which freed most bytes first.
+    fn group_free_sites(&self) -> FxHashMap<ReducedEventKey, Vec<(u32, FreeSiteStats)>> {
+        let mut grouped: FxHashMap<ReducedEventKey, Vec<(u32, FreeSiteStats)>> =
+            FxHashMap::default();
+        for (key, stats) in &self.free_sites {
+            let mut stats = *stats;
+            stats.count = stats.count.saturating_mul(self.scale_factor);
+            stats.bytes = stats.bytes.saturating_mul(self.scale_factor);
+            grouped
+                .entry(key.alloc_key())
+                .or_default()
+                .push((key.free_stack_id, stats));
+        }
+        for sites in grouped.values_mut() {
+            sites.sort_by_key(|(stack_id, stats)| (std::cmp::Reverse(stats.bytes), *stack_id));
+        }
+        grouped
+    }
+
+    fn print_free_sites(
+        &self,
+        sites: &[(u32, FreeSiteStats)],
+        mut pager: impl std::fmt::Write,
+    ) -> anyhow::Result<()> {
+        const MAX_FREE_SITES: usize = 5;
+
+        let freed_bytes: u64 = sites.iter().map(|(_, stats)| stats.bytes).sum();
+        writeln!(pager, "\nFreed at:")?;
+        for (stack_id, stats) in sites.iter().take(MAX_FREE_SITES) {
+            let percentage = stats.bytes as f64 / freed_bytes.max(1) as f64 * 100.0;
+            writeln!(
+                pager,
+                "  {} frees, {} ({:.2}% of freed bytes):",
+                stats.count,
+                bytesize::to_string(stats.bytes, true),
+                percentage
+            )?;
+            match self.resolved_traces.get(stack_id) {
+                Some(trace) => {
+                    for fun in trace.symbols.iter() {
+                        writeln!(pager, "    {} - {}", fun.address, fun.symbol)?;
+                    }
+                }
+                None => writeln!(pager, "    No resolved stacktrace")?,
+            }
+        }
+        if sites.len() > MAX_FREE_SITES {
+            writeln!(
+                pager,
+                "  ... and {} more free sites",
+                sites.len() - MAX_FREE_SITES
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_pprof(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        writer: impl std::io::Write,
+        capture_duration: Duration,
+    ) -> anyhow::Result<()> {
+        let mut profile = ProfileBuilder::new();
+        for (key, hist) in stats {
+            if let Some(trace) = self.resolved_traces.get(&key.stack_id) {
+                profile.add_sample(trace, hist.allocation_count(), hist.total_bytes());
+            }
+        }
+        profile.write(writer, capture_duration)
+    }
+
+    /// Writes a `root;...;leaf value` line of every resolved stack, the format
+    /// of `flamegraph.pl`, speedscope and `jeprofl flame-diff`. Stacks with the
+    /// same frames, e.g. of different threads, are summed into one line.
+    fn write_folded(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        mut writer: impl std::io::Write,
+        weight: WeightMetric,
+    ) -> anyhow::Result<()> {
+        let mut lines: BTreeMap<String, u64> = BTreeMap::new();
+        for (key, hist) in stats {
+            let Some(symbols) = self.resolved_traces.get(&key.stack_id) else {
+                continue;
+            };
+            let value = self.weight_of(key, hist, weight);
+            if value == 0 {
+                continue;
+            }
+            *lines.entry(symbols.folded_frames()).or_default() += value;
+        }
+        for (frames, value) in lines {
+            writeln!(writer, "{frames} {value}")?;
+        }
+        Ok(())
+    }
+
+    fn write_flame_graph(
+        &self,
+        stats: &FxHashMap<ReducedEventKey, Histogram>,
+        writer: impl std::io::Write,
+        weight: WeightMetric,
+        options: &FlameOptions,
+    ) -> anyhow::Result<()> {
+        let traces = stats
+            .iter()
+            .filter_map(|st| {
+                let symbols = self.resolved_traces.get(&st.0.stack_id)?;
+                let stat = self.weight_of(st.0, st.1, weight);
+                // freed stacks have no retained bytes
+                (stat > 0).then(|| symbols.as_inferno(stat))
+            })
+            .collect_vec();
+
+        let count_name = match weight {
+            WeightMetric::Count => "count",
+            WeightMetric::Bytes => "total allocated",
+            WeightMetric::Live => "retained bytes",
+            WeightMetric::Peak => "peak retained bytes",
+        };
+
+        let mut settings = inferno::flamegraph::Options::default();
+        settings.count_name = if self.is_estimated() {
+            format!("estimated {count_name}")
+        } else {
+            count_name.to_string()
+        };
+        settings.reverse_stack_order = options.reverse;
+        settings.colors = options.palette;
+        settings.image_width = options.width;
+        if let Some(title) = &options.title {
+            settings.title = title.clone();
+        }
+
+        let vec_of_strs = traces.iter().map(|x| x.as_str()).collect_vec();
+
+        inferno::flamegraph::from_lines(&mut settings, vec_of_strs, writer)?;
+        Ok(())
+    }
+}
+
+/// Histogram bar of `length`, small buckets green, medium yellow and large red
+fn bar(size: u64, length: usize, color: bool) -> String {
+    use crossterm::style::Stylize;
+
+    let bar = "#".repeat(length);
+    if !color {
+        return bar;
+    }
+    match size {
+        ..4096 => bar.green(),
+        4096..0x100000 => bar.yellow(),
+        _ => bar.red(),
+    }
+    .to_string()
+}
+
+/// Blocks until the ring buffer has data or the timeout passes
+fn wait_readable(events: &RingBuf<MapData>, timeout: Duration) {
+    let mut fd = libc::pollfd {
+        fd: events.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a valid pollfd for the duration of the call
+    unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+}
+
+/// Sort key of the stacks, biggest first, ties broken by the key so repeated runs diff cleanly
+fn report_order(key: &ReducedEventKey, weight: u64) -> (Reverse<u64>, u32, u32, u32) {
+    (Reverse(weight), key.stack_id, key.pid, key.tid)
+}
+
+/// The `limit` biggest of the entries sorted biggest first, all of them without
+/// a limit. Reversed with `ascending`
+fn top_entries<T: Copy>(entries: &[T], limit: Option<usize>, ascending: bool) -> Vec<T> {
+    let limit = limit.unwrap_or(entries.len()).min(entries.len());
+    let mut top = entries[..limit].to_vec();
+    if ascending {
+        top.reverse();
+    }
+    top
+}
+
+fn print_section(mut pager: impl std::fmt::Write, char: char) -> anyhow::Result<()> {
+    let string = (0..80).map(|_| char).collect::<String>();
+    pager.write_str(&string)?;
+    pager.write_char('\n')?;
+    Ok(())
+}
+
+/// Allocated bytes and allocations per second of the capture, `None` if its
+/// duration isn't known
+fn rates(hist: &Histogram, capture_duration: Duration) -> Option<(f64, f64)> {
+    if capture_duration.is_zero() {
+        return None;
+    }
+    let seconds = capture_duration.as_secs_f64();
+    Some((
+        hist.total_bytes() as f64 / seconds,
+        hist.allocation_count() as f64 / seconds,
+    ))
+}
+
+/// Prints non empty buckets labeled with their smallest size
+pub(crate) fn print_histogram(
+    hist: &Histogram,
+    scheme: BucketScheme,
+    pager: impl std::fmt::Write,
+) -> anyhow::Result<()> {
+    write_histogram(hist, scheme, false, pager)
+}
+
+/// [`print_histogram`] with the bars colored by bucket size if `color` is set
+fn write_histogram(
+    hist: &Histogram,
+    scheme: BucketScheme,
+    color: bool,
+    mut pager: impl std::fmt::Write,
+) -> anyhow::Result<()> {
+    let mut entries: Vec<(String, u64, u64)> = hist
+        .data
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(size, &count)| {
+            let size_bytes = scheme.label(size);
+            (bytesize::to_string(size_bytes, true), size_bytes, count)
+        })
+        .collect();
+    if hist.overflow > 0 {
+        let max_tracked = 1u64 << MAX_TRACKED_ALLOCATION_SIZE;
+        entries.push((
+            format!("{}+", bytesize::to_string(max_tracked, true)),
+            max_tracked,
+            hist.overflow,
+        ));
+    }
+
+    let max_count = entries
+        .iter()
+        .map(|&(_, _, count)| count)
+        .max()
+        .unwrap_or(1);
+    let bar_width = 50; // Maximum width of the bar
+
+    writeln!(pager, "Size      | Count     | Percentage | Distribution")?;
+    writeln!(
+        pager,
+        "----------+-----------+------------+{}",
+        "-".repeat(bar_width)
+    )?;
+
+    let total_count: u64 = entries.iter().map(|&(_, _, count)| count).sum();
+
+    for (size, size_bytes, count) in entries {
+        let percentage = (count as f64 / total_count as f64) * 100.0;
+        let bar_length = ((count as f64 / max_count as f64) * bar_width as f64).round() as usize;
+
+        writeln!(
+            pager,
+            "{:10} | {:9} | {:9.2}% | {}",
+            size,
+            count,
+            percentage,
+            bar(size_bytes, bar_length, color)
+        )?;
+    }
+
+    writeln!(
+        pager,
+        "Total allocations: {} in {} allocations",
+        bytesize::to_string(hist.total_bytes(), true),
+        total_count
+    )?;
+    let [p50, p90, p99] =
+        PERCENTILES.map(|p| bytesize::to_string(hist.percentile(p, scheme), true));
+    writeln!(pager, "p50: {p50}, p90: {p90}, p99: {p99}")?;
+
+    Ok(())
+}
+
+/// Prints the share of the stack's allocated bytes of every cpu, and of every
+/// NUMA node if there is more than one
+fn print_cpu_split(
+    split: &BTreeMap<u32, u64>,
+    cpu_nodes: &FxHashMap<u32, u32>,
+    mut pager: impl std::fmt::Write,
+) -> anyhow::Result<()> {
+    let mut rows: Vec<(String, u64)> = split
+        .iter()
+        .map(|(cpu, bytes)| (format!("cpu {cpu}"), *bytes))
+        .collect();
+    let mut nodes: BTreeMap<u32, u64> = BTreeMap::new();
+    for (cpu, bytes) in split {
+        if let Some(node) = cpu_nodes.get(cpu) {
+            *nodes.entry(*node).or_default() += bytes;
+        }
+    }
+    if cpu_nodes.values().collect::<FxHashSet<_>>().len() > 1 {
+        rows.extend(
+            nodes
+                .iter()
+                .map(|(node, bytes)| (format!("node {node}"), *bytes)),
+        );
+    }
+
+    let total: u64 = split.values().sum();
+    let max = rows
+        .iter()
+        .map(|(_, bytes)| *bytes)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let bar_width = 50;
+    writeln!(pager, "Cpu       | Size      | Percentage | Distribution")?;
+    writeln!(
+        pager,
+        "----------+-----------+------------+{}",
+        "-".repeat(bar_width)
+    )?;
+    for (label, bytes) in rows {
+        let bar_length = ((bytes as f64 / max as f64) * bar_width as f64).round() as usize;
+        writeln!(
+            pager,
+            "{:10}| {:>9} | {:9.2}% | {}",
+            label,
+            bytesize::to_string(bytes, true),
+            percentage(bytes, total),
+            "#".repeat(bar_length)
+        )?;
+    }
+    Ok(())
+}
+
+/// Flags a stack which histogram hit `u64::MAX`, see [`Histogram::is_saturated`]
+const SATURATED_NOTE: &str = "overflowed — numbers are lower bounds";
+
+/// Allocation size quantiles shown in the histogram footer and the CSV
+const PERCENTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+fn percentage(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 / total as f64 * 100.0
+}
+
+/// Name of the allocation function with the id from the histogram key, with
+/// the alignment an `x` function requested
+fn function_name(function_id: u32) -> String {
+    let (id, alignment) = split_function_id(function_id);
+    let name = match JemallocAllocFunctions::from_id(id) {
+        Some(function) => function.to_string(),
+        None => format!("unknown function {id}"),
+    };
+    match alignment {
+        Some(alignment) => format!("{name} align {alignment}"),
+        None => name,
+    }
+}
+
+/// Total size and count allocated through every function, biggest first
+fn print_per_function(
+    stats: &FxHashMap<ReducedEventKey, Histogram>,
+    mut pager: impl std::fmt::Write,
+) -> anyhow::Result<()> {
+    let mut functions: FxHashMap<u32, (u64, u64)> = FxHashMap::default();
+    for (key, hist) in stats {
+        let (bytes, count) = functions.entry(key.function_id).or_default();
+        *bytes = bytes.saturating_add(hist.total_bytes());
+        *count = count.saturating_add(hist.allocation_count());
+    }
+    let mut functions: Vec<_> = functions.into_iter().collect();
+    functions.sort_by_key(|(id, (bytes, _))| (Reverse(*bytes), *id));
+    for (id, (bytes, count)) in functions {
+        writeln!(
+            pager,
+            "{:<10} {} in {} allocations",
+            format!("{}:", function_name(id)),
+            bytesize::to_string(bytes, true),
+            count
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct JsonStack<'a> {
+    pid: u32,
+    tid: u32,
+    stack_id: u32,
+    function: String,
+    total: u64,
+    count: u64,
+    /// allocations too large for any bucket
+    overflow: u64,
+    scale_factor: u64,
+    live_bytes: Option<u64>,
+    live_count: Option<u64>,
+    peak_live_bytes: Option<u64>,
+    buckets: Vec<JsonBucket>,
+    frames: Vec<JsonFrame<'a>>,
+}
+
+/// Allocations of `lower..=upper` bytes
+#[derive(serde::Serialize)]
+struct JsonBucket {
+    lower: u64,
+    upper: u64,
+    count: u64,
+}
+
+#[derive(serde::Serialize)]
+struct JsonFrame<'a> {
+    address: u64,
+    symbol: &'a str,
+    file: Option<&'a str>,
+}
+
+impl<'a> JsonStack<'a> {
+    fn new(key: &ReducedEventKey, hist: &Histogram, processor: &'a EventProcessor) -> Self {
+        let scheme = processor.bucket_scheme;
+        let buckets = hist.data[..scheme.bucket_count()]
+            .iter()
+            .enumerate()
+            .map(|(bucket, &count)| {
+                let (lower, upper) = scheme.bounds(bucket);
+                JsonBucket {
+                    lower,
+                    upper,
+                    count,
+                }
+            })
+            .collect();
+        let frames = processor
+            .resolved_traces
+            .get(&key.stack_id)
+            .map(|trace| {
+                trace
+                    .symbols
+                    .iter()
+                    .map(|fun| JsonFrame {
+                        address: fun.address,
+                        symbol: &fun.symbol,
+                        file: fun.file.as_deref(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let live = processor.live_of(key);
+
+        Self {
+            pid: key.pid,
+            tid: key.tid,
+            stack_id: key.stack_id,
+            function: function_name(key.function_id),
+            total: hist.total_bytes(),
+            count: hist.allocation_count(),
+            overflow: hist.overflow,
+            scale_factor: processor.scale_factor,
+            live_bytes: live.map(|(bytes, _)| bytes),
+            live_count: live.map(|(_, count)| count),
+            peak_live_bytes: processor.peak_live_of(key),
+            buckets,
+            frames,
+        }
+    }
+}
+
+struct CsvWriter {
+    writer: Option<csv::Writer<std::io::BufWriter<std::fs::File>>>,
+    format: CsvFormat,
+    scale_factor: u64,
+    source_lines: bool,
+    capture_duration: Duration,
+}
+
+impl CsvWriter {
+    pub fn new(
+        path: Option<PathBuf>,
+        format: CsvFormat,
+        bucket_scheme: BucketScheme,
+        scale_factor: u64,
+        source_lines: bool,
+        capture_duration: Duration,
+    ) -> anyhow::Result<Self> {
+        const HEADERS: [&str; 16] = [
+            "pid",
+            "tid",
+            "stack_id",
+            "total",
+            "count",
+            "histogram",
+            "stacktrace",
+            "scale_factor",
+            "live_bytes",
+            "live_count",
+            "p50",
+            "p90",
+            "p99",
+            "peak_live_bytes",
+            "bytes_per_sec",
+            "count_per_sec",
+        ];
+        let writer =
+            match path {
+                Some(path) => {
+                    let mut writer = csv::Writer::from_writer(std::io::BufWriter::new(
+                        std::fs::File::create(&path)?,
+                    ));
+                    match format {
+                        CsvFormat::Text => writer.write_record(HEADERS)?,
+                        // the buckets replace the histogram table, after the other columns
+                        CsvFormat::Wide => writer.write_record(
+                            HEADERS
+                                .iter()
+                                .filter(|header| **header != "histogram")
+                                .map(|header| header.to_string())
+                                .chain((0..bucket_scheme.bucket_count()).map(|bucket| {
+                                    format!("bucket_{}B", bucket_scheme.label(bucket))
+                                }))
+                                .chain(["overflow".to_string()]),
+                        )?,
+                    }
+                    Some(writer)
+                }
+                None => None,
+            };
+
+        Ok(Self {
+            writer,
+            format,
+            scale_factor,
+            source_lines,
+            capture_duration,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        key: &ReducedEventKey,
+        hist: &Histogram,
+        processor: &EventProcessor,
+    ) -> anyhow::Result<()> {
+        if let Some(writer) = &mut self.writer {
+            let stacktrace = processor
+                .resolved_traces
+                .get(&key.stack_id)
+                .map(|trace| {
+                    trace
+                        .symbols
+                        .iter()
+                        .map(|fun| {
+                            format!("{:x} - {}", fun.address, fun.display(self.source_lines))
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .unwrap_or_else(|| "No resolved stacktrace".to_string());
+            let live = processor.live_of(key);
+            let [p50, p90, p99] = PERCENTILES.map(|p| hist.percentile(p, processor.bucket_scheme));
+            let rates = rates(hist, self.capture_duration);
+            if self.format == CsvFormat::Wide {
+                let optional = |value: Option<String>| value.unwrap_or_default();
+                let mut record = vec![
+                    key.pid.to_string(),
+                    key.tid.to_string(),
+                    key.stack_id.to_string(),
+                    hist.total_bytes().to_string(),
+                    hist.allocation_count().to_string(),
+                    stacktrace,
+                    self.scale_factor.to_string(),
+                    optional(live.map(|(bytes, _)| bytes.to_string())),
+                    optional(live.map(|(_, count)| count.to_string())),
+                    p50.to_string(),
+                    p90.to_string(),
+                    p99.to_string(),
+                    optional(processor.peak_live_of(key).map(|peak| peak.to_string())),
+                    optional(rates.map(|(bytes, _)| bytes.to_string())),
+                    optional(rates.map(|(_, count)| count.to_string())),
+                ];
+                let buckets = &hist.data[..processor.bucket_scheme.bucket_count()];
+                record.extend(buckets.iter().map(u64::to_string));
+                record.push(hist.overflow.to_string());
+                writer.write_record(record)?;
+                return Ok(());
+            }
+            let mut histogram = String::new();
+            print_histogram(hist, processor.bucket_scheme, &mut histogram)?;
+            writer.serialize((
+                key.pid,
+                key.tid,
+                key.stack_id,
+                hist.total_bytes(),
+                hist.allocation_count(),
+                histogram,
+                stacktrace,
+                self.scale_factor,
+                live.map(|(bytes, _)| bytes),
+                live.map(|(_, count)| count),
+                p50,
+                p90,
+                p99,
+                processor.peak_live_of(key),
+                rates.map(|(bytes, _)| bytes),
+                rates.map(|(_, count)| count),
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        if let Some(mut writer) = self.writer {
+            writer.flush()?;
+        }
         Ok(())
     }
 }
 
-fn print_section(mut pager: impl std::fmt::Write, char: char) -> anyhow::Result<()> {
-    let string = (0..80).map(|_| char).collect::<String>();
-    pager.write_str(&string)?;
-    pager.write_char('\n')?;
-    Ok(())
-}
+#[cfg(test)]
+mod test {
+    use crate::collector::{
+        effective_sampling_fraction, function_name, orphaned_stacks_of, print_cpu_split,
+        print_histogram, wait_canceled, write_histogram, CsvWriter, EventProcessor, FlameOptions,
+        JsonStack, ReportOptions, SizeBiasedSampling, Skipped, ThreadFilter, Timeline,
+        SATURATED_NOTE,
+    };
+    use crate::resolver::{OwnedSymbol, ResolvedStackTrace};
+    use crate::{CsvFormat, JemallocAllocFunctions, MergeBy, OrderBy, WeightMetric};
+    use jeprofl_common::{
+        byte_sample_weight, function_id_with_flags, is_sampled, BucketScheme, Histogram, LiveStats,
+        ReducedEventKey, UnpackedHistogramKey, MALLOCX_ID, MALLOC_ID, NO_KERNEL_STACK,
+    };
+    use regex::Regex;
+    use rustc_hash::{FxHashMap, FxHashSet};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn report_options() -> ReportOptions {
+            ReportOptions {
+                capture_duration: Duration::from_secs(10),
+                ..ReportOptions::default()
+            }
+        }
+
+        /// `malloc` of pid 1 on cpu 0
+        fn key(stack_id: u32) -> UnpackedHistogramKey {
+            UnpackedHistogramKey {
+                pid: 1,
+                tid: 0,
+                stack_id,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+                cpu: 0,
+            }
+        }
+
+        fn frame(symbol: &str) -> OwnedSymbol {
+            OwnedSymbol {
+                address: 1,
+                symbol: symbol.to_string(),
+                file: None,
+                line: None,
+                inlined: Vec::new(),
+            }
+        }
+
+        #[test]
+        fn every_cpu_samples_its_own_allocations() {
+            // the ebpf side increments the counter of the cpu, then checks it
+            for count in 0..50 {
+                let sampled = (1..=count).filter(|&c| is_sampled(c, 7)).count() as u64;
+                assert_eq!(sampled, count / 7);
+            }
+            assert!((1..10).all(|c| is_sampled(c, 1) && is_sampled(c, 0)));
+
+            // 5 + 2 + 0 sampled of 23 + 9 + 3 seen
+            let fraction = effective_sampling_fraction(&[23, 9, 3], 4).unwrap();
+            assert_eq!(fraction, 7.0 / 35.0);
+            assert_eq!((1.0 / fraction).round(), 5.0);
+            // long captures converge to 1/sample_every
+            let fraction = effective_sampling_fraction(&[1_000_003, 999_998], 100).unwrap();
+            assert_eq!((1.0 / fraction).round(), 100.0);
+            assert_eq!(effective_sampling_fraction(&[3, 3], 4), None);
+            assert_eq!(effective_sampling_fraction(&[], 4), None);
+            assert_eq!(effective_sampling_fraction(&[5], 1), Some(1.0));
+        }
+
+        #[test]
+        fn histograms_of_fewer_buckets_overflow() {
+            let mut hist = Histogram::<4>::zeroed();
+            hist.increment(8);
+            hist.increment(1024);
+            assert_eq!(hist.data, [0, 0, 0, 1]);
+            assert_eq!(hist.overflow, 1);
+            assert_eq!(hist.allocation_count(), 2);
+            let mut merged = Histogram::<4>::zeroed();
+            merged.merge(&hist);
+            assert_eq!(merged.total_bytes(), 1032);
+        }
+
+        #[test]
+        fn threads_are_filtered_by_name() {
+            let filter = ThreadFilter {
+                exclude: vec!["tokio-runtime-worker".to_string()],
+                only: Vec::new(),
+            };
+            assert!(!filter.keeps(Some("tokio-runtime-w")));
+            assert!(filter.keeps(Some("main")));
+            assert!(filter.keeps(None));
+            let filter = ThreadFilter {
+                exclude: Vec::new(),
+                only: vec!["main".to_string()],
+            };
+            assert!(filter.keeps(Some("main")));
+            assert!(!filter.keeps(Some("mainly")));
+            assert!(!filter.keeps(None));
+
+            let pid = std::process::id();
+            let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+            let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+            let background = std::thread::Builder::new()
+                .name("jeprofl-background".to_string())
+                .spawn(move || {
+                    tid_tx.send(unsafe { libc::gettid() } as u32).unwrap();
+                    done_rx.recv().ok();
+                })
+                .unwrap();
+            let tid = tid_rx.recv().unwrap();
+            let mut processor = EventProcessor::new(1);
+            processor.thread_filter = ThreadFilter {
+                exclude: vec!["jeprofl-background".to_string()],
+                only: Vec::new(),
+            };
+            // the name is read while the thread runs and remembered after it exits
+            assert!(!processor.keeps_thread(pid, tid));
+            drop(done_tx);
+            background.join().unwrap();
+            let key = |tid| ReducedEventKey {
+                pid,
+                tid,
+                stack_id: 1,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+            };
+            processor.update_live(
+                key(tid),
+                LiveStats {
+                    bytes: 64,
+                    count: 1,
+                },
+            );
+            processor.update_live(
+                key(pid),
+                LiveStats {
+                    bytes: 32,
+                    count: 1,
+                },
+            );
+            assert_eq!(processor.live.len(), 1);
+            assert!(processor.live.contains_key(&key(pid)));
+        }
+
+        #[test]
+        fn cancellation_interrupts_the_wait() {
+            let canceled = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let waiter = {
+                let canceled = canceled.clone();
+                std::thread::spawn(move || {
+                    let start = std::time::Instant::now();
+                    wait_canceled(&canceled, Duration::from_secs(60));
+                    start.elapsed()
+                })
+            };
+            std::thread::sleep(Duration::from_millis(50));
+            canceled.store(true, std::sync::atomic::Ordering::Release);
+            waiter.thread().unpark();
+            assert!(waiter.join().unwrap() < Duration::from_secs(10));
+        }
+
+        #[test]
+        fn print_histogram_empty() {
+            let histogram = Histogram::new();
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_single_allocation() {
+            let mut histogram = Histogram::new();
+            histogram.increment(1023);
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_multiple_sizes() {
+            let mut histogram = Histogram::new();
+            histogram.increment(1); // 1 B
+            histogram.increment(512); // 512 B
+            histogram.increment(1026); // 2 KB
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_large_allocations() {
+            let mut histogram = Histogram::new();
+            histogram.increment(1 << 20); // 1 MB
+            histogram.increment(1u64 << 30); // 1 GB
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_many_small_allocations() {
+            let mut histogram = Histogram::new();
+            for _ in 0..1000 {
+                histogram.increment(1); // 1 B
+            }
+            histogram.increment(1023); // 1 KB
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_realloc_deltas() {
+            let mut histogram = Histogram::new();
+            histogram.add_delta(4096, BucketScheme::Log2); // grow from nothing
+            histogram.add_delta(1024, BucketScheme::Log2); // grow by 1 KB
+            histogram.add_delta(-2048, BucketScheme::Log2); // shrink, only lowers the total
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_subdivided_buckets() {
+            let scheme = BucketScheme::Log2Subdivided;
+            let mut histogram = Histogram::new();
+            for size in [64, 70, 80, 96, 100, 127, 128] {
+                histogram.record(size, scheme);
+            }
+            assert_eq!(scheme.bounds(scheme.bucket(80).unwrap()), (80, 95));
+            let mut buf = String::new();
+            print_histogram(&histogram, scheme, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_jemalloc_buckets() {
+            let scheme = BucketScheme::Jemalloc;
+            let classes: Vec<_> = (0..14).map(|bucket| scheme.bounds(bucket).1).collect();
+            assert_eq!(
+                classes,
+                [8, 16, 32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320]
+            );
+            for size in 1..=100_000 {
+                let (lower, upper) = scheme.bounds(scheme.bucket(size).unwrap());
+                assert!(lower <= size && size <= upper, "{size}");
+            }
+            assert_eq!(scheme.bounds(0).0, 1);
+            for bucket in 1..scheme.bucket_count() {
+                assert_eq!(
+                    scheme.bounds(bucket).0,
+                    scheme.bounds(bucket - 1).1 + 1,
+                    "bucket {bucket} overlaps or leaves a gap"
+                );
+            }
+            let max_tracked = 1 << jeprofl_common::MAX_TRACKED_ALLOCATION_SIZE;
+            assert_eq!(scheme.bucket(max_tracked), Some(scheme.bucket_count() - 1));
+            assert_eq!(scheme.bucket(max_tracked + 1), None);
+
+            let mut histogram = Histogram::new();
+            for size in [1, 8, 9, 100, 129, 160, 5000] {
+                histogram.record(size, scheme);
+            }
+            let mut buf = String::new();
+            print_histogram(&histogram, scheme, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_histogram_linear_buckets() {
+            let scheme = BucketScheme::Linear(64);
+            let mut histogram = Histogram::new();
+            for size in [1, 63, 64, 200, 1 << 20] {
+                histogram.record(size, scheme);
+            }
+            // the last bucket catches everything above the tracked range
+            assert_eq!(
+                scheme.bounds(scheme.bucket(1 << 20).unwrap()),
+                (64 * (histogram.data.len() as u64 - 1), u64::MAX)
+            );
+            let mut buf = String::new();
+            print_histogram(&histogram, scheme, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn percentiles() {
+            let mut histogram = Histogram::new();
+            assert_eq!(histogram.percentile(0.5, BucketScheme::Log2), 0);
+            for _ in 0..90 {
+                histogram.increment(16);
+            }
+            for _ in 0..9 {
+                histogram.increment(1024);
+            }
+            histogram.increment(1 << 20);
+            assert_eq!(histogram.percentile(0.5, BucketScheme::Log2), 16);
+            assert_eq!(histogram.percentile(0.9, BucketScheme::Log2), 16);
+            assert_eq!(histogram.percentile(0.99, BucketScheme::Log2), 1024);
+            assert_eq!(histogram.percentile(1.0, BucketScheme::Log2), 1 << 20);
+
+            // saturated buckets, e.g. after scaling, sum up to `u64::MAX`
+            let scheme = BucketScheme::Log2;
+            let mut saturated = Histogram::new();
+            for size in [16, 1024] {
+                saturated.data[scheme.bucket(size).unwrap()] = u64::MAX - 1;
+            }
+            assert!(saturated.is_saturated());
+            assert_eq!(saturated.percentile(0.5, scheme), 16);
+            assert_eq!(saturated.percentile(1.0, scheme), 1024);
+        }
+
+        #[test]
+        fn color_bars_by_bucket_size() {
+            let mut histogram = Histogram::new();
+            histogram.increment(16);
+            histogram.increment(64 * 1024);
+            histogram.increment(16 * 1024 * 1024);
+            let mut buf = String::new();
+            write_histogram(&histogram, BucketScheme::Log2, true, &mut buf).unwrap();
+            let rows: Vec<_> = buf.lines().skip(2).take(3).collect();
+            assert!(rows[0].ends_with(
+                "\x1b[38;5;10m##################################################\x1b[39m"
+            ));
+            assert!(rows[1].contains("\x1b[38;5;11m"));
+            assert!(rows[2].contains("\x1b[38;5;9m"));
+
+            let mut plain = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut plain).unwrap();
+            assert!(!plain.contains('\x1b'));
+        }
+
+        #[test]
+        fn print_histogram_overflow() {
+            let mut histogram = Histogram::new();
+            histogram.increment(1024);
+            histogram.increment(1 << 40); // 1 TiB, above the tracked range
+            assert_eq!(histogram.overflow, 1);
+            assert_eq!(histogram.allocation_count(), 2);
+            let mut buf = String::new();
+            print_histogram(&histogram, BucketScheme::Log2, &mut buf).unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_top_stacks() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size) in [(1, 16), (2, 1024), (3, 4096)] {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            let mut buf = String::new();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        top: Some(1),
+                        ..report_options()
+                    },
+                )
+                .unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn print_compact_summary() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size, count) in [(1, 16, 100), (2, 4096, 3), (3, 1024, 1)] {
+                let mut histogram = Histogram::new();
+                histogram.record_weighted(size, count, BucketScheme::Log2);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            processor.resolved_traces.insert(
+                2,
+                ResolvedStackTrace {
+                    symbols: vec![frame("malloc"), frame("app::Cache::grow"), frame("main")],
+                },
+            );
+            let mut buf = String::new();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        compact: true,
+                        ..report_options()
+                    },
+                )
+                .unwrap();
+            insta::assert_snapshot!(buf);
+        }
+
+        #[test]
+        fn saturated_stacks_are_flagged() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size, count) in [(1, u64::MAX / 2, 3), (2, 4096, 3)] {
+                let mut histogram = Histogram::new();
+                histogram.record_weighted(size, count, BucketScheme::Log2);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            let render = |compact| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            compact,
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                buf
+            };
+            let full = render(false);
+            assert!(full.contains("1 stacks overflowed their counters"));
+            assert_eq!(full.matches(SATURATED_NOTE).count(), 1);
+            let compact = render(true);
+            let flagged: Vec<_> = compact
+                .lines()
+                .filter(|line| line.contains(SATURATED_NOTE))
+                .collect();
+            assert_eq!(flagged.len(), 1);
+            assert!(flagged[0].starts_with("  16.0 EiB"));
+        }
+
+        #[test]
+        fn latencies_are_listed_by_time_spent() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, latency, calls) in [(1, 1_000, 10), (2, 100_000, 2)] {
+                let key = key(stack_id);
+                let mut histogram = Histogram::new();
+                histogram.record_weighted(64, calls, BucketScheme::Log2);
+                processor.allocations_stats.insert(key, histogram);
+                let mut latencies = Histogram::new();
+                latencies.record_weighted(latency, calls, BucketScheme::Log2);
+                processor.latencies.insert(key.as_reduced(), latencies);
+            }
+            let mut buf = String::new();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        compact: true,
+                        ..report_options()
+                    },
+                )
+                .unwrap();
+            let rows: Vec<_> = buf
+                .lines()
+                .skip_while(|line| !line.starts_with("Latency of the sampled allocation calls"))
+                .skip(2)
+                .take_while(|line| !line.is_empty())
+                .map(|line| {
+                    let columns: Vec<_> = line.split('|').map(str::trim).collect();
+                    (columns[0].to_string(), columns[3].to_string())
+                })
+                .collect();
+            assert_eq!(
+                rows,
+                [
+                    ("2".to_string(), "200µs".to_string()),
+                    ("10".to_string(), "10µs".to_string()),
+                ]
+            );
+        }
+
+        #[test]
+        fn print_by_depth() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size, count, frames) in [
+                (
+                    1,
+                    16,
+                    100,
+                    ["malloc", "app::Cache::grow", "app::a", "main"].as_slice(),
+                ),
+                (
+                    2,
+                    4096,
+                    3,
+                    &["malloc", "app::Cache::grow", "app::b", "main"],
+                ),
+                (3, 1024, 20, &["malloc", "app::parse", "main"]),
+            ] {
+                let mut histogram = Histogram::new();
+                histogram.record_weighted(size, count, BucketScheme::Log2);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: frames.iter().map(|symbol| frame(symbol)).collect(),
+                    },
+                );
+            }
+            let render = |order_by_depth, max_depth| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            compact: true,
+                            order_by_depth,
+                            max_depth,
+                            capture_duration: Duration::ZERO,
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                buf
+            };
+            // the stacks through app::Cache::grow are summed once truncated
+            insta::assert_snapshot!(format!(
+                "{}\n{}\n{}",
+                render(false, None),
+                render(true, None),
+                render(false, Some(2))
+            ));
+        }
+
+        #[test]
+        fn print_by_function() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size, caller) in [
+                (1, 64, "app::Cache::grow"),
+                (2, 4096, "app::Cache::grow"),
+                (3, 1024, "app::parse"),
+            ] {
+                let mut histogram = Histogram::new();
+                histogram.record_weighted(size, 2, BucketScheme::Log2);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: vec![frame("malloc"), frame(caller), frame("main")],
+                    },
+                );
+            }
+            let render = |depth, top| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            top,
+                            by_function: Some(depth),
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                buf
+            };
+            insta::assert_snapshot!(render(1, None));
+            // every stack goes through main
+            assert!(render(2, None).contains("  10.1 kiB |         6 |    1.0 kiB |      3 | main"));
+            assert!(render(1, Some(1)).ends_with("... and 1 more functions omitted\n"));
+        }
+
+        #[test]
+        fn print_biggest_first() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, size) in [(1, 16), (2, 4096), (3, 1024)] {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            let render = |ascending| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            ascending,
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                [
+                    "Total allocations: 4.0 kiB",
+                    "Total allocations: 1.0 kiB",
+                    "Total allocations: 16 B",
+                ]
+                .map(|total| buf.find(total).unwrap())
+            };
+            let [big, medium, small] = render(false);
+            assert!(big < medium && medium < small);
+            let [big, medium, small] = render(true);
+            assert!(big > medium && medium > small);
+        }
 
-pub(crate) fn print_histogram(
-    hist: &Histogram,
-    mut pager: impl std::fmt::Write,
-) -> anyhow::Result<()> {
-    let mut entries: Vec<(usize, u64)> = hist
-        .data
-        .iter()
-        .enumerate()
-        .filter(|(_, &count)| count > 0)
-        .map(|(size, &count)| (size, count))
-        .collect();
+        #[test]
+        fn shallow_stacks_are_dropped() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, depth) in [(1, 1), (2, 3)] {
+                let key = key(stack_id);
+                let mut histogram = Histogram::new();
+                histogram.increment(64);
+                processor.allocations_stats.insert(key, histogram);
+                let symbol = OwnedSymbol {
+                    address: 0x10,
+                    symbol: "malloc".to_string(),
+                    file: None,
+                    line: None,
+                    inlined: Vec::new(),
+                };
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: vec![symbol; depth],
+                    },
+                );
+            }
+            // a stack which wasn't resolved has no frames
+            let mut key = *processor.allocations_stats.keys().next().unwrap();
+            key.stack_id = 3;
+            processor.allocations_stats.insert(key, Histogram::new());
 
-    entries.sort_by_key(|&(size, _)| size);
+            processor.drop_shallow_stacks(2);
+            let kept: Vec<_> = processor.stacks().map(|(key, _, _)| key.stack_id).collect();
+            assert_eq!(kept, [2]);
+            assert!(processor
+                .exit_summary(&[None], &[JemallocAllocFunctions::Malloc], Duration::ZERO)
+                .ends_with("shallow_stacks=2"));
+        }
 
-    let max_count = entries.iter().map(|&(_, count)| count).max().unwrap_or(1);
-    let bar_width = 50; // Maximum width of the bar
+        #[test]
+        fn totals_are_rolled_up_per_pid() {
+            let mut processor = EventProcessor::new(1);
+            for (pid, stack_id, cpu, size) in [
+                (1, 1, 0, 16),
+                (1, 2, 0, 64),
+                (2, 1, 0, 4096),
+                (2, 1, 1, 4096),
+            ] {
+                let key = UnpackedHistogramKey {
+                    pid,
+                    cpu,
+                    ..key(stack_id)
+                };
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            processor.process_names.insert(2, "server".to_string());
 
-    writeln!(pager, "Size      | Count     | Percentage | Distribution")?;
-    writeln!(
-        pager,
-        "----------+-----------+------------+{}",
-        "-".repeat(bar_width)
-    )?;
+            let mut buf = String::new();
+            assert!(processor
+                .print_per_pid(&processor.merge(), &mut buf)
+                .unwrap());
+            assert_eq!(
+                buf,
+                "2 processes:\n\
+                 \u{20}    pid process                 bytes  allocations   stacks\n\
+                 \u{20}      2 server                8.0 kiB            2        1\n\
+                 \u{20}      1 ?                        80 B            2        2\n"
+            );
+            // a single process needs no rollup
+            processor.allocations_stats.retain(|key, _| key.pid == 1);
+            let mut buf = String::new();
+            assert!(!processor
+                .print_per_pid(&processor.merge(), &mut buf)
+                .unwrap());
+            assert!(buf.is_empty());
+        }
 
-    let total_count: u64 = entries.iter().map(|&(_, count)| count).sum();
+        #[test]
+        fn children_are_summed_into_their_parent() {
+            let mut processor = EventProcessor::new(1);
+            for (pid, stack_id, size) in [(10, 1, 16), (11, 1, 4096), (12, 2, 64)] {
+                let key = UnpackedHistogramKey {
+                    pid,
+                    ..key(stack_id)
+                };
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            processor.process_names.insert(11, "worker".to_string());
+            processor.set_parent_pids(FxHashMap::from_iter([(11, 10), (12, 10)]));
 
-    for (size, count) in entries {
-        let size_bytes = size_bytes(size);
-        let percentage = (count as f64 / total_count as f64) * 100.0;
-        let bar_length = ((count as f64 / max_count as f64) * bar_width as f64).round() as usize;
+            let mut buf = String::new();
+            processor.print_children(&mut buf).unwrap();
+            assert_eq!(
+                buf,
+                "2 child processes, summed into their parent below:\n\
+                 \u{20} parent      pid process                 bytes  allocations\n\
+                 \u{20}     10       11 worker                4.0 kiB            1\n\
+                 \u{20}     10       12 ?                        64 B            1\n\n"
+            );
+            let folded = processor.children_folded().merge();
+            let totals: BTreeMap<_, _> = folded
+                .iter()
+                .map(|(key, hist)| ((key.pid, key.stack_id), hist.total_bytes()))
+                .collect();
+            assert_eq!(
+                totals,
+                BTreeMap::from_iter([((10, 1), 4112), ((10, 2), 64)])
+            );
+        }
 
-        writeln!(
-            pager,
-            "{:10} | {:9} | {:9.2}% | {}",
-            bytesize::to_string(size_bytes, true),
-            count,
-            percentage,
-            "#".repeat(bar_length)
-        )?;
-    }
+        #[test]
+        fn wide_csv_has_a_column_per_bucket() {
+            let path =
+                std::env::temp_dir().join(format!("jeprofl-wide-{}.csv", std::process::id()));
+            let _cleanup = scopeguard::guard((), |_| {
+                std::fs::remove_file(&path).ok();
+            });
+            let processor = EventProcessor::new(1);
+            let mut histogram = Histogram::new();
+            for size in [16, 20, 4096] {
+                histogram.increment(size);
+            }
+            let key = ReducedEventKey {
+                pid: 1,
+                tid: 0,
+                stack_id: 7,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+            };
+            let mut writer = CsvWriter::new(
+                Some(path.clone()),
+                CsvFormat::Wide,
+                BucketScheme::Log2,
+                1,
+                false,
+                Duration::from_secs(10),
+            )
+            .unwrap();
+            writer.write(&key, &histogram, &processor).unwrap();
+            writer.finish().unwrap();
 
-    writeln!(
-        pager,
-        "Total allocations: {} in {} allocations",
-        bytesize::to_string(hist.total, true),
-        total_count
-    )?;
+            let mut reader = csv::Reader::from_path(&path).unwrap();
+            let headers = reader.headers().unwrap().clone();
+            let row = reader.records().next().unwrap().unwrap();
+            let column = |name: &str| {
+                let index = headers.iter().position(|header| header == name).unwrap();
+                row[index].to_string()
+            };
+            assert!(!headers.iter().any(|header| header == "histogram"));
+            assert_eq!(
+                headers.iter().take(3).collect::<Vec<_>>(),
+                ["pid", "tid", "stack_id"]
+            );
+            assert_eq!(column("total"), "4132");
+            assert_eq!(column("bucket_8B"), "0");
+            assert_eq!(column("bucket_16B"), "2");
+            assert_eq!(column("bucket_4096B"), "1");
+            assert_eq!(column("overflow"), "0");
+            assert_eq!(column("live_bytes"), "");
+            assert_eq!(headers.len(), row.len());
+        }
 
-    Ok(())
-}
+        #[test]
+        fn unsymbolized_stacks_are_counted() {
+            let mut processor = EventProcessor::new(1);
+            let mut stats = FxHashMap::default();
+            for (stack_id, symbol, address) in [
+                (1, "main", 0x10),
+                (2, "missing debug info", 0),
+                (3, "[unknown]", 0x30),
+            ] {
+                let key = ReducedEventKey {
+                    pid: 1,
+                    tid: 0,
+                    stack_id,
+                    kernel_stack_id: NO_KERNEL_STACK,
+                    function_id: MALLOC_ID,
+                };
+                stats.insert(key, Histogram::new());
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: vec![OwnedSymbol {
+                            address,
+                            symbol: symbol.to_string(),
+                            file: None,
+                            line: None,
+                            inlined: Vec::new(),
+                        }],
+                    },
+                );
+            }
+            assert!(!processor.is_unsymbolized(1));
+            assert!(processor.is_unsymbolized(2));
+            assert!(processor.is_unsymbolized(3));
 
-fn size_bytes(size: usize) -> u64 {
-    1u64 << size
-}
+            let mut buf = String::new();
+            processor
+                .print_unsymbolized_note(&stats, true, &mut buf)
+                .unwrap();
+            assert_eq!(
+                buf,
+                "2 stacks could not be symbolized, check --debuginfod or frame pointers, they are dropped\n"
+            );
+            stats.retain(|key, _| key.stack_id == 1);
+            let mut buf = String::new();
+            processor
+                .print_unsymbolized_note(&stats, false, &mut buf)
+                .unwrap();
+            assert!(buf.is_empty());
+        }
 
-struct CsvWriter {
-    writer: Option<csv::Writer<std::io::BufWriter<std::fs::File>>>,
-}
+        #[test]
+        fn query_stacks() {
+            let mut processor = EventProcessor::new(2);
+            for (stack_id, sizes) in [(1, &[16; 8][..]), (2, &[4096]), (3, &[1024, 1024])] {
+                let mut histogram = Histogram::new();
+                for &size in sizes {
+                    histogram.increment(size);
+                }
+                // the same stack on two cpus
+                for cpu in 0..2 {
+                    let key = UnpackedHistogramKey {
+                        cpu,
+                        ..key(stack_id)
+                    };
+                    processor.allocations_stats.insert(key, histogram);
+                }
+            }
+            processor.resolved_traces.insert(
+                2,
+                ResolvedStackTrace {
+                    symbols: vec![OwnedSymbol {
+                        address: 0x10,
+                        symbol: "load".to_string(),
+                        file: None,
+                        line: None,
+                        inlined: Vec::new(),
+                    }],
+                },
+            );
 
-impl CsvWriter {
-    pub fn new(path: Option<PathBuf>) -> anyhow::Result<Self> {
-        const HEADERS: [&str; 6] = [
-            "pid",
-            "stack_id",
-            "total",
-            "count",
-            "histogram",
-            "stacktrace",
-        ];
-        let writer = match path {
-            Some(path) => {
-                let mut writer = csv::Writer::from_writer(std::io::BufWriter::new(
-                    std::fs::File::create(&path)?,
-                ));
-                writer.write_record(HEADERS)?;
-                Some(writer)
-            }
-            None => None,
-        };
+            assert_eq!(processor.stacks().count(), 3);
+            assert_eq!(processor.total_bytes(), 2 * 2 * (8 * 16 + 4096 + 2048));
 
-        Ok(Self { writer })
-    }
+            let top = processor.top_n(OrderBy::Traffic, 2);
+            let ids: Vec<_> = top.iter().map(|(key, _, _)| key.stack_id).collect();
+            assert_eq!(ids, [2, 3]);
+            assert_eq!(top[0].1.total_bytes(), 2 * 2 * 4096);
+            assert_eq!(top[0].2.unwrap().symbols[0].symbol, "load");
+            assert!(top[1].2.is_none());
 
-    pub fn write(
-        &mut self,
-        key: &ReducedEventKey,
-        hist: &Histogram,
-        processor: &EventProcessor,
-    ) -> anyhow::Result<()> {
-        if let Some(writer) = &mut self.writer {
-            let stacktrace = processor
-                .resolved_traces
-                .get(&key.stack_id)
-                .map(|trace| {
-                    trace
-                        .symbols
-                        .iter()
-                        .map(|fun| format!("{:x} - {}", fun.address, fun.symbol))
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                })
-                .unwrap_or_else(|| "No resolved stacktrace".to_string());
-            let mut histogram = String::new();
-            print_histogram(hist, &mut histogram)?;
-            writer.serialize((
-                key.pid,
-                key.stack_id,
-                hist.total,
-                hist.data.iter().sum::<u64>(),
-                histogram,
-                stacktrace,
-            ))?;
-        }
-        Ok(())
-    }
+            let top = processor.top_n(OrderBy::Count, 1);
+            assert_eq!(top[0].0.stack_id, 1);
+            assert_eq!(top[0].1.allocation_count(), 2 * 2 * 8);
 
-    pub fn finish(self) -> anyhow::Result<()> {
-        if let Some(mut writer) = self.writer {
-            writer.flush()?;
+            // the biggest stack freed everything, half of the third one is retained
+            processor.tracks_frees = true;
+            let key = ReducedEventKey {
+                pid: 1,
+                tid: 0,
+                stack_id: 3,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+            };
+            processor.live.insert(
+                key,
+                LiveStats {
+                    bytes: 1024,
+                    count: 1,
+                },
+            );
+            let top = processor.top_n(OrderBy::Live, 1);
+            assert_eq!(top[0].0.stack_id, 3);
+            assert_eq!(processor.live_of(&top[0].0), Some((2048, 2)));
         }
-        Ok(())
-    }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::collector::print_histogram;
-    use jeprofl_common::Histogram;
+        #[test]
+        fn count_and_bytes_stay_apart() {
+            let mut few_big = Histogram::new();
+            few_big.increment(1 << 20);
+            let mut many_small = Histogram::new();
+            for _ in 0..100 {
+                many_small.increment(16);
+            }
+            assert_eq!(few_big.allocation_count(), 1);
+            assert_eq!(few_big.total_bytes(), 1 << 20);
+            assert_eq!(many_small.allocation_count(), 100);
+            assert_eq!(many_small.total_bytes(), 1600);
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, histogram) in [(1, few_big), (2, many_small)] {
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
+            let render = |order_by| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            order_by,
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                buf.find("Total allocations: 1.0 MiB").unwrap()
+                    < buf.find("Total allocations: 1.6 kiB").unwrap()
+            };
+            assert!(render(OrderBy::Traffic));
+            assert!(!render(OrderBy::Count));
+        }
 
         #[test]
-        fn print_histogram_empty() {
-            let histogram = Histogram::new();
+        fn print_per_function() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, function_id, size) in [
+                (1, MALLOC_ID, 16),
+                (2, MALLOCX_ID, 4096),
+                (3, MALLOC_ID, 64),
+            ] {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                let key = UnpackedHistogramKey {
+                    function_id,
+                    ..key(stack_id)
+                };
+                processor.allocations_stats.insert(key, histogram);
+            }
             let mut buf = String::new();
-            print_histogram(&histogram, &mut buf).unwrap();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        top: Some(1),
+                        per_function: true,
+                        multiple_functions: true,
+                        ..report_options()
+                    },
+                )
+                .unwrap();
             insta::assert_snapshot!(buf);
         }
 
         #[test]
-        fn print_histogram_single_allocation() {
-            let mut histogram = Histogram::new();
-            histogram.increment(1023);
+        fn alignment_of_x_functions_is_kept_apart() {
+            // MALLOCX_LG_ALIGN(6), a zeroed allocation
+            let aligned = function_id_with_flags(MALLOCX_ID, 6 | 0x40);
+            assert_eq!(function_name(aligned), "mallocx align 64");
+            assert_eq!(
+                function_name(function_id_with_flags(MALLOCX_ID, 0)),
+                "mallocx"
+            );
+
+            let mut stats = FxHashMap::default();
+            for (function_id, size) in [(MALLOCX_ID, 4096), (aligned, 100)] {
+                let key = ReducedEventKey {
+                    pid: 1,
+                    tid: 0,
+                    stack_id: 1,
+                    kernel_stack_id: NO_KERNEL_STACK,
+                    function_id,
+                };
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                stats.insert(key, histogram);
+            }
             let mut buf = String::new();
-            print_histogram(&histogram, &mut buf).unwrap();
-            insta::assert_snapshot!(buf);
+            crate::collector::print_per_function(&stats, &mut buf).unwrap();
+            assert_eq!(
+                buf,
+                "mallocx:   4.0 kiB in 1 allocations\nmallocx align 64: 100 B in 1 allocations\n"
+            );
         }
 
         #[test]
-        fn print_histogram_multiple_sizes() {
-            let mut histogram = Histogram::new();
-            histogram.increment(1); // 1 B
-            histogram.increment(512); // 512 B
-            histogram.increment(1026); // 2 KB
+        fn print_by_cpu() {
+            let mut processor = EventProcessor::new(1);
+            for (cpu, size) in [(0, 1024), (0, 1024), (3, 4096)] {
+                let key = UnpackedHistogramKey { cpu, ..key(1) };
+                processor.record_event(key, size, 1);
+            }
+            let split = processor.split_by_cpu();
+            let nodes = [(0, 0), (3, 1)].into_iter().collect();
             let mut buf = String::new();
-            print_histogram(&histogram, &mut buf).unwrap();
+            print_cpu_split(split.values().next().unwrap(), &nodes, &mut buf).unwrap();
             insta::assert_snapshot!(buf);
         }
 
         #[test]
-        fn print_histogram_large_allocations() {
-            let mut histogram = Histogram::new();
-            histogram.increment(1 << 20); // 1 MB
-            histogram.increment(1u64 << 30); // 1 GB
+        fn metrics_keep_biggest_stacks() {
+            let mut processor = EventProcessor::new(1);
+            for (pid, stack_id, size) in [(1, 1, 16), (2, 1, 16), (1, 2, 4096), (1, 3, 64)] {
+                let key = UnpackedHistogramKey {
+                    pid,
+                    ..key(stack_id)
+                };
+                processor.record_event(key, size, 1);
+            }
+            let snapshot = processor.metrics_snapshot(2, 4);
+            assert_eq!(snapshot.distinct_stacks, 3);
+            assert_eq!(snapshot.alloc_bytes, 4192);
+            let stacks: Vec<_> = snapshot
+                .stacks
+                .iter()
+                .map(|stack| (stack.stack_id, stack.bytes, stack.count))
+                .collect();
+            assert_eq!(stacks, [(2, 4096, 1), (3, 64, 1)]);
+        }
+
+        #[test]
+        fn merge_stacks_by_symbols() {
+            let mut processor = EventProcessor::new(1);
+            processor.tracks_frees = true;
+            // 5 and 9 differ only in the addresses within the same functions
+            for (stack_id, address, symbols) in [
+                (9, 0x20, ["malloc", "parse"]),
+                (5, 0x10, ["malloc", "parse"]),
+                (7, 0x30, ["malloc", "load"]),
+            ] {
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: symbols
+                            .iter()
+                            .map(|symbol| OwnedSymbol {
+                                address,
+                                symbol: symbol.to_string(),
+                                file: None,
+                                line: None,
+                                inlined: Vec::new(),
+                            })
+                            .collect(),
+                    },
+                );
+                let mut histogram = Histogram::new();
+                histogram.increment(64);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+                processor.live.insert(
+                    key.as_reduced(),
+                    LiveStats {
+                        bytes: 64,
+                        count: 1,
+                    },
+                );
+            }
+
+            let merged = processor.merged_by_symbols(None);
+            let mut stacks: Vec<_> = merged
+                .stacks()
+                .map(|(key, hist, _)| (key.stack_id, hist.allocation_count()))
+                .collect();
+            stacks.sort();
+            assert_eq!(stacks, [(5, 2), (7, 1)]);
+            let key = merged
+                .stacks()
+                .find(|(key, ..)| key.stack_id == 5)
+                .unwrap()
+                .0;
+            assert_eq!(merged.live_of(&key), Some((128, 2)));
+
+            let render = |merge_by| {
+                let mut buf = String::new();
+                processor
+                    .print_histogram(
+                        &mut buf,
+                        ReportOptions {
+                            order_by: OrderBy::Count,
+                            merge_by,
+                            ..report_options()
+                        },
+                    )
+                    .unwrap();
+                buf
+            };
+            assert!(render(MergeBy::Stack).starts_with("captured 3 stacks"));
+            assert!(render(MergeBy::Symbols).starts_with("captured 2 stacks"));
+        }
+
+        #[test]
+        fn filter_and_exclude_symbols() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, symbols) in [
+                (1, ["malloc", "my_module::parse"]),
+                (2, ["malloc", "my_module::tests::setup"]),
+                (3, ["malloc", "other::load"]),
+            ] {
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: symbols.iter().map(|symbol| frame(symbol)).collect(),
+                    },
+                );
+            }
+            let filters = [Regex::new("my_module::").unwrap()];
+            let excludes = [Regex::new("::tests::").unwrap()];
+            let kept: Vec<_> = [1, 2, 3, 4]
+                .into_iter()
+                .filter(|stack_id| processor.matches_symbols(*stack_id, &filters, &excludes))
+                .collect();
+            assert_eq!(kept, [1]);
+            // unresolved stacks match no exclude pattern
+            let kept: Vec<_> = [3, 4]
+                .into_iter()
+                .filter(|stack_id| processor.matches_symbols(*stack_id, &[], &excludes))
+                .collect();
+            assert_eq!(kept, [3, 4]);
+        }
+
+        #[test]
+        fn dropped_stacks_are_explained() {
+            let mut processor = EventProcessor::new(1);
+            for (stack_id, symbol, size) in [
+                (1, "app::parse", Some(4096)),
+                (2, "app::load", Some(64)),
+                (3, "app::tests::setup", Some(16)),
+                (4, "app::parse", None),
+            ] {
+                let key = key(stack_id);
+                let mut histogram = Histogram::new();
+                if let Some(size) = size {
+                    histogram.increment(size);
+                }
+                processor.allocations_stats.insert(key, histogram);
+                let symbol = frame(symbol);
+                processor.resolved_traces.insert(
+                    stack_id,
+                    ResolvedStackTrace {
+                        symbols: vec![symbol],
+                    },
+                );
+            }
+            let mut skipped = processor
+                .allocations_stats
+                .keys()
+                .next()
+                .unwrap()
+                .as_reduced();
+            skipped.stack_id = 5;
+            processor.skipped_stacks.insert(skipped, Skipped::Size);
+            processor.shallow_stacks = 1;
+
+            let mut buf = String::new();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        excludes: vec![Regex::new("::tests::").unwrap()],
+                        compact: true,
+                        top: Some(1),
+                        ..report_options()
+                    },
+                )
+                .unwrap();
+            let header: Vec<_> = buf.lines().take(6).collect();
+            assert_eq!(
+                header,
+                [
+                    "captured 6 stacks, displaying 1 after filters",
+                    "  1 below --skip-size",
+                    "  1 shorter than --min-frames",
+                    "  1 not matching --include-file, --filter or --exclude",
+                    "  1 without allocated bytes",
+                    "  1 beyond --top",
+                ]
+            );
+        }
+
+        #[test]
+        fn print_summary_only() {
+            let mut processor = EventProcessor::new(10);
+            for (stack_id, size) in [(1, 16), (2, 1024)] {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                let key = key(stack_id);
+                processor.allocations_stats.insert(key, histogram);
+            }
             let mut buf = String::new();
-            print_histogram(&histogram, &mut buf).unwrap();
+            processor
+                .print_histogram(
+                    &mut buf,
+                    ReportOptions {
+                        summary_only: true,
+                        ..report_options()
+                    },
+                )
+                .unwrap();
             insta::assert_snapshot!(buf);
         }
 
         #[test]
-        fn print_histogram_many_small_allocations() {
+        fn exit_summary_is_one_line() {
+            let mut processor = EventProcessor::new(1);
             let mut histogram = Histogram::new();
-            for _ in 0..1000 {
-                histogram.increment(1); // 1 B
+            histogram.record_weighted(64, 3, BucketScheme::Log2);
+            let key = UnpackedHistogramKey { pid: 7, ..key(1) };
+            processor.allocations_stats.insert(key, histogram);
+            processor.dropped_events = 2;
+            processor.insert_failures = 1;
+            assert_eq!(
+                processor.exit_summary(
+                    &[Some(7), None],
+                    &[JemallocAllocFunctions::Malloc, JemallocAllocFunctions::Realloc],
+                    Duration::from_millis(30_400)
+                ),
+                "jeprofl: pid=7,all functions=malloc,realloc duration=30.4s distinct_stacks=1 total_bytes=192 total_allocs=3 dropped=3 evicted_stacks=0 shallow_stacks=0"
+            );
+        }
+
+        #[test]
+        fn evicts_smallest_stacks_beyond_max() {
+            let mut processor = EventProcessor::new(1);
+            processor.max_stacks = Some((2, OrderBy::Traffic));
+            for (stack_id, size) in [(1, 4096), (2, 16), (3, 1024)] {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                let key = UnpackedHistogramKey {
+                    tid: 1,
+                    ..key(stack_id)
+                };
+                processor.allocations_stats.insert(key, histogram);
             }
-            histogram.increment(1023); // 1 KB
+            processor.evict_smallest_stacks();
+            let mut kept: Vec<_> = processor
+                .allocations_stats
+                .keys()
+                .map(|key| key.stack_id)
+                .collect();
+            kept.sort_unstable();
+            assert_eq!(kept, [1, 3]);
+            assert!(processor.capped_stacks.contains(&2));
+
             let mut buf = String::new();
-            print_histogram(&histogram, &mut buf).unwrap();
-            insta::assert_snapshot!(buf);
+            processor.print_loss_notes(&mut buf).unwrap();
+            assert!(
+                buf.contains("1 of the smallest stacks were evicted to stay within --max-stacks")
+            );
+        }
+
+        #[test]
+        fn summary_reports_evicted_stacks() {
+            let mut processor = EventProcessor::new(1);
+            let mut buf = String::new();
+            processor
+                .print_summary(&FxHashMap::default(), Duration::from_secs(1), &mut buf)
+                .unwrap();
+            assert!(!buf.contains("evicted"));
+
+            processor.evicted_stacks = 3;
+            let mut buf = String::new();
+            processor
+                .print_summary(&FxHashMap::default(), Duration::from_secs(1), &mut buf)
+                .unwrap();
+            assert!(buf.starts_with("3 stacks were evicted"));
+
+            processor.stack_id_failures = 5;
+            let mut buf = String::new();
+            processor.print_loss_notes(&mut buf).unwrap();
+            assert!(buf.ends_with(
+                "5 allocations and frees were dropped because the stack map was full\n"
+            ));
+
+            processor.insert_failures = 2;
+            let mut buf = String::new();
+            processor.print_loss_notes(&mut buf).unwrap();
+            assert!(buf
+                .ends_with("2 allocations and frees were dropped because an ebpf map was full\n"));
+        }
+
+        #[test]
+        fn only_unreferenced_stacks_are_orphaned() {
+            let referenced: FxHashSet<u32> = [1, 3].into_iter().collect();
+            let orphaned = orphaned_stacks_of([1, 2, 3, 4].into_iter(), &referenced);
+            assert_eq!(orphaned, [2, 4].into_iter().collect());
+        }
+
+        #[test]
+        fn record_event_aggregates_ring_buffer_allocations() {
+            let mut processor = EventProcessor::new(1);
+            let key = key(1);
+            processor.record_event(key, 16, 1);
+            processor.record_event(key, 64, 1);
+            processor.record_event(key, 32, 1);
+            let hist = processor.allocations_stats[&key];
+            assert_eq!(hist.allocation_count(), 3);
+            assert_eq!(hist.total_bytes(), 112);
+        }
+
+        #[test]
+        fn timeline_counts_every_second() {
+            let mut timeline = Timeline::new(5_000_000_000);
+            // before the start, counted in the first second
+            timeline.record(4_000_000_000, 16, 1);
+            timeline.record(5_500_000_000, 32, 1);
+            timeline.record(7_100_000_000, 1024, 10);
+            timeline.record(7_900_000_000, -8, 1);
+            assert_eq!(timeline.rows(2), [(0, 96, 4), (1, 0, 0), (2, 20464, 22)]);
+        }
+
+        #[test]
+        fn flame_graph_labels_scaled_numbers() {
+            let mut processor = EventProcessor::new(100);
+            processor.resolved_traces.insert(
+                1,
+                ResolvedStackTrace {
+                    symbols: vec![frame("main")],
+                },
+            );
+            let key = key(1);
+            processor.record_event(key, 16, 1);
+            let stats = processor.merge();
+            assert_eq!(stats[&key.as_reduced()].total_bytes(), 1600);
+
+            let mut svg = Vec::new();
+            processor
+                .write_flame_graph(
+                    &stats,
+                    &mut svg,
+                    WeightMetric::Bytes,
+                    &FlameOptions::default(),
+                )
+                .unwrap();
+            let svg = String::from_utf8(svg).unwrap();
+            assert!(svg.contains("1,600 estimated total allocated"));
+
+            let mut svg = Vec::new();
+            let options = FlameOptions {
+                width: Some(640),
+                title: Some("allocations of main".to_string()),
+                ..Default::default()
+            };
+            processor
+                .write_flame_graph(&stats, &mut svg, WeightMetric::Count, &options)
+                .unwrap();
+            let svg = String::from_utf8(svg).unwrap();
+            assert!(svg.contains("allocations of main"));
+            assert!(svg.contains("width=\"640\""));
+            assert!(svg.contains("100 estimated count"));
+        }
+
+        #[test]
+        fn folded_stacks_are_summed() {
+            let mut processor = EventProcessor::new(1);
+            let trace = |names: &[&str]| ResolvedStackTrace {
+                symbols: names.iter().map(|name| frame(name)).collect(),
+            };
+            processor
+                .resolved_traces
+                .insert(1, trace(&["malloc", "parse", "main"]));
+            processor
+                .resolved_traces
+                .insert(2, trace(&["malloc", "load", "main"]));
+            let key = |tid, stack_id| UnpackedHistogramKey {
+                tid,
+                ..key(stack_id)
+            };
+            processor.record_event(key(1, 1), 16, 2);
+            processor.record_event(key(2, 1), 16, 1);
+            processor.record_event(key(1, 2), 4096, 1);
+            // unresolved stacks have no frames to fold
+            processor.record_event(key(1, 3), 8, 1);
+            let stats = processor.merge();
+
+            let mut folded = Vec::new();
+            processor
+                .write_folded(&stats, &mut folded, WeightMetric::Bytes)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(folded).unwrap(),
+                "main;load;malloc 4096\nmain;parse;malloc 48\n"
+            );
+            let mut folded = Vec::new();
+            processor
+                .write_folded(&stats, &mut folded, WeightMetric::Count)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(folded).unwrap(),
+                "main;load;malloc 1\nmain;parse;malloc 3\n"
+            );
+        }
+
+        #[test]
+        fn size_biased_samples_are_weighted() {
+            let mut processor = EventProcessor::new(1);
+            processor.size_biased = Some(SizeBiasedSampling {
+                threshold: 1024 * 1024,
+                sample_every: 100,
+            });
+            let key = key(1);
+            processor.record_event(key, 16, 100); // sampled small allocation
+            processor.record_event(key, 4 << 30, 1); // big one, always recorded
+            processor.record_event(key, -8, 100); // sampled shrinking realloc
+            let hist = processor.allocations_stats[&key];
+            assert_eq!(hist.allocation_count(), 101);
+            assert_eq!(hist.total_bytes(), 16 * 100 + (4 << 30) - 8 * 100);
+
+            let mut buf = String::new();
+            processor.print_scale_note(&mut buf).unwrap();
+            assert_eq!(
+                buf,
+                "allocations below 1.0 MiB sampled 1/100: sizes and counts below are estimates\n"
+            );
+        }
+
+        #[test]
+        fn byte_samples_stand_for_every_bytes() {
+            // the ebpf side weights the samples, every one is about 1 KiB
+            let mut accumulated = 0;
+            let weights: Vec<_> = [100, 300, 700, 100, 4096, 0]
+                .into_iter()
+                .map(|size| byte_sample_weight(&mut accumulated, size, 1024))
+                .collect();
+            assert_eq!(weights, [None, None, Some(1), None, Some(1), None]);
+            assert_eq!(accumulated, 176);
+            let mut accumulated = 1020;
+            assert_eq!(byte_sample_weight(&mut accumulated, 8, 1024), Some(128));
+            assert_eq!(byte_sample_weight(&mut accumulated, 0, 1), Some(1));
+
+            let mut processor = EventProcessor::new(1);
+            processor.sample_every_bytes = Some(512 * 1024);
+            let mut buf = String::new();
+            processor.print_scale_note(&mut buf).unwrap();
+            assert_eq!(
+                buf,
+                "sampled every 512.0 kiB allocated: sizes and counts below are estimates\n"
+            );
+        }
+
+        #[test]
+        fn record_accumulates_deltas() {
+            let mut processor = EventProcessor::new(1);
+            let key = key(1);
+            let mut snapshot = Histogram::new();
+            snapshot.increment(16);
+            processor.record(key, &snapshot);
+            // polled again without new allocations
+            processor.record(key, &snapshot);
+            snapshot.increment(32);
+            processor.record(key, &snapshot);
+            assert_eq!(processor.allocations_stats[&key].allocation_count(), 2);
+            assert_eq!(processor.allocations_stats[&key].total_bytes(), 48);
+
+            // the map entry was evicted and started over
+            let mut restarted = Histogram::new();
+            restarted.increment(64);
+            processor.record(key, &restarted);
+            assert_eq!(processor.allocations_stats[&key].allocation_count(), 3);
+            assert_eq!(processor.allocations_stats[&key].total_bytes(), 112);
+        }
+
+        #[test]
+        fn discarded_allocations_stay_uncounted() {
+            let mut processor = EventProcessor::new(1);
+            let key = key(1);
+            let mut snapshot = Histogram::new();
+            snapshot.increment(4096);
+            processor.record(key, &snapshot);
+            processor.discard_captured();
+            assert!(processor.allocations_stats.is_empty());
+
+            snapshot.increment(16);
+            processor.record(key, &snapshot);
+            assert_eq!(processor.allocations_stats[&key].allocation_count(), 1);
+            assert_eq!(processor.allocations_stats[&key].total_bytes(), 16);
+        }
+
+        #[test]
+        fn window_has_only_later_allocations() {
+            let mut processor = EventProcessor::new(1);
+            let mut first = Histogram::new();
+            first.increment(4096);
+            let mut second = Histogram::new();
+            second.increment(16);
+            processor.record(key(1), &first);
+            processor.record(key(2), &second);
+            let baseline = processor.clone();
+
+            first.increment(64);
+            processor.record(key(1), &first);
+            let window = processor.since(&baseline);
+            assert_eq!(window.allocations_stats.len(), 1);
+            assert_eq!(window.allocations_stats[&key(1)].allocation_count(), 1);
+            assert_eq!(window.allocations_stats[&key(1)].total_bytes(), 64);
+            // the whole capture is kept
+            assert_eq!(processor.allocations_stats[&key(1)].total_bytes(), 4160);
+        }
+
+        #[test]
+        fn live_peak_survives_frees() {
+            let mut processor = EventProcessor::new(1);
+            let key = ReducedEventKey {
+                pid: 1,
+                tid: 0,
+                stack_id: 7,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+            };
+            assert_eq!(processor.peak_live_of(&key), None);
+            processor.tracks_frees = true;
+            for (bytes, count) in [(1024, 1), (8192, 4), (512, 1)] {
+                processor.update_live(key, LiveStats { bytes, count });
+            }
+            assert_eq!(processor.live_of(&key), Some((512, 1)));
+            assert_eq!(processor.peak_live_of(&key), Some(8192));
+
+            let mut hist = Histogram::new();
+            hist.increment(3000);
+            let weights = [
+                WeightMetric::Count,
+                WeightMetric::Bytes,
+                WeightMetric::Live,
+                WeightMetric::Peak,
+            ]
+            .map(|weight| processor.weight_of(&key, &hist, weight));
+            assert_eq!(weights, [1, 3000, 512, 8192]);
+        }
+
+        #[test]
+        fn json_stack_bucket_bounds() {
+            let processor = EventProcessor::new(1);
+            let mut histogram = Histogram::new();
+            histogram.increment(24);
+            let key = ReducedEventKey {
+                pid: 1,
+                tid: 0,
+                stack_id: 7,
+                kernel_stack_id: NO_KERNEL_STACK,
+                function_id: MALLOC_ID,
+            };
+            let stack = JsonStack::new(&key, &histogram, &processor);
+            let json = serde_json::to_value(&stack).unwrap();
+            assert_eq!(
+                json["buckets"].as_array().unwrap().len(),
+                BucketScheme::Log2.bucket_count()
+            );
+            assert_eq!(
+                json["buckets"][4],
+                serde_json::json!({"lower": 16, "upper": 31, "count": 1})
+            );
+            assert_eq!(json["total"], 24);
+            assert_eq!(json["frames"], serde_json::json!([]));
         }
     }
 }
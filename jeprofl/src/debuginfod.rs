@@ -0,0 +1,92 @@
+//! Separate debug info for `--debuginfod`. Files are fetched by build id with
+//! `debuginfod-find`, which keeps the downloads in its local cache
+//! (`~/.cache/debuginfod_client`), so every file is downloaded only once.
+
+use anyhow::Context;
+use rustc_hash::FxHashMap;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct Debuginfod {
+    /// Space separated server urls, as in `DEBUGINFOD_URLS`
+    urls: String,
+    /// Fetched debug files by build id, `None` if no server has one
+    found: RefCell<FxHashMap<String, Option<PathBuf>>>,
+}
+
+impl Debuginfod {
+    /// Client of the `url` servers, or of `DEBUGINFOD_URLS` if `url` is empty
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let urls = servers(url, std::env::var("DEBUGINFOD_URLS").ok().as_deref())
+            .context("--debuginfod needs a server url or DEBUGINFOD_URLS")?;
+        Ok(Self {
+            urls,
+            found: Default::default(),
+        })
+    }
+
+    /// Debug info file of the ELF `binary`, downloaded if not cached yet
+    pub fn debug_file(&self, binary: &Path) -> Option<PathBuf> {
+        let build_id = blazesym::helper::read_elf_build_id(&binary).ok()??;
+        let build_id: String = build_id.iter().map(|b| format!("{b:02x}")).collect();
+        self.found
+            .borrow_mut()
+            .entry(build_id)
+            .or_insert_with_key(|build_id| {
+                let path = self.fetch(build_id);
+                match &path {
+                    Ok(path) => log::info!("Using debug info of {binary:?} from {path:?}"),
+                    Err(e) => log::debug!("No debug info of {binary:?} from debuginfod: {e:#}"),
+                }
+                path.ok()
+            })
+            .clone()
+    }
+
+    fn fetch(&self, build_id: &str) -> anyhow::Result<PathBuf> {
+        let output = Command::new("debuginfod-find")
+            .args(["debuginfo", build_id])
+            .env("DEBUGINFOD_URLS", &self.urls)
+            .output()
+            .context("Failed to run debuginfod-find, is elfutils installed?")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        // prints the path of the cached file
+        Ok(PathBuf::from(
+            String::from_utf8_lossy(&output.stdout).trim(),
+        ))
+    }
+}
+
+/// Servers to ask: the `--debuginfod` url, or `env` when the flag has none
+fn servers(url: &str, env: Option<&str>) -> Option<String> {
+    let urls = match url {
+        "" => env?,
+        url => url,
+    };
+    (!urls.trim().is_empty()).then(|| urls.trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flag_url_overrides_environment() {
+        assert_eq!(
+            servers("https://debuginfod.example.org", Some("https://env")),
+            Some("https://debuginfod.example.org".to_string())
+        );
+        assert_eq!(
+            servers("", Some("https://a https://b ")),
+            Some("https://a https://b".to_string())
+        );
+        assert_eq!(servers("", Some(" ")), None);
+        assert_eq!(servers("", None), None);
+    }
+}
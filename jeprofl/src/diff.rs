@@ -0,0 +1,227 @@
+//! Comparison of two profiles saved with `--json`. Stacks are matched by
+//! their resolved symbols, so profiles of different runs with different
+//! stack ids and pids can be compared.
+
+use anyhow::Context;
+use rustc_hash::FxHashMap;
+use std::cmp::Reverse;
+use std::io::BufRead;
+use std::path::Path;
+
+/// The part of a `--json` stack entry the diff needs
+#[derive(Debug, serde::Deserialize)]
+pub struct SavedStack {
+    pub total: u64,
+    pub count: u64,
+    pub frames: Vec<SavedFrame>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SavedFrame {
+    pub symbol: String,
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Vec<SavedStack>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path:?}, expected a --json export"))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    bytes: u64,
+    count: u64,
+}
+
+/// Sums stacks with the same symbols, e.g. of different threads or processes
+fn by_signature(stacks: &[SavedStack]) -> FxHashMap<Vec<&str>, Totals> {
+    let mut signatures: FxHashMap<Vec<&str>, Totals> = FxHashMap::default();
+    for stack in stacks {
+        let signature = stack.frames.iter().map(|f| f.symbol.as_str()).collect();
+        let totals = signatures.entry(signature).or_default();
+        totals.bytes += stack.total;
+        totals.count += stack.count;
+    }
+    signatures
+}
+
+/// Prints the per stack change of total bytes and count from `before` to `after`,
+/// the biggest change in bytes first.
+pub fn print_diff(
+    before: &[SavedStack],
+    after: &[SavedStack],
+    mut pager: impl std::fmt::Write,
+) -> anyhow::Result<()> {
+    let before = by_signature(before);
+    let after = by_signature(after);
+
+    let mut stacks: Vec<_> = before
+        .keys()
+        .chain(
+            after
+                .keys()
+                .filter(|signature| !before.contains_key(*signature)),
+        )
+        .map(|signature| {
+            (
+                signature,
+                before.get(signature).copied(),
+                after.get(signature).copied(),
+            )
+        })
+        .collect();
+    let delta = |before: Option<Totals>, after: Option<Totals>| {
+        let before = before.unwrap_or_default();
+        let after = after.unwrap_or_default();
+        (
+            after.bytes as i128 - before.bytes as i128,
+            after.count as i128 - before.count as i128,
+        )
+    };
+    stacks.sort_by(|(a, a_before, a_after), (b, b_before, b_after)| {
+        let a_delta = delta(*a_before, *a_after);
+        let b_delta = delta(*b_before, *b_after);
+        Reverse(a_delta.0.abs())
+            .cmp(&Reverse(b_delta.0.abs()))
+            .then(b_delta.0.cmp(&a_delta.0))
+            .then(a.cmp(b))
+    });
+
+    let bytes_before: u64 = before.values().map(|t| t.bytes).sum();
+    let bytes_after: u64 = after.values().map(|t| t.bytes).sum();
+    writeln!(
+        pager,
+        "total: {} -> {} ({})",
+        bytesize::to_string(bytes_before, true),
+        bytesize::to_string(bytes_after, true),
+        signed_size(bytes_after as i128 - bytes_before as i128)
+    )?;
+    writeln!(pager, "stacks: {} -> {}\n", before.len(), after.len())?;
+
+    for (signature, stack_before, stack_after) in stacks {
+        let (bytes, count) = delta(stack_before, stack_after);
+        if bytes == 0 && count == 0 {
+            continue;
+        }
+        let label = match (stack_before, stack_after) {
+            (None, _) => "[new]",
+            (_, None) => "[gone]",
+            _ => "[changed]",
+        };
+        writeln!(pager, "{}", "*".repeat(80))?;
+        writeln!(
+            pager,
+            "{label} {} ({count:+} allocations)",
+            signed_size(bytes)
+        )?;
+        let before = stack_before.unwrap_or_default();
+        let after = stack_after.unwrap_or_default();
+        writeln!(
+            pager,
+            "{} in {} allocations -> {} in {} allocations",
+            bytesize::to_string(before.bytes, true),
+            before.count,
+            bytesize::to_string(after.bytes, true),
+            after.count
+        )?;
+        writeln!(pager, "{}", "-".repeat(80))?;
+        if signature.is_empty() {
+            writeln!(pager, "No resolved stacktrace")?;
+        }
+        for symbol in signature {
+            writeln!(pager, "{symbol}")?;
+        }
+        writeln!(pager)?;
+    }
+    Ok(())
+}
+
+/// Renders a differential flame graph of two `--folded` captures: the
+/// frames are sized by `after`, red ones grew and blue ones shrank since `before`
+pub fn write_flame_diff(
+    before: impl BufRead,
+    after: impl BufRead,
+    normalize: bool,
+    writer: impl std::io::Write,
+) -> anyhow::Result<()> {
+    let mut folded = Vec::new();
+    let options = inferno::differential::Options {
+        normalize,
+        strip_hex: false,
+    };
+    inferno::differential::from_readers(options, before, after, &mut folded)?;
+    let mut settings = inferno::flamegraph::Options::default();
+    settings.title = "Differential Flame Graph".to_string();
+    inferno::flamegraph::from_reader(&mut settings, folded.as_slice(), writer)?;
+    Ok(())
+}
+
+fn signed_size(bytes: i128) -> String {
+    let sign = if bytes < 0 { '-' } else { '+' };
+    format!(
+        "{sign}{}",
+        bytesize::to_string(bytes.unsigned_abs() as u64, true)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stack(total: u64, count: u64, symbols: &[&str]) -> SavedStack {
+        SavedStack {
+            total,
+            count,
+            frames: symbols
+                .iter()
+                .map(|symbol| SavedFrame {
+                    symbol: symbol.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn diff_labels_new_and_gone_stacks() {
+        let before = [
+            stack(1024, 4, &["malloc", "parse", "main"]),
+            stack(512, 2, &["malloc", "parse", "main"]),
+            stack(4096, 1, &["malloc", "load", "main"]),
+            stack(64, 1, &["malloc", "idle", "main"]),
+            stack(2048, 8, &["malloc", "legacy", "main"]),
+        ];
+        let after = [
+            stack(8192, 2, &["malloc", "load", "main"]),
+            stack(256, 1, &["malloc", "parse", "main"]),
+            stack(64, 1, &["malloc", "idle", "main"]),
+            stack(100, 10, &["malloc", "cache", "main"]),
+        ];
+        let mut buf = String::new();
+        print_diff(&before, &after, &mut buf).unwrap();
+        insta::assert_snapshot!(buf);
+    }
+
+    #[test]
+    fn flame_diff_colors_changed_frames() {
+        let before = "main;load;malloc 4096\nmain;parse;malloc 1024\n";
+        let after = "main;load;malloc 8192\nmain;parse;malloc 1024\n";
+        let mut svg = Vec::new();
+        write_flame_diff(before.as_bytes(), after.as_bytes(), false, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("Differential Flame Graph"));
+        // the malloc under load grew by 4096 bytes, 44% of the total after
+        assert!(svg.contains("malloc (8,192 samples, 88.89%; +44.44%)"));
+        assert!(svg.contains("parse (1,024 samples, 11.11%; 0.00%)"));
+    }
+
+    #[test]
+    fn parses_json_export() {
+        let json = r#"[{"pid": 1, "tid": 0, "stack_id": 3, "function": "malloc",
+            "total": 16, "count": 1, "overflow": 0, "scale_factor": 1,
+            "live_bytes": null, "live_count": null, "buckets": [],
+            "frames": [{"address": 16, "symbol": "main", "file": null}]}]"#;
+        let stacks: Vec<SavedStack> = serde_json::from_str(json).unwrap();
+        assert_eq!(stacks[0].total, 16);
+        assert_eq!(stacks[0].frames[0].symbol, "main");
+    }
+}
@@ -0,0 +1,273 @@
+//! Allocation profiling of jemalloc and libc allocators with eBPF uprobes.
+//! [`Profiler`] attaches the probes and collects the allocations in the
+//! background, the returned [`EventProcessor`](collector::EventProcessor)
+//! renders the reports. The `jeprofl` binary is a thin wrapper over it.
+
+use jeprofl_common::{
+    Histogram, ALIGNED_ALLOC_ID, CALLOC_ID, MALLOCX_ID, MALLOC_ID, MAX_FUNCTIONS, MEMALIGN_ID,
+    POSIX_MEMALIGN_ID, RALLOCX_ID, REALLOC_ID, XALLOCX_ID,
+};
+use std::fmt::Display;
+use std::str::FromStr;
+
+pub mod collector;
+pub mod debuginfod;
+pub mod diff;
+pub mod merge;
+pub mod metrics;
+pub mod pprof;
+pub mod process;
+pub mod profiler;
+pub mod raw;
+pub mod resolver;
+pub mod self_test;
+pub mod symbol_cache;
+pub mod topology;
+pub mod tui;
+mod unwind;
+pub mod watch;
+
+pub use profiler::{Profiler, ProfilerOptions};
+
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
+pub enum GroupBy {
+    /// Stacks of all threads of a process are merged
+    Process,
+    /// Every thread has its own stacks
+    Thread,
+}
+
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
+pub enum SkipMode {
+    /// Skipped if the size or the count is below its threshold
+    Any,
+    /// Skipped only if both are below their thresholds
+    All,
+}
+
+impl SkipMode {
+    /// Whether the histogram is too small to be kept
+    pub fn skips(self, hist: &Histogram, min_size: u64, min_count: u64) -> bool {
+        let small_size = hist.total_bytes() < min_size;
+        let small_count = hist.allocation_count() < min_count;
+        match self {
+            Self::Any => small_size || small_count,
+            Self::All => small_size && small_count,
+        }
+    }
+}
+
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
+pub enum OrderBy {
+    Count,
+    Traffic,
+    /// Not yet freed bytes, needs tracked frees
+    Live,
+}
+
+/// What a stack is weighted by in the flame graphs, the folded stacks and the
+/// CSV, independently of the `OrderBy` of the text report
+#[derive(derive_more::Display, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WeightMetric {
+    Count,
+    /// Allocated bytes, `traffic` is accepted as well
+    Bytes,
+    /// Not yet freed bytes, needs tracked frees
+    Live,
+    /// Highest live bytes seen while profiling, needs tracked frees
+    Peak,
+}
+
+impl WeightMetric {
+    pub fn needs_frees(self) -> bool {
+        matches!(self, Self::Live | Self::Peak)
+    }
+}
+
+impl From<OrderBy> for WeightMetric {
+    fn from(order_by: OrderBy) -> Self {
+        match order_by {
+            OrderBy::Count => Self::Count,
+            OrderBy::Traffic => Self::Bytes,
+            OrderBy::Live => Self::Live,
+        }
+    }
+}
+
+impl FromStr for WeightMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "count" => Ok(Self::Count),
+            "bytes" | "traffic" => Ok(Self::Bytes),
+            "live" => Ok(Self::Live),
+            "peak" => Ok(Self::Peak),
+            _ => Err(anyhow::anyhow!(
+                "Invalid weight {s}, expected count, bytes, live or peak"
+            )),
+        }
+    }
+}
+
+/// Layout of the `--csv` rows
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CsvFormat {
+    /// The histogram as the text table of the report, in one cell
+    Text,
+    /// One column with the count of every bucket, for pandas or a spreadsheet
+    Wide,
+}
+
+/// What makes two captured stacks the same one in the report
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
+pub enum MergeBy {
+    /// The stack id of the ebpf stack map
+    Stack,
+    /// The symbols of the resolved frames, stacks which differ only in the
+    /// addresses within the same functions are summed
+    Symbols,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JemallocAllocFunctions {
+    Malloc,
+    Calloc,
+    Realloc,
+    Mallocx,
+    Rallocx,
+    Xallocx,
+    AlignedAlloc,
+    PosixMemalign,
+    Memalign,
+}
+
+impl FromStr for JemallocAllocFunctions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "malloc" => Ok(Self::Malloc),
+            "calloc" => Ok(Self::Calloc),
+            "realloc" => Ok(Self::Realloc),
+            "mallocx" => Ok(Self::Mallocx),
+            "rallocx" => Ok(Self::Rallocx),
+            "xallocx" => Ok(Self::Xallocx),
+            "aligned_alloc" => Ok(Self::AlignedAlloc),
+            "posix_memalign" => Ok(Self::PosixMemalign),
+            "memalign" => Ok(Self::Memalign),
+            _ => Err(anyhow::anyhow!("Invalid function name {}", s)),
+        }
+    }
+}
+
+impl Display for JemallocAllocFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malloc => write!(f, "malloc"),
+            Self::Calloc => write!(f, "calloc"),
+            Self::Realloc => write!(f, "realloc"),
+            Self::Mallocx => write!(f, "mallocx"),
+            Self::Rallocx => write!(f, "rallocx"),
+            Self::Xallocx => write!(f, "xallocx"),
+            Self::AlignedAlloc => write!(f, "aligned_alloc"),
+            Self::PosixMemalign => write!(f, "posix_memalign"),
+            Self::Memalign => write!(f, "memalign"),
+        }
+    }
+}
+
+impl JemallocAllocFunctions {
+    const ALL: [Self; MAX_FUNCTIONS as usize] = [
+        Self::Malloc,
+        Self::Calloc,
+        Self::Realloc,
+        Self::Mallocx,
+        Self::Rallocx,
+        Self::Xallocx,
+        Self::AlignedAlloc,
+        Self::PosixMemalign,
+        Self::Memalign,
+    ];
+
+    /// Id of the function in the histogram keys and the per-function config slots
+    pub fn id(&self) -> u32 {
+        match self {
+            Self::Malloc => MALLOC_ID,
+            Self::Calloc => CALLOC_ID,
+            Self::Realloc => REALLOC_ID,
+            Self::Mallocx => MALLOCX_ID,
+            Self::Rallocx => RALLOCX_ID,
+            Self::Xallocx => XALLOCX_ID,
+            Self::AlignedAlloc => ALIGNED_ALLOC_ID,
+            Self::PosixMemalign => POSIX_MEMALIGN_ID,
+            Self::Memalign => MEMALIGN_ID,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.id() == id)
+    }
+
+    /// Functions only jemalloc has, the C library's allocator never calls them
+    pub fn is_jemalloc_only(&self) -> bool {
+        matches!(self, Self::Mallocx | Self::Rallocx | Self::Xallocx)
+    }
+
+    /// `realloc`-shaped functions, `(ptr, size, ...)` returning the new pointer.
+    /// They are accounted on return, when the size of the old allocation is known.
+    pub fn is_resizing(&self) -> bool {
+        matches!(self, Self::Realloc | Self::Rallocx)
+    }
+
+    /// Name of the ebpf uprobe program for the function, every function has its
+    /// own one, so the recorded function id is known without a probe cookie
+    pub fn entry_program(&self) -> &'static str {
+        match self {
+            Self::Malloc => "malloc",
+            Self::Calloc => "calloc",
+            Self::Realloc => "realloc",
+            Self::Mallocx => "mallocx",
+            Self::Rallocx => "rallocx",
+            Self::Xallocx => "xallocx",
+            Self::AlignedAlloc => "aligned_alloc",
+            Self::PosixMemalign => "posix_memalign",
+            Self::Memalign => "memalign",
+        }
+    }
+
+    /// Name of the ebpf uretprobe program for the function
+    pub fn return_program(&self) -> &'static str {
+        match self {
+            _ if self.is_resizing() => "realloc_ret",
+            Self::PosixMemalign => "posix_memalign_ret",
+            Self::Xallocx => "xallocx_ret",
+            _ => "malloc_ret",
+        }
+    }
+
+    pub fn allocation_arg_index(&self) -> u64 {
+        match self {
+            Self::Malloc => 0,
+            Self::Calloc => 1,
+            Self::Realloc => 1,
+            Self::Mallocx => 0,
+            Self::Rallocx => 1,
+            Self::Xallocx => 1,
+            // aligned_alloc(alignment, size) and memalign(alignment, size)
+            Self::AlignedAlloc | Self::Memalign => 1,
+            // posix_memalign(&ptr, alignment, size) returns an error code, the
+            // size is read from its argument, the pointer from `ptr` on return
+            Self::PosixMemalign => 2,
+        }
+    }
+
+    /// Index of the argument the size is multiplied by, if the allocation size is a
+    /// product of two arguments like `calloc(count, size)`
+    pub fn size_multiplier_arg_index(&self) -> Option<u64> {
+        match self {
+            Self::Calloc => Some(0),
+            _ => None,
+        }
+    }
+}
@@ -1,49 +1,191 @@
-use crate::collector::spawn_collector;
-use aya::maps::{PerCpuArray, PerCpuHashMap, PerCpuValues, StackTraceMap};
-use aya::programs::UProbe;
-use aya::util::nr_cpus;
-use aya::{include_bytes_aligned, Ebpf};
-
-use aya_log::EbpfLogger;
+use anyhow::Context;
 use bytesize::ByteSize;
 use clap::Parser;
-use jeprofl_common::{
-    Histogram, HistogramKey, COUNT_INDEX, FUNCTION_INFO_INDEX, MAX_ALLOC_INDEX, MIN_ALLOC_INDEX,
-    SAMPLE_EVERY_INDEX,
+use itertools::Itertools;
+use jeprofl::collector::{
+    CollectorConfig, EventProcessor, FlameOptions, ReportOptions, ThreadFilter, WindowOutput,
+};
+use jeprofl::debuginfod::Debuginfod;
+use jeprofl::resolver::{self, ResolverOptions};
+use jeprofl::symbol_cache::SymbolCache;
+use jeprofl::{
+    diff, merge, metrics, process, raw, tui, watch, CsvFormat, GroupBy, JemallocAllocFunctions,
+    MergeBy, OrderBy, Profiler, ProfilerOptions, SkipMode, WeightMetric,
 };
-use log::{debug, info, warn};
+use jeprofl_common::BucketScheme;
+use log::info;
 use minus::{ExitStrategy, Pager};
-use std::fmt::Display;
-use std::num::NonZeroU32;
+use regex::Regex;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::signal;
-
-mod collector;
-mod resolver;
+use tokio::sync::oneshot;
 
 #[derive(Debug, Parser)]
+#[clap(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Opt {
-    #[clap(short, long)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(short, long, conflicts_with = "process_name")]
     pid: Option<i32>,
 
+    /// Host pid of a process running in a container. `--program` is then a path
+    /// inside the container, it is opened through `/proc/<pid>/root`
+    #[clap(long, value_name = "PID", conflicts_with_all = ["pid", "process_name"])]
+    container_pid: Option<i32>,
+
+    /// Attach to the processes which `comm` or executable file name is NAME,
+    /// instead of passing `--pid`
+    #[clap(long, value_name = "NAME")]
+    process_name: Option<String>,
+
+    /// Attach to every process matching `--process-name`, otherwise more than
+    /// one match is an error
+    #[clap(long, requires = "process_name")]
+    all: bool,
+
+    /// Attach to every process of the cgroup and the cgroups below it, e.g.
+    /// `/system.slice/server.service` or its directory in `/sys/fs/cgroup`
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["pid", "container_pid", "process_name"])]
+    cgroup: Option<PathBuf>,
+
+    /// Also trace the processes the targets fork while profiling, e.g. the
+    /// workers of a pre-fork server. They are summed into their parent, with a
+    /// table of every child first
+    #[clap(long)]
+    include_children: bool,
+
+    /// Binary or library to attach to. Without it and `--library` it is the file
+    /// mapped by the traced process which defines the first `--function`
+    #[clap(
+        long,
+        conflicts_with = "library",
+        required_unless_present_any = ["library", "pid", "container_pid", "process_name", "cgroup"]
+    )]
+    program: Option<PathBuf>,
+
+    /// Attach to this library mapped by the traced process instead of a
+    /// `--program` path, e.g. `libjemalloc.so.2`. Its path is read from
+    /// `/proc/<pid>/maps`, so it is found in containers too
+    #[clap(long, value_name = "SONAME")]
+    library: Option<String>,
+
+    /// Allocation functions to probe, comma separated, e.g. `malloc,mallocx,realloc`
+    #[clap(short, long, default_value = "malloc", value_delimiter = ',')]
+    function: Vec<JemallocAllocFunctions>,
+
+    /// Don't resolve inlined functions, every frame shows only the function the
+    /// code was inlined into
+    #[clap(long)]
+    no_inline: bool,
+
+    /// Don't read or write the symbols cached between runs in
+    /// `$XDG_CACHE_HOME/jeprofl/symbols`
+    #[clap(long)]
+    no_symbol_cache: bool,
+
+    /// Show the source file and line of frames in the pager and the CSV. Reads the
+    /// debug info, which is also needed for inlined functions and `--include-file`
     #[clap(long)]
-    program: PathBuf,
+    source_lines: bool,
+
+    /// Prefix of the allocation functions in the program, e.g. `_rjem_` of
+    /// tikv-jemallocator. Without it the plain names, `_rjem_` and `je_` are tried
+    #[clap(long)]
+    symbol_prefix: Option<String>,
+
+    /// Attach to the traced function at this hex file offset of the program
+    /// instead of by symbol, for binaries without one. Needs a single `--function`
+    #[clap(long, value_parser = parse_hex)]
+    offset: Option<u64>,
+
+    /// Also attach the traced function's probe at this hex file offset of the
+    /// program, e.g. a call site of an inlined allocator wrapper. Offsets are
+    /// relative to the file, as `jeprofl symbols` prints them, not runtime
+    /// addresses. Can be repeated, needs a single `--function`
+    #[clap(long, value_name = "HEX", value_parser = parse_hex)]
+    attach_offset: Vec<u64>,
+
+    /// Read the allocation size from this argument, 0 to 3, instead of the one
+    /// of the function's signature, for custom allocators attached through
+    /// `--offset` or `--symbol-prefix`. Needs a single `--function`
+    #[clap(long, value_name = "INDEX", value_parser = clap::value_parser!(u64).range(0..4))]
+    size_arg_index: Option<u64>,
+
+    /// Fetch the debug info of stripped binaries by build id from debuginfod
+    /// servers, `DEBUGINFOD_URLS` unless a url is given. Needs `debuginfod-find`,
+    /// downloads are cached in `~/.cache/debuginfod_client`
+    #[clap(long, value_name = "URL", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+    debuginfod: Option<String>,
 
-    #[clap(short, long, default_value = "malloc")]
-    function: JemallocAllocFunctions,
+    /// Symbolize only frames of binaries and libraries which path matches the
+    /// regex, e.g. `myapp`. Other frames show their module, like `[libc]`,
+    /// which saves symbolizing deep stacks through system libraries. Can be
+    /// repeated.
+    #[clap(long, value_name = "PATTERN")]
+    symbolize_only: Vec<Regex>,
+
+    /// Also print the total size and count allocated by every probed function
+    #[clap(long)]
+    per_function: bool,
+
+    /// Print how the allocated bytes of every stack split across cpus, and NUMA
+    /// nodes if there are several
+    #[clap(long)]
+    by_cpu: bool,
 
     #[clap(short, long, default_value_t = OrderBy::Count)]
     order_by: OrderBy,
 
-    /// Max alloc size to track
+    /// Report stacks with the same 'stack' id separately, or sum stacks which
+    /// resolve to the same 'symbols', e.g. if stack ids are unstable
+    #[clap(long, default_value_t = MergeBy::Stack)]
+    merge_by: MergeBy,
+
+    /// Stop profiling after this many seconds instead of waiting for Ctrl-C.
+    /// Counted after the warmup
+    #[clap(long)]
+    duration: Option<u64>,
+
+    /// Attach right away but discard the allocations of the first this many
+    /// seconds, e.g. of the startup of a server
+    #[clap(long, value_name = "SECONDS", default_value_t = 0)]
+    warmup: u64,
+
+    /// Also write a report of every window of this many seconds to `--interval-dir`,
+    /// to see how the allocations change across the phases of a workload
+    #[clap(long, value_name = "SECONDS", requires = "interval_dir")]
+    interval: Option<u64>,
+
+    /// Directory of the `--interval` reports, `window-0001.txt` and so on
+    #[clap(long, value_name = "DIR", requires = "interval")]
+    interval_dir: Option<PathBuf>,
+
+    /// Whether stacks of different threads of a process are kept apart
+    #[clap(long, default_value_t = GroupBy::Process)]
+    group_by: GroupBy,
+
+    /// Drop allocations of threads with this name, e.g. of background threads
+    /// drowning out the application. Stacks are then kept apart per thread.
+    /// Can be repeated.
+    #[clap(long, value_name = "NAME")]
+    exclude_thread: Vec<String>,
+
+    /// Keep only allocations of threads with this name, the inverse of
+    /// `--exclude-thread`. Can be repeated.
+    #[clap(long, value_name = "NAME")]
+    only_thread: Vec<String>,
+
+    /// Max alloc size to track, inclusive
     #[clap(short, long, default_value_t = u64::MAX)]
     max_alloc_size: u64,
-    /// Min allocation size to track
-    #[clap(short, long)]
+    /// Min allocation size to track, inclusive
+    #[clap(long)]
     #[clap(default_value_t = 0)]
     min_alloc_size: u64,
 
@@ -53,203 +195,1073 @@ struct Opt {
     #[clap(default_value_t = NonZeroU32::new(1).unwrap())]
     sample_every: NonZeroU32,
 
-    /// skip allocations with total alocated < `skip_size` bytes
-    #[clap(short, long, default_value_t = ByteSize(1))]
+    /// Size-biased sampling: allocations of at least this size are always recorded,
+    /// only smaller ones are sampled with `--sample-every` and weighted by it, so
+    /// rare big allocations aren't missed
+    #[clap(long, conflicts_with = "raw_samples")]
+    sample_above: Option<ByteSize>,
+
+    /// Byte based sampling: record an allocation about every SIZE allocated
+    /// bytes, weighted by how many allocations of its size SIZE bytes are, like
+    /// jemalloc's `lg_prof_sample`. Replaces `--sample-every`
+    #[clap(long, value_name = "SIZE", conflicts_with_all = ["sample_every", "sample_above", "raw_samples"])]
+    sample_every_bytes: Option<ByteSize>,
+
+    /// How allocation sizes are bucketed: `log2`, `log2-subdivided` (4 buckets per
+    /// power of two), `jemalloc` (one bucket per jemalloc size class) or
+    /// `linear:<WIDTH>` (WIDTH bytes wide buckets, the last one holds everything
+    /// above it)
+    #[clap(long, default_value_t = BucketScheme::Log2)]
+    buckets: BucketScheme,
+
+    /// Print raw sampled numbers instead of scaling them by `sample_every`
+    #[clap(long)]
+    raw_samples: bool,
+
+    /// Skips stack traces with total allocated < `skip_size` bytes, see `--skip-mode`.
+    /// Nothing is skipped by default
+    #[clap(long, default_value_t = ByteSize(0))]
     skip_size: ByteSize,
 
-    /// Skips stack traces with total count < `skip_count`
-    #[clap(long, default_value_t = 1000)]
+    /// Skips stack traces with total count < `skip_count`, see `--skip-mode`
+    #[clap(long, default_value_t = 0)]
     skip_count: u64,
 
+    /// Skip stacks below any of `--skip-size` and `--skip-count` ('any'), or only
+    /// those below both of them ('all')
+    #[clap(long, default_value_t = SkipMode::Any)]
+    skip_mode: SkipMode,
+
+    /// Skip stacks with fewer than 1000 allocations unless they allocated at
+    /// least 1 MiB, which keeps long captures small without hiding rare large
+    /// allocations
+    #[clap(long, conflicts_with_all = ["skip_size", "skip_count", "skip_mode"])]
+    auto_skip: bool,
+
     #[clap(long("csv"))]
     csv_path: Option<PathBuf>,
 
-    /// Writes a flamegraph to the path_by_size.svg and path_by_count.svg
+    /// 'text' puts the histogram table in one cell, 'wide' writes a
+    /// `bucket_<SIZE>B` column per bucket instead, for pandas or a spreadsheet
+    #[clap(long, value_name = "FORMAT", default_value_t = CsvFormat::Text, requires = "csv_path")]
+    csv_format: CsvFormat,
+
+    /// Writes flame graphs to PATH-by-count.svg and PATH-by-traffic.svg
     #[clap(long("flame"))]
     flame_graph: Option<PathBuf>,
-}
 
-#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
-enum OrderBy {
-    Count,
-    Traffic,
+    /// Root the flame graphs at the entry of the program, `false` roots them at
+    /// the allocation function with its callers above
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    flame_reverse: bool,
+
+    /// Color palette of the flame graphs, e.g. `hot`, `mem`, `rust` or `perl`
+    #[clap(long, default_value = "hot")]
+    flame_palette: inferno::flamegraph::Palette,
+
+    /// Width of the flame graphs in pixels
+    #[clap(long, value_name = "PX")]
+    flame_width: Option<usize>,
+
+    #[clap(long)]
+    flame_title: Option<String>,
+
+    /// Write only the flame graph weighted by 'count', 'bytes', 'live' or
+    /// 'peak' live bytes, to the `--flame` path itself, independently of `--order-by`
+    #[clap(long, value_name = "WEIGHT")]
+    flame_weight: Option<WeightMetric>,
+
+    /// Write the folded stacks to the path, `root;...;leaf value` weighted like
+    /// `--flame-weight` (default: traffic), for `flamegraph.pl`, speedscope or
+    /// `jeprofl flame-diff`
+    #[clap(long, alias = "folded-output", value_name = "PATH")]
+    folded: Option<PathBuf>,
+
+    /// Writes a gzipped pprof profile, viewable with `go tool pprof -http`
+    #[clap(long)]
+    pprof: Option<PathBuf>,
+
+    /// Writes every stack with its histogram buckets and frames as a json array
+    #[clap(long)]
+    json: Option<PathBuf>,
+
+    /// Probe `free`, `sdallocx` and `dallocx` to report currently live memory
+    /// of every stack next to the total allocated one
+    #[clap(long)]
+    track_frees: bool,
+
+    /// Also capture the stack of `free` calls and report where memory of
+    /// every allocation site is freed. Uses considerably more map space.
+    /// Implies `--track-frees`.
+    #[clap(long)]
+    track_free_sites: bool,
+
+    /// Max number of not yet freed pointers tracked with `--track-frees`.
+    /// Allocations beyond it are not accounted as live.
+    #[clap(long, default_value_t = 1024 * 1024)]
+    max_tracked_pointers: u32,
+
+    /// Also time every sampled allocation call and report the latency of
+    /// every stack, the slowest first, after the stacks
+    #[clap(long)]
+    profile_duration_histogram: bool,
+
+    /// Max number of stacks kept in memory. Beyond it the smallest by
+    /// `--order-by` are evicted and their later allocations ignored, the
+    /// profile becomes approximate.
+    #[clap(long, value_name = "N")]
+    max_stacks: Option<NonZeroUsize>,
+
+    /// Also capture kernel stacks of allocations, printed beneath the user frames.
+    /// Allocations with the same user stack but different kernel ones are kept apart.
+    #[clap(long)]
+    kernel_stacks: bool,
+
+    /// Keep only stacks with a frame from a source file which path contains
+    /// the substring, e.g. `src/parser/`. Can be repeated.
+    #[clap(long)]
+    include_file: Vec<String>,
+
+    /// Keep only stacks with a frame which symbol matches the regex, e.g.
+    /// `my_module::`. Can be repeated.
+    #[clap(long, value_name = "PATTERN")]
+    filter: Vec<Regex>,
+
+    /// Drop stacks with a frame which symbol matches the regex. Can be repeated.
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<Regex>,
+
+    /// Drop stacks of which no frame could be symbolized, e.g. for missing
+    /// debug info
+    #[clap(long)]
+    drop_unsymbolized: bool,
+
+    /// Render only the N biggest stacks by `--order-by`, also in the CSV and the
+    /// flame graphs
+    #[clap(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Write only the N biggest stacks to the CSV, overrides `--top` for it
+    #[clap(long, value_name = "N")]
+    csv_top: Option<usize>,
+
+    /// Sort the CSV rows by 'count', 'bytes', 'live' or 'peak' live bytes
+    /// instead of `--order-by`, also picks the `--csv-top` ones
+    #[clap(long, value_name = "WEIGHT")]
+    csv_weight: Option<WeightMetric>,
+
+    /// Render the smallest stacks first instead of the biggest ones
+    #[clap(long)]
+    ascending: bool,
+
+    /// Render the deepest stacks first, stacks of the same depth by
+    /// `--order-by`
+    #[clap(long)]
+    order_by_depth: bool,
+
+    /// Keep only the N frames from the allocation function on and sum the
+    /// stacks which agree in them, in the text, CSV and flame graphs alike
+    #[clap(long, value_name = "N")]
+    max_depth: Option<NonZeroUsize>,
+
+    /// Drop stacks with fewer than N resolved frames, e.g. the single frame of
+    /// the allocator `get_stackid` returns without frame pointers
+    #[clap(long, value_name = "N")]
+    min_frames: Option<usize>,
+
+    /// Print only the aggregate numbers to stdout, without per stack sections
+    /// and the pager
+    #[clap(long)]
+    summary_only: bool,
+
+    /// One line per stack with its total, count, median size and top frame,
+    /// for an overview before expanding the stacks
+    #[clap(long, conflicts_with = "summary_only")]
+    summary: bool,
+
+    /// Sum the stacks by the function at DEPTH, 1 is the caller of the
+    /// allocation function, and print one line per function instead of the
+    /// stacks
+    #[clap(long, value_name = "DEPTH", num_args = 0..=1, default_missing_value = "1", conflicts_with_all = ["summary_only", "summary"])]
+    by_function: Option<usize>,
+
+    /// Color histogram bars by allocation size: `auto` colors only if stdout is a
+    /// terminal and `NO_COLOR` isn't set, `always` or `never`
+    #[clap(long, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Browse the stacks interactively while profiling instead of the pager.
+    /// Requested files are still written on exit
+    #[clap(long, conflicts_with = "summary_only")]
+    tui: bool,
+
+    /// Reprint the biggest stacks every SECONDS while profiling, like `top`,
+    /// instead of the pager. `--top` caps the rows, Ctrl-C keeps the last view
+    #[clap(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2", conflicts_with_all = ["summary_only", "tui"])]
+    watch: Option<NonZeroU64>,
+
+    /// Write the text report to the file instead of the pager. Without it the
+    /// report goes to stdout when stdout isn't a terminal
+    #[clap(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Print the text report straight to stdout instead of the pager, also
+    /// when stdout is a terminal
+    #[clap(long, conflicts_with = "tui")]
+    no_pager: bool,
+
+    /// Send every allocation to userspace through a ring buffer instead of
+    /// aggregating histograms in a map polled every `--poll-interval`
+    #[clap(long)]
+    ring_buffer: bool,
+
+    /// Write the allocated bytes and allocations of every second since the
+    /// start as CSV to the path, to correlate spikes with other events
+    #[clap(long, value_name = "PATH", requires = "ring_buffer")]
+    timeline: Option<PathBuf>,
+
+    /// Unwind user stacks with the DWARF CFI of the binaries instead of frame
+    /// pointers, for binaries built without them. Every allocation sends 16 KiB
+    /// of its stack through the ring buffer, so it implies `--ring-buffer` and
+    /// costs considerably more. Reallocation stacks start at their caller. x86_64 only
+    #[clap(long, conflicts_with_all = ["track_frees", "track_free_sites", "profile_duration_histogram"])]
+    dwarf_unwind: bool,
+
+    /// Size of the `--ring-buffer`, a power of two of at least a page
+    #[clap(long, default_value_t = ByteSize::mib(16))]
+    ring_buffer_size: ByteSize,
+
+    /// Serve aggregate metrics in the Prometheus text format on this address,
+    /// e.g. `127.0.0.1:9464`
+    #[clap(long, value_name = "IP:PORT")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Number of the biggest stacks with their own metrics
+    #[clap(long, value_name = "N", default_value_t = 50)]
+    metrics_top: usize,
+
+    /// Also save the unsymbolized stacks with the memory mappings of the traced
+    /// processes, to be symbolized later with `jeprofl symbolize`
+    #[clap(long, value_name = "PATH")]
+    raw_output: Option<PathBuf>,
+
+    /// How often the maps are read, in milliseconds. Shorter intervals catch
+    /// short-lived stacks before the full map evicts them, longer ones cost less
+    #[clap(long, value_name = "MS", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    poll_interval: u64,
+
+    /// How often stacks below `--skip-size` and `--skip-count` and stacks no
+    /// allocation refers to anymore are removed from the maps, in seconds
+    #[clap(long, value_name = "SECONDS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    cleanup_interval: u64,
+
+    /// Keep what the maps already hold instead of clearing them before
+    /// attaching, to accumulate across runs against pinned maps
+    #[clap(long)]
+    no_clear: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum JemallocAllocFunctions {
-    Malloc,
-    Calloc,
-    Realloc,
-    Mallocx,
-    Rallocx,
-    Xallocx,
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Compare two profiles saved with `--json`, stacks are matched by their symbols
+    Diff { before: PathBuf, after: PathBuf },
+    /// Combine profiles saved with `--json`, e.g. of several hosts, into one.
+    /// Stacks are matched by their function and symbols
+    Merge {
+        #[clap(required = true, num_args = 2..)]
+        profiles: Vec<PathBuf>,
+        #[clap(long, short)]
+        output: PathBuf,
+    },
+    /// Render a differential flame graph of two `--folded` captures, red
+    /// frames allocate more in `after`, blue ones less
+    FlameDiff {
+        before: PathBuf,
+        after: PathBuf,
+        #[clap(long, short, default_value = "flame-diff.svg")]
+        output: PathBuf,
+        /// Scale `before` to the total of `after`, e.g. for captures of
+        /// different lengths
+        #[clap(long)]
+        normalize: bool,
+    },
+    /// Symbolize a profile saved with `--raw-output` against local copies of
+    /// the traced binaries and print the report
+    Symbolize {
+        raw: PathBuf,
+        /// Binary or shared library the stacks go through, matched by build id
+        /// or file name. Can be repeated.
+        #[clap(long, required = true)]
+        binary: Vec<PathBuf>,
+        #[clap(short, long, default_value_t = OrderBy::Count)]
+        order_by: OrderBy,
+        /// Writes every stack with its histogram buckets and frames as a json array
+        #[clap(long)]
+        json: Option<PathBuf>,
+        /// Render only the N biggest stacks by `--order-by`
+        #[clap(long, value_name = "N")]
+        top: Option<usize>,
+    },
+    /// List the allocation functions the binary defines with their file
+    /// offsets, to check whether it can be profiled
+    Symbols { binary: PathBuf },
+    /// Profile a child allocating known sizes through the function and check
+    /// that every allocation is captured
+    SelfTest {
+        #[clap(short, long, default_value = "malloc")]
+        function: JemallocAllocFunctions,
+        /// Allocations of every size
+        #[clap(long, default_value_t = 1000)]
+        count: u64,
+    },
+    /// The allocating child of `self-test`
+    #[clap(hide = true)]
+    SelfTestChild {
+        #[clap(long)]
+        function: JemallocAllocFunctions,
+        #[clap(long)]
+        count: u64,
+    },
 }
 
-impl FromStr for JemallocAllocFunctions {
-    type Err = anyhow::Error;
+/// `--auto-skip` thresholds, applied with [`SkipMode::All`]
+const AUTO_SKIP_SIZE: u64 = 1024 * 1024;
+const AUTO_SKIP_COUNT: u64 = 1000;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "malloc" => Ok(Self::Malloc),
-            "calloc" => Ok(Self::Calloc),
-            "realloc" => Ok(Self::Realloc),
-            "mallocx" => Ok(Self::Mallocx),
-            "rallocx" => Ok(Self::Rallocx),
-            "xallocx" => Ok(Self::Xallocx),
-            _ => Err(anyhow::anyhow!("Invalid function name {}", s)),
+impl Opt {
+    /// Size and count thresholds of skipped stacks and how they combine
+    fn skip_thresholds(&self) -> (u64, u64, SkipMode) {
+        if self.auto_skip {
+            (AUTO_SKIP_SIZE, AUTO_SKIP_COUNT, SkipMode::All)
+        } else {
+            (self.skip_size.0, self.skip_count, self.skip_mode)
         }
     }
-}
 
-impl Display for JemallocAllocFunctions {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Malloc => write!(f, "malloc"),
-            Self::Calloc => write!(f, "calloc"),
-            Self::Realloc => write!(f, "realloc"),
-            Self::Mallocx => write!(f, "mallocx"),
-            Self::Rallocx => write!(f, "rallocx"),
-            Self::Xallocx => write!(f, "xallocx"),
+    /// Pids to attach the probes to, `None` attaches to every process running the program
+    fn target_pids(&self) -> anyhow::Result<Vec<Option<i32>>> {
+        if let Some(cgroup) = &self.cgroup {
+            let pids = process::find_pids_in_cgroup(cgroup)?;
+            if pids.is_empty() {
+                anyhow::bail!("The cgroup {} has no processes", cgroup.display());
+            }
+            info!(
+                "Attaching to processes of the cgroup {}: {pids:?}",
+                cgroup.display()
+            );
+            return Ok(pids.into_iter().map(Some).collect());
+        }
+        let Some(name) = &self.process_name else {
+            return Ok(vec![self.container_pid.or(self.pid)]);
+        };
+        let pids = process::find_pids_by_name(name)?;
+        match pids.len() {
+            0 => anyhow::bail!("No process named {name} found"),
+            1 => {}
+            _ if self.all => {}
+            _ => anyhow::bail!(
+                "{} processes named {name} found: {pids:?}, pass --all to attach to all of them",
+                pids.len()
+            ),
         }
+        info!("Attaching to processes named {name}: {pids:?}");
+        Ok(pids.into_iter().map(Some).collect())
     }
 }
 
-impl JemallocAllocFunctions {
-    pub fn allocation_arg_index(&self) -> u64 {
+#[derive(derive_more::Display, derive_more::FromStr, Debug, Copy, Clone)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
         match self {
-            Self::Malloc => 0,
-            Self::Calloc => 1,
-            Self::Realloc => 1,
-            Self::Mallocx => 1,
-            Self::Rallocx => 1,
-            Self::Xallocx => 1,
+            Self::Always => true,
+            Self::Never => false,
+            // https://no-color.org: set and not empty disables colors
+            Self::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let opt = Opt::parse();
+    // without a terminal or the pager there is no cursor to restore
+    let interactive = std::io::stdout().is_terminal() && !opt.no_pager;
     scopeguard::defer! {
-          crossterm::execute!(std::io::stdout(),crossterm::cursor::Show).ok();
+        if interactive {
+            crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).ok();
+        }
     };
-    let opt = Opt::parse();
 
     env_logger::init();
+    match &opt.command {
+        Some(Command::Diff { before, after }) => {
+            let mut report = String::new();
+            diff::print_diff(&diff::load(before)?, &diff::load(after)?, &mut report)?;
+            print!("{report}");
+            return Ok(());
+        }
+        Some(Command::Merge { profiles, output }) => {
+            let loaded = profiles
+                .iter()
+                .map(|path| merge::load(path))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let merged = merge::merge(&loaded)?;
+            merge::write(output, &merged)?;
+            info!(
+                "Merged {} stacks of {} profiles into {} stacks, written to {output:?}",
+                loaded.iter().map(Vec::len).sum::<usize>(),
+                loaded.len(),
+                merged.len()
+            );
+            return Ok(());
+        }
+        Some(Command::FlameDiff {
+            before,
+            after,
+            output,
+            normalize,
+        }) => {
+            let open = |path: &PathBuf| {
+                std::fs::File::open(path)
+                    .map(std::io::BufReader::new)
+                    .with_context(|| format!("Failed to open {path:?}"))
+            };
+            let file = std::io::BufWriter::new(std::fs::File::create(output)?);
+            diff::write_flame_diff(open(before)?, open(after)?, *normalize, file)?;
+            info!("Differential flame graph written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Symbolize {
+            raw,
+            binary,
+            order_by,
+            json,
+            top,
+        }) => {
+            let symbolizer = raw::OfflineSymbolizer::new(binary)?;
+            let processor = EventProcessor::from_raw(raw::RawProfile::load(raw)?, &symbolizer)?;
+            let mut report = String::new();
+            processor.print_histogram(
+                &mut report,
+                ReportOptions {
+                    order_by: *order_by,
+                    json_path: json.clone(),
+                    top: *top,
+                    ..ReportOptions::default()
+                },
+            )?;
+            print!("{report}");
+            return Ok(());
+        }
+        Some(Command::Symbols { binary }) => {
+            let symbols = resolver::allocator_symbols(binary)?;
+            if symbols.is_empty() {
+                anyhow::bail!(
+                    "{} defines no allocation function jeprofl traces, they may come from a shared library it links, see `ldd`",
+                    binary.display()
+                );
+            }
+            println!(
+                "{:<16} {:<24} {:>12} {:>12}",
+                "Function", "Symbol", "Address", "File offset"
+            );
+            for symbol in symbols {
+                let file_offset = symbol
+                    .file_offset
+                    .map_or_else(|| "-".to_string(), |offset| format!("{offset:#x}"));
+                println!(
+                    "{:<16} {:<24} {:>12} {file_offset:>12}",
+                    symbol.function.to_string(),
+                    symbol.name,
+                    format!("{:#x}", symbol.address)
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::SelfTest { function, count }) => {
+            return jeprofl::self_test::run(*function, *count);
+        }
+        Some(Command::SelfTestChild { function, count }) => {
+            return jeprofl::self_test::run_child(*function, *count);
+        }
+        None => {}
+    }
+    let orders_by_live = matches!(opt.order_by, OrderBy::Live)
+        || [opt.flame_weight, opt.csv_weight]
+            .into_iter()
+            .flatten()
+            .any(WeightMetric::needs_frees);
+    if orders_by_live && !opt.track_frees && !opt.track_free_sites {
+        anyhow::bail!("Live bytes are known only with --track-frees");
+    }
+    let target_pids = opt.target_pids()?;
+    let target = match (&opt.program, &opt.library) {
+        (Some(program), _) => match opt.container_pid {
+            Some(pid) => process::container_path(pid, program)?,
+            None => program.clone(),
+        },
+        (None, Some(soname)) => {
+            let Some(Some(pid)) = target_pids.first() else {
+                anyhow::bail!(
+                    "--library needs --pid, --container-pid, --process-name or --cgroup to find it"
+                );
+            };
+            let path = process::find_library(*pid, soname)?;
+            info!("Found {soname} at {}", path.display());
+            path
+        }
+        (None, None) => {
+            let Some(Some(pid)) = target_pids.first() else {
+                anyhow::bail!("--program or --library is required to trace every process");
+            };
+            let function = opt.function[0];
+            let path = process::find_module_defining(*pid, function, opt.symbol_prefix.as_deref())?;
+            info!("Attaching to {function} of {}", path.display());
+            path
+        }
+    };
+    let target = target.as_path();
 
-    // Bump the memlock rlimit. This is needed for older kernels that don't use the
-    // new memcg based accounting, see https://lwn.net/Articles/837122/
-    let rlim = libc::rlimit {
-        rlim_cur: libc::RLIM_INFINITY,
-        rlim_max: libc::RLIM_INFINITY,
+    let functions: Vec<_> = opt.function.iter().copied().unique().collect();
+    for pid in target_pids.iter().flatten() {
+        if process::uses_jemalloc(*pid, target)? {
+            continue;
+        }
+        if let Some(function) = functions.iter().find(|f| f.is_jemalloc_only()) {
+            anyhow::bail!(
+                "Process {pid} doesn't use jemalloc, it never calls the jemalloc only {function}"
+            );
+        }
+        log::warn!(
+            "Process {pid} doesn't use jemalloc: no jemalloc library is mapped and {} doesn't define mallocx. The traced {} are probably the C library's",
+            target.display(),
+            functions.iter().join(", ")
+        );
+    }
+    let mut report_options = ReportOptions {
+        order_by: opt.order_by,
+        csv_path: opt.csv_path.clone(),
+        csv_format: opt.csv_format,
+        flame_graph: opt.flame_graph.clone(),
+        flame: FlameOptions {
+            reverse: opt.flame_reverse,
+            palette: opt.flame_palette,
+            width: opt.flame_width,
+            title: opt.flame_title.clone(),
+            weight: opt.flame_weight,
+        },
+        folded_path: opt.folded.clone(),
+        pprof: opt.pprof.clone(),
+        json_path: opt.json.clone(),
+        include_files: opt.include_file.clone(),
+        filters: opt.filter.clone(),
+        excludes: opt.exclude.clone(),
+        summary_only: opt.summary_only,
+        compact: opt.summary,
+        // `auto` decides by stdout, the file is plain text unless forced
+        color: match opt.output {
+            Some(_) => matches!(opt.color, ColorChoice::Always),
+            None => opt.color.enabled(),
+        },
+        source_lines: opt.source_lines,
+        top: opt.top,
+        csv_top: opt.csv_top,
+        csv_weight: opt.csv_weight,
+        ascending: opt.ascending,
+        order_by_depth: opt.order_by_depth,
+        max_depth: opt.max_depth.map(NonZeroUsize::get),
+        drop_unsymbolized: opt.drop_unsymbolized,
+        per_function: opt.per_function,
+        by_function: opt.by_function,
+        by_cpu: opt.by_cpu,
+        multiple_functions: functions.len() > 1,
+        // set once the capture is over
+        capture_duration: Duration::ZERO,
+        merge_by: opt.merge_by,
     };
-    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
-    if ret != 0 {
-        debug!("remove limit on locked memory failed, ret is: {}", ret);
-    }
-
-    // This will include your eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
-    #[cfg(debug_assertions)]
-    let mut bpf = Ebpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/jeprofl"
-    ))?;
-    #[cfg(not(debug_assertions))]
-    let mut bpf = Ebpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/jeprofl"
-    ))?;
-    if let Err(e) = EbpfLogger::init(&mut bpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
-    }
-
-    {
-        let config_map = bpf.map_mut("CONFIG").expect("CONFIG not found");
-        let mut config_map = PerCpuArray::try_from(config_map)?;
-        let num_cpus = nr_cpus().unwrap();
-        config_map.set(
-            MIN_ALLOC_INDEX,
-            PerCpuValues::try_from(vec![opt.min_alloc_size; num_cpus])?,
-            0,
-        )?;
-        config_map.set(
-            MAX_ALLOC_INDEX,
-            PerCpuValues::try_from(vec![opt.max_alloc_size; num_cpus])?,
-            0,
-        )?;
-        config_map.set(COUNT_INDEX, PerCpuValues::try_from(vec![0; num_cpus])?, 0)?;
-        config_map.set(
-            SAMPLE_EVERY_INDEX,
-            PerCpuValues::try_from(vec![opt.sample_every.get() as u64; num_cpus])?,
-            0,
-        )?;
-        config_map.set(
-            FUNCTION_INFO_INDEX,
-            PerCpuValues::try_from(vec![opt.function.allocation_arg_index(); num_cpus])?,
-            0,
-        )?;
-    }
-
-    let program: &mut UProbe = bpf.program_mut("malloc").unwrap().try_into()?;
-    program.load()?;
-
-    let function = opt.function.to_string();
-    log::info!(
-        "Attaching to function: {}:{}",
-        opt.program.display(),
-        function
-    );
-
-    program.attach(Some(function.as_str()), 0, &opt.program, opt.pid)?;
-
-    let stack_traces = StackTraceMap::try_from(bpf.take_map("STACKTRACES").unwrap())?;
-
-    let start = std::time::Instant::now();
-    let per_cpu_map: PerCpuHashMap<_, HistogramKey, Histogram> =
-        PerCpuHashMap::try_from(bpf.take_map("HISTOGRAMS").unwrap())?;
-    log::info!(
-        "Opened per_cpu_map, took {:?}",
-        start.elapsed().as_secs_f64()
-    );
-    log::info!(
-        "Will not save stack traces which has total alocation size < {} or count < {}",
-        opt.skip_count,
-        opt.skip_size
-    );
-
-    let canceled = Arc::new(AtomicBool::new(false));
-    let handle = spawn_collector(
-        per_cpu_map,
-        canceled.clone(),
-        stack_traces,
-        opt.skip_size.0,
-        opt.skip_count,
-    );
-
-    info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
-    info!("Exiting...");
-    canceled.store(true, std::sync::atomic::Ordering::Release);
-    // to reduce the probability of installing 2 signal handlers
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    // Initialize the pager
-    let mut pager = Pager::new();
-    pager.set_exit_strategy(ExitStrategy::PagerQuit)?;
-    // Run the pager in a separate thread
-    let t = {
-        let pager = pager.clone();
-        std::thread::spawn(move || minus::dynamic_paging(pager))
+    let windows = match (opt.interval, &opt.interval_dir) {
+        (Some(seconds), Some(dir)) => {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+            Some(WindowOutput {
+                interval: Duration::from_secs(seconds.max(1)),
+                dir: dir.clone(),
+                // the windows get only the text report, the files are written once on exit
+                report: ReportOptions {
+                    csv_path: None,
+                    flame_graph: None,
+                    pprof: None,
+                    json_path: None,
+                    color: false,
+                    ..report_options.clone()
+                },
+            })
+        }
+        _ => None,
     };
 
-    let handle = handle.join().expect("failed to join thread");
+    let published_metrics = match opt.metrics_addr {
+        Some(addr) => {
+            let published = metrics::Published::default();
+            metrics::spawn_server(addr, published.clone())?;
+            Some(published)
+        }
+        None => None,
+    };
+
+    let published_stacks = (opt.tui || opt.watch.is_some()).then(|| {
+        tui::Published::new(Mutex::new(Arc::new(tui::Snapshot {
+            stacks: Vec::new(),
+            bucket_scheme: opt.buckets,
+            note: None,
+        })))
+    });
 
-    // let mut str = String::new();
-    handle.print_histogram(opt.order_by, &mut pager, opt.csv_path, opt.flame_graph)?;
+    let (skip_size, skip_count, skip_mode) = opt.skip_thresholds();
+    if skip_size > 0 || skip_count > 0 {
+        log::info!(
+            "Will not save stack traces which have total allocation size < {} {} count < {}",
+            ByteSize(skip_size),
+            match skip_mode {
+                SkipMode::Any => "or",
+                SkipMode::All => "and",
+            },
+            skip_count
+        );
+    }
+    let profiler = Profiler::attach(
+        target,
+        &functions,
+        ProfilerOptions {
+            pids: target_pids.clone(),
+            include_children: opt.include_children,
+            min_alloc_size: opt.min_alloc_size,
+            max_alloc_size: opt.max_alloc_size,
+            sample_every: opt.sample_every,
+            sample_above: opt.sample_above.map(|size| size.0),
+            sample_every_bytes: opt.sample_every_bytes.map(|size| size.0),
+            raw_samples: opt.raw_samples,
+            group_by: opt.group_by,
+            track_frees: opt.track_frees,
+            track_free_sites: opt.track_free_sites,
+            max_tracked_pointers: opt.max_tracked_pointers,
+            track_latency: opt.profile_duration_histogram,
+            kernel_stacks: opt.kernel_stacks,
+            buckets: opt.buckets,
+            ring_buffer: opt.ring_buffer,
+            ring_buffer_size: opt.ring_buffer_size.0,
+            dwarf_unwind: opt.dwarf_unwind,
+            symbol_prefix: opt.symbol_prefix.clone(),
+            offset: opt.offset,
+            attach_offsets: opt.attach_offset.clone(),
+            size_arg_index: opt.size_arg_index,
+            no_clear: opt.no_clear,
+            collector: CollectorConfig {
+                skip_total_alloc_size_lower_than: skip_size,
+                skip_total_count_lower_than: skip_count,
+                skip_mode,
+                raw_capture: opt.raw_output.is_some(),
+                metrics: published_metrics,
+                metrics_top: opt.metrics_top,
+                resolver: ResolverOptions {
+                    // inlined functions are found through the debug info too
+                    code_info: opt.source_lines || !opt.no_inline || !opt.include_file.is_empty(),
+                    inlined_fns: !opt.no_inline,
+                    debuginfod: opt.debuginfod.as_deref().map(Debuginfod::new).transpose()?,
+                    symbolize_only: opt.symbolize_only.clone(),
+                    fallback_binary: Some(target.to_path_buf()),
+                    symbol_cache: if opt.no_symbol_cache {
+                        None
+                    } else {
+                        SymbolCache::default_dir()
+                    },
+                },
+                tui: published_stacks.clone(),
+                warmup: Duration::from_secs(opt.warmup),
+                windows,
+                timeline: opt.timeline.is_some(),
+                poll_interval: Duration::from_millis(opt.poll_interval),
+                clean_up_interval: Duration::from_secs(opt.cleanup_interval),
+                thread_filter: ThreadFilter {
+                    exclude: opt.exclude_thread.clone(),
+                    only: opt.only_thread.clone(),
+                },
+                max_stacks: opt.max_stacks.map(NonZeroUsize::get),
+                order_by: opt.order_by,
+                ..ProfilerOptions::default().collector
+            },
+        },
+    )?;
+    let canceled = profiler.canceled();
+
+    // quitting the ui stops the profiling, like Ctrl-C which it captures
+    let (ui_quit_tx, mut ui_quit_rx) = oneshot::channel();
+    let ui = published_stacks.map(|published| {
+        let canceled = canceled.clone();
+        let order_by = opt.order_by;
+        let (watch, top) = (opt.watch, opt.top);
+        std::thread::spawn(move || match watch {
+            Some(seconds) => {
+                let interval = Duration::from_secs(seconds.get());
+                watch::run(published, order_by, top, interval, &canceled)
+            }
+            None => {
+                let res = tui::run(published, order_by, &canceled);
+                ui_quit_tx.send(()).ok();
+                res
+            }
+        })
+    });
 
-    t.join().unwrap()?;
+    let capture_start = std::time::Instant::now();
+    if opt.warmup > 0 {
+        info!(
+            "Warming up, allocations of the first {}s are discarded",
+            opt.warmup
+        );
+    }
+    match opt.duration {
+        Some(seconds) => {
+            info!("Profiling for {seconds}s, Ctrl-C to stop earlier...");
+            tokio::select! {
+                res = signal::ctrl_c() => res?,
+                _ = tokio::time::sleep(Duration::from_secs(opt.warmup + seconds)) => {}
+                Ok(()) = &mut ui_quit_rx => {}
+            }
+        }
+        None => {
+            info!("Waiting for Ctrl-C...");
+            tokio::select! {
+                res = signal::ctrl_c() => res?,
+                Ok(()) = &mut ui_quit_rx => {}
+            }
+        }
+    }
+    info!("Exiting..., Ctrl-C to exit without the report");
+    tokio::spawn(exit_on_ctrl_c());
+    // waits for the collector to drain the maps one last time, the ui exits meanwhile
+    let mut processor = profiler.stop()?;
+    if let Some(min_frames) = opt.min_frames {
+        processor.drop_shallow_stacks(min_frames);
+    }
+    if let Some(ui) = ui {
+        ui.join().expect("failed to join ui thread")?;
+    }
+    // the allocations of the warmup aren't part of the profile
+    report_options.capture_duration = capture_start
+        .elapsed()
+        .saturating_sub(Duration::from_secs(opt.warmup));
+    let exit_summary =
+        processor.exit_summary(&target_pids, &functions, report_options.capture_duration);
 
+    let save_outputs = |processor: &EventProcessor| -> anyhow::Result<()> {
+        if let Some(path) = &opt.raw_output {
+            processor.to_raw().write(path)?;
+            info!("raw profile written to {:?}", path);
+        }
+        if let Some(path) = &opt.timeline {
+            processor.write_timeline(path)?;
+            info!("timeline written to {:?}", path);
+        }
+        Ok(())
+    };
+
+    save_outputs(&processor)?;
+    if (opt.tui || opt.watch.is_some()) && opt.output.is_none() {
+        // the stacks were browsed live, only the requested files are written
+        processor.print_histogram(&mut String::new(), report_options)?;
+    } else if opt.summary_only || opt.output.is_some() || !interactive {
+        let mut report = String::new();
+        processor.print_histogram(&mut report, report_options)?;
+        match &opt.output {
+            Some(path) => {
+                std::fs::write(path, report)
+                    .with_context(|| format!("Failed to write the report to {path:?}"))?;
+                info!("Report written to {path:?}");
+            }
+            None => print!("{report}"),
+        }
+    } else {
+        // Initialize the pager
+        let mut pager = Pager::new();
+        pager.set_exit_strategy(ExitStrategy::PagerQuit)?;
+        // Run the pager in a separate thread
+        let t = {
+            let pager = pager.clone();
+            std::thread::spawn(move || minus::dynamic_paging(pager))
+        };
+
+        processor.print_histogram(&mut pager, report_options)?;
+
+        t.join().unwrap()?;
+    }
+
+    // after the pager, so it stays on the terminal
+    eprintln!("{exit_summary}");
     log::info!("Exited");
     Ok(())
 }
+
+/// Exits right away on Ctrl-C. Once the profiling stopped the handler stays
+/// installed, without this a slow final drain couldn't be interrupted
+async fn exit_on_ctrl_c() {
+    if signal::ctrl_c().await.is_ok() {
+        crossterm::execute!(std::io::stderr(), crossterm::cursor::Show).ok();
+        std::process::exit(130);
+    }
+}
+
+/// Parses a hex number with or without the `0x` prefix
+fn parse_hex(value: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use jeprofl_common::{is_size_tracked, Histogram};
+
+    use crate::{Command, Opt};
+    use jeprofl::{JemallocAllocFunctions, OrderBy, SkipMode, WeightMetric};
+
+    #[test]
+    fn size_filter_includes_boundaries() {
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--min-alloc-size",
+            "64",
+            "--max-alloc-size",
+            "1024",
+        ]);
+        let tracked = |size| is_size_tracked(size, opt.min_alloc_size, opt.max_alloc_size);
+        assert!(!tracked(63));
+        assert!(tracked(64));
+        assert!(tracked(1024));
+        assert!(!tracked(1025));
+    }
+
+    #[test]
+    fn diff_does_not_require_program() {
+        let opt = Opt::parse_from(["jeprofl", "diff", "before.json", "after.json"]);
+        assert!(matches!(opt.command, Some(Command::Diff { .. })));
+        assert!(Opt::try_parse_from(["jeprofl"]).is_err());
+        let opt = Opt::parse_from(["jeprofl", "merge", "a.json", "b.json", "-o", "all.json"]);
+        assert!(matches!(opt.command, Some(Command::Merge { .. })));
+        assert!(Opt::try_parse_from(["jeprofl", "merge", "a.json", "-o", "all.json"]).is_err());
+    }
+
+    #[test]
+    fn program_is_found_through_the_pid() {
+        let opt = Opt::parse_from(["jeprofl", "--pid", "42"]);
+        assert_eq!(opt.program, None);
+        assert!(Opt::try_parse_from(["jeprofl", "--process-name", "server"]).is_ok());
+        assert!(Opt::try_parse_from(["jeprofl", "--function", "mallocx"]).is_err());
+    }
+
+    #[test]
+    fn by_function_depth_defaults_to_the_caller() {
+        let parse = |args: &[&str]| {
+            Opt::parse_from(["jeprofl", "--program", "/bin/true"].iter().chain(args)).by_function
+        };
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&["--by-function"]), Some(1));
+        assert_eq!(parse(&["--by-function", "3"]), Some(3));
+        assert_eq!(parse(&["--by-function", "--top", "5"]), Some(1));
+    }
+
+    #[test]
+    fn self_test_does_not_require_program() {
+        let opt = Opt::parse_from(["jeprofl", "self-test", "-f", "calloc"]);
+        assert!(matches!(
+            opt.command,
+            Some(Command::SelfTest {
+                function: JemallocAllocFunctions::Calloc,
+                count: 1000
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_aligned_allocation_functions() {
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "-f",
+            "aligned_alloc,posix_memalign,memalign",
+        ]);
+        let args: Vec<_> = opt
+            .function
+            .iter()
+            .map(|f| (f.to_string(), f.allocation_arg_index(), f.return_program()))
+            .collect();
+        assert_eq!(
+            args,
+            [
+                ("aligned_alloc".to_string(), 1, "malloc_ret"),
+                ("posix_memalign".to_string(), 2, "posix_memalign_ret"),
+                ("memalign".to_string(), 1, "malloc_ret"),
+            ]
+        );
+        for function in opt.function {
+            assert_eq!(
+                JemallocAllocFunctions::from_id(function.id()),
+                Some(function)
+            );
+        }
+    }
+
+    #[test]
+    fn skip_mode_predicate() {
+        // 50 bytes in 2000 tiny allocations
+        let mut hist = Histogram::new();
+        for _ in 0..2000 {
+            hist.increment(1);
+        }
+        hist.total = 50;
+        assert!(SkipMode::Any.skips(&hist, 1024, 1000));
+        assert!(!SkipMode::All.skips(&hist, 1024, 1000));
+        assert!(SkipMode::All.skips(&hist, 1024, 4000));
+        assert!(!SkipMode::Any.skips(&hist, 50, 2000));
+        // zero thresholds keep everything
+        assert!(!SkipMode::Any.skips(&Histogram::new(), 0, 0));
+
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true"]);
+        assert!(matches!(opt.skip_mode, SkipMode::Any));
+        // a single large allocation of a short capture is kept by default
+        let (size, count, mode) = opt.skip_thresholds();
+        let mut huge = Histogram::new();
+        huge.increment(4 << 30);
+        assert!(!mode.skips(&huge, size, count));
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true", "--auto-skip"]);
+        let (size, count, mode) = opt.skip_thresholds();
+        assert!(!mode.skips(&huge, size, count));
+        assert!(!mode.skips(&hist, size, count));
+        let mut rare = Histogram::new();
+        rare.increment(16);
+        assert!(mode.skips(&rare, size, count));
+        assert!(Opt::try_parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--auto-skip",
+            "--skip-count",
+            "10"
+        ])
+        .is_err());
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true", "--skip-mode", "all"]);
+        assert!(matches!(opt.skip_mode, SkipMode::All));
+    }
+
+    #[test]
+    fn debuginfod_url_is_optional() {
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true"]);
+        assert_eq!(opt.debuginfod, None);
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true", "--debuginfod"]);
+        assert_eq!(opt.debuginfod.as_deref(), Some(""));
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--debuginfod=https://debuginfod.example.org",
+        ]);
+        assert_eq!(
+            opt.debuginfod.as_deref(),
+            Some("https://debuginfod.example.org")
+        );
+    }
+
+    #[test]
+    fn poll_intervals() {
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true"]);
+        assert_eq!((opt.poll_interval, opt.cleanup_interval), (1000, 60));
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--poll-interval",
+            "250",
+            "--cleanup-interval",
+            "10",
+        ]);
+        assert_eq!((opt.poll_interval, opt.cleanup_interval), (250, 10));
+        assert!(
+            Opt::try_parse_from(["jeprofl", "--program", "/bin/true", "--poll-interval", "0"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn offset_is_hex() {
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true", "--offset", "0x1a2b0"]);
+        assert_eq!(opt.offset, Some(0x1a2b0));
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true", "--offset", "ff"]);
+        assert_eq!(opt.offset, Some(0xff));
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--attach-offset",
+            "0x1000",
+            "--attach-offset",
+            "2f0",
+        ]);
+        assert_eq!(opt.attach_offset, [0x1000, 0x2f0]);
+    }
+
+    #[test]
+    fn size_arg_index_is_one_of_four_arguments() {
+        let parse = |index: &str| {
+            Opt::try_parse_from([
+                "jeprofl",
+                "--program",
+                "/bin/true",
+                "--size-arg-index",
+                index,
+            ])
+        };
+        assert_eq!(parse("3").unwrap().size_arg_index, Some(3));
+        assert!(parse("4").is_err());
+    }
+
+    #[test]
+    fn weights_are_independent_of_the_order() {
+        let opt = Opt::parse_from([
+            "jeprofl",
+            "--program",
+            "/bin/true",
+            "--order-by",
+            "count",
+            "--flame-weight",
+            "traffic",
+            "--csv-weight",
+            "peak",
+        ]);
+        assert!(matches!(opt.order_by, OrderBy::Count));
+        assert_eq!(opt.flame_weight, Some(WeightMetric::Bytes));
+        assert_eq!(opt.csv_weight, Some(WeightMetric::Peak));
+        assert!(opt.csv_weight.unwrap().needs_frees());
+        assert!("size".parse::<WeightMetric>().is_err());
+    }
+
+    #[test]
+    fn default_size_filter_tracks_everything() {
+        let opt = Opt::parse_from(["jeprofl", "--program", "/bin/true"]);
+        assert!(is_size_tracked(1, opt.min_alloc_size, opt.max_alloc_size));
+        assert!(is_size_tracked(
+            u64::MAX,
+            opt.min_alloc_size,
+            opt.max_alloc_size
+        ));
+    }
+}
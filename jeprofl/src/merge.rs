@@ -0,0 +1,239 @@
+//! `jeprofl merge`: combines profiles saved with `--json`, e.g. of the
+//! instances of a service on different hosts. Stacks are matched by their
+//! function and resolved symbols, pids and stack ids of different hosts say
+//! nothing about each other.
+
+use anyhow::Context;
+use jeprofl_common::Histogram;
+use rustc_hash::FxHashMap;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+
+/// A `--json` stack entry, written back unchanged apart from the merged numbers
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ExportedStack {
+    /// Zero when the merged stacks come from different processes
+    pub pid: u32,
+    pub tid: u32,
+    /// Renumbered, ids of different captures collide
+    pub stack_id: u32,
+    pub function: String,
+    pub total: u64,
+    pub count: u64,
+    pub overflow: u64,
+    pub scale_factor: u64,
+    pub live_bytes: Option<u64>,
+    pub live_count: Option<u64>,
+    /// Sum of the peaks of the merged stacks, an upper bound of their peak
+    pub peak_live_bytes: Option<u64>,
+    pub buckets: Vec<ExportedBucket>,
+    pub frames: Vec<ExportedFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ExportedBucket {
+    pub lower: u64,
+    pub upper: u64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ExportedFrame {
+    pub address: u64,
+    pub symbol: String,
+    pub file: Option<String>,
+}
+
+impl ExportedStack {
+    fn histogram(&self) -> anyhow::Result<Histogram> {
+        let mut histogram = Histogram::new();
+        if self.buckets.len() > histogram.data.len() {
+            anyhow::bail!(
+                "{} buckets, at most {} are known",
+                self.buckets.len(),
+                histogram.data.len()
+            );
+        }
+        for (count, bucket) in histogram.data.iter_mut().zip(&self.buckets) {
+            *count = bucket.count;
+        }
+        histogram.overflow = self.overflow;
+        histogram.total = self.total;
+        Ok(histogram)
+    }
+
+    fn signature(&self) -> (&str, Vec<&str>) {
+        let symbols = self.frames.iter().map(|f| f.symbol.as_str()).collect();
+        (&self.function, symbols)
+    }
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Vec<ExportedStack>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path:?}, expected a --json export"))
+}
+
+pub fn write(path: &Path, stacks: &[ExportedStack]) -> anyhow::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(file, stacks)?;
+    Ok(())
+}
+
+/// Sums the stacks of all profiles with the same function and symbols, the
+/// biggest first. The profiles must share their `--buckets` scheme
+pub fn merge(profiles: &[Vec<ExportedStack>]) -> anyhow::Result<Vec<ExportedStack>> {
+    let bounds = |stack: &ExportedStack| -> Vec<(u64, u64)> {
+        stack.buckets.iter().map(|b| (b.lower, b.upper)).collect()
+    };
+    let scheme = profiles.iter().flatten().next().map(bounds);
+    let mut merged: FxHashMap<(&str, Vec<&str>), (ExportedStack, Histogram)> = FxHashMap::default();
+    for stack in profiles.iter().flatten() {
+        if Some(bounds(stack)) != scheme {
+            anyhow::bail!(
+                "The profiles were captured with different --buckets, they can't be merged"
+            );
+        }
+        let histogram = stack
+            .histogram()
+            .with_context(|| format!("Stack {} has an unknown bucket layout", stack.stack_id))?;
+        match merged.entry(stack.signature()) {
+            Entry::Vacant(e) => {
+                e.insert((stack.clone(), histogram));
+            }
+            Entry::Occupied(mut e) => {
+                let (into, into_histogram) = e.get_mut();
+                into_histogram.merge(&histogram);
+                if into.pid != stack.pid {
+                    into.pid = 0;
+                    into.tid = 0;
+                } else if into.tid != stack.tid {
+                    into.tid = 0;
+                }
+                into.scale_factor = into.scale_factor.max(stack.scale_factor);
+                into.live_bytes = add(into.live_bytes, stack.live_bytes);
+                into.live_count = add(into.live_count, stack.live_count);
+                into.peak_live_bytes = add(into.peak_live_bytes, stack.peak_live_bytes);
+            }
+        }
+    }
+    let mut stacks: Vec<ExportedStack> = merged
+        .into_values()
+        .map(|(mut stack, histogram)| {
+            stack.total = histogram.total_bytes();
+            stack.count = histogram.allocation_count();
+            stack.overflow = histogram.overflow;
+            for (bucket, count) in stack.buckets.iter_mut().zip(histogram.data) {
+                bucket.count = count;
+            }
+            stack
+        })
+        .collect();
+    stacks
+        .sort_by(|a, b| (Reverse(a.total), a.signature()).cmp(&(Reverse(b.total), b.signature())));
+    for (stack_id, stack) in stacks.iter_mut().enumerate() {
+        stack.stack_id = stack_id as u32 + 1;
+    }
+    Ok(stacks)
+}
+
+fn add(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_add(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stack(pid: u32, sizes: &[u64], symbols: &[&str]) -> ExportedStack {
+        let mut histogram = Histogram::new();
+        for size in sizes {
+            histogram.increment(*size);
+        }
+        let scheme = jeprofl_common::BucketScheme::Log2;
+        ExportedStack {
+            pid,
+            tid: 0,
+            stack_id: 7,
+            function: "malloc".to_string(),
+            total: histogram.total_bytes(),
+            count: histogram.allocation_count(),
+            overflow: 0,
+            scale_factor: 1,
+            live_bytes: None,
+            live_count: None,
+            peak_live_bytes: None,
+            buckets: histogram.data[..scheme.bucket_count()]
+                .iter()
+                .enumerate()
+                .map(|(bucket, &count)| {
+                    let (lower, upper) = scheme.bounds(bucket);
+                    ExportedBucket {
+                        lower,
+                        upper,
+                        count,
+                    }
+                })
+                .collect(),
+            frames: symbols
+                .iter()
+                .map(|symbol| ExportedFrame {
+                    address: 0x10,
+                    symbol: symbol.to_string(),
+                    file: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn stacks_of_different_hosts_are_merged_by_symbols() {
+        let host_a = vec![
+            stack(10, &[16, 16], &["malloc", "parse", "main"]),
+            stack(10, &[64], &["malloc", "idle", "main"]),
+        ];
+        let host_b = vec![
+            stack(20, &[16, 4096], &["malloc", "parse", "main"]),
+            stack(20, &[128], &["malloc", "load", "main"]),
+        ];
+        let merged = merge(&[host_a, host_b]).unwrap();
+        let summary: Vec<_> = merged
+            .iter()
+            .map(|s| {
+                (
+                    s.stack_id,
+                    s.pid,
+                    s.frames[1].symbol.as_str(),
+                    s.total,
+                    s.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, 0, "parse", 4144, 4),
+                (2, 20, "load", 128, 1),
+                (3, 10, "idle", 64, 1),
+            ]
+        );
+        let sixteen = merged[0].buckets.iter().find(|b| b.lower == 16).unwrap();
+        assert_eq!(sixteen.count, 3);
+        // the output is a --json export again
+        let json = serde_json::to_string(&merged).unwrap();
+        let reloaded: Vec<ExportedStack> = serde_json::from_str(&json).unwrap();
+        assert_eq!(merge(&[reloaded]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn different_bucket_schemes_are_rejected() {
+        let mut linear = stack(1, &[16], &["malloc"]);
+        linear.buckets[0].upper = 7;
+        let log2 = stack(1, &[16], &["malloc"]);
+        assert!(merge(&[vec![log2], vec![linear]]).is_err());
+    }
+}
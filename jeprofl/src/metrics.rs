@@ -0,0 +1,219 @@
+//! Prometheus text format endpoint for `--metrics-addr`. The collector
+//! publishes a rendered snapshot after every poll, the server only hands out
+//! the latest one.
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// Latest rendered metrics, shared by the collector and the server
+pub type Published = Arc<Mutex<String>>;
+
+/// Aggregates of the profile at one poll
+#[derive(Debug, Default)]
+pub struct MetricsSnapshot {
+    /// Biggest stacks by allocated bytes, capped to keep the label cardinality low
+    pub stacks: Vec<StackMetrics>,
+    pub distinct_stacks: u64,
+    pub alloc_bytes: u64,
+    pub alloc_count: u64,
+    /// Entries in the ebpf `HISTOGRAMS` map
+    pub map_entries: u64,
+    pub evicted_stacks: u64,
+    pub dropped_events: u64,
+    pub stack_id_failures: u64,
+    pub arg_read_failures: u64,
+    pub insert_failures: u64,
+}
+
+#[derive(Debug)]
+pub struct StackMetrics {
+    pub stack_id: u32,
+    /// Caller of the allocation function, the most telling frame of the stack
+    pub frame: String,
+    pub bytes: u64,
+    pub count: u64,
+    /// `None` when frees aren't tracked
+    pub live_bytes: Option<u64>,
+}
+
+impl MetricsSnapshot {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {kind}").ok();
+            writeln!(out, "{name} {value}").ok();
+        };
+        metric(
+            "jeprofl_alloc_bytes",
+            "counter",
+            "Allocated bytes of all stacks",
+            self.alloc_bytes,
+        );
+        metric(
+            "jeprofl_alloc_count",
+            "counter",
+            "Number of allocations of all stacks",
+            self.alloc_count,
+        );
+        metric(
+            "jeprofl_stacks",
+            "gauge",
+            "Distinct allocation stacks",
+            self.distinct_stacks,
+        );
+        metric(
+            "jeprofl_map_entries",
+            "gauge",
+            "Entries of the ebpf histogram map",
+            self.map_entries,
+        );
+        metric(
+            "jeprofl_evicted_stacks",
+            "counter",
+            "Stacks evicted from the full ebpf map",
+            self.evicted_stacks,
+        );
+        metric(
+            "jeprofl_dropped_events",
+            "counter",
+            "Allocations dropped because the ring buffer was full",
+            self.dropped_events,
+        );
+        metric(
+            "jeprofl_stack_map_failures",
+            "counter",
+            "Allocations and frees dropped because the stack map was full",
+            self.stack_id_failures,
+        );
+        metric(
+            "jeprofl_arg_read_failures",
+            "counter",
+            "Allocations and frees dropped because their arguments couldn't be read",
+            self.arg_read_failures,
+        );
+        metric(
+            "jeprofl_insert_failures",
+            "counter",
+            "Allocations and frees dropped because an ebpf map was full",
+            self.insert_failures,
+        );
+
+        type Value = fn(&StackMetrics) -> Option<u64>;
+        let per_stack: [(&str, &str, &str, Value); 3] = [
+            (
+                "jeprofl_stack_alloc_bytes",
+                "counter",
+                "Allocated bytes of the biggest stacks",
+                |stack| Some(stack.bytes),
+            ),
+            (
+                "jeprofl_stack_alloc_count",
+                "counter",
+                "Number of allocations of the biggest stacks",
+                |stack| Some(stack.count),
+            ),
+            (
+                "jeprofl_live_bytes",
+                "gauge",
+                "Not yet freed bytes of the biggest stacks",
+                |stack| stack.live_bytes,
+            ),
+        ];
+        for (name, kind, help, value) in per_stack {
+            let values: Vec<_> = self
+                .stacks
+                .iter()
+                .filter_map(|stack| Some((stack, value(stack)?)))
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {kind}").ok();
+            for (stack, value) in values {
+                writeln!(
+                    out,
+                    "{name}{{stack=\"{}\",frame=\"{}\"}} {value}",
+                    stack.stack_id,
+                    escape_label(&stack.frame)
+                )
+                .ok();
+            }
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the published metrics on every request until the process exits
+pub fn spawn_server(addr: SocketAddr, published: Published) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("Serving metrics on http://{addr}/metrics");
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &published));
+            if let Err(e) = result {
+                log::debug!("Failed to serve metrics: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, published: &Published) -> std::io::Result<()> {
+    // the request itself doesn't matter, every path gets the metrics
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+    let body = published.lock().unwrap().clone();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_prometheus_text() {
+        let snapshot = MetricsSnapshot {
+            stacks: vec![
+                StackMetrics {
+                    stack_id: 7,
+                    frame: "app::cache::\"insert\"".to_string(),
+                    bytes: 4096,
+                    count: 2,
+                    live_bytes: Some(1024),
+                },
+                StackMetrics {
+                    stack_id: 3,
+                    frame: "main".to_string(),
+                    bytes: 64,
+                    count: 4,
+                    live_bytes: Some(0),
+                },
+            ],
+            distinct_stacks: 12,
+            alloc_bytes: 5000,
+            alloc_count: 20,
+            map_entries: 30,
+            evicted_stacks: 0,
+            dropped_events: 0,
+            stack_id_failures: 0,
+            arg_read_failures: 0,
+            insert_failures: 0,
+        };
+        insta::assert_snapshot!(snapshot.render());
+    }
+}
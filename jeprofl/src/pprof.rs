@@ -0,0 +1,275 @@
+//! Minimal encoder of the pprof `profile.proto` format, readable by
+//! `go tool pprof`. Only the fields jeprofl has data for are written.
+
+use crate::resolver::ResolvedStackTrace;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rustc_hash::FxHashMap;
+use std::io::Write as _;
+use std::time::Duration;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_LEN: u8 = 2;
+
+/// Builds a profile with `alloc_objects/count` and `alloc_space/bytes` sample types.
+pub struct ProfileBuilder {
+    strings: Vec<String>,
+    string_ids: FxHashMap<String, i64>,
+    /// (name, file) -> function id
+    functions: FxHashMap<(i64, i64), u64>,
+    /// (address, function id) -> location id. Unknown frames all have address
+    /// zero and frames of different binaries may share one, so the address
+    /// alone doesn't tell locations apart
+    locations: FxHashMap<(u64, u64), u64>,
+    samples: Vec<Sample>,
+}
+
+struct Sample {
+    location_ids: Vec<u64>,
+    count: u64,
+    bytes: u64,
+}
+
+impl Default for ProfileBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProfileBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self {
+            strings: Vec::new(),
+            string_ids: FxHashMap::default(),
+            functions: FxHashMap::default(),
+            locations: FxHashMap::default(),
+            samples: Vec::new(),
+        };
+        // string table must start with an empty string
+        builder.string_id("");
+        builder
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    /// Adds a sample, frames of the trace go from the leaf to the root.
+    pub fn add_sample(&mut self, trace: &ResolvedStackTrace, count: u64, bytes: u64) {
+        let mut location_ids = Vec::with_capacity(trace.symbols.len());
+        for symbol in &trace.symbols {
+            let name = self.string_id(&symbol.symbol);
+            let file = self.string_id(symbol.file.as_deref().unwrap_or(""));
+
+            let next_function_id = self.functions.len() as u64 + 1;
+            let function_id = *self
+                .functions
+                .entry((name, file))
+                .or_insert(next_function_id);
+
+            let next_location_id = self.locations.len() as u64 + 1;
+            let location_id = *self
+                .locations
+                .entry((symbol.address, function_id))
+                .or_insert(next_location_id);
+            location_ids.push(location_id);
+        }
+        self.samples.push(Sample {
+            location_ids,
+            count,
+            bytes,
+        });
+    }
+
+    /// Encodes the profile and gzips it, as `go tool pprof` expects.
+    pub fn write(mut self, writer: impl std::io::Write, duration: Duration) -> anyhow::Result<()> {
+        let alloc_objects = self.string_id("alloc_objects");
+        let count = self.string_id("count");
+        let alloc_space = self.string_id("alloc_space");
+        let bytes = self.string_id("bytes");
+
+        let mut profile = ProtoWriter::default();
+        for (ty, unit) in [(alloc_objects, count), (alloc_space, bytes)] {
+            profile.message(1, |value_type| {
+                value_type.int64(1, ty);
+                value_type.int64(2, unit);
+            });
+        }
+        for sample in &self.samples {
+            profile.message(2, |s| {
+                s.packed(1, sample.location_ids.iter().copied());
+                s.packed(2, [sample.count, sample.bytes]);
+            });
+        }
+
+        let mut locations: Vec<_> = self.locations.iter().collect();
+        locations.sort_by_key(|(_, id)| **id);
+        for ((address, function_id), id) in locations {
+            profile.message(4, |location| {
+                location.uint64(1, *id);
+                location.uint64(3, *address);
+                location.message(4, |line| line.uint64(1, *function_id));
+            });
+        }
+
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by_key(|(_, id)| **id);
+        for ((name, file), id) in functions {
+            profile.message(5, |function| {
+                function.uint64(1, *id);
+                function.int64(2, *name);
+                function.int64(3, *name);
+                function.int64(4, *file);
+            });
+        }
+
+        for s in &self.strings {
+            profile.bytes(6, s.as_bytes());
+        }
+        profile.int64(10, duration.as_nanos() as i64);
+        profile.message(11, |period_type| {
+            period_type.int64(1, alloc_space);
+            period_type.int64(2, bytes);
+        });
+
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        encoder.write_all(&profile.buf)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint64(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_VARINT);
+        self.varint(value);
+    }
+
+    fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, data: &[u8]) {
+        self.key(field, WIRE_LEN);
+        self.varint(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = ProtoWriter::default();
+        for value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.buf);
+    }
+
+    fn message(&mut self, field: u32, f: impl FnOnce(&mut ProtoWriter)) {
+        let mut message = ProtoWriter::default();
+        f(&mut message);
+        self.bytes(field, &message.buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::resolver::OwnedSymbol;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn varint_encoding() {
+        let mut writer = ProtoWriter::default();
+        writer.varint(1);
+        writer.varint(300);
+        assert_eq!(writer.buf, [0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn locations_are_deduplicated_by_address() {
+        let trace = ResolvedStackTrace {
+            symbols: vec![
+                OwnedSymbol {
+                    address: 0x10,
+                    symbol: "malloc".to_string(),
+                    file: None,
+                    line: None,
+                    inlined: Vec::new(),
+                },
+                OwnedSymbol {
+                    address: 0x20,
+                    symbol: "main".to_string(),
+                    file: Some("src/main.rs".to_string()),
+                    line: None,
+                    inlined: Vec::new(),
+                },
+            ],
+        };
+        let mut builder = ProfileBuilder::new();
+        builder.add_sample(&trace, 1, 16);
+        builder.add_sample(&trace, 2, 64);
+        assert_eq!(builder.locations.len(), 2);
+        assert_eq!(builder.functions.len(), 2);
+        assert_eq!(
+            builder.samples[0].location_ids,
+            builder.samples[1].location_ids
+        );
+
+        let mut gzipped = Vec::new();
+        builder.write(&mut gzipped, Duration::from_secs(1)).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(gzipped.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        let decoded = String::from_utf8_lossy(&decoded);
+        assert!(decoded.contains("alloc_space"));
+        assert!(decoded.contains("src/main.rs"));
+    }
+
+    #[test]
+    fn unknown_frames_keep_their_names() {
+        let unknown = |symbol: &str| OwnedSymbol {
+            address: 0,
+            symbol: symbol.to_string(),
+            file: None,
+            line: None,
+            inlined: Vec::new(),
+        };
+        let trace = ResolvedStackTrace {
+            symbols: vec![
+                unknown("[unknown] libfoo.so"),
+                unknown("[unknown] libbar.so"),
+            ],
+        };
+        let mut builder = ProfileBuilder::new();
+        builder.add_sample(&trace, 1, 16);
+        assert_eq!(builder.locations.len(), 2);
+        let [first, second] = builder.samples[0].location_ids[..] else {
+            panic!("expected two locations");
+        };
+        assert_ne!(first, second);
+    }
+}
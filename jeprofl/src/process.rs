@@ -0,0 +1,311 @@
+//! Lookup of running processes in `/proc`.
+
+use crate::{raw, JemallocAllocFunctions};
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use std::path::{Path, PathBuf};
+
+/// Pids of processes which `comm` or executable file name is `name`
+pub fn find_pids_by_name(name: &str) -> anyhow::Result<Vec<i32>> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        // the process may exit while we are scanning
+        let comm = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let cmdline = std::fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        if matches_name(&comm, &cmdline, name) {
+            pids.push(pid);
+        }
+    }
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// Pids of the processes in the cgroup and the cgroups below it. `path` is the
+/// directory of the cgroup or its path in the cgroup v2 hierarchy, like
+/// `/system.slice/server.service` of `/proc/<pid>/cgroup`
+pub fn find_pids_in_cgroup(path: &Path) -> anyhow::Result<Vec<i32>> {
+    let dir = if path.join("cgroup.procs").exists() {
+        path.to_path_buf()
+    } else {
+        Path::new("/sys/fs/cgroup").join(path.strip_prefix("/").unwrap_or(path))
+    };
+    if !dir.join("cgroup.procs").exists() {
+        anyhow::bail!("{} is not a cgroup, it has no cgroup.procs", dir.display());
+    }
+    let mut pids = Vec::new();
+    collect_cgroup_pids(&dir, &mut pids)?;
+    pids.sort_unstable();
+    pids.dedup();
+    Ok(pids)
+}
+
+/// With cgroup v2 only leaf cgroups hold processes, e.g. the containers of a pod
+fn collect_cgroup_pids(dir: &Path, pids: &mut Vec<i32>) -> anyhow::Result<()> {
+    let procs = std::fs::read_to_string(dir.join("cgroup.procs"))?;
+    pids.extend(
+        procs
+            .lines()
+            .filter_map(|line| line.trim().parse::<i32>().ok()),
+    );
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_cgroup_pids(&entry.path(), pids)?;
+        }
+    }
+    Ok(())
+}
+
+/// Processes forked by the `roots` and their children, each with the root it
+/// descends from
+pub fn find_descendants(roots: &[i32]) -> anyhow::Result<Vec<(i32, i32)>> {
+    let mut children: FxHashMap<i32, Vec<i32>> = FxHashMap::default();
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        // the process may exit while we are scanning
+        let stat = std::fs::read_to_string(entry.path().join("stat")).unwrap_or_default();
+        if let Some(ppid) = parent_pid(&stat) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+    let mut descendants = Vec::new();
+    for &root in roots {
+        let mut pending = vec![root];
+        while let Some(parent) = pending.pop() {
+            for &child in children.get(&parent).into_iter().flatten() {
+                descendants.push((child, root));
+                pending.push(child);
+            }
+        }
+    }
+    descendants.sort_unstable();
+    Ok(descendants)
+}
+
+/// The 4th field of `/proc/<pid>/stat`, after the `comm` in parentheses which
+/// may contain spaces and parentheses itself
+fn parent_pid(stat: &str) -> Option<i32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Path of the library `soname` mapped by the process, e.g. `libjemalloc.so.2`.
+/// It goes through `/proc/<pid>/root`, so it is reachable in containers too.
+pub fn find_library(pid: i32, soname: &str) -> anyhow::Result<PathBuf> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    let path = maps
+        .lines()
+        .filter_map(raw::parse_mapping)
+        .map(|mapping| mapping.path)
+        .find(|path| is_library(path, soname))
+        .ok_or_else(|| anyhow::anyhow!("Process {pid} has no {soname} mapped"))?;
+    Ok(PathBuf::from(format!("/proc/{pid}/root{path}")))
+}
+
+/// Mapped file of the process which defines `function`, reachable through
+/// `/proc/<pid>/root`. Jemalloc libraries are tried first, their `malloc`
+/// replaces the one of the C library, then the files in the order of the maps,
+/// starting with the executable
+pub fn find_module_defining(
+    pid: i32,
+    function: JemallocAllocFunctions,
+    prefix: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    let mut paths: Vec<_> = maps
+        .lines()
+        .filter_map(raw::parse_mapping)
+        .map(|mapping| mapping.path)
+        .unique()
+        .collect();
+    // stable, the executable stays first among the rest
+    paths.sort_by_key(|path| !is_jemalloc_library(path));
+    paths
+        .into_iter()
+        .map(|path| PathBuf::from(format!("/proc/{pid}/root{path}")))
+        .find(|path| {
+            matches!(
+                crate::resolver::find_function(path, &function.to_string(), prefix),
+                Ok(Some(_))
+            )
+        })
+        .ok_or_else(|| anyhow::anyhow!("No file mapped by process {pid} defines {function}"))
+}
+
+/// Whether the process allocates with jemalloc: it maps a jemalloc library, or
+/// maps `target` which defines jemalloc's `mallocx`, e.g. a binary with
+/// tikv-jemallocator statically linked
+pub fn uses_jemalloc(pid: i32, target: &Path) -> anyhow::Result<bool> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    let paths: Vec<_> = maps
+        .lines()
+        .filter_map(raw::parse_mapping)
+        .map(|mapping| mapping.path)
+        .collect();
+    if paths.iter().any(|path| is_jemalloc_library(path)) {
+        return Ok(true);
+    }
+    let maps_target = paths
+        .iter()
+        .any(|path| Path::new(path).file_name() == target.file_name());
+    Ok(maps_target && crate::resolver::find_function(target, "mallocx", None)?.is_some())
+}
+
+fn is_jemalloc_library(path: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains("jemalloc"))
+}
+
+/// Host visible path of `path` in the mount namespace of the process, e.g. of a
+/// binary in a container. Stacks are symbolized through `/proc/<pid>/map_files`,
+/// which works across the namespaces as well.
+pub fn container_path(pid: i32, path: &Path) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        path.is_absolute(),
+        "{} must be an absolute path inside the container",
+        path.display()
+    );
+    Ok(Path::new(&format!("/proc/{pid}/root")).join(path.strip_prefix("/")?))
+}
+
+/// Whether the file is `soname` or a more specific version of it,
+/// e.g. `libjemalloc.so.2.1` for `libjemalloc.so.2`
+fn is_library(path: &str, soname: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            name.strip_prefix(soname)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+}
+
+fn matches_name(comm: &str, cmdline: &[u8], name: &str) -> bool {
+    if comm.trim_end() == name {
+        return true;
+    }
+    // comm is truncated to 15 bytes, the executable path in cmdline is not
+    let exe = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+    let exe = String::from_utf8_lossy(exe);
+    Path::new(exe.as_ref())
+        .file_name()
+        .is_some_and(|file_name| file_name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_comm_or_executable() {
+        assert!(matches_name("server\n", b"", "server"));
+        assert!(matches_name(
+            "very-long-serv\n",
+            b"/usr/bin/very-long-server\0--verbose\0",
+            "very-long-server"
+        ));
+        assert!(!matches_name("server\n", b"/usr/bin/server\0", "serv"));
+        assert!(!matches_name("bash\n", b"bash\0server\0", "server"));
+    }
+
+    #[test]
+    fn finds_pids_of_nested_cgroups() {
+        let root = std::env::temp_dir().join(format!("jeprofl-cgroup-{}", std::process::id()));
+        let _cleanup = scopeguard::guard((), |_| {
+            std::fs::remove_dir_all(&root).ok();
+        });
+        std::fs::create_dir_all(root.join("container")).unwrap();
+        std::fs::write(root.join("cgroup.procs"), "").unwrap();
+        std::fs::write(root.join("container/cgroup.procs"), "42\n7\n").unwrap();
+        assert_eq!(find_pids_in_cgroup(&root).unwrap(), [7, 42]);
+        assert!(find_pids_in_cgroup(&root.join("missing")).is_err());
+    }
+
+    #[test]
+    fn finds_forked_children() {
+        assert_eq!(
+            parent_pid("42 (worker (1) x) S 7 42 42 0 -1 4194560"),
+            Some(7)
+        );
+        assert_eq!(parent_pid(""), None);
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let root = std::process::id() as i32;
+        let descendants = find_descendants(&[root]).unwrap();
+        child.kill().ok();
+        child.wait().ok();
+        assert!(descendants.contains(&(child.id() as i32, root)));
+    }
+
+    #[test]
+    fn finds_the_module_defining_malloc() {
+        // the test binary allocates with the C library
+        let module = find_module_defining(
+            std::process::id() as i32,
+            JemallocAllocFunctions::Malloc,
+            None,
+        )
+        .unwrap();
+        let name = module.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("libc"), "{name}");
+        assert!(find_module_defining(
+            std::process::id() as i32,
+            JemallocAllocFunctions::Mallocx,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detects_jemalloc() {
+        assert!(is_jemalloc_library(
+            "/usr/lib/x86_64-linux-gnu/libjemalloc.so.2"
+        ));
+        assert!(!is_jemalloc_library("/usr/lib/jemalloc/libc.so.6"));
+        // the test binary allocates with the C library
+        let exe = std::env::current_exe().unwrap();
+        assert!(!uses_jemalloc(std::process::id() as i32, &exe).unwrap());
+    }
+
+    #[test]
+    fn paths_inside_the_container() {
+        assert_eq!(
+            container_path(42, Path::new("/usr/bin/server")).unwrap(),
+            Path::new("/proc/42/root/usr/bin/server")
+        );
+        assert!(container_path(42, Path::new("bin/server")).is_err());
+    }
+
+    #[test]
+    fn matches_versioned_libraries() {
+        let path = "/usr/lib/x86_64-linux-gnu/libjemalloc.so.2";
+        assert!(is_library(path, "libjemalloc.so.2"));
+        assert!(is_library(path, "libjemalloc.so"));
+        assert!(is_library(
+            "/opt/lib/libjemalloc.so.2.5.1",
+            "libjemalloc.so.2"
+        ));
+        assert!(!is_library(path, "libjemalloc.so.1"));
+        assert!(!is_library("/usr/lib/libjemalloc_pic.so", "libjemalloc"));
+    }
+}
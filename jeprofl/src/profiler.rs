@@ -0,0 +1,886 @@
+//! Attaching the ebpf programs and running the collector, what the `jeprofl`
+//! binary does between parsing its arguments and rendering the report.
+
+use crate::collector::{
+    spawn_collector, CollectorConfig, CollectorMaps, EventProcessor, SizeBiasedSampling,
+    ThreadFilter,
+};
+use crate::process;
+use crate::resolver::{self, ResolverOptions};
+use crate::{GroupBy, JemallocAllocFunctions, OrderBy, SkipMode};
+use aya::maps::{PerCpuArray, PerCpuHashMap, PerCpuValues, RingBuf, StackTraceMap};
+use aya::programs::UProbe;
+use aya::util::nr_cpus;
+use aya::{include_bytes_aligned, Ebpf, EbpfLoader};
+use aya_log::EbpfLogger;
+use itertools::Itertools;
+use jeprofl_common::{
+    BucketScheme, Histogram, HistogramKey, ARG_READ_FAILURES_INDEX, BUCKET_PARAM_INDEX,
+    BUCKET_SCHEME_INDEX, CONFIG_READY_INDEX, COUNT_INDEX, DWARF_UNWIND_INDEX, FUNCTION_INFO_INDEX,
+    GROUP_BY_THREAD_INDEX, HISTOGRAMS_INSERTED_INDEX, INSERT_FAILURES_INDEX, KERNEL_STACKS_INDEX,
+    MAX_ALLOC_INDEX, MIN_ALLOC_INDEX, NO_SIZE_MULTIPLIER, RING_BUFFER_DROPS_INDEX,
+    RING_BUFFER_INDEX, SAMPLED_BYTES_INDEX, SAMPLE_ABOVE_INDEX, SAMPLE_EVERY_BYTES_INDEX,
+    SAMPLE_EVERY_INDEX, SIZE_MULTIPLIER_INDEX, STACK_COPY_SIZE, STACK_ID_FAILURES_INDEX,
+    TRACK_FREES, TRACK_FREES_INDEX, TRACK_FREE_SITES, TRACK_LATENCY_INDEX, TRACK_POINTERS,
+};
+use log::{debug, info, warn};
+use rustc_hash::FxHashMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// What is traced and how the ebpf side records it.
+#[derive(Debug, Clone)]
+pub struct ProfilerOptions {
+    /// Pids to attach the probes to, `None` attaches to every process running the target
+    pub pids: Vec<Option<i32>>,
+    /// Also attach to the processes the `pids` fork while profiling, summed
+    /// into them in the report
+    pub include_children: bool,
+    /// Allocations outside of these sizes, inclusive, aren't recorded
+    pub min_alloc_size: u64,
+    pub max_alloc_size: u64,
+    /// Record every Nth allocation
+    pub sample_every: NonZeroU32,
+    /// Size-biased sampling: allocations of at least this many bytes are always
+    /// recorded, smaller ones every `sample_every`th time
+    pub sample_above: Option<u64>,
+    /// Byte based sampling: an allocation is recorded about every this many
+    /// allocated bytes, weighted by the allocations of its size they stand for
+    pub sample_every_bytes: Option<u64>,
+    /// Don't scale the sampled numbers by `sample_every`
+    pub raw_samples: bool,
+    pub group_by: GroupBy,
+    /// Probe the deallocation functions to know the live memory of every stack
+    pub track_frees: bool,
+    /// Also capture the stacks of frees, implies `track_frees`
+    pub track_free_sites: bool,
+    pub max_tracked_pointers: u32,
+    /// Also record how long every sampled allocation call took
+    pub track_latency: bool,
+    pub kernel_stacks: bool,
+    pub buckets: BucketScheme,
+    /// Send every allocation through a ring buffer of `ring_buffer_size` bytes
+    /// instead of aggregating them in a map
+    pub ring_buffer: bool,
+    pub ring_buffer_size: u64,
+    /// Unwind user stacks with DWARF CFI, implies `ring_buffer`
+    pub dwarf_unwind: bool,
+    /// Prefix of the allocation functions, e.g. `_rjem_`
+    pub symbol_prefix: Option<String>,
+    /// File offset of the single traced function, for binaries without symbols
+    pub offset: Option<u64>,
+    /// File offsets of inlined wrappers of the single traced function, probed
+    /// on entry next to the function itself
+    pub attach_offsets: Vec<u64>,
+    /// Argument holding the size of the single traced function instead of the
+    /// one of its signature, for allocators shaped differently
+    pub size_arg_index: Option<u64>,
+    /// Keep what the maps already hold instead of clearing them
+    pub no_clear: bool,
+    /// Settings of the collector thread. Its sampling, bucket and unwinding
+    /// settings are overwritten with the ones above
+    pub collector: CollectorConfig,
+}
+
+impl Default for ProfilerOptions {
+    fn default() -> Self {
+        Self {
+            pids: vec![None],
+            include_children: false,
+            min_alloc_size: 0,
+            max_alloc_size: u64::MAX,
+            sample_every: NonZeroU32::MIN,
+            sample_above: None,
+            sample_every_bytes: None,
+            raw_samples: false,
+            group_by: GroupBy::Process,
+            track_frees: false,
+            track_free_sites: false,
+            max_tracked_pointers: 1024 * 1024,
+            track_latency: false,
+            kernel_stacks: false,
+            buckets: BucketScheme::Log2,
+            ring_buffer: false,
+            ring_buffer_size: 16 * 1024 * 1024,
+            dwarf_unwind: false,
+            symbol_prefix: None,
+            offset: None,
+            attach_offsets: Vec::new(),
+            size_arg_index: None,
+            no_clear: false,
+            collector: CollectorConfig {
+                skip_total_alloc_size_lower_than: 0,
+                skip_total_count_lower_than: 0,
+                skip_mode: SkipMode::Any,
+                scale_factor: 1,
+                size_biased: None,
+                sample_every_bytes: None,
+                bucket_scheme: BucketScheme::Log2,
+                raw_capture: false,
+                metrics: None,
+                metrics_top: 50,
+                resolver: ResolverOptions::default(),
+                tui: None,
+                warmup: Duration::ZERO,
+                windows: None,
+                timeline: false,
+                dwarf_unwind: false,
+                poll_interval: Duration::from_secs(1),
+                clean_up_interval: Duration::from_secs(60),
+                thread_filter: ThreadFilter::default(),
+                max_stacks: None,
+                order_by: OrderBy::Traffic,
+            },
+        }
+    }
+}
+
+impl ProfilerOptions {
+    /// Factor applied to every rendered number to estimate the real traffic
+    /// from the sampled one.
+    fn scale_factor(&self) -> u64 {
+        // size-biased and byte based samples are weighted on the ebpf side
+        if self.raw_samples || self.sample_above.is_some() || self.sample_every_bytes.is_some() {
+            1
+        } else {
+            self.sample_every.get() as u64
+        }
+    }
+
+    fn size_biased(&self) -> Option<SizeBiasedSampling> {
+        self.sample_above.map(|threshold| SizeBiasedSampling {
+            threshold,
+            sample_every: self.sample_every.get() as u64,
+        })
+    }
+
+    /// Allocations go through the `EVENTS` ring buffer
+    pub fn uses_ring_buffer(&self) -> bool {
+        self.ring_buffer || self.dwarf_unwind
+    }
+
+    /// Free tracking the report shows the live memory of
+    fn free_tracking_flags(&self) -> u64 {
+        match (self.track_frees, self.track_free_sites) {
+            (_, true) => TRACK_FREES | TRACK_FREE_SITES,
+            (true, false) => TRACK_FREES,
+            (false, false) => 0,
+        }
+    }
+
+    /// Value of the `TRACK_FREES_INDEX` config slot. Resizing functions need
+    /// the size of the pointers they get, so returned pointers are tracked
+    /// whenever one of them is traced
+    fn pointer_tracking_flags(&self, functions: &[JemallocAllocFunctions]) -> u64 {
+        let resizing = functions.iter().any(|f| f.is_resizing());
+        self.free_tracking_flags() | if resizing { TRACK_POINTERS } else { 0 }
+    }
+
+    /// The return of `function`, one of the traced `functions`, is probed for
+    /// its result or its latency
+    fn probes_return(
+        &self,
+        function: JemallocAllocFunctions,
+        functions: &[JemallocAllocFunctions],
+    ) -> bool {
+        match function {
+            // returns a size, not a pointer to track
+            JemallocAllocFunctions::Xallocx => self.track_latency,
+            _ => self.pointer_tracking_flags(functions) != 0 || self.track_latency,
+        }
+    }
+
+    /// The deallocation functions are probed, for the live memory or to forget
+    /// tracked pointers once the allocator may reuse them
+    fn probes_frees(&self, functions: &[JemallocAllocFunctions]) -> bool {
+        self.pointer_tracking_flags(functions) != 0
+    }
+}
+
+/// How often `--include-children` looks for new children of the targets
+const CHILDREN_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Probes attached to the allocation functions of a binary, with the collector
+/// thread aggregating what they record. Dropping it detaches the probes.
+pub struct Profiler {
+    /// Owns the attached programs, shared with the thread attaching to children
+    _bpf: Arc<Mutex<Ebpf>>,
+    canceled: Arc<AtomicBool>,
+    collector: JoinHandle<EventProcessor>,
+    /// Attaches to the children of the targets, returns the target each
+    /// descends from
+    children: Option<JoinHandle<FxHashMap<u32, u32>>>,
+}
+
+/// What is attached to every traced process
+struct Probes {
+    target: PathBuf,
+    functions: Vec<JemallocAllocFunctions>,
+    /// Symbol of every function, `None` with `offset`
+    symbols: Vec<Option<String>>,
+    opts: ProfilerOptions,
+}
+
+impl Profiler {
+    /// Attaches to the `functions` of the `target` binary or library, the
+    /// collection starts right away
+    pub fn attach(
+        target: &Path,
+        functions: &[JemallocAllocFunctions],
+        opts: ProfilerOptions,
+    ) -> anyhow::Result<Self> {
+        let functions: Vec<_> = functions.iter().copied().unique().collect();
+        if functions.is_empty() {
+            anyhow::bail!("No allocation function to attach to");
+        }
+        if opts.offset.is_some() && functions.len() != 1 {
+            anyhow::bail!("--offset needs exactly one --function");
+        }
+        if opts.include_children && opts.pids.contains(&None) {
+            anyhow::bail!("--include-children needs the pids of the targets");
+        }
+        if opts.sample_every_bytes == Some(0) {
+            anyhow::bail!("--sample-every-bytes must not be zero");
+        }
+        if opts.sample_every_bytes.is_some()
+            && (opts.sample_every.get() > 1 || opts.sample_above.is_some())
+        {
+            anyhow::bail!("--sample-every-bytes replaces --sample-every and --sample-above");
+        }
+        if !opts.attach_offsets.is_empty() {
+            if functions.len() != 1 {
+                anyhow::bail!("--attach-offset needs exactly one --function");
+            }
+            // the return of an inlined wrapper can't be probed
+            if opts.probes_return(functions[0], &functions) {
+                anyhow::bail!(
+                    "--attach-offset probes only the entry, which doesn't account {}, freed memory or latencies",
+                    functions[0]
+                );
+            }
+        }
+        if let Some(index) = opts.size_arg_index {
+            if functions.len() != 1 {
+                anyhow::bail!("--size-arg-index needs exactly one --function");
+            }
+            // the ebpf side reads the size of resizing functions from a fixed argument
+            if functions[0].is_resizing() {
+                anyhow::bail!("--size-arg-index can't be used with {}", functions[0]);
+            }
+            if index > 3 {
+                anyhow::bail!(
+                    "--size-arg-index must be one of the first four arguments, got {index}"
+                );
+            }
+        }
+
+        // Bump the memlock rlimit. This is needed for older kernels that don't use the
+        // new memcg based accounting, see https://lwn.net/Articles/837122/
+        let rlim = libc::rlimit {
+            rlim_cur: libc::RLIM_INFINITY,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+        if ret != 0 {
+            debug!("remove limit on locked memory failed, ret is: {}", ret);
+        }
+
+        // This will include your eBPF object file as raw bytes at compile-time and load it at
+        // runtime. This approach is recommended for most real-world use cases. If you would
+        // like to specify the eBPF program at runtime rather than at compile-time, you can
+        // reach for `Bpf::load_file` instead.
+        let mut loader = EbpfLoader::new();
+        loader.set_max_entries("ALLOCATIONS", opts.max_tracked_pointers);
+        if !opts.kernel_stacks {
+            loader.set_max_entries("KERNEL_STACKTRACES", 1);
+        }
+        if !opts.track_latency {
+            loader.set_max_entries("LATENCIES", 1);
+        }
+        if opts.dwarf_unwind && !cfg!(target_arch = "x86_64") {
+            anyhow::bail!("--dwarf-unwind is supported only on x86_64");
+        }
+        // unwound samples have no stack id to key the latencies by
+        if opts.dwarf_unwind && opts.track_latency {
+            anyhow::bail!("--profile-duration-histogram can't be used with --dwarf-unwind");
+        }
+        if opts.uses_ring_buffer() {
+            let size = opts.ring_buffer_size;
+            if !size.is_power_of_two() || size < PAGE_SIZE as u64 || size > u32::MAX as u64 {
+                anyhow::bail!("--ring-buffer-size must be a power of two between 4 KiB and 2 GiB");
+            }
+            if opts.dwarf_unwind && size < 4 * STACK_COPY_SIZE as u64 {
+                anyhow::bail!("--dwarf-unwind needs a --ring-buffer-size of at least 64 KiB");
+            }
+            loader.set_max_entries("EVENTS", size as u32);
+        } else {
+            loader.set_max_entries("EVENTS", PAGE_SIZE);
+        }
+        #[cfg(debug_assertions)]
+        let mut bpf = loader.load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/debug/jeprofl"
+        ))?;
+        #[cfg(not(debug_assertions))]
+        let mut bpf = loader.load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/release/jeprofl"
+        ))?;
+        // the logger reads the ebpf logs on a tokio task
+        if tokio::runtime::Handle::try_current().is_err() {
+            debug!("Not in a tokio runtime, ebpf logs are not forwarded");
+        } else if let Err(e) = EbpfLogger::init(&mut bpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
+        }
+
+        write_config(&mut bpf, &functions, &opts)?;
+        if !opts.no_clear {
+            let cleared = clear_maps(&mut bpf)?;
+            if cleared > 0 {
+                info!("Cleared {cleared} entries a previous run left in the maps");
+            }
+        }
+
+        let symbol_prefix = opts.symbol_prefix.as_deref();
+        // all symbols are checked before anything is attached
+        let symbols: Vec<Option<String>> = match opts.offset {
+            Some(_) => vec![None],
+            None => find_symbols(target, &functions, symbol_prefix)?
+                .into_iter()
+                .map(Some)
+                .collect(),
+        };
+        for (function, symbol) in functions.iter().zip(&symbols) {
+            let offset = opts.offset.unwrap_or(0);
+            match symbol {
+                Some(symbol) => info!("Attaching to function: {}:{}", target.display(), symbol),
+                None => info!("Attaching {function} to {}+{offset:#x}", target.display()),
+            }
+
+            let program: &mut UProbe = bpf
+                .program_mut(function.entry_program())
+                .unwrap()
+                .try_into()?;
+            program.load()?;
+            if opts.probes_return(*function, &functions) {
+                let program: &mut UProbe = bpf
+                    .program_mut(function.return_program())
+                    .unwrap()
+                    .try_into()?;
+                // the return programs are shared between functions
+                if program.fd().is_err() {
+                    program.load()?;
+                }
+            }
+        }
+        for offset in &opts.attach_offsets {
+            info!(
+                "Attaching {} to {}+{offset:#x}",
+                functions[0],
+                target.display()
+            );
+        }
+        let probes = Probes {
+            target: target.to_path_buf(),
+            functions,
+            symbols,
+            opts: opts.clone(),
+        };
+        probes.attach(&mut bpf, &opts.pids)?;
+
+        let live = if opts.free_tracking_flags() != 0 {
+            Some(PerCpuHashMap::try_from(bpf.take_map("LIVE").unwrap())?)
+        } else {
+            None
+        };
+        let free_sites = if opts.track_free_sites {
+            Some(PerCpuHashMap::try_from(
+                bpf.take_map("FREE_SITES").unwrap(),
+            )?)
+        } else {
+            None
+        };
+
+        let latencies = if opts.track_latency {
+            Some(PerCpuHashMap::try_from(bpf.take_map("LATENCIES").unwrap())?)
+        } else {
+            None
+        };
+
+        let stack_traces = StackTraceMap::try_from(bpf.take_map("STACKTRACES").unwrap())?;
+        let kernel_stack_traces = if opts.kernel_stacks {
+            Some(StackTraceMap::try_from(
+                bpf.take_map("KERNEL_STACKTRACES").unwrap(),
+            )?)
+        } else {
+            None
+        };
+
+        let start = std::time::Instant::now();
+        let per_cpu_map: PerCpuHashMap<_, HistogramKey, Histogram> =
+            PerCpuHashMap::try_from(bpf.take_map("HISTOGRAMS").unwrap())?;
+        info!(
+            "Opened per_cpu_map, took {:?}",
+            start.elapsed().as_secs_f64()
+        );
+
+        let maps = CollectorMaps {
+            histograms: per_cpu_map,
+            stack_traces,
+            kernel_stack_traces,
+            free_sites,
+            live,
+            latencies,
+            config: PerCpuArray::try_from(bpf.take_map("CONFIG").unwrap())?,
+            events: if opts.uses_ring_buffer() {
+                Some(RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?)
+            } else {
+                None
+            },
+        };
+
+        let bpf = Arc::new(Mutex::new(bpf));
+        let canceled = Arc::new(AtomicBool::new(false));
+        let children = opts.include_children.then(|| {
+            let roots = opts.pids.iter().flatten().copied().collect();
+            spawn_children_watcher(bpf.clone(), probes, roots, canceled.clone())
+        });
+        let collector = spawn_collector(
+            maps,
+            canceled.clone(),
+            CollectorConfig {
+                scale_factor: opts.scale_factor(),
+                size_biased: opts.size_biased(),
+                sample_every_bytes: opts.sample_every_bytes,
+                bucket_scheme: opts.buckets,
+                dwarf_unwind: opts.dwarf_unwind,
+                ..opts.collector
+            },
+        );
+        Ok(Self {
+            _bpf: bpf,
+            canceled,
+            collector,
+            children,
+        })
+    }
+
+    /// Set once the profiling stops, e.g. for a UI to exit with it
+    pub fn canceled(&self) -> Arc<AtomicBool> {
+        self.canceled.clone()
+    }
+
+    /// Collects for `duration`, then stops
+    pub fn collect_for(self, duration: Duration) -> anyhow::Result<EventProcessor> {
+        std::thread::sleep(duration);
+        self.stop()
+    }
+
+    /// Stops the profiling and returns everything captured, after the collector
+    /// drained the maps one last time
+    pub fn stop(self) -> anyhow::Result<EventProcessor> {
+        self.canceled.store(true, Ordering::Release);
+        // wakes the collector from its wait between polls
+        self.collector.thread().unpark();
+        let parent_pids = match self.children {
+            Some(children) => {
+                children.thread().unpark();
+                children
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("children watcher thread panicked"))?
+            }
+            None => FxHashMap::default(),
+        };
+        let mut processor = self
+            .collector
+            .join()
+            .map_err(|_| anyhow::anyhow!("collector thread panicked"))?;
+        processor.set_parent_pids(parent_pids);
+        Ok(processor)
+    }
+}
+
+impl Probes {
+    /// Attaches the loaded programs to the processes
+    fn attach(&self, bpf: &mut Ebpf, pids: &[Option<i32>]) -> anyhow::Result<()> {
+        let target = self.target.as_path();
+        let offset = self.opts.offset.unwrap_or(0);
+        for (function, symbol) in self.functions.iter().zip(&self.symbols) {
+            let program: &mut UProbe = bpf
+                .program_mut(function.entry_program())
+                .unwrap()
+                .try_into()?;
+            for pid in pids {
+                program.attach(symbol.as_deref(), offset, target, *pid)?;
+            }
+
+            if self.opts.probes_return(*function, &self.functions) {
+                let program: &mut UProbe = bpf
+                    .program_mut(function.return_program())
+                    .unwrap()
+                    .try_into()?;
+                for pid in pids {
+                    program.attach(symbol.as_deref(), offset, target, *pid)?;
+                }
+            }
+        }
+        for offset in &self.opts.attach_offsets {
+            let program: &mut UProbe = bpf
+                .program_mut(self.functions[0].entry_program())
+                .unwrap()
+                .try_into()?;
+            for pid in pids {
+                program.attach(None, *offset, target, *pid)?;
+            }
+        }
+        if self.opts.probes_frees(&self.functions) {
+            attach_free_tracking(bpf, target, pids, self.opts.symbol_prefix.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+/// Attaches the probes to every process the `roots` fork, until the profiling
+/// is `canceled`. Uprobes of a pid don't follow its children, so `/proc` is
+/// rescanned every [`CHILDREN_RESCAN_INTERVAL`]
+fn spawn_children_watcher(
+    bpf: Arc<Mutex<Ebpf>>,
+    probes: Probes,
+    roots: Vec<i32>,
+    canceled: Arc<AtomicBool>,
+) -> JoinHandle<FxHashMap<u32, u32>> {
+    std::thread::spawn(move || {
+        let mut parent_pids = FxHashMap::default();
+        while !canceled.load(Ordering::Acquire) {
+            let descendants = match process::find_descendants(&roots) {
+                Ok(descendants) => descendants,
+                Err(e) => {
+                    warn!("Failed to look for children of {roots:?}: {e}");
+                    Vec::new()
+                }
+            };
+            for (child, root) in descendants {
+                if parent_pids.contains_key(&(child as u32)) {
+                    continue;
+                }
+                // tried once, a failure is usually a child which already exited
+                parent_pids.insert(child as u32, root as u32);
+                match probes.attach(&mut bpf.lock().unwrap(), &[Some(child)]) {
+                    Ok(()) => info!("Attached to {child}, a child of {root}"),
+                    Err(e) => warn!("Failed to attach to {child}, a child of {root}: {e}"),
+                }
+            }
+            std::thread::park_timeout(CHILDREN_RESCAN_INTERVAL);
+        }
+        parent_pids
+    })
+}
+
+/// Writes the settings of the ebpf programs to the `CONFIG` map, in the order
+/// of [`config_values`]
+fn write_config(
+    bpf: &mut Ebpf,
+    functions: &[JemallocAllocFunctions],
+    opts: &ProfilerOptions,
+) -> anyhow::Result<()> {
+    let config_map = bpf.map_mut("CONFIG").expect("CONFIG not found");
+    let mut config_map = PerCpuArray::try_from(config_map)?;
+    let num_cpus = nr_cpus().unwrap();
+    for (index, value) in config_values(functions, opts) {
+        config_map.set(index, PerCpuValues::try_from(vec![value; num_cpus])?, 0)?;
+    }
+    Ok(())
+}
+
+/// Every `CONFIG` slot with its value, the same on every cpu. The ready flag
+/// is the last one, the probes record nothing until they see it
+fn config_values(functions: &[JemallocAllocFunctions], opts: &ProfilerOptions) -> Vec<(u32, u64)> {
+    let mut values = vec![
+        (MIN_ALLOC_INDEX, opts.min_alloc_size),
+        (MAX_ALLOC_INDEX, opts.max_alloc_size),
+    ];
+    if !opts.no_clear {
+        values.extend([(COUNT_INDEX, 0), (SAMPLED_BYTES_INDEX, 0)]);
+    }
+    values.extend([
+        // zero disables it on the ebpf side
+        (
+            SAMPLE_EVERY_BYTES_INDEX,
+            opts.sample_every_bytes.unwrap_or(0),
+        ),
+        (HISTOGRAMS_INSERTED_INDEX, 0),
+        (RING_BUFFER_INDEX, opts.uses_ring_buffer() as u64),
+        (DWARF_UNWIND_INDEX, opts.dwarf_unwind as u64),
+        (RING_BUFFER_DROPS_INDEX, 0),
+        (STACK_ID_FAILURES_INDEX, 0),
+        (ARG_READ_FAILURES_INDEX, 0),
+        (INSERT_FAILURES_INDEX, 0),
+        (SAMPLE_EVERY_INDEX, opts.sample_every.get() as u64),
+        // zero disables it on the ebpf side
+        (
+            SAMPLE_ABOVE_INDEX,
+            opts.sample_above.map_or(0, |size| size.max(1)),
+        ),
+    ]);
+    for function in functions {
+        values.push((
+            FUNCTION_INFO_INDEX + function.id(),
+            opts.size_arg_index
+                .unwrap_or_else(|| function.allocation_arg_index()),
+        ));
+        // a custom size argument is the whole size, not a factor of it
+        let multiplier_index = match opts.size_arg_index {
+            Some(_) => None,
+            None => function.size_multiplier_arg_index(),
+        };
+        values.push((
+            SIZE_MULTIPLIER_INDEX + function.id(),
+            multiplier_index.unwrap_or(NO_SIZE_MULTIPLIER),
+        ));
+    }
+    let (bucket_scheme, bucket_param) = opts.buckets.to_config();
+    values.extend([
+        // thread filters need the tid of every allocation
+        (
+            GROUP_BY_THREAD_INDEX,
+            (matches!(opts.group_by, GroupBy::Thread) || opts.collector.thread_filter.is_active())
+                as u64,
+        ),
+        (TRACK_FREES_INDEX, opts.pointer_tracking_flags(functions)),
+        (TRACK_LATENCY_INDEX, opts.track_latency as u64),
+        (BUCKET_SCHEME_INDEX, bucket_scheme),
+        (BUCKET_PARAM_INDEX, bucket_param),
+        (KERNEL_STACKS_INDEX, opts.kernel_stacks as u64),
+        // last, see above
+        (CONFIG_READY_INDEX, 1),
+    ]);
+    values
+}
+
+/// Symbols of the allocation `functions` in the binary. A missing one is an
+/// error which lists the allocation functions the binary does have.
+fn find_symbols(
+    target: &Path,
+    functions: &[JemallocAllocFunctions],
+    symbol_prefix: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    let mut symbols = Vec::with_capacity(functions.len());
+    for function in functions {
+        if let Some(symbol) = resolver::find_function(target, &function.to_string(), symbol_prefix)?
+        {
+            symbols.push(symbol);
+            continue;
+        }
+        let present = JemallocAllocFunctions::ALL
+            .iter()
+            .filter(|f| {
+                matches!(
+                    resolver::find_function(target, &f.to_string(), symbol_prefix),
+                    Ok(Some(_))
+                )
+            })
+            .join(", ");
+        let present = match present.as_str() {
+            "" => "no allocation function jeprofl traces".to_string(),
+            present => format!("only {present}"),
+        };
+        anyhow::bail!(
+            "{} has no {function} function but {present}. Pass --symbol-prefix or --offset if it is named differently",
+            target.display()
+        );
+    }
+    Ok(symbols)
+}
+
+/// Smallest ring buffer size, ring buffers are page aligned
+const PAGE_SIZE: u32 = 4096;
+
+/// Removes the histograms and stacks in the maps, so the capture starts from a
+/// clean slate even if the maps outlived a previous run. Returns the number of
+/// removed entries
+fn clear_maps(bpf: &mut Ebpf) -> anyhow::Result<usize> {
+    let mut histograms: PerCpuHashMap<_, HistogramKey, Histogram> =
+        PerCpuHashMap::try_from(bpf.map_mut("HISTOGRAMS").unwrap())?;
+    let keys: Vec<_> = histograms.keys().collect::<Result<_, _>>()?;
+    for key in &keys {
+        histograms.remove(key)?;
+    }
+    let mut cleared = keys.len();
+    for name in ["STACKTRACES", "KERNEL_STACKTRACES"] {
+        let mut stack_traces = StackTraceMap::try_from(bpf.map_mut(name).unwrap())?;
+        let stack_ids: Vec<_> = stack_traces.stack_ids().collect::<Result<_, _>>()?;
+        for stack_id in &stack_ids {
+            stack_traces.remove(stack_id)?;
+        }
+        cleared += stack_ids.len();
+    }
+    Ok(cleared)
+}
+
+/// Functions which free memory, the pointer is the first argument of all of them.
+/// Only `free` is mandatory, sized deallocation is jemalloc specific.
+const FREE_FUNCTIONS: [&str; 3] = ["free", "sdallocx", "dallocx"];
+
+/// Attaches uprobes on the deallocation functions. Returned pointers are
+/// learned by the uretprobe on the allocation function.
+fn attach_free_tracking(
+    bpf: &mut Ebpf,
+    target: &Path,
+    pids: &[Option<i32>],
+    symbol_prefix: Option<&str>,
+) -> anyhow::Result<()> {
+    let program: &mut UProbe = bpf.program_mut("free").unwrap().try_into()?;
+    // loaded by the first of the processes
+    if program.fd().is_err() {
+        program.load()?;
+    }
+    for function in FREE_FUNCTIONS {
+        // unresolved names are attached as is, to report why they failed
+        let symbol = resolver::find_function(target, function, symbol_prefix)?
+            .unwrap_or_else(|| function.to_string());
+        info!("Attaching to function: {}:{}", target.display(), symbol);
+        for pid in pids {
+            match program.attach(Some(symbol.as_str()), 0, target, *pid) {
+                Ok(_) => {}
+                Err(e) if function == "free" => return Err(e.into()),
+                Err(e) => {
+                    warn!("Failed to attach to {function}, its frees are not tracked: {e}");
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_functions_are_reported() {
+        // the test binary imports malloc from libc instead of defining it
+        let binary = std::env::current_exe().unwrap();
+        let err = find_symbols(&binary, &[JemallocAllocFunctions::Mallocx], None).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has no mallocx function but no allocation function jeprofl traces"));
+    }
+
+    #[test]
+    fn sampling_settings_reach_the_collector() {
+        let opts = ProfilerOptions {
+            sample_every: NonZeroU32::new(100).unwrap(),
+            ..ProfilerOptions::default()
+        };
+        assert_eq!(opts.scale_factor(), 100);
+        assert_eq!(opts.size_biased(), None);
+        let opts = ProfilerOptions {
+            sample_above: Some(4096),
+            track_frees: true,
+            ..opts
+        };
+        assert_eq!(opts.scale_factor(), 1);
+        assert_eq!(
+            opts.size_biased(),
+            Some(SizeBiasedSampling {
+                threshold: 4096,
+                sample_every: 100,
+            })
+        );
+        assert_eq!(opts.free_tracking_flags(), TRACK_FREES);
+        assert!(!opts.uses_ring_buffer());
+        let opts = ProfilerOptions {
+            sample_every_bytes: Some(512 * 1024),
+            ..ProfilerOptions::default()
+        };
+        assert_eq!(opts.scale_factor(), 1);
+    }
+
+    #[test]
+    fn resized_pointers_are_forgotten_when_freed() {
+        // `realloc` looks up the old size of its pointer, a freed one the
+        // allocator hands out again must not keep its stale size
+        let opts = ProfilerOptions::default();
+        assert!(opts.probes_frees(&[JemallocAllocFunctions::Realloc]));
+        assert!(opts.probes_frees(&[
+            JemallocAllocFunctions::Malloc,
+            JemallocAllocFunctions::Rallocx
+        ]));
+        assert!(!opts.probes_frees(&[JemallocAllocFunctions::Malloc]));
+        let opts = ProfilerOptions {
+            track_frees: true,
+            ..opts
+        };
+        assert!(opts.probes_frees(&[JemallocAllocFunctions::Malloc]));
+    }
+
+    #[test]
+    fn malloc_pointers_are_tracked_for_realloc() {
+        // `realloc` of a pointer `malloc` returned must find its old size,
+        // also without `--track-frees`
+        let opts = ProfilerOptions::default();
+        let malloc = JemallocAllocFunctions::Malloc;
+        let functions = [malloc, JemallocAllocFunctions::Realloc];
+        assert!(opts.probes_return(malloc, &functions));
+        assert_eq!(opts.pointer_tracking_flags(&functions), TRACK_POINTERS);
+        assert!(config_values(&functions, &opts).contains(&(TRACK_FREES_INDEX, TRACK_POINTERS)));
+        // the live memory is still reported only with `--track-frees`
+        assert_eq!(opts.free_tracking_flags(), 0);
+        assert!(!opts.probes_return(malloc, &[malloc]));
+        assert_eq!(opts.pointer_tracking_flags(&[malloc]), 0);
+    }
+
+    #[test]
+    fn xallocx_results_are_not_tracked_as_pointers() {
+        let opts = ProfilerOptions {
+            track_free_sites: true,
+            ..ProfilerOptions::default()
+        };
+        let functions = [
+            JemallocAllocFunctions::Mallocx,
+            JemallocAllocFunctions::Xallocx,
+        ];
+        assert!(opts.probes_return(JemallocAllocFunctions::Mallocx, &functions));
+        assert!(!opts.probes_return(JemallocAllocFunctions::Xallocx, &functions));
+        let opts = ProfilerOptions {
+            track_latency: true,
+            ..opts
+        };
+        assert!(opts.probes_return(JemallocAllocFunctions::Xallocx, &functions));
+        assert_eq!(
+            JemallocAllocFunctions::Xallocx.return_program(),
+            "xallocx_ret"
+        );
+    }
+
+    #[test]
+    fn config_ready_flag_is_written_last() {
+        let functions = [
+            JemallocAllocFunctions::Malloc,
+            JemallocAllocFunctions::Rallocx,
+        ];
+        for opts in [
+            ProfilerOptions::default(),
+            ProfilerOptions {
+                no_clear: true,
+                track_latency: true,
+                ..ProfilerOptions::default()
+            },
+        ] {
+            let values = config_values(&functions, &opts);
+            assert_eq!(values.last(), Some(&(CONFIG_READY_INDEX, 1)));
+            let ready = values
+                .iter()
+                .filter(|(index, _)| *index == CONFIG_READY_INDEX)
+                .count();
+            assert_eq!(ready, 1);
+            assert!(values
+                .iter()
+                .all(|(index, _)| *index < jeprofl_common::CONFIG_SIZE));
+        }
+    }
+}
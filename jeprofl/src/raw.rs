@@ -0,0 +1,210 @@
+//! Unsymbolized profiles written with `--raw-output`. They keep the raw
+//! instruction pointers of every stack together with the memory mappings of
+//! the traced processes, so the stacks can be symbolized later, after the
+//! target exited or on a different machine, with `jeprofl symbolize`.
+
+use crate::collector::SizeBiasedSampling;
+use crate::resolver::{OwnedSymbol, ResolvedStackTrace, Resolver};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RawProfile {
+    pub scale_factor: u64,
+    /// `--sample-above` sampling, the stacks are already weighted
+    #[serde(default)]
+    pub size_biased: Option<SizeBiasedSampling>,
+    /// `--sample-every-bytes` sampling, the stacks are already weighted
+    #[serde(default)]
+    pub sample_every_bytes: Option<u64>,
+    /// `--buckets` scheme the histograms were recorded with
+    pub bucket_scheme: String,
+    pub processes: Vec<RawProcess>,
+    pub stacks: Vec<RawStack>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RawProcess {
+    pub pid: u32,
+    pub mappings: Vec<RawMapping>,
+}
+
+/// Executable file backed mapping from `/proc/<pid>/maps`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RawMapping {
+    pub start: u64,
+    pub end: u64,
+    /// Offset of the mapping in the file
+    pub offset: u64,
+    pub path: String,
+    /// Hex encoded GNU build id of the file, if it has one
+    pub build_id: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RawStack {
+    pub pid: u32,
+    pub tid: u32,
+    pub stack_id: u32,
+    pub function_id: u32,
+    pub total: u64,
+    pub overflow: u64,
+    /// Counts of the histogram buckets
+    pub buckets: Vec<u64>,
+    /// Instruction pointers from the leaf to the root, empty if the stack was lost
+    pub ips: Vec<u64>,
+}
+
+impl RawProfile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse {path:?}, expected a --raw-output file"))
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+/// Executable mappings of the process, read while it is still running
+pub fn read_mappings(pid: u32) -> anyhow::Result<Vec<RawMapping>> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    let mut mappings: Vec<RawMapping> = maps.lines().filter_map(parse_mapping).collect();
+    for mapping in &mut mappings {
+        // the target may live in another mount namespace
+        let path = format!("/proc/{pid}/root{}", mapping.path);
+        mapping.build_id = blazesym::helper::read_elf_build_id(&path)
+            .ok()
+            .flatten()
+            .map(|id| hex(&id));
+    }
+    Ok(mappings)
+}
+
+/// Parses an executable file backed line of `/proc/<pid>/maps`:
+/// `start-end perms offset dev inode path`
+pub(crate) fn parse_mapping(line: &str) -> Option<RawMapping> {
+    let mut fields = line.split_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?;
+    let offset = fields.next()?;
+    let path = fields.nth(2)?;
+    if !perms.contains('x') || !path.starts_with('/') {
+        return None;
+    }
+    Some(RawMapping {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        offset: u64::from_str_radix(offset, 16).ok()?,
+        path: path.to_string(),
+        build_id: None,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Symbolizes captured stacks against local copies of the traced binaries.
+pub struct OfflineSymbolizer {
+    resolver: Resolver,
+    /// Local binaries with their build ids
+    binaries: Vec<(PathBuf, Option<String>)>,
+}
+
+impl OfflineSymbolizer {
+    pub fn new(binaries: &[PathBuf]) -> anyhow::Result<Self> {
+        let binaries = binaries
+            .iter()
+            .map(|path| {
+                let build_id = blazesym::helper::read_elf_build_id(path)
+                    .with_context(|| format!("Failed to read {path:?}"))?
+                    .map(|id| hex(&id));
+                Ok((path.clone(), build_id))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            resolver: Resolver::new(),
+            binaries,
+        })
+    }
+
+    /// Local binary for the mapping: the one with the same build id, or with
+    /// the same file name if build ids are unknown
+    fn binary_for(&self, mapping: &RawMapping) -> Option<&Path> {
+        let file_name = Path::new(&mapping.path).file_name();
+        self.binaries
+            .iter()
+            .find(|(_, build_id)| build_id.is_some() && *build_id == mapping.build_id)
+            .or_else(|| {
+                self.binaries.iter().find(|(path, build_id)| {
+                    path.file_name() == file_name
+                        && (build_id.is_none() || mapping.build_id.is_none())
+                })
+            })
+            .map(|(path, _)| path.as_path())
+    }
+
+    pub fn symbolize(&self, ips: &[u64], mappings: &[RawMapping]) -> ResolvedStackTrace {
+        let symbols = ips
+            .iter()
+            .flat_map(|&ip| {
+                let resolved = mappings
+                    .iter()
+                    .find(|m| (m.start..m.end).contains(&ip))
+                    .and_then(|m| Some((m, self.binary_for(m)?)))
+                    .and_then(|(m, binary)| {
+                        let offset = ip - m.start + m.offset;
+                        self.resolver.resolve_file_offsets(binary, &[offset]).ok()
+                    });
+                match resolved {
+                    Some(trace) => trace.symbols,
+                    None => vec![OwnedSymbol {
+                        address: 0,
+                        symbol: format!("{ip:#x}"),
+                        file: None,
+                        line: None,
+                        inlined: Vec::new(),
+                    }],
+                }
+            })
+            .collect();
+        ResolvedStackTrace { symbols }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_executable_mappings() {
+        let maps = "\
+55d0c0a00000-55d0c0a42000 r--p 00000000 fd:01 1234 /usr/bin/app
+55d0c0a42000-55d0c0b00000 r-xp 00042000 fd:01 1234 /usr/bin/app
+7f0000000000-7f0000021000 rw-p 00000000 00:00 0
+7ffd00000000-7ffd00021000 r-xp 00000000 00:00 0 [vdso]";
+        let mappings: Vec<_> = maps.lines().filter_map(parse_mapping).collect();
+        assert_eq!(
+            mappings,
+            [RawMapping {
+                start: 0x55d0c0a42000,
+                end: 0x55d0c0b00000,
+                offset: 0x42000,
+                path: "/usr/bin/app".to_string(),
+                build_id: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn unmapped_addresses_stay_raw() {
+        let symbolizer = OfflineSymbolizer::new(&[]).unwrap();
+        let trace = symbolizer.symbolize(&[0x1000], &[]);
+        assert_eq!(trace.symbols[0].symbol, "0x1000");
+        assert_eq!(trace.symbols[0].address, 0);
+    }
+}
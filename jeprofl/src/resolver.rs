@@ -1,19 +1,122 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use std::cell::{Cell, RefCell};
 use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 
+use crate::debuginfod::Debuginfod;
+use crate::raw::{self, RawMapping};
+use crate::symbol_cache::SymbolCache;
+use crate::JemallocAllocFunctions;
 use aya::maps::stack_trace::StackTrace;
-use blazesym::symbolize::{Input, Process, Source, Symbolized};
-use blazesym::Pid;
+use blazesym::helper::ElfResolver;
+use blazesym::symbolize::{
+    Elf, Input, Kernel, Process, ProcessMemberInfo, ProcessMemberType, Resolve, Source, Symbolized,
+};
+use blazesym::{inspect, Pid};
 use itertools::Itertools;
+use regex::Regex;
+
+/// Pid under which kernel frames are cached
+const KERNEL_PID: u32 = 0;
 
 pub struct Resolver {
     symbolizer: blazesym::symbolize::Symbolizer,
+    /// Resolved frames by pid and address, different stacks share most of their frames
+    frames: RefCell<FxHashMap<(u32, u64), OwnedSymbol>>,
+    cache_hits: Cell<u64>,
+    cache_misses: Cell<u64>,
+    symbolize_only: Vec<Regex>,
+    fallback_binary: Option<PathBuf>,
+    symbol_cache: Option<RefCell<SymbolCache>>,
+    /// Executable mappings by pid, read once `symbolize_only`, `fallback_binary`
+    /// or `symbol_cache` needs them
+    mappings: RefCell<FxHashMap<u32, Vec<RawMapping>>>,
+}
+
+/// What is resolved besides the symbol names
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    /// Source file and line of every frame, reads the DWARF debug info
+    pub code_info: bool,
+    /// Functions inlined at every frame, needs `code_info`
+    pub inlined_fns: bool,
+    /// Fetch the debug info of binaries from debuginfod servers
+    pub debuginfod: Option<Debuginfod>,
+    /// Symbolize only frames of mappings which path matches one of these,
+    /// others get the name of their module, see `--symbolize-only`
+    pub symbolize_only: Vec<Regex>,
+    /// Binary on disk the frames of its mappings are symbolized with once the
+    /// process exited, usually the traced program
+    pub fallback_binary: Option<PathBuf>,
+    /// Directory of the symbols cached between runs, see [`SymbolCache`]
+    pub symbol_cache: Option<PathBuf>,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        Self {
+            code_info: true,
+            inlined_fns: true,
+            debuginfod: None,
+            symbolize_only: Vec::new(),
+            fallback_binary: None,
+            symbol_cache: None,
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Resolver {
     pub fn new() -> Resolver {
-        let symbolizer = blazesym::symbolize::Symbolizer::new();
-        Resolver { symbolizer }
+        Self::with_options(ResolverOptions::default())
+    }
+
+    pub fn with_options(options: ResolverOptions) -> Resolver {
+        // the target binary doesn't change while it runs, so parsed ELF and DWARF
+        // data is kept for the whole run without checking the files for updates
+        let mut builder = blazesym::symbolize::Symbolizer::builder()
+            .enable_auto_reload(false)
+            .enable_code_info(options.code_info)
+            .enable_inlined_fns(options.inlined_fns);
+        if let Some(debuginfod) = options.debuginfod {
+            // blazesym caches the resolver of every mapped file, so each one is looked up once
+            builder = builder.set_process_dispatcher(move |info: ProcessMemberInfo<'_>| {
+                let ProcessMemberType::Path(path) = info.member_entry else {
+                    return Ok(None);
+                };
+                // `None` falls back to the symbols of the binary itself
+                let Some(debug_file) = debuginfod.debug_file(&path.maps_file) else {
+                    return Ok(None);
+                };
+                let resolver: Box<dyn Resolve> = Box::new(ElfResolver::open(debug_file)?);
+                Ok(Some(resolver))
+            });
+        }
+        let symbolizer = builder.build();
+        Resolver {
+            symbolizer,
+            frames: Default::default(),
+            cache_hits: Cell::new(0),
+            cache_misses: Cell::new(0),
+            symbolize_only: options.symbolize_only,
+            fallback_binary: options.fallback_binary,
+            symbol_cache: options.symbol_cache.map(|dir| {
+                // symbols resolved with fewer details can't serve a run which wants them
+                let details = match (options.code_info, options.inlined_fns) {
+                    (true, true) => "inlined",
+                    (true, false) => "lines",
+                    (false, _) => "names",
+                };
+                RefCell::new(SymbolCache::new(dir.join(details)))
+            }),
+            mappings: Default::default(),
+        }
     }
 
     pub fn resolve_stacktrace(
@@ -21,21 +124,301 @@ impl Resolver {
         stacktrace: &StackTrace,
         pid: u32,
     ) -> Result<ResolvedStackTrace> {
-        let pid = Pid::Pid(NonZeroU32::new(pid).unwrap());
-        let stacktrace: Vec<_> = stacktrace.frames().iter().map(|x| x.ip).collect();
-        let stacktrace = Input::AbsAddr(stacktrace.as_slice());
+        self.resolve_ips(&ips(stacktrace), pid)
+    }
+
+    /// Resolves instruction pointers of the process, e.g. of an unwound stack
+    /// Fails for pid 0, which no user process has
+    pub fn resolve_ips(&self, ips: &[u64], pid: u32) -> Result<ResolvedStackTrace> {
+        let pid = NonZeroU32::new(pid).context("invalid pid 0")?;
+        let source = Source::Process(Process::new(Pid::Pid(pid)));
+        let pid = pid.get();
+        if self.fallback_binary.is_some() || self.symbol_cache.is_some() {
+            // read while the process is alive, the fallback needs them after it exited
+            self.with_mappings(pid, |_| ());
+        }
+        self.resolve_cached(pid, ips, |ips| {
+            self.resolve_through_symbol_cache(pid, ips, |ips| {
+                match self.resolve_through_process(&source, pid, ips) {
+                    Ok(trace) => {
+                        log::debug!(
+                            "Symbolized {} frames of pid {pid} through the process",
+                            ips.len()
+                        );
+                        Ok(trace)
+                    }
+                    Err(e) => match self.resolve_through_binary(pid, ips) {
+                        Some((binary, trace)) => {
+                            log::debug!(
+                                "Symbolized {} frames of pid {pid} through {} as the process failed: {e}",
+                                ips.len(),
+                                binary.display()
+                            );
+                            Ok(trace)
+                        }
+                        None => Err(e),
+                    },
+                }
+            })
+        })
+    }
+
+    /// Takes the frames of binaries with a build id from the symbol cache and
+    /// resolves the others with `resolve`, caching what it symbolized
+    fn resolve_through_symbol_cache(
+        &self,
+        pid: u32,
+        ips: &[u64],
+        resolve: impl FnOnce(&[u64]) -> Result<ResolvedStackTrace>,
+    ) -> Result<ResolvedStackTrace> {
+        let Some(cache) = &self.symbol_cache else {
+            return resolve(ips);
+        };
+        // frames outside of `symbolize_only` get placeholders, which aren't cached
+        let locations: Vec<Option<(String, u64)>> = self.with_mappings(pid, |mappings| {
+            ips.iter()
+                .map(|ip| {
+                    let m = mappings.iter().find(|m| (m.start..m.end).contains(ip))?;
+                    let symbolized = self.symbolize_only.is_empty()
+                        || self.symbolize_only.iter().any(|re| re.is_match(&m.path));
+                    if !symbolized {
+                        return None;
+                    }
+                    Some((m.build_id.clone()?, ip - m.start + m.offset))
+                })
+                .collect()
+        });
+        let mut cache = cache.borrow_mut();
+        let cached: Vec<Option<OwnedSymbol>> = locations
+            .iter()
+            .map(|location| {
+                let (build_id, offset) = location.as_ref()?;
+                cache.get(build_id, *offset).cloned()
+            })
+            .collect();
+        let missing: Vec<u64> = ips
+            .iter()
+            .zip(&cached)
+            .filter(|(_, symbol)| symbol.is_none())
+            .map(|(ip, _)| *ip)
+            .collect();
+        let mut resolved = if missing.is_empty() {
+            Vec::new()
+        } else {
+            resolve(&missing)?.symbols
+        }
+        .into_iter();
+        let symbols = cached
+            .into_iter()
+            .zip(locations)
+            .map(|(cached, location)| {
+                if cached.is_some() {
+                    return cached;
+                }
+                let symbol = resolved.next()?;
+                match location {
+                    Some((build_id, offset)) if !symbol.is_unknown() => {
+                        cache.insert(&build_id, offset, symbol.clone());
+                    }
+                    _ => {}
+                }
+                Some(symbol)
+            })
+            .collect::<Option<_>>()
+            .context("fewer symbols than addresses were resolved")?;
+        Ok(ResolvedStackTrace { symbols })
+    }
+
+    /// Writes the frames symbolized in this run to the symbol cache
+    pub fn save_symbol_cache(&self) -> Result<()> {
+        match &self.symbol_cache {
+            Some(cache) => cache.borrow_mut().save(),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve_through_process(
+        &self,
+        source: &Source,
+        pid: u32,
+        ips: &[u64],
+    ) -> Result<ResolvedStackTrace> {
+        if self.symbolize_only.is_empty() {
+            return self.symbolize(source, Input::AbsAddr(ips));
+        }
+        let placeholders = self.placeholders(pid, ips);
+        let wanted: Vec<u64> = ips
+            .iter()
+            .zip(&placeholders)
+            .filter(|(_, placeholder)| placeholder.is_none())
+            .map(|(ip, _)| *ip)
+            .collect();
+        let mut symbolized = if wanted.is_empty() {
+            Vec::new()
+        } else {
+            self.symbolize(source, Input::AbsAddr(&wanted))?.symbols
+        }
+        .into_iter();
+        let symbols = placeholders
+            .into_iter()
+            .map(|placeholder| placeholder.or_else(|| symbolized.next()))
+            .collect::<Option<_>>()
+            .context("blazesym returned fewer symbols than addresses")?;
+        Ok(ResolvedStackTrace { symbols })
+    }
+
+    /// Symbolizes the frames of `fallback_binary` with the file on disk and the
+    /// mappings read while the process was alive, other frames get the name of
+    /// their module. `None` without the binary or the mappings
+    fn resolve_through_binary(&self, pid: u32, ips: &[u64]) -> Option<(&Path, ResolvedStackTrace)> {
+        let binary = self.fallback_binary.as_deref()?;
+        let file_name = binary.file_name()?;
+        // the maps may name the file in another mount namespace
+        let frames: Vec<Result<u64, OwnedSymbol>> = self.with_mappings(pid, |mappings| {
+            ips.iter()
+                .map(|ip| {
+                    let mapping = mappings.iter().find(|m| (m.start..m.end).contains(ip));
+                    match mapping {
+                        Some(m) if Path::new(&m.path).file_name() == Some(file_name) => {
+                            Ok(ip - m.start + m.offset)
+                        }
+                        _ => Err(module_placeholder(*ip, mapping.map(|m| m.path.as_str()))),
+                    }
+                })
+                .collect()
+        });
+        let offsets: Vec<u64> = frames
+            .iter()
+            .filter_map(|frame| frame.clone().ok())
+            .collect();
+        if offsets.is_empty() {
+            return None;
+        }
+        let mut symbolized = self
+            .resolve_file_offsets(binary, &offsets)
+            .ok()?
+            .symbols
+            .into_iter();
+        let symbols = frames
+            .into_iter()
+            .map(|frame| match frame {
+                Ok(_) => symbolized.next(),
+                Err(placeholder) => Some(placeholder),
+            })
+            .collect::<Option<_>>()?;
+        Some((binary, ResolvedStackTrace { symbols }))
+    }
+
+    /// Calls `f` with the executable mappings of the process, read on first use
+    fn with_mappings<T>(&self, pid: u32, f: impl FnOnce(&[RawMapping]) -> T) -> T {
+        let mut mappings = self.mappings.borrow_mut();
+        let mappings = mappings.entry(pid).or_insert_with(|| {
+            // the symbol cache is keyed by build id, which is read from every file
+            if self.symbol_cache.is_some() {
+                return raw::read_mappings(pid).unwrap_or_default();
+            }
+            std::fs::read_to_string(format!("/proc/{pid}/maps"))
+                .map(|maps| maps.lines().filter_map(raw::parse_mapping).collect())
+                .unwrap_or_default()
+        });
+        f(mappings)
+    }
+
+    /// Module placeholders of the addresses which mappings don't match
+    /// `symbolize_only`, `None` for the ones to symbolize
+    fn placeholders(&self, pid: u32, ips: &[u64]) -> Vec<Option<OwnedSymbol>> {
+        self.with_mappings(pid, |mappings| {
+            ips.iter()
+                .map(|ip| {
+                    let path = mappings
+                        .iter()
+                        .find(|m| (m.start..m.end).contains(ip))
+                        .map(|m| m.path.as_str());
+                    match path {
+                        Some(path) if self.symbolize_only.iter().any(|re| re.is_match(path)) => {
+                            None
+                        }
+                        _ => Some(module_placeholder(*ip, path)),
+                    }
+                })
+                .collect()
+        })
+    }
+
+    pub fn resolve_kernel_stacktrace(&self, stacktrace: &StackTrace) -> Result<ResolvedStackTrace> {
+        let source = Source::Kernel(Kernel::default());
+        self.resolve_cached(KERNEL_PID, &ips(stacktrace), |ips| {
+            self.symbolize(&source, Input::AbsAddr(ips))
+        })
+    }
+
+    /// Number of frames served from the cache and resolved by blazesym
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.cache_hits.get(), self.cache_misses.get())
+    }
+
+    /// Resolves only the frames which aren't cached yet, with one `resolve` call
+    fn resolve_cached(
+        &self,
+        pid: u32,
+        ips: &[u64],
+        resolve: impl FnOnce(&[u64]) -> Result<ResolvedStackTrace>,
+    ) -> Result<ResolvedStackTrace> {
+        let missing: Vec<u64> = {
+            let frames = self.frames.borrow();
+            ips.iter()
+                .copied()
+                .filter(|ip| !frames.contains_key(&(pid, *ip)))
+                .unique()
+                .collect()
+        };
+        self.cache_hits
+            .set(self.cache_hits.get() + (ips.len() - missing.len()) as u64);
+        self.cache_misses
+            .set(self.cache_misses.get() + missing.len() as u64);
+
+        let mut frames = self.frames.borrow_mut();
+        if !missing.is_empty() {
+            let resolved = resolve(&missing)?;
+            for (ip, symbol) in missing.iter().zip(resolved.symbols) {
+                frames.insert((pid, *ip), symbol);
+            }
+        }
+        let symbols = ips
+            .iter()
+            .filter_map(|ip| frames.get(&(pid, *ip)).cloned())
+            .collect();
+        Ok(ResolvedStackTrace { symbols })
+    }
+
+    /// Resolves offsets into the ELF file, e.g. of a binary copied from the traced machine
+    pub fn resolve_file_offsets(&self, path: &Path, offsets: &[u64]) -> Result<ResolvedStackTrace> {
+        self.symbolize(&Source::Elf(Elf::new(path)), Input::FileOffset(offsets))
+    }
+
+    fn symbolize(&self, source: &Source, input: Input<&[u64]>) -> Result<ResolvedStackTrace> {
         let res = self
             .symbolizer
-            .symbolize(&Source::Process(Process::new(pid)), stacktrace)?
+            .symbolize(source, input)?
             .into_iter()
             .map(|x| match x {
                 Symbolized::Sym(s) => OwnedSymbol {
                     address: s.addr,
                     symbol: s.name.to_string(),
+                    file: s
+                        .code_info
+                        .as_ref()
+                        .map(|info| info.to_path().to_string_lossy().into_owned()),
+                    line: s.code_info.as_ref().and_then(|info| info.line),
+                    // blazesym reports them from the outermost call
+                    inlined: s.inlined.iter().rev().map(|f| f.name.to_string()).collect(),
                 },
                 Symbolized::Unknown(reason) => OwnedSymbol {
                     address: 0,
                     symbol: reason.to_string(),
+                    file: None,
+                    line: None,
+                    inlined: Vec::new(),
                 },
             })
             .collect();
@@ -44,22 +427,351 @@ impl Resolver {
     }
 }
 
+/// Frame named after the module it's mapped from, `[unknown]` if none is
+fn module_placeholder(ip: u64, path: Option<&str>) -> OwnedSymbol {
+    OwnedSymbol {
+        address: ip,
+        symbol: format!("[{}]", path.map_or("unknown", module_name)),
+        file: None,
+        line: None,
+        inlined: Vec::new(),
+    }
+}
+
+/// Short name of a mapped file, `libc` of `/usr/lib/libc.so.6`
+fn module_name(path: &str) -> &str {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    file.split(".so").next().unwrap_or(file)
+}
+
+/// Prefixes statically linked jemalloc gives its functions, e.g. `_rjem_` of
+/// tikv-jemallocator, tried in this order
+const JEMALLOC_PREFIXES: [&str; 3] = ["", "_rjem_", "je_"];
+
+/// Symbol the binary defines `function` under: with `prefix` if given, otherwise
+/// with the first of [`JEMALLOC_PREFIXES`] it has. `None` if there is none.
+pub fn find_function(
+    binary: &Path,
+    function: &str,
+    prefix: Option<&str>,
+) -> Result<Option<String>> {
+    let candidates: Vec<String> = match prefix {
+        Some(prefix) => vec![format!("{prefix}{function}")],
+        None => JEMALLOC_PREFIXES
+            .iter()
+            .map(|prefix| format!("{prefix}{function}"))
+            .collect(),
+    };
+    let names: Vec<&str> = candidates.iter().map(String::as_str).collect();
+    let mut elf = inspect::Elf::new(binary);
+    // only the symbol tables are needed
+    elf.debug_syms = false;
+    let inspector = inspect::Inspector::new();
+    let found = inspector.lookup(&inspect::Source::Elf(elf), &names)?;
+    // imported symbols have no address in the binary
+    Ok(candidates
+        .into_iter()
+        .zip(found)
+        .find(|(_, syms)| syms.iter().any(|sym| sym.addr != 0))
+        .map(|(name, _)| name))
+}
+
+/// Allocation function the binary defines
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AllocatorSymbol {
+    pub function: JemallocAllocFunctions,
+    /// Name with its prefix, e.g. `_rjem_malloc`
+    pub name: String,
+    pub address: u64,
+    /// Where in the file the uprobe attaches, `None` if unknown
+    pub file_offset: Option<u64>,
+}
+
+/// Every allocation function the binary defines, under any of
+/// [`JEMALLOC_PREFIXES`]
+pub fn allocator_symbols(binary: &Path) -> Result<Vec<AllocatorSymbol>> {
+    let candidates: Vec<(JemallocAllocFunctions, String)> = JemallocAllocFunctions::ALL
+        .iter()
+        .flat_map(|function| {
+            JEMALLOC_PREFIXES
+                .iter()
+                .map(move |prefix| (*function, format!("{prefix}{function}")))
+        })
+        .collect();
+    let names: Vec<&str> = candidates.iter().map(|(_, name)| name.as_str()).collect();
+    let mut elf = inspect::Elf::new(binary);
+    elf.debug_syms = false;
+    let inspector = inspect::Inspector::new();
+    let found = inspector.lookup(&inspect::Source::Elf(elf), &names)?;
+    Ok(candidates
+        .into_iter()
+        .zip(found)
+        .flat_map(|((function, name), syms)| {
+            syms.into_iter()
+                // imported symbols have no address in the binary
+                .filter(|sym| sym.addr != 0)
+                .map(move |sym| AllocatorSymbol {
+                    function,
+                    name: name.clone(),
+                    address: sym.addr,
+                    file_offset: sym.file_offset,
+                })
+        })
+        // a symbol may be in both the static and the dynamic symbol table
+        .unique()
+        .collect())
+}
+
+/// Instruction pointers of the stack, from the leaf to the root
+pub fn ips(stacktrace: &StackTrace) -> Vec<u64> {
+    stacktrace.frames().iter().map(|x| x.ip).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct ResolvedStackTrace {
     pub symbols: Vec<OwnedSymbol>,
 }
 
 impl ResolvedStackTrace {
+    /// Whether no frame could be symbolized, hinting at missing debug info or
+    /// a wrong binary rather than at a stack of unknown code
+    pub fn is_unsymbolized(&self) -> bool {
+        !self.symbols.is_empty() && self.symbols.iter().all(OwnedSymbol::is_unknown)
+    }
+
+    /// Whether any frame comes from a source file which path contains `pattern`
+    pub fn has_file(&self, pattern: &str) -> bool {
+        self.symbols
+            .iter()
+            .filter_map(|x| x.file.as_deref())
+            .any(|file| file.contains(pattern))
+    }
+
+    /// Whether any frame's symbol, or a function inlined into it, matches `pattern`
+    pub fn has_symbol(&self, pattern: &Regex) -> bool {
+        self.symbols
+            .iter()
+            .flat_map(OwnedSymbol::names)
+            .any(|name| pattern.is_match(name))
+    }
+
+    /// `;` separated frames of a folded line, from the root to the leaf, the
+    /// order other tools expect
+    pub fn folded_frames(&self) -> String {
+        let names: Vec<&str> = self.symbols.iter().flat_map(OwnedSymbol::names).collect();
+        names.iter().rev().join(";")
+    }
+
     pub fn as_inferno(&self, calculation: u64) -> String {
-        let mut symbols: String = self.symbols.iter().map(|x| x.symbol.clone()).join(";");
+        let mut symbols: String = self.symbols.iter().flat_map(OwnedSymbol::names).join(";");
         symbols.push(' ');
         symbols.push_str(&calculation.to_string());
         symbols
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct OwnedSymbol {
     pub address: u64,
     pub symbol: String,
+    /// Source file of the symbol, if debug info is available
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Functions inlined at the address, the innermost first
+    pub inlined: Vec<String>,
+}
+
+impl OwnedSymbol {
+    /// Whether the frame couldn't be symbolized: blazesym's reason or the raw
+    /// address stands in for the symbol, or the address wasn't mapped at all
+    pub fn is_unknown(&self) -> bool {
+        self.address == 0 || self.symbol == "[unknown]"
+    }
+
+    /// The symbol followed by its `(file:line)` with `source_lines`, if they are known
+    pub fn display(&self, source_lines: bool) -> String {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) if source_lines => format!("{} ({file}:{line})", self.symbol),
+            (Some(file), None) if source_lines => format!("{} ({file})", self.symbol),
+            _ => self.symbol.clone(),
+        }
+    }
+
+    /// Inlined functions and then the symbol, in stack order from the leaf
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.inlined
+            .iter()
+            .map(String::as_str)
+            .chain([self.symbol.as_str()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_frames_are_resolved_once() {
+        let resolver = Resolver::new();
+        let resolved = RefCell::new(Vec::new());
+        let resolve = |ips: &[u64]| {
+            resolved.borrow_mut().extend_from_slice(ips);
+            Ok(ResolvedStackTrace {
+                symbols: ips
+                    .iter()
+                    .map(|ip| OwnedSymbol {
+                        address: *ip,
+                        symbol: format!("fn_{ip}"),
+                        file: None,
+                        line: None,
+                        inlined: Vec::new(),
+                    })
+                    .collect(),
+            })
+        };
+
+        resolver.resolve_cached(1, &[1, 2, 3], resolve).unwrap();
+        let trace = resolver.resolve_cached(1, &[4, 2, 3], resolve).unwrap();
+        // same addresses in another process are different frames
+        resolver.resolve_cached(2, &[1], resolve).unwrap();
+
+        assert_eq!(*resolved.borrow(), [1, 2, 3, 4, 1]);
+        let symbols: Vec<_> = trace.symbols.iter().map(|s| s.symbol.as_str()).collect();
+        assert_eq!(symbols, ["fn_4", "fn_2", "fn_3"]);
+        assert_eq!(resolver.cache_stats(), (2, 5));
+    }
+
+    #[test]
+    fn pid_0_is_an_error() {
+        let resolver = Resolver::new();
+        let err = resolver.resolve_ips(&[0x1000, 0x2000], 0).unwrap_err();
+        assert_eq!(err.to_string(), "invalid pid 0");
+        // nothing is cached under the pid of the kernel frames
+        assert_eq!(resolver.cache_stats(), (0, 0));
+    }
+
+    #[test]
+    fn frames_of_the_binary_are_symbolized_from_disk() {
+        let resolver = Resolver::with_options(ResolverOptions {
+            code_info: false,
+            inlined_fns: false,
+            debuginfod: None,
+            symbolize_only: Vec::new(),
+            fallback_binary: Some(std::env::current_exe().unwrap()),
+            symbol_cache: None,
+        });
+        let own = frames_of_the_binary_are_symbolized_from_disk as fn() as usize as u64;
+        let libc = libc::malloc as unsafe extern "C" fn(_) -> _ as usize as u64;
+        let (binary, trace) = resolver
+            .resolve_through_binary(std::process::id(), &[own, libc])
+            .unwrap();
+        assert_eq!(binary, std::env::current_exe().unwrap());
+        assert!(trace.symbols[0]
+            .symbol
+            .ends_with("frames_of_the_binary_are_symbolized_from_disk"));
+        assert_eq!(trace.symbols[1].symbol, "[libc]");
+        // without frames of the binary there is nothing to fall back to
+        assert!(resolver
+            .resolve_through_binary(std::process::id(), &[libc])
+            .is_none());
+    }
+
+    #[test]
+    fn frames_outside_of_symbolize_only_show_their_module() {
+        let exe = std::env::current_exe().unwrap();
+        let exe_name = exe.file_name().unwrap().to_str().unwrap();
+        let resolver = Resolver::with_options(ResolverOptions {
+            code_info: false,
+            inlined_fns: false,
+            debuginfod: None,
+            symbolize_only: vec![Regex::new(&regex::escape(exe_name)).unwrap()],
+            fallback_binary: None,
+            symbol_cache: None,
+        });
+        let own = frames_outside_of_symbolize_only_show_their_module as fn() as usize as u64;
+        let libc = libc::malloc as unsafe extern "C" fn(_) -> _ as usize as u64;
+        let trace = resolver
+            .resolve_ips(&[own, libc, 1], std::process::id())
+            .unwrap();
+        let symbols: Vec<_> = trace.symbols.iter().map(|s| s.symbol.as_str()).collect();
+        assert!(symbols[0].ends_with("frames_outside_of_symbolize_only_show_their_module"));
+        assert_eq!(symbols[1..], ["[libc]", "[unknown]"]);
+        assert_eq!(module_name("/usr/lib/x86_64-linux-gnu/libc.so.6"), "libc");
+        assert_eq!(module_name("/usr/bin/app"), "app");
+    }
+
+    #[test]
+    fn inlined_frames_are_expanded() {
+        let trace = ResolvedStackTrace {
+            symbols: vec![
+                OwnedSymbol {
+                    address: 1,
+                    symbol: "parse".to_string(),
+                    file: None,
+                    line: None,
+                    inlined: vec!["Vec::push".to_string(), "parse_item".to_string()],
+                },
+                OwnedSymbol {
+                    address: 2,
+                    symbol: "main".to_string(),
+                    file: None,
+                    line: None,
+                    inlined: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(trace.as_inferno(64), "Vec::push;parse_item;parse;main 64");
+        assert_eq!(trace.folded_frames(), "main;parse;parse_item;Vec::push");
+        assert!(trace.has_symbol(&Regex::new("^parse_item$").unwrap()));
+    }
+
+    #[test]
+    fn source_lines_fall_back_to_the_symbol() {
+        let mut symbol = OwnedSymbol {
+            address: 1,
+            symbol: "parse".to_string(),
+            file: Some("src/parse.rs".to_string()),
+            line: Some(42),
+            inlined: Vec::new(),
+        };
+        assert_eq!(symbol.display(true), "parse (src/parse.rs:42)");
+        assert_eq!(symbol.display(false), "parse");
+        symbol.line = None;
+        assert_eq!(symbol.display(true), "parse (src/parse.rs)");
+        symbol.file = None;
+        assert_eq!(symbol.display(true), "parse");
+    }
+
+    #[test]
+    fn finds_functions_with_prefixes() {
+        let binary = std::env::current_exe().unwrap();
+        assert_eq!(
+            find_function(&binary, "main", None).unwrap().as_deref(),
+            Some("main")
+        );
+        assert_eq!(
+            find_function(&binary, "main", Some("_rjem_")).unwrap(),
+            None
+        );
+        assert_eq!(
+            find_function(&binary, "no_such_function", None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn lists_allocator_symbols_of_libc() {
+        let libc = crate::process::find_library(std::process::id() as i32, "libc.so.6").unwrap();
+        let symbols = allocator_symbols(&libc).unwrap();
+        let functions: Vec<_> = symbols.iter().map(|s| s.function).unique().collect();
+        assert!(functions.contains(&JemallocAllocFunctions::Malloc));
+        assert!(functions.contains(&JemallocAllocFunctions::PosixMemalign));
+        // jemalloc only
+        assert!(!functions.contains(&JemallocAllocFunctions::Mallocx));
+        let malloc = symbols.iter().find(|s| s.name == "malloc").unwrap();
+        assert!(malloc.file_offset.is_some());
+        assert!(allocator_symbols(&std::env::current_exe().unwrap())
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -0,0 +1,210 @@
+//! `jeprofl self-test`: profiles a child which allocates known sizes through
+//! the chosen function and checks that the capture holds all of them, to tell a
+//! misconfigured probe from a target which doesn't allocate.
+
+use crate::{JemallocAllocFunctions, Profiler, ProfilerOptions};
+use anyhow::Context;
+use jeprofl_common::{BucketScheme, Histogram};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+/// Sizes the child allocates, `count` times each. They differ from the small
+/// allocations of its runtime
+const SIZES: [u64; 3] = [100, 5000, 70_000];
+
+/// Symbol every stack of the child's allocations goes through
+const MARKER: &str = "self_test::allocate";
+
+/// Runs the self-test against a child allocating `count` times every size
+pub fn run(function: JemallocAllocFunctions, count: u64) -> anyhow::Result<()> {
+    if !is_libc_function(function) {
+        anyhow::bail!("{function} isn't a function of the C library, the self-test can't call it");
+    }
+    let child = Command::new(std::env::current_exe()?)
+        .args(["self-test-child", "--function", &function.to_string()])
+        .args(["--count", &count.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn the self-test child")?;
+    let mut child = scopeguard::guard(child, |mut child| {
+        child.kill().ok();
+        child.wait().ok();
+    });
+    let pid = child.id() as i32;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    expect_line(&mut stdout, "ready")?;
+    let target = crate::process::find_module_defining(pid, function, None)?;
+    log::info!("Attaching to {function} of {}", target.display());
+    let profiler = Profiler::attach(
+        &target,
+        &[function],
+        ProfilerOptions {
+            pids: vec![Some(pid)],
+            ..ProfilerOptions::default()
+        },
+    )?;
+    writeln!(stdin, "go")?;
+    expect_line(&mut stdout, "done")?;
+    let processor = profiler.stop()?;
+
+    let mut captured = Histogram::new();
+    let mut marked = false;
+    for (key, hist, trace) in processor.stacks() {
+        if key.pid != pid as u32 {
+            continue;
+        }
+        captured.merge(&hist);
+        marked |=
+            trace.is_some_and(|trace| trace.symbols.iter().any(|fun| fun.symbol.contains(MARKER)));
+    }
+    if captured.allocation_count() == 0 {
+        anyhow::bail!(
+            "No allocation of the child was captured, the probe on {function} of {} didn't fire",
+            target.display()
+        );
+    }
+    let mut expected = Histogram::new();
+    for size in SIZES {
+        expected.record_weighted(size, count, BucketScheme::Log2);
+    }
+    let missing = missing_allocations(&captured, &expected);
+    if !missing.is_empty() {
+        let buckets = missing
+            .iter()
+            .map(|(low, high, expected, captured)| {
+                format!("{captured} of {expected} allocations of {low}..{high} bytes")
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow::bail!("Allocations of the child are missing, captured {buckets}");
+    }
+    println!(
+        "Self-test passed: all {} allocations of {function} were captured",
+        expected.allocation_count()
+    );
+    if !marked {
+        log::warn!(
+            "No stack reaches {MARKER}, the C library or jeprofl may lack frame pointers, try --dwarf-unwind"
+        );
+    }
+    Ok(())
+}
+
+/// Entry of the child, allocates once the parent attached
+pub fn run_child(function: JemallocAllocFunctions, count: u64) -> anyhow::Result<()> {
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout();
+    writeln!(stdout, "ready")?;
+    stdout.flush()?;
+    let mut line = String::new();
+    stdin.read_line(&mut line)?;
+    allocate(function, count)?;
+    writeln!(stdout, "done")?;
+    stdout.flush()?;
+    // stays alive until the parent resolved the stacks
+    stdin.read_line(&mut line)?;
+    Ok(())
+}
+
+fn is_libc_function(function: JemallocAllocFunctions) -> bool {
+    use JemallocAllocFunctions::*;
+    matches!(
+        function,
+        Malloc | Calloc | Realloc | AlignedAlloc | PosixMemalign | Memalign
+    )
+}
+
+extern "C" {
+    // C11, not exported by the libc crate
+    fn aligned_alloc(alignment: libc::size_t, size: libc::size_t) -> *mut libc::c_void;
+}
+
+#[inline(never)]
+fn allocate(function: JemallocAllocFunctions, count: u64) -> anyhow::Result<()> {
+    use JemallocAllocFunctions::*;
+    for _ in 0..count {
+        for size in SIZES {
+            let size = size as usize;
+            // SAFETY: every pointer is freed right away
+            let ptr = unsafe {
+                match function {
+                    Malloc => libc::malloc(size),
+                    Calloc => libc::calloc(1, size),
+                    // without a pointer it allocates like malloc
+                    Realloc => libc::realloc(std::ptr::null_mut(), size),
+                    AlignedAlloc => aligned_alloc(64, size),
+                    Memalign => libc::memalign(64, size),
+                    PosixMemalign => {
+                        let mut ptr = std::ptr::null_mut();
+                        libc::posix_memalign(&mut ptr, 64, size);
+                        ptr
+                    }
+                    _ => anyhow::bail!("{function} isn't a function of the C library"),
+                }
+            };
+            // the compiler may elide an unused allocation
+            unsafe { libc::free(std::hint::black_box(ptr)) };
+        }
+    }
+    Ok(())
+}
+
+fn expect_line(reader: &mut impl BufRead, expected: &str) -> anyhow::Result<()> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != expected {
+        anyhow::bail!("The self-test child exited early, expected {expected:?}, got {line:?}");
+    }
+    Ok(())
+}
+
+/// Log2 buckets with fewer captured allocations than expected, as their
+/// bounds with the expected and the captured count
+fn missing_allocations(captured: &Histogram, expected: &Histogram) -> Vec<(u64, u64, u64, u64)> {
+    expected
+        .data
+        .iter()
+        .zip(&captured.data)
+        .enumerate()
+        .filter(|(_, (expected, captured))| captured < expected)
+        .map(|(bucket, (expected, captured))| {
+            let (low, high) = BucketScheme::Log2.bounds(bucket);
+            (low, high, *expected, *captured)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocations_are_checked_per_bucket() {
+        let mut expected = Histogram::new();
+        for size in SIZES {
+            expected.record_weighted(size, 10, BucketScheme::Log2);
+        }
+        // the runtime allocates besides the child's allocations
+        let mut captured = expected;
+        captured.increment(24);
+        assert!(missing_allocations(&captured, &expected).is_empty());
+
+        let mut captured = Histogram::new();
+        captured.record_weighted(100, 10, BucketScheme::Log2);
+        captured.record_weighted(5000, 7, BucketScheme::Log2);
+        let missing = missing_allocations(&captured, &expected);
+        assert_eq!(missing.len(), 2);
+        assert_eq!((missing[0].2, missing[0].3), (10, 7));
+        assert_eq!((missing[1].2, missing[1].3), (10, 0));
+    }
+
+    #[test]
+    fn child_allocates_through_every_libc_function() {
+        for function in JemallocAllocFunctions::ALL {
+            assert_eq!(allocate(function, 2).is_ok(), is_libc_function(function));
+        }
+    }
+}
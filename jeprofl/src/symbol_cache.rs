@@ -0,0 +1,132 @@
+//! Symbolized frames kept on disk between runs, so repeated captures of the
+//! same binaries don't parse their symbols and debug info again. Every binary
+//! has its own file named after its build id, a rebuilt binary starts over.
+
+use crate::resolver::OwnedSymbol;
+use anyhow::Context;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Symbols by build id and file offset, every file is read on first use and
+/// written by [`SymbolCache::save`]
+pub struct SymbolCache {
+    dir: PathBuf,
+    binaries: FxHashMap<String, CachedBinary>,
+}
+
+#[derive(Default)]
+struct CachedBinary {
+    symbols: BTreeMap<u64, OwnedSymbol>,
+    /// Symbols were added since the file was read
+    dirty: bool,
+}
+
+impl SymbolCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            binaries: Default::default(),
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/jeprofl/symbols`, or `~/.cache/jeprofl/symbols` without it
+    pub fn default_dir() -> Option<PathBuf> {
+        let cache = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(cache.join("jeprofl").join("symbols"))
+    }
+
+    pub fn get(&mut self, build_id: &str, offset: u64) -> Option<&OwnedSymbol> {
+        self.binary(build_id).symbols.get(&offset)
+    }
+
+    pub fn insert(&mut self, build_id: &str, offset: u64, symbol: OwnedSymbol) {
+        let binary = self.binary(build_id);
+        binary.symbols.insert(offset, symbol);
+        binary.dirty = true;
+    }
+
+    /// Writes the files of the binaries which got new symbols
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        for (build_id, binary) in &mut self.binaries {
+            if !binary.dirty {
+                continue;
+            }
+            std::fs::create_dir_all(&self.dir)
+                .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+            let path = self.dir.join(format!("{build_id}.json"));
+            // written aside and renamed, so a concurrent run never reads half of it
+            let partial = path.with_extension(format!("json.{}", std::process::id()));
+            let mut file = BufWriter::new(std::fs::File::create(&partial)?);
+            serde_json::to_writer(&mut file, &binary.symbols)?;
+            file.flush()?;
+            std::fs::rename(&partial, &path)?;
+            binary.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, build_id: &str) -> &mut CachedBinary {
+        if !self.binaries.contains_key(build_id) {
+            let binary = self.load(build_id);
+            self.binaries.insert(build_id.to_string(), binary);
+        }
+        self.binaries.get_mut(build_id).unwrap()
+    }
+
+    /// Symbols of the binary saved by earlier runs, none if there is no
+    /// readable file
+    fn load(&self, build_id: &str) -> CachedBinary {
+        let path = self.dir.join(format!("{build_id}.json"));
+        let Ok(file) = std::fs::File::open(&path) else {
+            return CachedBinary::default();
+        };
+        let symbols = serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+            log::warn!("Ignoring the broken symbol cache {}: {e}", path.display());
+            BTreeMap::new()
+        });
+        CachedBinary {
+            symbols,
+            dirty: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn symbols_are_reloaded_by_build_id() {
+        let dir = std::env::temp_dir().join(format!("jeprofl-symbols-{}", std::process::id()));
+        let _cleanup = scopeguard::guard((), |_| {
+            std::fs::remove_dir_all(&dir).ok();
+        });
+        let symbol = OwnedSymbol {
+            address: 0x1100,
+            symbol: "parse".to_string(),
+            file: Some("src/parse.rs".to_string()),
+            line: Some(12),
+            inlined: vec!["read".to_string()],
+        };
+        let mut cache = SymbolCache::new(dir.clone());
+        assert!(cache.get("abcd", 0x1120).is_none());
+        cache.insert("abcd", 0x1120, symbol);
+        cache.save().unwrap();
+
+        let mut cache = SymbolCache::new(dir.clone());
+        let cached = cache.get("abcd", 0x1120).unwrap();
+        assert_eq!(cached.symbol, "parse");
+        assert_eq!(cached.line, Some(12));
+        assert_eq!(cached.inlined, ["read"]);
+        // another build of the binary doesn't see them
+        assert!(cache.get("ef01", 0x1120).is_none());
+
+        std::fs::write(dir.join("ef01.json"), "{").unwrap();
+        assert!(SymbolCache::new(dir.clone()).get("ef01", 0x1120).is_none());
+    }
+}
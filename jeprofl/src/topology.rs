@@ -0,0 +1,54 @@
+//! CPU to NUMA node mapping from sysfs, for the `--by-cpu` breakdown.
+
+use rustc_hash::FxHashMap;
+
+/// NUMA node of every cpu, empty if the system doesn't expose NUMA topology
+pub fn cpu_nodes() -> FxHashMap<u32, u32> {
+    let mut nodes = FxHashMap::default();
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") else {
+        return nodes;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(node) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        let Ok(cpus) = std::fs::read_to_string(entry.path().join("cpulist")) else {
+            continue;
+        };
+        for cpu in parse_cpu_list(&cpus) {
+            nodes.insert(cpu, node);
+        }
+    }
+    nodes
+}
+
+/// Parses the kernel cpu list format, e.g. `0-3,8,10-11`
+fn parse_cpu_list(list: &str) -> Vec<u32> {
+    list.trim()
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                Some(cpu..=cpu)
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_ranges() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list(""), Vec::<u32>::new());
+    }
+}
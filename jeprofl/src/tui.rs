@@ -0,0 +1,346 @@
+//! Interactive stack browser for `--tui`. The collector publishes a snapshot
+//! of the stacks after every poll, the UI redraws from the latest one, so the
+//! view updates while the profile is still running.
+
+use crate::collector::print_histogram;
+use crate::OrderBy;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, style, terminal};
+use jeprofl_common::{BucketScheme, Histogram};
+use std::cmp::Reverse;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Latest snapshot, shared by the collector and the UI
+pub type Published = Arc<Mutex<Arc<Snapshot>>>;
+
+/// How often the view is redrawn without input, the collector publishes at every poll
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub stacks: Vec<TuiStack>,
+    pub bucket_scheme: BucketScheme,
+    /// Shown in the header, e.g. that the numbers are sampling estimates
+    pub note: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TuiStack {
+    pub pid: u32,
+    pub tid: u32,
+    pub stack_id: u32,
+    pub function: String,
+    /// Symbols from the allocation function to the root
+    pub frames: Vec<String>,
+    pub histogram: Histogram,
+    /// `None` when frees aren't tracked
+    pub live_bytes: Option<u64>,
+}
+
+impl TuiStack {
+    /// Caller of the allocation function, the most telling frame of the stack
+    pub(crate) fn caller(&self) -> &str {
+        self.frames
+            .get(1)
+            .or(self.frames.first())
+            .map_or("unknown", String::as_str)
+    }
+}
+
+#[derive(Debug)]
+struct App {
+    order_by: OrderBy,
+    /// Case insensitive substring any frame of a shown stack has
+    filter: String,
+    /// Keys go to the filter
+    editing: bool,
+    /// Index of the selected stack among the shown ones
+    selected: usize,
+    /// First shown line of the list or of the expanded stack
+    offset: usize,
+    expanded: bool,
+}
+
+impl App {
+    fn new(order_by: OrderBy) -> Self {
+        Self {
+            order_by,
+            filter: String::new(),
+            editing: false,
+            selected: 0,
+            offset: 0,
+            expanded: false,
+        }
+    }
+
+    /// Stacks passing the filter, the biggest first
+    fn shown<'a>(&self, snapshot: &'a Snapshot) -> Vec<&'a TuiStack> {
+        let filter = self.filter.to_lowercase();
+        let mut stacks: Vec<_> = snapshot
+            .stacks
+            .iter()
+            .filter(|stack| {
+                filter.is_empty()
+                    || stack
+                        .frames
+                        .iter()
+                        .any(|frame| frame.to_lowercase().contains(&filter))
+            })
+            .collect();
+        sort_stacks(&mut stacks, self.order_by);
+        stacks
+    }
+
+    /// Applies the key, returns `false` once the UI should exit
+    fn handle_key(&mut self, key: KeyEvent, shown: usize) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        if self.editing {
+            match key.code {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Enter | KeyCode::Esc => self.editing = false,
+                _ => {}
+            }
+            self.selected = 0;
+            self.offset = 0;
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.move_by(-1, shown),
+            KeyCode::Down | KeyCode::Char('j') => self.move_by(1, shown),
+            KeyCode::PageUp => self.move_by(-20, shown),
+            KeyCode::PageDown => self.move_by(20, shown),
+            KeyCode::Enter if shown > 0 => {
+                self.expanded = !self.expanded;
+                self.offset = 0;
+            }
+            KeyCode::Esc if self.expanded => {
+                self.expanded = false;
+                self.offset = 0;
+            }
+            KeyCode::Char('s') => {
+                self.order_by = match self.order_by {
+                    OrderBy::Count => OrderBy::Traffic,
+                    OrderBy::Traffic | OrderBy::Live => OrderBy::Count,
+                };
+            }
+            KeyCode::Char('/') => {
+                self.editing = true;
+                self.expanded = false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Moves the selection, or scrolls the expanded stack
+    fn move_by(&mut self, lines: isize, shown: usize) {
+        if self.expanded {
+            self.offset = self.offset.saturating_add_signed(lines);
+        } else {
+            self.selected = self
+                .selected
+                .saturating_add_signed(lines)
+                .min(shown.saturating_sub(1));
+        }
+    }
+
+    /// Lines of the screen, at most `height` of them cut to `width`
+    fn render(&mut self, snapshot: &Snapshot, width: usize, height: usize) -> Vec<String> {
+        let shown = self.shown(snapshot);
+        self.selected = self.selected.min(shown.len().saturating_sub(1));
+
+        let mut lines = vec![format!(
+            "{} of {} stacks by {} | filter: {}{} | enter expand, s sort, / filter, q quit",
+            shown.len(),
+            snapshot.stacks.len(),
+            self.order_by.to_string().to_lowercase(),
+            self.filter,
+            if self.editing { "_" } else { "" },
+        )];
+        if let Some(note) = &snapshot.note {
+            lines.push(note.clone());
+        }
+        let rows = height.saturating_sub(lines.len() + 1);
+
+        match shown.get(self.selected) {
+            Some(stack) if self.expanded => {
+                lines.push(format!(
+                    "stack {} of pid {}{} through {} (esc back)",
+                    stack.stack_id,
+                    stack.pid,
+                    match stack.tid {
+                        0 => String::new(),
+                        tid => format!(" tid {tid}"),
+                    },
+                    stack.function
+                ));
+                let mut details = stack.frames.clone();
+                if details.is_empty() {
+                    details.push("No resolved stacktrace".to_string());
+                }
+                details.push(String::new());
+                let mut histogram = String::new();
+                print_histogram(&stack.histogram, snapshot.bucket_scheme, &mut histogram).ok();
+                details.extend(histogram.lines().map(str::to_string));
+
+                self.offset = self.offset.min(details.len().saturating_sub(rows));
+                lines.extend(details.into_iter().skip(self.offset).take(rows));
+            }
+            _ => {
+                self.expanded = false;
+                lines.push(format!(
+                    "  {:>10} {:>10}  {:10}  caller",
+                    "bytes", "count", "function"
+                ));
+                if self.selected < self.offset {
+                    self.offset = self.selected;
+                } else if rows > 0 && self.selected >= self.offset + rows {
+                    self.offset = self.selected + 1 - rows;
+                }
+                for (i, stack) in shown.iter().enumerate().skip(self.offset).take(rows) {
+                    lines.push(format!(
+                        "{} {:>10} {:>10}  {:10}  {}",
+                        if i == self.selected { '>' } else { ' ' },
+                        bytesize::to_string(stack.histogram.total_bytes(), true),
+                        stack.histogram.allocation_count(),
+                        stack.function,
+                        stack.caller()
+                    ));
+                }
+            }
+        }
+
+        lines.truncate(height);
+        for line in &mut lines {
+            if let Some((cut, _)) = line.char_indices().nth(width) {
+                line.truncate(cut);
+            }
+        }
+        lines
+    }
+}
+
+/// Sorts the stacks by `order_by`, the biggest first
+pub(crate) fn sort_stacks(stacks: &mut [&TuiStack], order_by: OrderBy) {
+    stacks.sort_by_key(|stack| {
+        let value = match order_by {
+            OrderBy::Count => stack.histogram.allocation_count(),
+            OrderBy::Traffic => stack.histogram.total_bytes(),
+            OrderBy::Live => stack.live_bytes.unwrap_or(0),
+        };
+        (Reverse(value), stack.stack_id, stack.pid, stack.tid)
+    });
+}
+
+/// Runs the UI on the terminal until the user quits or the profiling is `canceled`
+pub fn run(published: Published, order_by: OrderBy, canceled: &AtomicBool) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout();
+    terminal::enable_raw_mode()?;
+    crossterm::execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    scopeguard::defer! {
+        crossterm::execute!(std::io::stdout(), terminal::LeaveAlternateScreen, cursor::Show).ok();
+        terminal::disable_raw_mode().ok();
+    };
+
+    let mut app = App::new(order_by);
+    while !canceled.load(Ordering::Acquire) {
+        let snapshot = published.lock().unwrap().clone();
+        let (width, height) = terminal::size()?;
+        let lines = app.render(&snapshot, width as usize, height as usize);
+        crossterm::queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+        for (row, line) in lines.iter().enumerate() {
+            crossterm::queue!(stdout, cursor::MoveTo(0, row as u16), style::Print(line))?;
+        }
+        stdout.flush()?;
+
+        if !event::poll(REFRESH_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && !app.handle_key(key, app.shown(&snapshot).len()) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stack(stack_id: u32, sizes: &[u64], frames: &[&str]) -> TuiStack {
+        let mut histogram = Histogram::new();
+        for &size in sizes {
+            histogram.increment(size);
+        }
+        TuiStack {
+            pid: 1,
+            tid: 0,
+            stack_id,
+            function: "malloc".to_string(),
+            frames: frames.iter().map(|frame| frame.to_string()).collect(),
+            histogram,
+            live_bytes: None,
+        }
+    }
+
+    fn press(app: &mut App, code: KeyCode, shown: usize) -> bool {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE), shown)
+    }
+
+    #[test]
+    fn browse_sort_filter_and_expand() {
+        let snapshot = Snapshot {
+            stacks: vec![
+                stack(1, &[16, 16, 16], &["malloc", "parse", "main"]),
+                stack(2, &[4096], &["malloc", "load", "main"]),
+            ],
+            bucket_scheme: BucketScheme::Log2,
+            note: None,
+        };
+        let mut app = App::new(OrderBy::Traffic);
+        let screen = app.render(&snapshot, 60, 10);
+        assert_eq!(
+            screen[1..],
+            [
+                "       bytes      count  function    caller",
+                ">    4.0 kiB          1  malloc      load",
+                "        48 B          3  malloc      parse",
+            ]
+        );
+
+        press(&mut app, KeyCode::Char('s'), 2);
+        let screen = app.render(&snapshot, 60, 10);
+        assert!(screen[0].starts_with("2 of 2 stacks by count"));
+        assert!(screen[2].ends_with("parse"));
+
+        press(&mut app, KeyCode::Char('/'), 2);
+        for c in "LOAD".chars() {
+            press(&mut app, KeyCode::Char(c), 2);
+        }
+        press(&mut app, KeyCode::Enter, 2);
+        let screen = app.render(&snapshot, 60, 10);
+        assert!(screen[0].starts_with("1 of 2 stacks by count | filter: LOAD |"));
+        assert_eq!(screen.len(), 3);
+
+        assert!(press(&mut app, KeyCode::Enter, 1));
+        let screen = app.render(&snapshot, 200, 20);
+        assert_eq!(screen[1], "stack 2 of pid 1 through malloc (esc back)");
+        assert_eq!(screen[2..5], ["malloc", "load", "main"]);
+        assert!(screen
+            .iter()
+            .any(|line| line.starts_with("Total allocations: 4.0 kiB")));
+
+        assert!(!press(&mut app, KeyCode::Char('q'), 1));
+    }
+}
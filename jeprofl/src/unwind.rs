@@ -0,0 +1,330 @@
+//! DWARF unwinding for `--dwarf-unwind`. The ebpf side copies the registers and
+//! the top of the user stack of every allocation, the frames are recovered here
+//! with the `.eh_frame` CFI of the mapped binaries, so binaries built without
+//! frame pointers get complete stacks too. Frames without CFI fall back to the
+//! frame pointer.
+
+use crate::raw::{self, RawMapping};
+use anyhow::Context;
+use gimli::{
+    BaseAddresses, CfaRule, EhFrame, EhFrameHdr, LittleEndian, RegisterRule, UnwindContext,
+    UnwindSection, X86_64,
+};
+use object::{Object, ObjectSection, ObjectSegment};
+use rustc_hash::FxHashMap;
+
+/// Deeper frames are dropped, like `get_stackid` does
+const MAX_FRAMES: usize = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    ip: u64,
+    sp: u64,
+    /// `None` once the frame pointer isn't known anymore
+    bp: Option<u64>,
+}
+
+/// Copy of the user stack from `start` up
+struct StackCopy<'a> {
+    start: u64,
+    bytes: &'a [u8],
+}
+
+impl StackCopy<'_> {
+    fn read(&self, address: u64) -> Option<u64> {
+        let offset = usize::try_from(address.checked_sub(self.start)?).ok()?;
+        let bytes = self.bytes.get(offset..offset.checked_add(8)?)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+/// How the registers of the caller are recovered at an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameRule {
+    /// The CFA is this register plus `cfa_offset`, `rsp` or `rbp`
+    cfa_register: gimli::Register,
+    cfa_offset: i64,
+    /// Offset of the return address from the CFA, `None` in the outermost frame
+    ra_offset: Option<i64>,
+    /// Offset of the saved `rbp` from the CFA, `None` if it wasn't saved
+    bp_offset: Option<i64>,
+}
+
+impl FrameRule {
+    fn caller(&self, regs: &Registers, stack: &StackCopy) -> Option<Registers> {
+        let base = match self.cfa_register {
+            X86_64::RSP => regs.sp,
+            X86_64::RBP => regs.bp?,
+            _ => return None,
+        };
+        let cfa = base.checked_add_signed(self.cfa_offset)?;
+        let ip = stack.read(cfa.checked_add_signed(self.ra_offset?)?)?;
+        let bp = match self.bp_offset {
+            Some(offset) => stack.read(cfa.checked_add_signed(offset)?),
+            None => regs.bp,
+        };
+        Some(Registers { ip, sp: cfa, bp })
+    }
+}
+
+/// Caller of a frame without CFI, through the saved frame pointer
+fn frame_pointer_caller(regs: &Registers, stack: &StackCopy) -> Option<Registers> {
+    let bp = regs.bp?;
+    Some(Registers {
+        ip: stack.read(bp.checked_add(8)?)?,
+        sp: bp.checked_add(16)?,
+        bp: stack.read(bp),
+    })
+}
+
+/// `.eh_frame` of a binary with what is needed to find the rows of its addresses
+struct UnwindTable {
+    eh_frame: Vec<u8>,
+    eh_frame_hdr: Option<Vec<u8>>,
+    bases: BaseAddresses,
+    /// File offset, size in the file and virtual address of the loaded segments
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl UnwindTable {
+    fn open(path: &str) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(data.as_slice())?;
+        let eh_frame = file
+            .section_by_name(".eh_frame")
+            .context("the binary has no .eh_frame")?;
+        let mut bases = BaseAddresses::default().set_eh_frame(eh_frame.address());
+        if let Some(text) = file.section_by_name(".text") {
+            bases = bases.set_text(text.address());
+        }
+        let eh_frame_hdr = file.section_by_name(".eh_frame_hdr");
+        if let Some(hdr) = &eh_frame_hdr {
+            bases = bases.set_eh_frame_hdr(hdr.address());
+        }
+        Ok(Self {
+            eh_frame: eh_frame.data()?.to_vec(),
+            eh_frame_hdr: eh_frame_hdr
+                .map(|hdr| hdr.data().map(<[u8]>::to_vec))
+                .transpose()?,
+            bases,
+            segments: file
+                .segments()
+                .map(|segment| {
+                    let (offset, size) = segment.file_range();
+                    (offset, size, segment.address())
+                })
+                .collect(),
+        })
+    }
+
+    fn address_of(&self, file_offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(offset, size, _)| (*offset..offset + size).contains(&file_offset))
+            .map(|(offset, _, address)| file_offset - offset + address)
+    }
+
+    fn rule_at(&self, address: u64) -> Option<FrameRule> {
+        let eh_frame = EhFrame::new(&self.eh_frame, LittleEndian);
+        let hdr = self.eh_frame_hdr.as_ref().and_then(|hdr| {
+            EhFrameHdr::new(hdr, LittleEndian)
+                .parse(&self.bases, 8)
+                .ok()
+        });
+        let mut ctx = UnwindContext::new();
+        let row = match hdr.as_ref().and_then(|hdr| hdr.table()) {
+            Some(table) => table.unwind_info_for_address(
+                &eh_frame,
+                &self.bases,
+                &mut ctx,
+                address,
+                EhFrame::cie_from_offset,
+            ),
+            // without the search table of `.eh_frame_hdr` every entry is scanned
+            None => eh_frame.unwind_info_for_address(
+                &self.bases,
+                &mut ctx,
+                address,
+                EhFrame::cie_from_offset,
+            ),
+        }
+        .ok()?;
+        // expressions, e.g. of PLT entries, aren't evaluated
+        let CfaRule::RegisterAndOffset { register, offset } = *row.cfa() else {
+            return None;
+        };
+        let offset_of = |rule| match rule {
+            RegisterRule::Offset(offset) => Some(offset),
+            _ => None,
+        };
+        Some(FrameRule {
+            cfa_register: register,
+            cfa_offset: offset,
+            ra_offset: offset_of(row.register(X86_64::RA)),
+            bp_offset: offset_of(row.register(X86_64::RBP)),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Unwinder {
+    /// Unwind tables by path, `None` if the binary has none
+    tables: FxHashMap<String, Option<UnwindTable>>,
+    mappings: FxHashMap<u32, Vec<RawMapping>>,
+    /// Rules by pid and address, most allocations come from a few hot stacks
+    rules: FxHashMap<(u32, u64), Option<FrameRule>>,
+    /// Ids of the unwound stacks, numbered from 1 in the order they were seen
+    stack_ids: FxHashMap<Vec<u64>, u32>,
+}
+
+impl Unwinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instruction pointers of the stack from the leaf to the root, `stack` is
+    /// the copy of the stack from `sp` up
+    pub fn unwind(&mut self, pid: u32, ip: u64, sp: u64, bp: u64, stack: &[u8]) -> Vec<u64> {
+        let stack = StackCopy {
+            start: sp,
+            bytes: stack,
+        };
+        let mut regs = Registers {
+            ip,
+            sp,
+            bp: Some(bp),
+        };
+        let mut ips = Vec::new();
+        while ips.len() < MAX_FRAMES && regs.ip != 0 {
+            ips.push(regs.ip);
+            // return addresses point after the call, which may end the function
+            let address = if ips.len() == 1 { regs.ip } else { regs.ip - 1 };
+            let caller = match self.rule_at(pid, address) {
+                Some(rule) => rule.caller(&regs, &stack),
+                None => frame_pointer_caller(&regs, &stack),
+            };
+            match caller {
+                // the stack grows down, anything else is garbage
+                Some(caller) if caller.sp > regs.sp => regs = caller,
+                _ => break,
+            }
+        }
+        ips
+    }
+
+    /// Id of the stack, the same for the same frames
+    pub fn stack_id(&mut self, ips: &[u64]) -> u32 {
+        let next = self.stack_ids.len() as u32 + 1;
+        *self.stack_ids.entry(ips.to_vec()).or_insert(next)
+    }
+
+    fn rule_at(&mut self, pid: u32, address: u64) -> Option<FrameRule> {
+        if let Some(rule) = self.rules.get(&(pid, address)) {
+            return *rule;
+        }
+        let rule = self.find_rule(pid, address);
+        self.rules.insert((pid, address), rule);
+        rule
+    }
+
+    fn find_rule(&mut self, pid: u32, address: u64) -> Option<FrameRule> {
+        let find = |mappings: &[RawMapping]| {
+            mappings
+                .iter()
+                .find(|m| (m.start..m.end).contains(&address))
+                .map(|m| (m.path.clone(), address - m.start + m.offset))
+        };
+        let mapping = match self.mappings.get(&pid).and_then(|m| find(m)) {
+            Some(mapping) => Some(mapping),
+            // libraries may have been loaded since the maps were read
+            None => {
+                let maps = std::fs::read_to_string(format!("/proc/{pid}/maps")).ok()?;
+                let mappings: Vec<_> = maps.lines().filter_map(raw::parse_mapping).collect();
+                let mapping = find(&mappings);
+                self.mappings.insert(pid, mappings);
+                mapping
+            }
+        };
+        let (path, file_offset) = mapping?;
+        // the target may live in another mount namespace
+        let table = self
+            .tables
+            .entry(format!("/proc/{pid}/root{path}"))
+            .or_insert_with_key(|path| {
+                UnwindTable::open(path)
+                    .inspect_err(|e| log::debug!("No unwind table of {path}: {e:#}"))
+                    .ok()
+            })
+            .as_ref()?;
+        table.rule_at(table.address_of(file_offset)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unwinds_through_cfi_and_frame_pointers() {
+        let words: [u64; 4] = [0xaaaa, 0x1111, 0x2020, 0x2222];
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let stack = StackCopy {
+            start: 0x2000,
+            bytes: &bytes,
+        };
+        let regs = Registers {
+            ip: 0x10,
+            sp: 0x2000,
+            bp: Some(0xdead),
+        };
+        // a leaf frame without frame pointer, the return address is on top
+        let rule = FrameRule {
+            cfa_register: X86_64::RSP,
+            cfa_offset: 16,
+            ra_offset: Some(-8),
+            bp_offset: None,
+        };
+        let caller = rule.caller(&regs, &stack).unwrap();
+        assert_eq!(
+            caller,
+            Registers {
+                ip: 0x1111,
+                sp: 0x2010,
+                bp: Some(0xdead),
+            }
+        );
+        let caller = frame_pointer_caller(
+            &Registers {
+                bp: Some(0x2010),
+                ..caller
+            },
+            &stack,
+        )
+        .unwrap();
+        assert_eq!(
+            caller,
+            Registers {
+                ip: 0x2222,
+                sp: 0x2020,
+                bp: Some(0x2020),
+            }
+        );
+        // outside of the copy
+        assert_eq!(frame_pointer_caller(&caller, &stack), None);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn finds_rules_of_the_running_binary() {
+        let mut unwinder = Unwinder::new();
+        let ip = finds_rules_of_the_running_binary as fn() as usize as u64;
+        let rule = unwinder.rule_at(std::process::id(), ip).unwrap();
+        // a function entry: the return address was just pushed
+        assert_eq!(rule.cfa_register, X86_64::RSP);
+        assert_eq!(rule.cfa_offset, 8);
+        assert_eq!(rule.ra_offset, Some(-8));
+        assert_eq!(unwinder.stack_id(&[ip, 1]), 1);
+        assert_eq!(unwinder.stack_id(&[ip, 2]), 2);
+        assert_eq!(unwinder.stack_id(&[ip, 1]), 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! `--watch`: reprints the biggest stacks of the snapshot the collector
+//! publishes every interval, like `top` for allocations. Unlike `--tui` it
+//! reads no keys and leaves the last view on the terminal when it stops.
+
+use crate::tui::{self, Published, Snapshot};
+use crate::OrderBy;
+use crossterm::{cursor, terminal};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often `canceled` is checked between two refreshes
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reprints the `top` stacks every `interval` until the profiling is `canceled`,
+/// without `top` as many as fit the terminal
+pub fn run(
+    published: Published,
+    order_by: OrderBy,
+    top: Option<usize>,
+    interval: Duration,
+    canceled: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout();
+    while !canceled.load(Ordering::Acquire) {
+        let snapshot = published.lock().unwrap().clone();
+        let (width, height) = terminal::size().unwrap_or((u16::MAX, u16::MAX));
+        let lines = render(&snapshot, order_by, top, interval, height as usize);
+        crossterm::queue!(
+            stdout,
+            terminal::Clear(terminal::ClearType::All),
+            cursor::MoveTo(0, 0)
+        )?;
+        for mut line in lines {
+            if let Some((cut, _)) = line.char_indices().nth(width as usize) {
+                line.truncate(cut);
+            }
+            // the terminal isn't in raw mode, a plain newline starts the next row
+            writeln!(stdout, "{line}")?;
+        }
+        stdout.flush()?;
+
+        let refreshed = Instant::now();
+        while refreshed.elapsed() < interval && !canceled.load(Ordering::Acquire) {
+            std::thread::sleep(CANCEL_CHECK_INTERVAL.min(interval));
+        }
+    }
+    Ok(())
+}
+
+/// Lines of one refresh, at most `height - 1` of them so the view doesn't scroll
+fn render(
+    snapshot: &Snapshot,
+    order_by: OrderBy,
+    top: Option<usize>,
+    interval: Duration,
+    height: usize,
+) -> Vec<String> {
+    let mut stacks: Vec<_> = snapshot.stacks.iter().collect();
+    tui::sort_stacks(&mut stacks, order_by);
+
+    let mut lines = vec![format!(
+        "{} stacks by {}, every {}s, Ctrl-C to stop",
+        stacks.len(),
+        order_by.to_string().to_lowercase(),
+        interval.as_secs_f64()
+    )];
+    if let Some(note) = &snapshot.note {
+        lines.push(note.clone());
+    }
+    lines.push(format!(
+        "{:>10} {:>10} {:>10}  {:10}  caller",
+        "bytes", "count", "live", "function"
+    ));
+    let rows = height.saturating_sub(lines.len() + 1);
+    let shown = top.map_or(rows, |top| top.min(rows));
+    for stack in stacks.iter().take(shown) {
+        lines.push(format!(
+            "{:>10} {:>10} {:>10}  {:10}  {}",
+            bytesize::to_string(stack.histogram.total_bytes(), true),
+            stack.histogram.allocation_count(),
+            stack
+                .live_bytes
+                .map_or_else(|| "-".to_string(), |live| bytesize::to_string(live, true)),
+            stack.function,
+            stack.caller()
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tui::TuiStack;
+    use jeprofl_common::{BucketScheme, Histogram};
+
+    #[test]
+    fn render_the_biggest_stacks() {
+        let stacks = [(1, 16, "parse"), (2, 4096, "load"), (3, 64, "read")]
+            .into_iter()
+            .map(|(stack_id, size, caller)| {
+                let mut histogram = Histogram::new();
+                histogram.increment(size);
+                TuiStack {
+                    pid: 1,
+                    tid: 0,
+                    stack_id,
+                    function: "malloc".to_string(),
+                    frames: vec!["malloc".to_string(), caller.to_string()],
+                    histogram,
+                    live_bytes: None,
+                }
+            })
+            .collect();
+        let snapshot = Snapshot {
+            stacks,
+            bucket_scheme: BucketScheme::Log2,
+            note: None,
+        };
+        let lines = render(
+            &snapshot,
+            OrderBy::Traffic,
+            Some(2),
+            Duration::from_secs(2),
+            40,
+        );
+        assert_eq!(
+            lines,
+            [
+                "3 stacks by traffic, every 2s, Ctrl-C to stop",
+                "     bytes      count       live  function    caller",
+                "   4.0 kiB          1          -  malloc      load",
+                "      64 B          1          -  malloc      read",
+            ]
+        );
+        // the terminal bounds the rows
+        let lines = render(&snapshot, OrderBy::Traffic, None, Duration::from_secs(2), 4);
+        assert_eq!(lines.len(), 3);
+    }
+}
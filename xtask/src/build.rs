@@ -13,6 +13,9 @@ pub struct Options {
     /// Build and run the release target
     #[clap(long)]
     pub release: bool,
+    /// Give allocations up to 1 TiB their own histogram bucket instead of 16 GiB
+    #[clap(long)]
+    pub max_1tib: bool,
 }
 
 /// Build the project
@@ -21,6 +24,9 @@ fn build_project(opts: &Options) -> Result<(), anyhow::Error> {
     if opts.release {
         args.push("--release")
     }
+    if opts.max_1tib {
+        args.extend(["--features", "jeprofl/max-1tib"]);
+    }
     let status = Command::new("cargo")
         .args(&args)
         .env("RUSTFLAGS", "-Cforce-frame-pointers=true")
@@ -36,6 +42,7 @@ pub fn build(opts: Options) -> Result<(), anyhow::Error> {
     build_ebpf(BuildOptions {
         target: opts.bpf_target,
         release: opts.release,
+        max_1tib: opts.max_1tib,
     })
     .context("Error while building eBPF program")?;
     build_project(&opts).context("Error while building userspace application")?;
@@ -37,6 +37,9 @@ pub struct Options {
     /// Build the release target
     #[clap(long)]
     pub release: bool,
+    /// Give allocations up to 1 TiB their own histogram bucket instead of 16 GiB
+    #[clap(long)]
+    pub max_1tib: bool,
 }
 
 pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
@@ -46,6 +49,9 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
     if opts.release {
         args.push("--release")
     }
+    if opts.max_1tib {
+        args.extend(["--features", "max-1tib"]);
+    }
 
     // Command::new creates a child process which inherits all env variables. This means env
     // vars set by the cargo xtask command are also inherited. RUSTUP_TOOLCHAIN is removed
@@ -16,6 +16,9 @@ pub struct Options {
     /// Build and run the release target
     #[clap(long)]
     pub release: bool,
+    /// Give allocations up to 1 TiB their own histogram bucket instead of 16 GiB
+    #[clap(long)]
+    pub max_1tib: bool,
     /// The command used to wrap your application
     #[clap(short, long, default_value = "sudo -E")]
     pub runner: String,
@@ -30,6 +33,7 @@ pub fn run(opts: Options) -> Result<(), anyhow::Error> {
     build(BuildOptions {
         bpf_target: opts.bpf_target,
         release: opts.release,
+        max_1tib: opts.max_1tib,
     })
     .context("Error while building project")?;
 